@@ -0,0 +1,157 @@
+//! Broker ACL snippets granting a bridge's mqtt account access to exactly
+//! the topics it publishes and subscribes to, as configured
+
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::config::Config;
+use crate::control::Operation;
+
+#[derive(Error, Debug)]
+pub enum AclError {
+    #[error("Argument error: unknown ACL format '{0}'")]
+    UnknownFormat(String),
+    #[error("No mqtt username configured to grant access to")]
+    MissingUsername,
+}
+
+/// Broker whose ACL syntax is generated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AclFormat {
+    /// Entries for a Mosquitto `acl_file`
+    #[default]
+    Mosquitto,
+    /// Rules for an EMQX `acl.conf`
+    Emqx,
+}
+
+impl std::str::FromStr for AclFormat {
+    type Err = AclError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mosquitto" => Ok(Self::Mosquitto),
+            "emqx" => Ok(Self::Emqx),
+            _ => Err(AclError::UnknownFormat(s.to_owned())),
+        }
+    }
+}
+
+/// What the bridge does with a topic filter
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// Subscribes to it
+    Read,
+    /// Publishes to it
+    Write,
+    /// Both subscribes and publishes to it
+    ReadWrite,
+}
+
+/// The topic filters the bridge publishes and subscribes to with the given
+/// configuration
+pub fn topics(conf: &Config) -> anyhow::Result<Vec<(Access, String)>> {
+    let status_topic = conf.status_topic();
+    let mut topics = vec![
+        (Access::Write, status_topic.clone()),
+        (Access::Write, crate::errors::errors_topic()),
+    ];
+    if conf.control.allow.contains(&Operation::Status) {
+        topics.push((
+            Access::Write,
+            crate::control::status_dump_topic(&status_topic),
+        ));
+    }
+    if conf.update.check {
+        topics.push((Access::Write, crate::update::version_topic(&status_topic)));
+    }
+    for rule in &conf.automation {
+        if let crate::automation::Output::Topic(ref topic) = rule.output {
+            topics.push((Access::Write, topic.clone()));
+        }
+    }
+    if conf.unparsed.publish {
+        topics.push((Access::Write, conf.unparsed.get_topic()));
+    }
+    if conf.throttle.get_batch_interval().is_some() {
+        topics.push((Access::Write, crate::throttle::batch_topic()));
+    }
+    if conf.get_telemetry_interval().is_some() {
+        topics.push((Access::Write, crate::latency::telemetry_topic()));
+        topics.push((Access::Write, crate::quality::telemetry_topic()));
+        topics.push((Access::Write, crate::counters::stats_topic()));
+    }
+    // Expired maintenance windows are cleared by the bridge itself
+    topics.push((
+        Access::ReadWrite,
+        format!("{}/#", crate::control::control_topic()),
+    ));
+    // Sensor topics, and those of their measurements, availability,
+    // lifecycle and metadata, are all under the root of the topic layout
+    let root = conf.topics()?.root();
+    if root.is_empty() {
+        topics.push((Access::Write, "#".to_owned()));
+    } else {
+        topics.push((Access::Write, format!("{}/#", root)));
+    }
+    Ok(topics)
+}
+
+/// Renders an ACL granting `username` access to `topics`, and nothing else
+pub fn render(format: AclFormat, username: &str, topics: &[(Access, String)]) -> String {
+    let mut acl = String::new();
+    let unconfined = topics.iter().any(|(_, topic)| topic == "#");
+    match format {
+        AclFormat::Mosquitto => {
+            if unconfined {
+                acl.push_str(UNCONFINED_NOTE_MOSQUITTO);
+            }
+            let _ = writeln!(acl, "user {}", username);
+            for (access, topic) in topics {
+                let access = match access {
+                    Access::Read => "read",
+                    Access::Write => "write",
+                    Access::ReadWrite => "readwrite",
+                };
+                let _ = writeln!(acl, "topic {} {}", access, topic);
+            }
+        }
+        AclFormat::Emqx => {
+            if unconfined {
+                acl.push_str(UNCONFINED_NOTE_EMQX);
+            }
+            let who = format!("{{username, {:?}}}", username);
+            for (action, filter) in [("subscribe", Access::Read), ("publish", Access::Write)] {
+                let filters: Vec<String> = topics
+                    .iter()
+                    .filter(|(access, _)| *access == filter || *access == Access::ReadWrite)
+                    .map(|(_, topic)| format!("{:?}", topic))
+                    .collect();
+                if !filters.is_empty() {
+                    let _ = writeln!(
+                        acl,
+                        "{{allow, {}, {}, [{}]}}.",
+                        who,
+                        action,
+                        filters.join(", ")
+                    );
+                }
+            }
+            let _ = writeln!(acl, "{{deny, {}, all, [\"#\"]}}.", who);
+        }
+    }
+    acl
+}
+
+const UNCONFINED_NOTE_MOSQUITTO: &str = "\
+# Sensor topics start at the root, so they can't be confined; set a topic
+# prefix to publish them under a topic of their own
+";
+
+const UNCONFINED_NOTE_EMQX: &str = "\
+%% Sensor topics start at the root, so they can't be confined; set a topic
+%% prefix to publish them under a topic of their own
+";
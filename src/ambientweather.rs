@@ -3,10 +3,16 @@ use chrono::{Local, TimeZone};
 use anyhow::Result;
 use thiserror::Error;
 
+use uom::si::{angle, u16::Angle};
+use uom::si::{electric_potential, f32::ElectricPotential};
+use uom::si::{f32::Length, length};
+use uom::si::{f32::MassDensity, mass_density};
+use uom::si::{f32::Pressure, pressure};
 use uom::si::{f32::ThermodynamicTemperature, thermodynamic_temperature};
+use uom::si::{u16::Velocity, velocity};
 
 #[derive(Error, Debug)]
-pub(crate) enum MeasurementError {
+pub enum MeasurementError {
     #[error("Record root not dictionary")]
     NotDictionary,
     #[error("Record missing timestamp")]
@@ -18,7 +24,20 @@ pub(crate) enum MeasurementError {
 }
 
 // {"time" : "2021-08-15 16:13:12", "model" : "AmbientWeather-WH31E", "id" : 248, "channel" : 5, "battery_ok" : 1, "temperature_F" : 74.480, "humidity" : 54, "data" : "2200000000", "mic" : "CRC"}
-pub(crate) fn try_parse(json: &serde_json::Value) -> Result<crate::radio::Record> {
+// {"time" : "2021-08-15 16:14:05", "model" : "EcoWitt-WH40", "id" : 52591, "rain_in" : 0.862, "data" : "0000da0000", "mic" : "CRC"}
+// {"time" : "2021-10-02 09:41:27", "model" : "Fineoffset-WH45", "id" : 12345, "battery_ok" : 1, "temperature_C" : 21.300, "humidity" : 48, "pm2_5_ug_m3" : 8.600, "pm10_ug_m3" : 11.200, "co2_ppm" : 612, "mic" : "CRC"}
+// {"time" : "2021-10-04 07:12:44", "model" : "Fineoffset-WH32B", "id" : 146, "battery_ok" : 1, "temperature_F" : 70.340, "humidity" : 41, "pressure_inHg" : 29.478, "mic" : "CHECKSUM"}
+// {"time" : "2023-06-11 14:02:31", "model" : "Fineoffset-WS90", "id" : 44714, "battery_ok" : 0.860, "battery_mV" : 3180, "temperature_F" : 80.420, "humidity" : 38, "wind_dir_deg" : 212, "wind_avg_mi_h" : 4.474, "wind_max_mi_h" : 8.276, "uvi" : 6.300, "light_lux" : 84530.000, "flags" : 130, "rain_in" : 0.488, "supercap_V" : 5.300, "firmware" : 135, "data" : "3fff000000000000000000", "mic" : "CRC"}
+// {"time" : "2023-08-19 17:45:10", "model" : "Inkbird-ITH20R", "id" : 3, "temperature_1_C" : 24.600, "temperature_2_C" : 71.300, "mic" : "CRC"}
+// {"time" : "2023-07-02 12:30:08", "model" : "Fineoffset-WS68", "id" : 2517, "battery_ok" : 1, "light_lux" : 61200, "uv" : 5.100, "wind_dir_deg" : 145, "wind_avg_m_s" : 2.100, "wind_max_m_s" : 3.800, "mic" : "CRC"}
+
+// Wind speeds are held as whole m/s
+fn whole_m_s(m_s: f64) -> Velocity {
+    Velocity::new::<velocity::meter_per_second>(m_s.round().max(0.0) as u16)
+}
+
+/// Parses a json record from rtl_433 as an Ambient Weather/EcoWitt sensor
+pub fn try_parse(json: &serde_json::Value) -> Result<crate::radio::Record> {
     if let serde_json::Value::Object(m) = json {
         let timestamp: chrono::DateTime<chrono::Local> =
             if let Some(serde_json::Value::String(time)) = m.get("time") {
@@ -54,8 +73,9 @@ pub(crate) fn try_parse(json: &serde_json::Value) -> Result<crate::radio::Record
             (_, None, None) => return Err(MeasurementError::MissingSensorId.into()),
         };
         let mut measurements = Vec::new();
+        // Some decoders report a battery level between 0 and 1 instead
         if let Some(serde_json::Value::Number(b)) = m.get("battery_ok") {
-            if let Some(ok) = b.as_u64().map(|b| b != 0) {
+            if let Some(ok) = b.as_f64().map(|b| b > 0.0) {
                 measurements.push(crate::radio::Measurement::BatteryOk(ok));
             }
         }
@@ -77,16 +97,123 @@ pub(crate) fn try_parse(json: &serde_json::Value) -> Result<crate::radio::Record
                 ));
             }
         }
+        // Multi-probe devices, e.g. BBQ thermometers, suffix each probe's
+        // fields with its number
+        for (field, value) in m {
+            let (probe, unit) = match field
+                .strip_prefix("temperature_")
+                .and_then(|f| f.split_once('_'))
+                .filter(|(n, _)| n.parse::<u8>().is_ok())
+            {
+                Some(probe) => probe,
+                None => continue,
+            };
+            let temp = match (unit, value.as_f64().map(|t| t as f32)) {
+                ("F", Some(f)) => {
+                    ThermodynamicTemperature::new::<thermodynamic_temperature::degree_fahrenheit>(f)
+                }
+                ("C", Some(c)) => {
+                    ThermodynamicTemperature::new::<thermodynamic_temperature::degree_celsius>(c)
+                }
+                _ => continue,
+            };
+            measurements.push(crate::radio::Measurement::ProbeTemperature(
+                format!("Probe{}", probe),
+                temp,
+            ));
+        }
         if let Some(serde_json::Value::Number(h)) = m.get("humidity") {
             if let Some(hum) = h.as_u64().map(|h| h as u8) {
                 measurements.push(crate::radio::Measurement::RelativeHumidity(hum));
             }
         }
+        if let Some(serde_json::Value::Number(r)) = m.get("rain_mm") {
+            if let Some(rain_mm) = r.as_f64().map(|r| r as f32) {
+                let rainfall = Length::new::<length::millimeter>(rain_mm);
+                measurements.push(crate::radio::Measurement::Rainfall(rainfall));
+            }
+        }
+        if let Some(serde_json::Value::Number(r)) = m.get("rain_in") {
+            if let Some(rain_in) = r.as_f64().map(|r| r as f32) {
+                let rainfall = Length::new::<length::inch>(rain_in);
+                measurements.push(crate::radio::Measurement::Rainfall(rainfall));
+            }
+        }
+        if let Some(serde_json::Value::Number(p)) = m.get("pressure_hPa") {
+            if let Some(hpa) = p.as_f64().map(|p| p as f32) {
+                let pressure = Pressure::new::<pressure::hectopascal>(hpa);
+                measurements.push(crate::radio::Measurement::Pressure(pressure));
+            }
+        }
+        if let Some(serde_json::Value::Number(p)) = m.get("pressure_inHg") {
+            if let Some(inhg) = p.as_f64().map(|p| p as f32) {
+                let pressure = Pressure::new::<pressure::inch_of_mercury>(inhg);
+                measurements.push(crate::radio::Measurement::Pressure(pressure));
+            }
+        }
+        if let Some(serde_json::Value::Number(p)) = m.get("pm2_5_ug_m3") {
+            if let Some(pm) = p.as_f64().map(|p| p as f32) {
+                let pm = MassDensity::new::<mass_density::microgram_per_cubic_meter>(pm);
+                measurements.push(crate::radio::Measurement::Pm2_5(pm));
+            }
+        }
+        if let Some(serde_json::Value::Number(p)) = m.get("pm10_ug_m3") {
+            if let Some(pm) = p.as_f64().map(|p| p as f32) {
+                let pm = MassDensity::new::<mass_density::microgram_per_cubic_meter>(pm);
+                measurements.push(crate::radio::Measurement::Pm10(pm));
+            }
+        }
+        if let Some(serde_json::Value::Number(c)) = m.get("co2_ppm") {
+            if let Some(co2) = c.as_u64().map(|c| c as u16) {
+                measurements.push(crate::radio::Measurement::Co2(co2));
+            }
+        }
+        // Wind speeds are reported in m/s, or mi/h once rtl_433 converts
+        // them to customary units
+        for (unit, to_m_s) in [("m_s", 1.0), ("mi_h", 0.44704), ("km_h", 1.0 / 3.6)] {
+            if let Some(serde_json::Value::Number(w)) = m.get(&format!("wind_avg_{}", unit)) {
+                if let Some(speed) = w.as_f64() {
+                    let speed = whole_m_s(speed * to_m_s);
+                    measurements.push(crate::radio::Measurement::WindSpeed(speed));
+                }
+            }
+            if let Some(serde_json::Value::Number(w)) = m.get(&format!("wind_max_{}", unit)) {
+                if let Some(gust) = w.as_f64() {
+                    let gust = whole_m_s(gust * to_m_s);
+                    measurements.push(crate::radio::Measurement::WindGust(gust));
+                }
+            }
+        }
+        if let Some(serde_json::Value::Number(d)) = m.get("wind_dir_deg") {
+            if let Some(deg) = d.as_f64().map(|d| d.round() as u16) {
+                let direction = Angle::new::<angle::degree>(deg);
+                measurements.push(crate::radio::Measurement::WindDirection(direction));
+            }
+        }
+        // Decoders reporting both give the sensor's raw reading as "uv", and
+        // the index as "uvi"; others give the index as "uv"
+        if let Some(serde_json::Value::Number(u)) = m.get("uvi").or_else(|| m.get("uv")) {
+            if let Some(uvi) = u.as_f64().map(|u| u as f32) {
+                measurements.push(crate::radio::Measurement::UvIndex(uvi));
+            }
+        }
+        if let Some(serde_json::Value::Number(l)) = m.get("light_lux") {
+            if let Some(lux) = l.as_f64().map(|l| l.round().max(0.0) as u32) {
+                measurements.push(crate::radio::Measurement::Lux(lux));
+            }
+        }
+        if let Some(serde_json::Value::Number(v)) = m.get("supercap_V") {
+            if let Some(volts) = v.as_f64().map(|v| v as f32) {
+                let voltage = ElectricPotential::new::<electric_potential::volt>(volts);
+                measurements.push(crate::radio::Measurement::Voltage(voltage));
+            }
+        }
         Ok(crate::radio::Record {
             timestamp,
             sensor_id,
             record_json: json.clone(),
             measurements,
+            radio: None,
         })
     } else {
         Err(MeasurementError::NotDictionary.into())
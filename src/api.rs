@@ -0,0 +1,419 @@
+//! A small HTTP server for pulling the sensors' current state, alongside
+//! what's pushed to the broker
+//!
+//! Endpoints, all answering in json:
+//!
+//! - `GET /sensors`: every sensor heard from, with when it was last heard
+//!   from, its quality score, and its configured name, location and tags
+//! - `GET /sensors/<id>/latest`: the sensor's latest record, as a normalized
+//!   payload; ids containing `/` may be given as is or percent-encoded, and
+//!   sensors with a name may be given by it instead
+//! - `GET /health`: whether records are coming in, answering 503 when none
+//!   have for the sensor timeout, when rtl_433 has reported a fault within
+//!   it, or once it's reported a fatal one
+//! - `GET /metrics`: counters of the faults rtl_433 has reported, by radio,
+//!   and of the records none of the parsers made sense of, in the Prometheus
+//!   text format
+//!
+//! Each connection is answered on its own thread, up to a few at a time,
+//! with its request's line and headers limited in size and the whole
+//! exchange in time, so a slow or oversized client can't hold up the
+//! others. The server is still meant for the occasional dashboard or script
+//! rather than heavy traffic.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+
+use crate::config::SensorInfo;
+use crate::errors::{ErrorCode, ErrorEvent};
+use crate::events::Event;
+use crate::faults::Fault;
+use crate::quality::QualityTracker;
+use crate::units::UnitSystem;
+
+// Seconds a client gets to send its request and take the response before
+// being dropped
+const REQUEST_TIMEOUT_SECS: u64 = 5;
+
+// Bytes of request line and headers read before the request is refused
+const MAX_REQUEST_BYTES: u64 = 8 * 1024;
+
+// Connections answered at once; those beyond it are refused
+const MAX_CONNECTIONS: usize = 8;
+
+#[derive(Debug, Default)]
+struct Latest {
+    records: BTreeMap<String, (DateTime<Local>, serde_json::Value)>,
+    last_record: Option<DateTime<Local>>,
+    // Faults reported, by radio and fault
+    faults: BTreeMap<(String, Fault), u64>,
+    last_fault: Option<ErrorEvent>,
+    // Whether a fatal fault has been reported
+    failed: bool,
+    // Records none of the parsers made sense of
+    unparsed: u64,
+}
+
+/// The state the server answers from. Cloning the state produces another
+/// handle to the same records.
+#[derive(Clone, Debug)]
+pub struct ApiState {
+    latest: Arc<Mutex<Latest>>,
+    quality: QualityTracker,
+    // What's configured of the sensors, by sensor id
+    sensors: Arc<BTreeMap<String, SensorInfo>>,
+    include_raw: bool,
+    include_lineage: bool,
+    sensor_timeout: Duration,
+    started: DateTime<Local>,
+    up_since: Instant,
+}
+
+impl ApiState {
+    pub fn new(
+        quality: QualityTracker,
+        sensors: BTreeMap<String, SensorInfo>,
+        include_raw: bool,
+        include_lineage: bool,
+        sensor_timeout: Duration,
+    ) -> Self {
+        ApiState {
+            latest: Arc::new(Mutex::new(Latest::default())),
+            quality,
+            sensors: Arc::new(sensors),
+            include_raw,
+            include_lineage,
+            sensor_timeout,
+            started: crate::clock::now(),
+            up_since: Instant::now(),
+        }
+    }
+
+    /// Keeps a processed record as its sensor's latest
+    pub fn observe(&self, record: &crate::radio::Record) {
+        let payload = crate::normalize::normalize(
+            record,
+            UnitSystem::Metric,
+            self.include_raw,
+            self.include_lineage,
+        );
+        let mut latest = self.latest.lock().expect("api state poisoned");
+        latest
+            .records
+            .insert(record.sensor_id.clone(), (record.timestamp, payload));
+        latest.last_record = Some(crate::clock::now());
+    }
+
+    /// Counts a fault rtl_433 reported, as reported on the event bus
+    pub fn fault(&self, error: &ErrorEvent) {
+        let fault = match error.context.get("fault").map(|f| f.parse::<Fault>()) {
+            Some(Ok(fault)) => fault,
+            _ => return,
+        };
+        let radio = error
+            .context
+            .get("radio")
+            .cloned()
+            .unwrap_or_else(|| "default".to_owned());
+        let mut latest = self.latest.lock().expect("api state poisoned");
+        *latest.faults.entry((radio, fault)).or_default() += 1;
+        latest.failed |= fault.is_fatal();
+        latest.last_fault = Some(error.clone());
+    }
+
+    /// Counts a record none of the parsers made sense of
+    pub fn unparsed(&self) {
+        self.latest.lock().expect("api state poisoned").unparsed += 1;
+    }
+
+    fn sensors(&self) -> serde_json::Value {
+        let latest = self.latest.lock().expect("api state poisoned");
+        let sensors: serde_json::Map<String, serde_json::Value> = latest
+            .records
+            .iter()
+            .map(|(sensor_id, (timestamp, _))| {
+                let quality = self.quality.score(sensor_id).map(|q| q.score);
+                let mut sensor = serde_json::json!({ "last_seen": timestamp, "quality": quality });
+                if let Some(info) = self.sensors.get(sensor_id) {
+                    if let Some(ref name) = info.name {
+                        sensor["name"] = name.as_str().into();
+                    }
+                    if let Some(ref location) = info.location {
+                        sensor["location"] = location.as_str().into();
+                    }
+                    if !info.tags.is_empty() {
+                        sensor["tags"] = serde_json::json!(info.tags);
+                    }
+                }
+                (sensor_id.clone(), sensor)
+            })
+            .collect();
+        sensors.into()
+    }
+
+    fn latest(&self, sensor_id: &str) -> Option<serde_json::Value> {
+        let latest = self.latest.lock().expect("api state poisoned");
+        // Ids take precedence over names
+        let named = || {
+            self.sensors
+                .iter()
+                .find(|(_, info)| info.name.as_deref() == Some(sensor_id))
+                .and_then(|(sensor_id, _)| latest.records.get(sensor_id))
+        };
+        latest
+            .records
+            .get(sensor_id)
+            .or_else(named)
+            .map(|(_, payload)| payload.clone())
+    }
+
+    fn health(&self) -> (bool, serde_json::Value) {
+        let latest = self.latest.lock().expect("api state poisoned");
+        let now = crate::clock::now();
+        let since = latest.last_record.unwrap_or(self.started);
+        let recent =
+            |t: DateTime<Local>| (now - t).to_std().unwrap_or_default() <= self.sensor_timeout;
+        let faulty = latest.last_fault.as_ref().is_some_and(|e| recent(e.time));
+        let status = match (latest.failed, faulty, recent(since)) {
+            (true, _, _) => "radio_failed",
+            (false, true, _) => "radio_faults",
+            (false, false, false) => "no_records",
+            (false, false, true) => "ok",
+        };
+        let health = serde_json::json!({
+            "status": status,
+            "version": clap::crate_version!(),
+            "uptime_secs": self.up_since.elapsed().as_secs(),
+            "sensors": latest.records.len(),
+            "last_record": latest.last_record,
+            "radio_faults": latest.faults.values().sum::<u64>(),
+            "last_fault": latest.last_fault,
+        });
+        (status == "ok", health)
+    }
+
+    fn metrics(&self) -> String {
+        let latest = self.latest.lock().expect("api state poisoned");
+        let name = format!("{}_rtl433_faults_total", clap::crate_name!());
+        let mut metrics = format!(
+            "# HELP {0} Failures rtl_433 reported on its stderr\n# TYPE {0} counter\n",
+            name
+        );
+        for ((radio, fault), count) in &latest.faults {
+            metrics += &format!(
+                "{}{{radio=\"{}\",fault=\"{}\"}} {}\n",
+                name,
+                radio.replace('\\', "\\\\").replace('"', "\\\""),
+                fault,
+                count
+            );
+        }
+        let name = format!("{}_unparsed_records_total", clap::crate_name!());
+        metrics += &format!(
+            "# HELP {0} Records from rtl_433 none of the parsers made sense of\n# TYPE {0} counter\n{0} {1}\n",
+            name, latest.unparsed
+        );
+        metrics
+    }
+}
+
+// Decodes the percent-encoding of a path segment
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// The status and json body answering a request for `path`
+fn route(state: &ApiState, path: &str) -> (u16, serde_json::Value) {
+    let path = path.split('?').next().unwrap_or_default();
+    let not_found = || (404, serde_json::json!({ "error": "not found" }));
+    match path.trim_end_matches('/') {
+        "/sensors" => (200, state.sensors()),
+        "/health" => match state.health() {
+            (true, health) => (200, health),
+            (false, health) => (503, health),
+        },
+        path => match path
+            .strip_prefix("/sensors/")
+            .and_then(|rest| rest.strip_suffix("/latest"))
+        {
+            Some(sensor_id) => match state.latest(&decode(sensor_id)) {
+                Some(payload) => (200, payload),
+                None => not_found(),
+            },
+            None => not_found(),
+        },
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        431 => "Request Header Fields Too Large",
+        _ => "Service Unavailable",
+    }
+}
+
+// The connection, reading and writing with whatever's left of the time
+// given to the exchange
+struct Deadline {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Deadline {
+    fn remaining(&self) -> std::io::Result<Duration> {
+        match self.deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) if !remaining.is_zero() => Ok(remaining),
+            _ => Err(std::io::ErrorKind::TimedOut.into()),
+        }
+    }
+}
+
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.remaining()?))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Deadline {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.remaining()?))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn respond(state: &ApiState, stream: TcpStream) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(REQUEST_TIMEOUT_SECS);
+    let connection = |stream| Deadline { stream, deadline };
+    let mut reader = BufReader::new(connection(stream.try_clone()?).take(MAX_REQUEST_BYTES));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // The headers don't matter, but are read so the client isn't cut off
+    // mid-request
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    // Whether the request was read before running into the size limit
+    let complete = reader.get_ref().limit() > 0;
+    let mut parts = request.split_whitespace();
+    let json = |(status, body): (u16, serde_json::Value)| {
+        serde_json::to_vec(&body).map(|body| (status, "application/json", body))
+    };
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        _ if !complete => json((431, serde_json::json!({ "error": "request too large" })))?,
+        (Some("GET") | Some("HEAD"), Some(path)) if path.split('?').next() == Some("/metrics") => (
+            200,
+            "text/plain; version=0.0.4",
+            state.metrics().into_bytes(),
+        ),
+        (Some("GET") | Some("HEAD"), Some(path)) => json(route(state, path))?,
+        (Some(_), Some(_)) => json((405, serde_json::json!({ "error": "method not allowed" })))?,
+        _ => json((400, serde_json::json!({ "error": "bad request" })))?,
+    };
+    log::debug!("http {} ==> {}", request.trim(), status);
+    let mut stream = connection(stream);
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason(status),
+        content_type,
+        body.len()
+    )?;
+    if !request.starts_with("HEAD") {
+        stream.write_all(&body)?;
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+/// Starts serving the state on `listen`, an address like `127.0.0.1:8080`,
+/// and a background thread keeping it up to date with the records and
+/// faults on `events` until the radio stops. The server itself runs until the process
+/// exits.
+pub fn spawn_server(
+    listen: &str,
+    state: ApiState,
+    events: Receiver<Event>,
+) -> Result<JoinHandle<()>> {
+    let listener =
+        TcpListener::bind(listen).with_context(|| format!("Failed to listen on {}", listen))?;
+    log::info!("Serving sensor state on http://{}", listener.local_addr()?);
+    let server = state.clone();
+    std::thread::spawn(move || {
+        let connections = Arc::new(AtomicUsize::new(0));
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("Failed to accept http connection: {}", e);
+                    continue;
+                }
+            };
+            if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                connections.fetch_sub(1, Ordering::SeqCst);
+                log::warn!(
+                    "Refused http connection from {:?}, already answering {}",
+                    stream.peer_addr(),
+                    MAX_CONNECTIONS
+                );
+                continue;
+            }
+            let (server, connections) = (server.clone(), connections.clone());
+            std::thread::spawn(move || {
+                if let Err(e) = respond(&server, stream) {
+                    log::warn!("Failed to answer http request: {:#}", e);
+                }
+                connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+    Ok(std::thread::spawn(move || {
+        for event in events {
+            match event {
+                Event::Record(record) => state.observe(&record),
+                Event::Error(error) if error.code == ErrorCode::RadioFault => state.fault(&error),
+                Event::Unparsed(_) => state.unparsed(),
+                Event::RadioStopped => return,
+                _ => {}
+            }
+        }
+    }))
+}
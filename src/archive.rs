@@ -0,0 +1,243 @@
+//! An archive of the records published, as newline-delimited json files,
+//! for keeping the raw data to replay later or import into a database
+//!
+//! Each record is appended to the file of the day it was received, or of
+//! the hour with `"rotation": "hourly"`, named like
+//! `records-2024-01-15.ndjson` or `records-2024-01-15T13.ndjson`, as its
+//! normalized payload, along with the record as rtl_433 reported it when
+//! `include_raw` is set, which `replay` needs to read the archive. A file is
+//! finished once records start going to the next one, and with `compress`,
+//! finished files are compressed by running `gzip` on them. With
+//! `retention_days`, files from before that many days ago are deleted as the
+//! archive moves on to a new file, and when the bridge starts.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::thread::JoinHandle;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{ErrorCode, ErrorEvent};
+use crate::events::{Event, EventBus};
+use crate::radio::Record;
+use crate::units::UnitSystem;
+
+const PREFIX: &str = "records-";
+const EXTENSION: &str = ".ndjson";
+const COMPRESSED_EXTENSION: &str = ".ndjson.gz";
+
+/// How often the archive starts a new file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    #[default]
+    Daily,
+    Hourly,
+}
+
+impl Rotation {
+    // The part of a file's name saying which day or hour it's of
+    fn stamp(&self, time: DateTime<Local>) -> String {
+        match self {
+            Rotation::Daily => time.format("%Y-%m-%d").to_string(),
+            Rotation::Hourly => time.format("%Y-%m-%dT%H").to_string(),
+        }
+    }
+}
+
+/// Archiving the records published to files
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ArchiveConfig {
+    /// Directory the files are kept in, created if it doesn't exist
+    pub directory: PathBuf,
+    #[serde(default)]
+    pub rotation: Rotation,
+    /// Whether finished files are compressed with `gzip`
+    #[serde(default)]
+    pub compress: bool,
+    /// Days files are kept for; they're kept forever when unset
+    #[serde(default)]
+    pub retention_days: Option<u64>,
+    /// Whether each record is archived along with the record as rtl_433
+    /// reported it, so that the archive can be replayed
+    #[serde(default)]
+    pub include_raw: bool,
+}
+
+impl ArchiveConfig {
+    pub fn new(directory: PathBuf) -> Self {
+        ArchiveConfig {
+            directory,
+            rotation: Rotation::default(),
+            compress: false,
+            retention_days: None,
+            include_raw: false,
+        }
+    }
+}
+
+// The stamp of an archive file's name, and whether it's compressed
+fn parse_name(name: &str) -> Option<(&str, bool)> {
+    let stamp = name.strip_prefix(PREFIX)?;
+    match stamp.strip_suffix(COMPRESSED_EXTENSION) {
+        Some(stamp) => Some((stamp, true)),
+        None => Some((stamp.strip_suffix(EXTENSION)?, false)),
+    }
+}
+
+/// Files of records, one per day or hour
+#[derive(Debug)]
+pub struct Archive {
+    conf: ArchiveConfig,
+    // The file being written, and its stamp
+    current: Option<(String, File)>,
+}
+
+impl Archive {
+    /// Opens the archive in the configured directory, finishing the files
+    /// left from before now
+    pub fn open(conf: ArchiveConfig) -> Result<Self> {
+        std::fs::create_dir_all(&conf.directory).with_context(|| {
+            format!(
+                "Failed to create archive directory {}",
+                conf.directory.display()
+            )
+        })?;
+        let archive = Archive {
+            conf,
+            current: None,
+        };
+        let now = crate::clock::now();
+        archive.tidy(now, &archive.conf.rotation.stamp(now))?;
+        Ok(archive)
+    }
+
+    fn path(&self, stamp: &str) -> PathBuf {
+        self.conf
+            .directory
+            .join(format!("{}{}{}", PREFIX, stamp, EXTENSION))
+    }
+
+    /// Appends a record to the file of its day or hour
+    pub fn append(&mut self, record: &Record) -> Result<()> {
+        let stamp = self.conf.rotation.stamp(record.timestamp);
+        // A record from just before the one before it stays in the current
+        // file, rather than going back to one that may have been compressed
+        let rotate = match self.current {
+            Some((ref current, _)) => stamp > *current,
+            None => true,
+        };
+        if rotate {
+            self.current = None;
+            self.tidy(record.timestamp, &stamp)?;
+            let path = self.path(&stamp);
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("Failed to open archive file {}", path.display()))?;
+            log::debug!("Archiving records to {}", path.display());
+            self.current = Some((stamp, file));
+        }
+        let payload =
+            crate::normalize::normalize(record, UnitSystem::Metric, self.conf.include_raw, false);
+        let mut line = serde_json::to_vec(&payload)?;
+        line.push(b'\n');
+        if let Some((_, ref mut file)) = self.current {
+            file.write_all(&line)?;
+        }
+        Ok(())
+    }
+
+    // Deletes the files from before the retention period, and compresses
+    // those other than `current`'s, as of `now`
+    fn tidy(&self, now: DateTime<Local>, current: &str) -> Result<()> {
+        let oldest = self
+            .conf
+            .retention_days
+            .map(|days| now.date_naive() - chrono::Duration::days(days as i64));
+        let entries = std::fs::read_dir(&self.conf.directory).with_context(|| {
+            format!(
+                "Failed to read archive directory {}",
+                self.conf.directory.display()
+            )
+        })?;
+        for entry in entries {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default();
+            let (stamp, compressed) = match parse_name(name) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            let date = stamp
+                .get(..10)
+                .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+            if let (Some(date), Some(oldest)) = (date, oldest) {
+                if date < oldest {
+                    log::info!("Deleting archive file {} past retention", path.display());
+                    std::fs::remove_file(&path)
+                        .with_context(|| format!("Failed to delete {}", path.display()))?;
+                    continue;
+                }
+            }
+            if self.conf.compress && !compressed && stamp != current {
+                compress(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Compresses a finished file with gzip, which replaces it with the
+// compressed file
+fn compress(path: &Path) -> Result<()> {
+    log::debug!("Compressing archive file {}", path.display());
+    let output = std::process::Command::new("gzip")
+        .arg("--force")
+        .arg(path)
+        .output()
+        .with_context(|| "Unable to run gzip")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "Failed to compress {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Starts a background thread archiving every record published on the
+/// event bus until the radio stops, reporting failures on `errors`
+pub fn spawn_archiver(
+    conf: ArchiveConfig,
+    events: Receiver<Event>,
+    errors: EventBus,
+) -> Result<JoinHandle<()>> {
+    let mut archive = Archive::open(conf)?;
+    Ok(std::thread::spawn(move || {
+        for event in events {
+            let record = match event {
+                Event::Record(record) => record,
+                Event::RadioStopped => return,
+                _ => continue,
+            };
+            // Like the history, the archive shouldn't take the sink down
+            // with it
+            if let Err(e) = archive.append(&record) {
+                log::error!("Failed to archive record: {:#}", e);
+                errors.publish(Event::Error(
+                    ErrorEvent::new(ErrorCode::Archive, format!("{:#}", e))
+                        .context("sensor_id", &record.sensor_id),
+                ));
+            }
+        }
+    }))
+}
@@ -0,0 +1,492 @@
+//! Normalized records served as an [Apache Arrow IPC
+//! stream](https://arrow.apache.org/docs/format/Columnar.html#ipc-streaming-format),
+//! for analysis clients to read as columnar data without parsing json
+//!
+//! Records are served in long form, a row per measurement, with the
+//! columns `ts` (a UTC timestamp in milliseconds), `sensor`, `measurement`
+//! (its name in normalized payloads, e.g. `temperature_c`) and `value` (as
+//! in normalized payloads, with booleans as 0 and 1). A client connecting
+//! is sent the schema, a batch of the rows of the last few minutes, and then
+//! a batch for every record as it's received, until the bridge stops and
+//! ends the stream, e.g. with `pyarrow.ipc.open_stream(socket.makefile("rb"))`.
+//!
+//! The stream is written by hand, rather than with the arrow crates, as it
+//! only ever holds the four columns; Arrow Flight would take a gRPC stack.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+
+use crate::events::Event;
+use crate::radio::Record;
+
+// How long a client may take to accept a batch before it's dropped, so that
+// a stalled client doesn't hold up the others
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Marks the start of each message, and with a zero length the end of the
+// stream
+const CONTINUATION: u32 = 0xffff_ffff;
+
+// Values of the enums and unions of the Arrow flatbuffers schema
+const METADATA_V5: i16 = 4;
+const HEADER_SCHEMA: u8 = 1;
+const HEADER_RECORD_BATCH: u8 = 3;
+const TYPE_FLOATING_POINT: u8 = 3;
+const TYPE_UTF8: u8 = 5;
+const TYPE_TIMESTAMP: u8 = 10;
+const PRECISION_DOUBLE: i16 = 2;
+const TIME_UNIT_MILLISECOND: i16 = 1;
+
+/// A measurement of a record, as a row of the stream
+#[derive(Clone, Debug, PartialEq)]
+pub struct Row {
+    pub ts: DateTime<Local>,
+    pub sensor: String,
+    pub measurement: String,
+    pub value: f64,
+}
+
+/// The rows of a record's measurements, with the names and values of
+/// normalized payloads
+pub fn rows(record: &Record) -> Vec<Row> {
+    let payload =
+        crate::normalize::normalize(record, crate::units::UnitSystem::Metric, false, false);
+    let measurements = match payload["measurements"].as_object() {
+        Some(measurements) => measurements,
+        None => return Vec::new(),
+    };
+    measurements
+        .iter()
+        .filter_map(|(name, value)| {
+            let value = match value {
+                serde_json::Value::Bool(b) => f64::from(u8::from(*b)),
+                value => value.as_f64()?,
+            };
+            Some(Row {
+                ts: record.timestamp,
+                sensor: record.sensor_id.clone(),
+                measurement: name.clone(),
+                value,
+            })
+        })
+        .collect()
+}
+
+// A flatbuffer object, laid out with each object before those it refers to
+enum Fb {
+    // Fields of a table, by their slot in the schema
+    Table(Vec<(u16, Scalar)>),
+    Str(String),
+    // Vector of structs of 8 byte alignment, and their count
+    Structs(Vec<u8>, u32),
+    Tables(Vec<Fb>),
+}
+
+enum Scalar {
+    U8(u8),
+    Bool(bool),
+    I16(i16),
+    I64(i64),
+    Offset(Fb),
+}
+
+impl Scalar {
+    fn size(&self) -> usize {
+        match self {
+            Scalar::U8(_) | Scalar::Bool(_) => 1,
+            Scalar::I16(_) => 2,
+            Scalar::Offset(_) => 4,
+            Scalar::I64(_) => 8,
+        }
+    }
+}
+
+fn pad(buf: &mut Vec<u8>, align: usize) {
+    let len = buf.len().next_multiple_of(align);
+    buf.resize(len, 0);
+}
+
+fn patch_offset(buf: &mut [u8], at: usize, target: usize) {
+    buf[at..at + 4].copy_from_slice(&((target - at) as u32).to_le_bytes());
+}
+
+// Writes an object, returning where offsets to it point
+fn write_fb(buf: &mut Vec<u8>, fb: &Fb) -> usize {
+    match fb {
+        Fb::Str(s) => {
+            pad(buf, 4);
+            let at = buf.len();
+            buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            buf.extend_from_slice(s.as_bytes());
+            buf.push(0);
+            at
+        }
+        Fb::Structs(bytes, count) => {
+            // The structs after the length are 8 byte aligned
+            let len = (buf.len() + 4).next_multiple_of(8) - 4;
+            buf.resize(len, 0);
+            let at = buf.len();
+            buf.extend_from_slice(&count.to_le_bytes());
+            buf.extend_from_slice(bytes);
+            at
+        }
+        Fb::Tables(tables) => {
+            pad(buf, 4);
+            let at = buf.len();
+            buf.extend_from_slice(&(tables.len() as u32).to_le_bytes());
+            let slots = buf.len();
+            buf.resize(slots + 4 * tables.len(), 0);
+            for (i, table) in tables.iter().enumerate() {
+                let target = write_fb(buf, table);
+                patch_offset(buf, slots + 4 * i, target);
+            }
+            at
+        }
+        Fb::Table(fields) => {
+            // Inline fields follow the offset to the vtable, each aligned to
+            // its size
+            let mut layout = Vec::with_capacity(fields.len());
+            let mut size: usize = 4;
+            for (_, scalar) in fields {
+                size = size.div_ceil(scalar.size()) * scalar.size();
+                layout.push(size);
+                size += scalar.size();
+            }
+            let slots = fields.iter().map(|(slot, _)| slot + 1).max().unwrap_or(0);
+            pad(buf, 2);
+            let vtable = buf.len();
+            buf.extend_from_slice(&(4 + 2 * slots).to_le_bytes());
+            buf.extend_from_slice(&(size as u16).to_le_bytes());
+            for slot in 0..slots {
+                let offset = fields
+                    .iter()
+                    .zip(&layout)
+                    .find(|((s, _), _)| *s == slot)
+                    .map_or(0, |(_, offset)| *offset as u16);
+                buf.extend_from_slice(&offset.to_le_bytes());
+            }
+            pad(buf, 8);
+            let table = buf.len();
+            buf.extend_from_slice(&((table - vtable) as i32).to_le_bytes());
+            buf.resize(table + size, 0);
+            for ((_, scalar), offset) in fields.iter().zip(&layout) {
+                let at = table + offset;
+                match scalar {
+                    Scalar::U8(v) => buf[at] = *v,
+                    Scalar::Bool(v) => buf[at] = u8::from(*v),
+                    Scalar::I16(v) => buf[at..at + 2].copy_from_slice(&v.to_le_bytes()),
+                    Scalar::I64(v) => buf[at..at + 8].copy_from_slice(&v.to_le_bytes()),
+                    Scalar::Offset(_) => {}
+                }
+            }
+            for ((_, scalar), offset) in fields.iter().zip(&layout) {
+                if let Scalar::Offset(fb) = scalar {
+                    let target = write_fb(buf, fb);
+                    patch_offset(buf, table + offset, target);
+                }
+            }
+            table
+        }
+    }
+}
+
+// Frames a message with its flatbuffer metadata and body
+fn message(header_type: u8, header: Fb, body: &[u8]) -> Vec<u8> {
+    let root = Fb::Table(vec![
+        (0, Scalar::I16(METADATA_V5)),
+        (1, Scalar::U8(header_type)),
+        (2, Scalar::Offset(header)),
+        (3, Scalar::I64(body.len() as i64)),
+    ]);
+    let mut metadata = vec![0; 4];
+    let at = write_fb(&mut metadata, &root);
+    patch_offset(&mut metadata, 0, at);
+    pad(&mut metadata, 8);
+    let mut message = Vec::with_capacity(8 + metadata.len() + body.len());
+    message.extend_from_slice(&CONTINUATION.to_le_bytes());
+    message.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+    message.extend_from_slice(&metadata);
+    message.extend_from_slice(body);
+    message
+}
+
+fn field(name: &str, type_type: u8, type_table: Fb) -> Fb {
+    Fb::Table(vec![
+        (0, Scalar::Offset(Fb::Str(name.to_owned()))),
+        (1, Scalar::Bool(false)),
+        (2, Scalar::U8(type_type)),
+        (3, Scalar::Offset(type_table)),
+        (5, Scalar::Offset(Fb::Tables(Vec::new()))),
+    ])
+}
+
+/// The schema message starting the stream
+pub fn schema() -> Vec<u8> {
+    let timestamp = Fb::Table(vec![
+        (0, Scalar::I16(TIME_UNIT_MILLISECOND)),
+        (1, Scalar::Offset(Fb::Str("UTC".to_owned()))),
+    ]);
+    let double = Fb::Table(vec![(0, Scalar::I16(PRECISION_DOUBLE))]);
+    let fields = vec![
+        field("ts", TYPE_TIMESTAMP, timestamp),
+        field("sensor", TYPE_UTF8, Fb::Table(Vec::new())),
+        field("measurement", TYPE_UTF8, Fb::Table(Vec::new())),
+        field("value", TYPE_FLOATING_POINT, double),
+    ];
+    let schema = Fb::Table(vec![(1, Scalar::Offset(Fb::Tables(fields)))]);
+    message(HEADER_SCHEMA, schema, &[])
+}
+
+/// The message ending the stream
+pub fn end_of_stream() -> Vec<u8> {
+    [CONTINUATION.to_le_bytes(), 0u32.to_le_bytes()].concat()
+}
+
+// Body of a record batch, and the offset and length of each of its buffers
+#[derive(Default)]
+struct Body {
+    bytes: Vec<u8>,
+    buffers: Vec<u8>,
+}
+
+impl Body {
+    fn buffer(&mut self, data: &[u8]) {
+        self.buffers
+            .extend_from_slice(&(self.bytes.len() as i64).to_le_bytes());
+        self.buffers
+            .extend_from_slice(&(data.len() as i64).to_le_bytes());
+        self.bytes.extend_from_slice(data);
+        pad(&mut self.bytes, 8);
+    }
+
+    // A column without nulls, whose validity bitmap is left out
+    fn column(&mut self, buffers: &[&[u8]]) {
+        self.buffer(&[]);
+        for data in buffers {
+            self.buffer(data);
+        }
+    }
+
+    fn strings<'a>(&mut self, strings: impl Iterator<Item = &'a str>) {
+        let mut offsets = 0i32.to_le_bytes().to_vec();
+        let mut data = Vec::new();
+        for s in strings {
+            data.extend_from_slice(s.as_bytes());
+            offsets.extend_from_slice(&(data.len() as i32).to_le_bytes());
+        }
+        self.column(&[&offsets, &data]);
+    }
+}
+
+/// A record batch message holding `rows`
+pub fn record_batch(rows: &[Row]) -> Vec<u8> {
+    let mut body = Body::default();
+    let ts: Vec<u8> = rows
+        .iter()
+        .flat_map(|row| row.ts.timestamp_millis().to_le_bytes())
+        .collect();
+    body.column(&[&ts]);
+    body.strings(rows.iter().map(|row| row.sensor.as_str()));
+    body.strings(rows.iter().map(|row| row.measurement.as_str()));
+    let values: Vec<u8> = rows
+        .iter()
+        .flat_map(|row| row.value.to_le_bytes())
+        .collect();
+    body.column(&[&values]);
+    let columns = 4;
+    let nodes: Vec<u8> = (0..columns)
+        .flat_map(|_| [(rows.len() as i64).to_le_bytes(), 0i64.to_le_bytes()].concat())
+        .collect();
+    let buffers = body.buffers.len() / 16;
+    let batch = Fb::Table(vec![
+        (0, Scalar::I64(rows.len() as i64)),
+        (1, Scalar::Offset(Fb::Structs(nodes, columns))),
+        (2, Scalar::Offset(Fb::Structs(body.buffers, buffers as u32))),
+    ]);
+    message(HEADER_RECORD_BATCH, batch, &body.bytes)
+}
+
+// The rows of the window, and the clients streaming them
+struct Shared {
+    window: chrono::Duration,
+    recent: VecDeque<Row>,
+    clients: Vec<(TcpStream, std::net::SocketAddr)>,
+}
+
+impl Shared {
+    // Sends a message to every client, dropping those it can't be sent to
+    fn send(&mut self, message: &[u8]) {
+        self.clients.retain_mut(|(stream, peer)| {
+            match stream.write_all(message).and_then(|_| stream.flush()) {
+                Ok(()) => true,
+                Err(e) => {
+                    log::info!("Arrow stream client {} dropped: {}", peer, e);
+                    false
+                }
+            }
+        });
+    }
+}
+
+// Starts a client's stream with the schema and the window's rows
+fn connect(shared: &Mutex<Shared>, mut stream: TcpStream) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    let mut shared = shared.lock().expect("arrow stream state poisoned");
+    stream.write_all(&schema())?;
+    if !shared.recent.is_empty() {
+        let recent: Vec<Row> = shared.recent.iter().cloned().collect();
+        stream.write_all(&record_batch(&recent))?;
+    }
+    stream.flush()?;
+    log::info!(
+        "Arrow stream client {} connected, sent {} recent rows",
+        peer,
+        shared.recent.len()
+    );
+    shared.clients.push((stream, peer));
+    Ok(())
+}
+
+/// Starts serving the stream on `listen`, with the rows of the last
+/// `window` of records, and appending those of records on `events` until
+/// the radio stops
+pub fn spawn_server(
+    listen: &str,
+    window: Duration,
+    events: Receiver<Event>,
+) -> Result<JoinHandle<()>> {
+    let listener =
+        TcpListener::bind(listen).with_context(|| format!("Failed to listen on {}", listen))?;
+    log::info!(
+        "Serving records as an Arrow stream on {}",
+        listener.local_addr()?
+    );
+    let shared = Arc::new(Mutex::new(Shared {
+        window: chrono::Duration::from_std(window).unwrap_or(chrono::Duration::MAX),
+        recent: VecDeque::new(),
+        clients: Vec::new(),
+    }));
+    let server = shared.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream
+                .map_err(anyhow::Error::from)
+                .and_then(|stream| connect(&server, stream));
+            if let Err(e) = result {
+                log::warn!("Failed to start Arrow stream: {:#}", e);
+            }
+        }
+    });
+    Ok(std::thread::spawn(move || {
+        for event in events {
+            let record = match event {
+                Event::Record(record) => record,
+                Event::RadioStopped => break,
+                _ => continue,
+            };
+            let rows = rows(&record);
+            if rows.is_empty() {
+                continue;
+            }
+            let mut shared = shared.lock().expect("arrow stream state poisoned");
+            let since = record.timestamp - shared.window;
+            while shared.recent.front().is_some_and(|row| row.ts < since) {
+                shared.recent.pop_front();
+            }
+            shared.recent.extend(rows.iter().cloned());
+            if !shared.clients.is_empty() {
+                shared.send(&record_batch(&rows));
+            }
+        }
+        let mut shared = shared.lock().expect("arrow stream state poisoned");
+        shared.send(&end_of_stream());
+        for (stream, _) in shared.clients.drain(..) {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Float64Type, TimestampMillisecondType};
+    use arrow_ipc::reader::StreamReader;
+    use arrow_schema::{DataType, TimeUnit};
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn stream_round_trips() {
+        let ts = |ms| Local.timestamp_millis_opt(ms).unwrap();
+        let rows = vec![
+            Row {
+                ts: ts(1_705_320_000_000),
+                sensor: "AmbientWeather-WH31E/2".to_owned(),
+                measurement: "temperature_c".to_owned(),
+                value: 21.5,
+            },
+            Row {
+                ts: ts(1_705_320_000_123),
+                sensor: "Acme/1".to_owned(),
+                measurement: "humidity".to_owned(),
+                value: 40.0,
+            },
+        ];
+        let stream = [schema(), record_batch(&rows), end_of_stream()].concat();
+        let reader = StreamReader::try_new(Cursor::new(stream), None).unwrap();
+
+        let schema = reader.schema();
+        let columns: Vec<(&str, &DataType, bool)> = schema
+            .fields()
+            .iter()
+            .map(|field| {
+                (
+                    field.name().as_str(),
+                    field.data_type(),
+                    field.is_nullable(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            columns,
+            [
+                (
+                    "ts",
+                    &DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+                    false
+                ),
+                ("sensor", &DataType::Utf8, false),
+                ("measurement", &DataType::Utf8, false),
+                ("value", &DataType::Float64, false),
+            ]
+        );
+
+        let batches: Vec<_> = reader.map(Result::unwrap).collect();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), rows.len());
+        let ts = batch.column(0).as_primitive::<TimestampMillisecondType>();
+        let sensor = batch.column(1).as_string::<i32>();
+        let measurement = batch.column(2).as_string::<i32>();
+        let value = batch.column(3).as_primitive::<Float64Type>();
+        for (i, row) in rows.iter().enumerate() {
+            assert_eq!(ts.value(i), row.ts.timestamp_millis());
+            assert_eq!(sensor.value(i), row.sensor);
+            assert_eq!(measurement.value(i), row.measurement);
+            assert_eq!(value.value(i), row.value);
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! Signed digests of the records published, so that consumers behind
+//! intermediary brokers can tell when records went missing or were altered
+//! on the way
+//!
+//! Every record published to its sensor's topic, or in a batch, extends a
+//! hash chain: the chain's hash becomes the SHA-256 of the previous hash,
+//! the record's topic, a zero byte, and its payload, starting from 32 zero
+//! bytes. Derived measurements, availability and the like, published apart
+//! from the records, aren't covered. Every interval, a digest is
+//! published to `weatherradio/audit` with how many records were published
+//! over it and the chain's hash before and after them, signed with
+//! HMAC-SHA256 over `<seq>:<from>:<until>:<count>:<first>:<last>`, with the
+//! times as published, using the configured key.
+//! A consumer following the chain over the records it received, from one
+//! digest's `last` to the next's, ends up on the same hash only if it
+//! received every record unaltered and in order, and a gap in `seq` means a
+//! digest itself went missing.
+//!
+//! Records are added to the chain as the bridge publishes them, before
+//! they're handed to the broker connection, so records lost because
+//! publishing failed show up as missing too.
+
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local, SecondsFormat};
+use clap::crate_name;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::sink::MqttSink;
+
+/// Seconds between digests, unless configured otherwise
+pub const DEFAULT_INTERVAL: u64 = 60;
+
+fn default_interval() -> u64 {
+    DEFAULT_INTERVAL
+}
+
+/// Topic digests are published to
+pub fn audit_topic() -> String {
+    format!("{}/audit", crate_name!())
+}
+
+/// Publishing digests of the records published
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AuditConfig {
+    /// Secret digests are signed with, shared with the consumers checking
+    /// them
+    pub key: String,
+    /// Seconds between digests
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+impl std::fmt::Debug for AuditConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("AuditConfig")
+            .field("key", &"<redacted>")
+            .field("interval", &self.interval)
+            .finish()
+    }
+}
+
+impl AuditConfig {
+    pub fn get_interval(&self) -> Duration {
+        Duration::from_secs(self.interval.max(1))
+    }
+}
+
+/// The SHA-256 hash of `data`
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// The HMAC-SHA256 of `data` with `key`
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The records published over an interval, as published on the audit topic
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct AuditDigest {
+    /// Counts up from 0 with every digest since the bridge started
+    pub seq: u64,
+    pub from: DateTime<Local>,
+    pub until: DateTime<Local>,
+    /// Records published over the interval
+    pub count: u64,
+    /// The chain's hash before the interval's records, in hex
+    pub first: String,
+    /// The chain's hash after the interval's records, in hex
+    pub last: String,
+    /// HMAC-SHA256 of `<seq>:<from>:<until>:<count>:<first>:<last>`, in hex
+    pub signature: String,
+}
+
+#[derive(Debug)]
+struct Chain {
+    seq: u64,
+    since: DateTime<Local>,
+    count: u64,
+    first: [u8; 32],
+    hash: [u8; 32],
+}
+
+/// The hash chain over the records published. Cloning the chain produces
+/// another handle to the same chain.
+#[derive(Clone, Debug)]
+pub struct AuditChain {
+    chain: Arc<Mutex<Chain>>,
+}
+
+impl Default for AuditChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AuditChain {
+    pub fn new() -> Self {
+        AuditChain {
+            chain: Arc::new(Mutex::new(Chain {
+                seq: 0,
+                since: crate::clock::now(),
+                count: 0,
+                first: [0; 32],
+                hash: [0; 32],
+            })),
+        }
+    }
+
+    /// Extends the chain with a record published to `topic`
+    pub fn record(&self, topic: &str, payload: &[u8]) {
+        let mut chain = self.chain.lock().expect("audit chain poisoned");
+        let mut link = Vec::with_capacity(32 + topic.len() + 1 + payload.len());
+        link.extend_from_slice(&chain.hash);
+        link.extend_from_slice(topic.as_bytes());
+        link.push(0);
+        link.extend_from_slice(payload);
+        chain.hash = sha256(&link);
+        chain.count += 1;
+    }
+
+    /// The digest of the records published since the last one, signed with
+    /// `key`, starting a new interval
+    pub fn digest(&self, key: &[u8]) -> AuditDigest {
+        let mut chain = self.chain.lock().expect("audit chain poisoned");
+        let now = crate::clock::now();
+        let (first, last) = (hex(&chain.first), hex(&chain.hash));
+        // Times as serde publishes them
+        let time = |t: DateTime<Local>| t.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+        let signed = format!(
+            "{}:{}:{}:{}:{}:{}",
+            chain.seq,
+            time(chain.since),
+            time(now),
+            chain.count,
+            first,
+            last
+        );
+        let digest = AuditDigest {
+            seq: chain.seq,
+            from: chain.since,
+            until: now,
+            count: chain.count,
+            first,
+            last,
+            signature: hex(&hmac_sha256(key, signed.as_bytes())),
+        };
+        chain.seq += 1;
+        chain.since = now;
+        chain.count = 0;
+        chain.first = chain.hash;
+        digest
+    }
+}
+
+// Publishes the digest of the records published since the last one
+fn publish(sink: &MqttSink, chain: &AuditChain, key: &str) {
+    let digest = chain.digest(key.as_bytes());
+    let topic = audit_topic();
+    let payload = match serde_json::to_vec(&digest) {
+        Ok(payload) => payload,
+        Err(e) => {
+            log::error!("Failed to serialize audit digest: {:?}", e);
+            return;
+        }
+    };
+    match sink.publish(&topic, payload) {
+        Ok(()) => log::debug!("mqtt <== {}({:?})", topic, digest),
+        Err(e) => log::error!("Failed to publish audit digest: {:#}", e),
+    }
+}
+
+/// Publishes digests of the records published from a background thread
+#[derive(Debug)]
+pub struct Auditor {
+    stop: Sender<()>,
+    publisher: JoinHandle<()>,
+}
+
+impl Auditor {
+    /// Starts a background thread that publishes a digest of the records
+    /// `chain` is extended with every interval
+    pub fn spawn(sink: MqttSink, chain: AuditChain, conf: AuditConfig) -> Self {
+        let (stop, stopped) = std::sync::mpsc::channel::<()>();
+        let interval = conf.get_interval();
+        let publisher = std::thread::spawn(move || {
+            let mut started = Instant::now();
+            while let Ok(()) | Err(RecvTimeoutError::Timeout) =
+                stopped.recv_timeout(interval.saturating_sub(started.elapsed()))
+            {
+                if started.elapsed() >= interval {
+                    started = Instant::now();
+                    publish(&sink, &chain, &conf.key);
+                }
+            }
+            publish(&sink, &chain, &conf.key);
+        });
+        Auditor { stop, publisher }
+    }
+
+    /// Publishes the digest of the last records, waiting for the publisher
+    /// to finish
+    pub fn finish(self) {
+        drop(self.stop);
+        if self.publisher.join().is_err() {
+            log::error!("Audit digest publisher panicked");
+        }
+    }
+}
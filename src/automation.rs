@@ -0,0 +1,173 @@
+//! Automation outputs switched by rules on sensor readings, e.g. turning on
+//! a rain gauge's heater below freezing, for bridges running on a Raspberry
+//! Pi wired to accessories
+//!
+//! Each rule watches one measurement, by its normalized name and in its
+//! metric unit, and switches its output on when the reading crosses the
+//! threshold. It's switched off again once the reading is back past the
+//! threshold by more than the hysteresis, so that readings hovering around
+//! the threshold don't chatter the relay. Outputs are either a GPIO pin,
+//! driven through sysfs, or a topic that a relay listens on, which is
+//! published `on` or `off`, retained.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{ConfigError, SensorPattern};
+use crate::errors::{ErrorCode, ErrorEvent};
+use crate::events::{Event, EventBus};
+use crate::radio::Record;
+use crate::sink::MqttSink;
+use crate::units::UnitSystem;
+
+// Where GPIO pins are exported and driven from
+const GPIO_ROOT: &str = "/sys/class/gpio";
+
+/// What a rule switches
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    /// A GPIO pin, by its sysfs number, driven high when on
+    Gpio(u32),
+    /// A topic a relay listens on, published `on` or `off`
+    Topic(String),
+}
+
+impl std::fmt::Display for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Gpio(pin) => write!(f, "GPIO {}", pin),
+            Self::Topic(topic) => write!(f, "{}", topic),
+        }
+    }
+}
+
+/// A rule switching an output on while a sensor's measurement is beyond a
+/// threshold
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AutomationRule {
+    /// Sensors the rule applies to, as a [`SensorPattern`]
+    pub sensor: String,
+    /// Normalized name of the measurement, e.g. `temperature_c`
+    pub measurement: String,
+    /// Switches the output on below this value
+    #[serde(default)]
+    pub below: Option<f64>,
+    /// Switches the output on above this value
+    #[serde(default)]
+    pub above: Option<f64>,
+    /// How far back past the threshold the value has to go to switch the
+    /// output off again
+    #[serde(default)]
+    pub hysteresis: f64,
+    pub output: Output,
+}
+
+impl AutomationRule {
+    // Whether the output should be on for `value`, given whether it is now
+    fn wants_on(&self, value: f64, on: bool) -> bool {
+        let margin = if on { self.hysteresis } else { 0.0 };
+        self.below
+            .map(|below| value < below + margin)
+            .unwrap_or(false)
+            || self
+                .above
+                .map(|above| value > above - margin)
+                .unwrap_or(false)
+    }
+}
+
+/// Evaluates the automation rules against each record, switching their
+/// outputs as readings cross thresholds
+#[derive(Debug, Default)]
+pub struct Automation {
+    rules: Vec<(SensorPattern, AutomationRule)>,
+    states: HashMap<Output, bool>,
+}
+
+impl Automation {
+    pub fn new(rules: &[AutomationRule]) -> Result<Self, ConfigError> {
+        let rules = rules
+            .iter()
+            .map(|rule| Ok((rule.sensor.parse()?, rule.clone())))
+            .collect::<Result<Vec<(SensorPattern, AutomationRule)>, ConfigError>>()?;
+        Ok(Automation {
+            rules,
+            states: HashMap::new(),
+        })
+    }
+
+    /// Switches the outputs of the rules that apply to the record, where its
+    /// readings call for a change. Outputs that fail to switch are reported
+    /// on `events`, and tried again with the next record.
+    pub fn evaluate(&mut self, record: &Record, sink: Option<&MqttSink>, events: &EventBus) {
+        for (pattern, rule) in &self.rules {
+            if !pattern.matches(&record.sensor_id) {
+                continue;
+            }
+            let value = record
+                .measurements
+                .iter()
+                .find(|m| crate::normalize::key(m).as_deref() == Some(rule.measurement.as_str()))
+                .and_then(|m| m.numeric_in(Some(UnitSystem::Metric)));
+            let value = match value {
+                Some(value) => value,
+                None => continue,
+            };
+            let state = self.states.get(&rule.output).copied();
+            let on = rule.wants_on(value, state.unwrap_or(false));
+            if state == Some(on) {
+                continue;
+            }
+            log::info!(
+                "[{}] {} is {}, switching {} {}",
+                record.sensor_id,
+                rule.measurement,
+                value,
+                rule.output,
+                if on { "on" } else { "off" }
+            );
+            match switch(&rule.output, on, sink) {
+                Ok(()) => {
+                    self.states.insert(rule.output.clone(), on);
+                }
+                Err(e) => {
+                    log::error!("Failed to switch {}: {:#}", rule.output, e);
+                    events.publish(Event::Error(
+                        ErrorEvent::new(ErrorCode::Automation, format!("{:#}", e))
+                            .context("output", &rule.output),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+fn switch(output: &Output, on: bool, sink: Option<&MqttSink>) -> Result<()> {
+    match output {
+        Output::Gpio(pin) => set_gpio(*pin, on),
+        Output::Topic(topic) => match sink {
+            Some(sink) => sink.publish_retained(topic, if on { "on" } else { "off" }, sink.qos()),
+            None => {
+                log::warn!("No broker to switch {} on", topic);
+                Ok(())
+            }
+        },
+    }
+}
+
+// Exports the pin as an output if it isn't already, and drives it
+fn set_gpio(pin: u32, on: bool) -> Result<()> {
+    let dir = PathBuf::from(GPIO_ROOT).join(format!("gpio{}", pin));
+    if !dir.exists() {
+        std::fs::write(PathBuf::from(GPIO_ROOT).join("export"), pin.to_string())
+            .with_context(|| format!("Failed to export GPIO {}", pin))?;
+        std::fs::write(dir.join("direction"), "out")
+            .with_context(|| format!("Failed to make GPIO {} an output", pin))?;
+    }
+    std::fs::write(dir.join("value"), if on { "1" } else { "0" })
+        .with_context(|| format!("Failed to set GPIO {}", pin))
+}
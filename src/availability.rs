@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+
+use clap::crate_name;
+
+use crate::control::Maintenance;
+use crate::events::{Event, EventBus};
+use crate::grace::StartupGrace;
+use crate::sink::MqttSink;
+
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+
+/// Retained topic reporting whether the bridge itself is running, under
+/// the topic prefix if there is one, so that bridges sharing a broker each
+/// have their own
+pub fn status_topic(prefix: Option<&str>) -> String {
+    match prefix
+        .map(|p| p.trim_matches('/'))
+        .filter(|p| !p.is_empty())
+    {
+        Some(prefix) => format!("{}/{}/status", prefix, crate_name!()),
+        None => format!("{}/status", crate_name!()),
+    }
+}
+
+/// Retained topic reporting whether a sensor is still being heard from
+pub fn sensor_topic(sensor_topic: &str) -> String {
+    format!("{}/availability", sensor_topic)
+}
+
+/// The Last Will and Testament registered with the broker, so that the
+/// status topic flips to offline if the bridge dies without saying goodbye
+pub fn last_will(status_topic: &str) -> crate::sink::Message {
+    crate::sink::Message::new_retained(status_topic, OFFLINE, 1)
+}
+
+#[derive(Debug)]
+struct SensorState {
+    sensor_id: String,
+    last_seen: DateTime<Local>,
+    online: bool,
+}
+
+/// Tracks when each sensor topic was last heard from, and flips its
+/// availability topic to offline when it goes quiet for too long
+#[derive(Clone, Debug)]
+pub struct AvailabilityMonitor {
+    sensors: Arc<Mutex<HashMap<String, SensorState>>>,
+    timeout: Duration,
+}
+
+impl AvailabilityMonitor {
+    pub fn new(timeout: Duration) -> Self {
+        AvailabilityMonitor {
+            sensors: Arc::new(Mutex::new(HashMap::new())),
+            timeout,
+        }
+    }
+
+    /// Records that a sensor was heard from, returning true if it was not
+    /// previously known to be online
+    pub fn seen(&self, sensor_id: &str, sensor_topic: &str) -> bool {
+        let mut sensors = self.sensors.lock().expect("availability state poisoned");
+        let state = sensors
+            .entry(sensor_topic.to_owned())
+            .or_insert(SensorState {
+                sensor_id: sensor_id.to_owned(),
+                last_seen: crate::clock::now(),
+                online: false,
+            });
+        state.last_seen = crate::clock::now();
+        !std::mem::replace(&mut state.online, true)
+    }
+
+    /// Takes up a sensor heard from before the last restart, so that it's
+    /// reported offline if it isn't heard from again in time
+    pub fn restore(&self, sensor_id: &str, sensor_topic: &str, last_seen: DateTime<Local>) {
+        let mut sensors = self.sensors.lock().expect("availability state poisoned");
+        sensors.insert(
+            sensor_topic.to_owned(),
+            SensorState {
+                sensor_id: sensor_id.to_owned(),
+                last_seen,
+                online: true,
+            },
+        );
+    }
+
+    /// Marks sensors that have exceeded the timeout as offline, returning
+    /// their ids and topics
+    fn expire(&self) -> Vec<(String, String)> {
+        let mut sensors = self.sensors.lock().expect("availability state poisoned");
+        let now = crate::clock::now();
+        sensors
+            .iter_mut()
+            .filter(|(_, state)| {
+                state.online && (now - state.last_seen).to_std().unwrap_or_default() > self.timeout
+            })
+            .map(|(topic, state)| {
+                state.online = false;
+                (state.sensor_id.clone(), topic.clone())
+            })
+            .collect()
+    }
+
+    /// Starts a background thread that periodically reports sensors that have
+    /// gone quiet on the event bus, and publishes their offline availability
+    /// to `sink` if there is one and the sensor isn't in maintenance. Nothing
+    /// is reported until the startup grace period is over.
+    pub fn spawn_watchdog(
+        &self,
+        sink: Option<MqttSink>,
+        events: EventBus,
+        maintenance: Maintenance,
+        grace: StartupGrace,
+    ) {
+        let monitor = self.clone();
+        let period = std::cmp::min(monitor.timeout, Duration::from_secs(30));
+        std::thread::spawn(move || loop {
+            std::thread::sleep(period);
+            if grace.is_active() {
+                continue;
+            }
+            for (sensor_id, topic) in monitor.expire() {
+                if maintenance.is_active(&sensor_id) {
+                    log::debug!("Sensor {} not heard from during maintenance", topic);
+                    events.publish(Event::SensorOffline { topic });
+                    continue;
+                }
+                log::warn!("Sensor {} not heard from in {:?}", topic, monitor.timeout);
+                if let Some(ref sink) = sink {
+                    if let Err(e) = sink.publish_retained(&sensor_topic(&topic), OFFLINE, 1) {
+                        log::error!("Failed to publish availability for {}: {:?}", topic, e);
+                        events.publish(Event::SinkError {
+                            sink: sink.broker().to_owned(),
+                            error: e.to_string(),
+                        });
+                    }
+                }
+                events.publish(Event::SensorOffline { topic });
+            }
+        });
+    }
+}
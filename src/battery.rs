@@ -0,0 +1,387 @@
+//! Prediction of when sensors' batteries run out, from the trend of the
+//! battery levels they report
+//!
+//! Sensors reporting their battery voltage get a level from rtl_433, which
+//! maps the voltage onto the model's range, and a few report a raw level
+//! taken as a percentage; sensors reporting only whether their battery is
+//! ok can't be predicted. Each sensor's readings are averaged over the hour,
+//! with the temperature it reported alongside, and a line is fitted through
+//! the hourly averages of the last few weeks. Battery voltage sags in the
+//! cold, so when the temperature varied enough the fit includes it, and the
+//! level is projected at the average temperature rather than following the
+//! weather. The days until the fitted level reaches the level a battery
+//! counts as flat at are added to each record reporting a level, as
+//! `BatteryDaysRemaining`, along with `BatteryWarning`, which is 1 while
+//! fewer days remain than the warning is given ahead of.
+//!
+//! The hourly averages are kept in a state file, so that the trend survives
+//! restarts. A level jumping up means the batteries were replaced, and the
+//! sensor's trend starts over.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Duration, DurationRound, Local};
+use serde::{Deserialize, Serialize};
+use uom::si::thermodynamic_temperature;
+
+use crate::derive::{DerivedCalculator, DerivedValue, Lineage};
+use crate::radio::{Measurement, Record};
+
+/// Version of the battery trend fit, in the lineage of its values
+pub const FORMULA_VERSION: u32 = 1;
+
+// Percentage points an hour's level has to rise by over the previous hour's
+// to be taken for new batteries
+const SWAP_RISE: f32 = 10.0;
+// Hourly averages needed before a trend is fitted
+const MIN_SAMPLES: usize = 3;
+// Variance of the temperature, in °C², below which it's left out of the fit
+const MIN_TEMPERATURE_VARIANCE: f64 = 1.0;
+
+fn default_window_days() -> u32 {
+    28
+}
+
+fn default_min_days() -> u32 {
+    7
+}
+
+fn default_warn_days() -> u32 {
+    30
+}
+
+fn default_empty_level() -> f32 {
+    10.0
+}
+
+/// Predicting when sensors' batteries run out
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatteryConfig {
+    /// Days of readings the trend is fitted over
+    #[serde(default = "default_window_days")]
+    pub window_days: u32,
+    /// Days of readings needed before the days remaining are estimated
+    #[serde(default = "default_min_days")]
+    pub min_days: u32,
+    /// Days ahead of running out the warning is given
+    #[serde(default = "default_warn_days")]
+    pub warn_days: u32,
+    /// Level, in percent, a battery counts as flat at, as sensors tend to
+    /// stop transmitting before reaching the bottom of rtl_433's range
+    #[serde(default = "default_empty_level")]
+    pub empty_level: f32,
+}
+
+impl Default for BatteryConfig {
+    fn default() -> Self {
+        BatteryConfig {
+            window_days: default_window_days(),
+            min_days: default_min_days(),
+            warn_days: default_warn_days(),
+            empty_level: default_empty_level(),
+        }
+    }
+}
+
+/// A sensor's battery level averaged over an hour
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Sample {
+    /// Start of the hour
+    pub hour: DateTime<Local>,
+    /// Battery level, in percent
+    pub level: f32,
+    /// Temperature the sensor reported, in °C, if it reports one
+    pub temperature_c: Option<f32>,
+}
+
+/// The battery level trend fitted over a sensor's hourly averages
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trend {
+    /// Fitted level, in percent, at the average temperature
+    pub level: f64,
+    /// Change in the level, in percentage points per day
+    pub slope: f64,
+    /// Change in the level, in percentage points per °C, when the
+    /// temperature was fitted
+    pub temperature_coefficient: Option<f64>,
+}
+
+impl Trend {
+    /// Days until the fitted level reaches `empty_level`, if it's falling
+    pub fn days_remaining(&self, empty_level: f32) -> Option<f64> {
+        if self.slope >= 0.0 {
+            return None;
+        }
+        Some(((self.level - f64::from(empty_level)) / -self.slope).max(0.0))
+    }
+}
+
+fn days(timestamp: DateTime<Local>) -> f64 {
+    timestamp.timestamp() as f64 / 86_400.0
+}
+
+/// Fits the level over `samples` by least squares, including the temperature
+/// when it varied enough, and projects it to `now` at the average
+/// temperature
+pub fn fit(samples: &[Sample], now: DateTime<Local>) -> Option<Trend> {
+    if samples.len() < MIN_SAMPLES {
+        return None;
+    }
+    let n = samples.len() as f64;
+    let mean = |f: &dyn Fn(&Sample) -> f64| samples.iter().map(f).sum::<f64>() / n;
+    let mean_t = mean(&|s| days(s.hour));
+    let mean_l = mean(&|s| f64::from(s.level));
+    let (mut stt, mut stl) = (0.0, 0.0);
+    for s in samples {
+        let dt = days(s.hour) - mean_t;
+        stt += dt * dt;
+        stl += dt * (f64::from(s.level) - mean_l);
+    }
+    if stt <= 0.0 {
+        return None;
+    }
+    let mut trend = Trend {
+        level: mean_l,
+        slope: stl / stt,
+        temperature_coefficient: None,
+    };
+    // Every sample needs a temperature to fit it, which sensors reporting
+    // one always send
+    let temperatures: Option<Vec<f64>> = samples
+        .iter()
+        .map(|s| s.temperature_c.map(f64::from))
+        .collect();
+    if let Some(temperatures) = temperatures {
+        let mean_c = temperatures.iter().sum::<f64>() / n;
+        let (mut scc, mut stc, mut scl) = (0.0, 0.0, 0.0);
+        for (s, c) in samples.iter().zip(&temperatures) {
+            let (dt, dc) = (days(s.hour) - mean_t, c - mean_c);
+            scc += dc * dc;
+            stc += dt * dc;
+            scl += dc * (f64::from(s.level) - mean_l);
+        }
+        let det = stt * scc - stc * stc;
+        // Left out when it barely varied, or varied in step with time, as
+        // then it can't be told apart from the discharge
+        if scc / n >= MIN_TEMPERATURE_VARIANCE && det > 1e-3 * stt * scc {
+            trend.slope = (stl * scc - scl * stc) / det;
+            trend.temperature_coefficient = Some((scl * stt - stl * stc) / det);
+        }
+    }
+    trend.level += trend.slope * (days(now) - mean_t);
+    Some(trend)
+}
+
+/// The battery level a record reports, in percent, if any
+pub fn level(record: &Record) -> Option<f32> {
+    // rtl_433 reports the level it maps the voltage to as `battery_ok`
+    if record.record_json.get("battery_mV").is_some() {
+        if let Some(level) = record
+            .record_json
+            .get("battery_ok")
+            .and_then(|b| b.as_f64())
+        {
+            return Some((level * 100.0) as f32);
+        }
+    }
+    record.measurements.iter().find_map(|m| match m {
+        Measurement::BatteryLevelRaw(level) => Some(f32::from(*level)),
+        _ => None,
+    })
+}
+
+fn temperature_c(record: &Record) -> Option<f32> {
+    record.measurements.iter().find_map(|m| match m {
+        Measurement::Temperature(t) => Some(t.get::<thermodynamic_temperature::degree_celsius>()),
+        _ => None,
+    })
+}
+
+// What's kept of each sensor's battery in the state file
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct SensorBattery {
+    samples: Vec<Sample>,
+    warned: bool,
+    // The hour being averaged, and the sums of its levels and temperatures
+    #[serde(skip)]
+    hour: Option<(DateTime<Local>, f32, f32, u32)>,
+}
+
+/// Fits each sensor's battery level trend, and derives the days until its
+/// battery runs out from it
+#[derive(Debug)]
+pub struct BatteryPredictor {
+    conf: BatteryConfig,
+    path: Option<PathBuf>,
+    sensors: HashMap<String, SensorBattery>,
+}
+
+impl BatteryPredictor {
+    /// Keeps the hourly averages in the state file at `path` if given, so
+    /// that they survive restarts
+    pub fn new(conf: BatteryConfig, path: Option<PathBuf>) -> Self {
+        let sensors = path.as_deref().and_then(load).unwrap_or_default();
+        BatteryPredictor {
+            conf,
+            path,
+            sensors,
+        }
+    }
+
+    /// Adds a record's battery level to its sensor's hourly average,
+    /// returning the days remaining and whether to warn, if the sensor
+    /// reports a level
+    pub fn observe(&mut self, record: &Record) -> Option<(Option<f64>, bool)> {
+        let level = level(record)?;
+        let temperature = temperature_c(record);
+        let hour = record
+            .timestamp
+            .duration_trunc(Duration::hours(1))
+            .unwrap_or(record.timestamp);
+        let conf = &self.conf;
+        let sensor = self.sensors.entry(record.sensor_id.clone()).or_default();
+        let mut closed = None;
+        match sensor.hour {
+            Some((start, ref mut levels, ref mut temperatures, ref mut count)) if start == hour => {
+                *levels += level;
+                *temperatures += temperature.unwrap_or(f32::NAN);
+                *count += 1;
+            }
+            previous => {
+                closed = previous;
+                sensor.hour = Some((hour, level, temperature.unwrap_or(f32::NAN), 1));
+            }
+        }
+        if let Some((start, levels, temperatures, count)) = closed {
+            let sample = Sample {
+                hour: start,
+                level: levels / count as f32,
+                temperature_c: Some(temperatures / count as f32).filter(|t| !t.is_nan()),
+            };
+            let swapped = matches!(sensor.samples.last(),
+                Some(last) if sample.level - last.level > SWAP_RISE);
+            if swapped {
+                log::info!(
+                    "[{}] Battery level rose to {:.0}%, taking it for new batteries",
+                    record.sensor_id,
+                    sample.level
+                );
+                sensor.samples.clear();
+                sensor.warned = false;
+            }
+            let window = Duration::days(i64::from(conf.window_days));
+            sensor
+                .samples
+                .retain(|s| record.timestamp - s.hour <= window);
+            sensor.samples.push(sample);
+        }
+        let span = match (sensor.samples.first(), sensor.samples.last()) {
+            (Some(first), Some(last)) => last.hour - first.hour,
+            _ => Duration::zero(),
+        };
+        let remaining = match span >= Duration::days(i64::from(conf.min_days)) {
+            true => fit(&sensor.samples, record.timestamp)
+                .and_then(|trend| trend.days_remaining(conf.empty_level)),
+            false => None,
+        };
+        let warn = remaining
+            .map(|days| days < f64::from(conf.warn_days))
+            .unwrap_or(sensor.warned);
+        if warn != sensor.warned {
+            sensor.warned = warn;
+            match (warn, remaining) {
+                (true, Some(days)) => log::warn!(
+                    "[{}] Battery estimated to run out in {:.0} days",
+                    record.sensor_id,
+                    days
+                ),
+                _ => log::info!("[{}] Battery no longer running low", record.sensor_id),
+            }
+        }
+        if closed.is_some() {
+            self.save();
+        }
+        Some((remaining, warn))
+    }
+
+    // Writes the state file, if there is one. Losing it only delays the
+    // estimates until the trend is fitted again, so failures are only logged.
+    fn save(&self) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+        let result = path
+            .parent()
+            .map(std::fs::create_dir_all)
+            .transpose()
+            .and_then(|_| std::fs::write(path, serde_json::to_vec(&self.sensors)?));
+        if let Err(e) = result {
+            log::warn!(
+                "Failed to save battery levels to {}: {:?}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+impl DerivedCalculator for BatteryPredictor {
+    fn calculate(&mut self, history: &[Record]) -> Vec<Measurement> {
+        let record = match history.last() {
+            Some(record) => record,
+            None => return Vec::new(),
+        };
+        let (remaining, warn) = match self.observe(record) {
+            Some(estimate) => estimate,
+            None => return Vec::new(),
+        };
+        let lineage = Lineage::new(
+            &["BatteryLevel", "TemperatureF"],
+            "battery_trend",
+            FORMULA_VERSION,
+        );
+        let derived = |name: &str, value: f32, unit: &str| {
+            Measurement::Derived(DerivedValue {
+                name: name.to_owned(),
+                value,
+                unit: unit.to_owned(),
+                lineage: Some(lineage.clone()),
+            })
+        };
+        let mut measurements = Vec::new();
+        if let Some(days) = remaining {
+            measurements.push(derived("BatteryDaysRemaining", days.round() as f32, "d"));
+        }
+        measurements.push(derived("BatteryWarning", f32::from(u8::from(warn)), ""));
+        measurements
+    }
+}
+
+// Reads the state file, which is started over if missing or unreadable
+fn load(path: &std::path::Path) -> Option<HashMap<String, SensorBattery>> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            log::warn!(
+                "Failed to read battery levels from {}: {:?}",
+                path.display(),
+                e
+            );
+            return None;
+        }
+    };
+    match serde_json::from_slice(&contents) {
+        Ok(saved) => Some(saved),
+        Err(e) => {
+            log::warn!(
+                "Ignoring unreadable battery levels in {}: {:?}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
@@ -0,0 +1,235 @@
+//! Assembly of the bursts of packets some devices split a message across
+//!
+//! Some Fine Offset devices send part of what they report in packets of its
+//! own right after their regular one, e.g. the WH1080 family's DCF77 time,
+//! or the WH31B's radio clock, under the same sensor id. With a `bursts`
+//! rule for them, a sensor's packets are held from the first for the rule's
+//! `window`, and their fields merged into a single record, which is parsed
+//! as rtl_433 json again, so that the parsers decode the combined message.
+//!
+//! A burst is released as soon as it has every one of the rule's `fields`,
+//! or once its window has passed, which is noticed when the next record
+//! arrives from any sensor, and when the bridge stops. A burst released
+//! for its window missing some of the fields is partial, and is released
+//! as far as it got, or dropped with `"partial": "drop"`.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{ConfigError, SensorPattern};
+use crate::radio::Record;
+
+fn default_window() -> u64 {
+    2
+}
+
+/// What happens to a burst missing some of its fields when its window
+/// passes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartialBurst {
+    /// Released with the fields it has
+    #[default]
+    Release,
+    /// Dropped, packets and all
+    Drop,
+}
+
+/// The packets of some sensors to assemble into bursts
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BurstRule {
+    /// Sensors whose packets are assembled, as a [`SensorPattern`], e.g. a
+    /// model's prefix `Fineoffset-WHx080/`
+    pub sensor: String,
+    /// Seconds from a burst's first packet its others are waited for
+    #[serde(default = "default_window")]
+    pub window: u64,
+    /// rtl_433 fields a complete burst has between its packets, e.g.
+    /// `radio_clock`; with none, bursts are only released once their window
+    /// passes
+    #[serde(default)]
+    pub fields: Vec<String>,
+    /// What becomes of bursts still missing some of the fields when their
+    /// window passes
+    #[serde(default)]
+    pub partial: PartialBurst,
+}
+
+// The packets of a sensor held so far
+#[derive(Debug)]
+struct Burst {
+    rule: usize,
+    started: chrono::DateTime<chrono::Local>,
+    records: Vec<Record>,
+}
+
+impl Burst {
+    fn has_fields(&self, fields: &[String]) -> bool {
+        !fields.is_empty()
+            && fields.iter().all(|field| {
+                self.records
+                    .iter()
+                    .any(|record| record.record_json.get(field).is_some())
+            })
+    }
+}
+
+/// Holds the packets of the sensors with burst rules until their bursts are
+/// complete
+#[derive(Debug, Default)]
+pub struct Assembler {
+    rules: Vec<(SensorPattern, BurstRule)>,
+    pending: HashMap<String, Burst>,
+    partial: usize,
+}
+
+impl Assembler {
+    pub fn new(rules: &[BurstRule]) -> Result<Self, ConfigError> {
+        let rules = rules
+            .iter()
+            .map(|rule| Ok((rule.sensor.parse()?, rule.clone())))
+            .collect::<Result<Vec<(SensorPattern, BurstRule)>, ConfigError>>()?;
+        Ok(Assembler {
+            rules,
+            pending: HashMap::new(),
+            partial: 0,
+        })
+    }
+
+    /// Takes a record, returning the records ready to go on: those of
+    /// bursts whose window has passed by the record's time, then the record
+    /// itself when its sensor's packets aren't assembled, or its burst if
+    /// the record completes it
+    pub fn assemble(&mut self, record: Record) -> Vec<Record> {
+        let mut ready = self.expire(record.timestamp);
+        let rule = match self
+            .rules
+            .iter()
+            .position(|(pattern, _)| pattern.matches(&record.sensor_id))
+        {
+            Some(rule) => rule,
+            None => {
+                ready.push(record);
+                return ready;
+            }
+        };
+        let sensor_id = record.sensor_id.clone();
+        let burst = self.pending.entry(sensor_id.clone()).or_insert(Burst {
+            rule,
+            started: record.timestamp,
+            records: Vec::new(),
+        });
+        burst.records.push(record);
+        if burst.has_fields(&self.rules[rule].1.fields) {
+            if let Some(burst) = self.pending.remove(&sensor_id) {
+                log::trace!(
+                    "[{}] Burst of {} packets complete",
+                    sensor_id,
+                    burst.records.len()
+                );
+                ready.extend(combine(burst.records));
+            }
+        }
+        ready
+    }
+
+    /// Whether packets from a sensor are being held for its burst
+    pub fn is_pending(&self, sensor_id: &str) -> bool {
+        self.pending.contains_key(sensor_id)
+    }
+
+    /// Releases the bursts still held, as partial ones, e.g. as the bridge
+    /// stops
+    pub fn flush(&mut self) -> Vec<Record> {
+        let sensors: Vec<String> = self.pending.keys().cloned().collect();
+        sensors
+            .into_iter()
+            .filter_map(|sensor_id| self.release_partial(&sensor_id))
+            .collect()
+    }
+
+    /// How many bursts were released or dropped partial
+    pub fn partial(&self) -> usize {
+        self.partial
+    }
+
+    // Releases the bursts whose window had passed by `now`
+    fn expire(&mut self, now: chrono::DateTime<chrono::Local>) -> Vec<Record> {
+        let expired: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(_, burst)| {
+                let window = self.rules[burst.rule].1.window;
+                now - burst.started > chrono::Duration::seconds(window as i64)
+            })
+            .map(|(sensor_id, _)| sensor_id.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|sensor_id| self.release_partial(&sensor_id))
+            .collect()
+    }
+
+    fn release_partial(&mut self, sensor_id: &str) -> Option<Record> {
+        let burst = self.pending.remove(sensor_id)?;
+        let rule = &self.rules[burst.rule].1;
+        if burst.has_fields(&rule.fields) || rule.fields.is_empty() {
+            return combine(burst.records);
+        }
+        self.partial += 1;
+        match rule.partial {
+            PartialBurst::Release => {
+                log::debug!(
+                    "[{}] Releasing partial burst of {} packets",
+                    sensor_id,
+                    burst.records.len()
+                );
+                combine(burst.records)
+            }
+            PartialBurst::Drop => {
+                log::debug!(
+                    "[{}] Dropping partial burst of {} packets",
+                    sensor_id,
+                    burst.records.len()
+                );
+                None
+            }
+        }
+    }
+}
+
+// Merges the fields of a burst's packets into the first's, later packets'
+// taking the place of earlier ones', and parses the result again. A burst
+// whose merged fields don't parse is released as its first packet.
+fn combine(records: Vec<Record>) -> Option<Record> {
+    let packets = records.len();
+    let mut records = records.into_iter();
+    let mut first = records.next()?;
+    if packets == 1 {
+        return Some(first);
+    }
+    let mut json = first.record_json.clone();
+    if let serde_json::Value::Object(ref mut merged) = json {
+        for record in records {
+            if let serde_json::Value::Object(fields) = record.record_json {
+                merged.extend(fields.into_iter().filter(|(field, _)| field != "time"));
+            }
+        }
+    }
+    match crate::radio::decode(&json) {
+        Some(mut combined) => {
+            combined.timestamp = first.timestamp;
+            combined.radio = first.radio.take();
+            Some(combined)
+        }
+        None => {
+            log::warn!(
+                "[{}] Burst of {} packets doesn't decode combined, keeping its first",
+                first.sensor_id,
+                packets
+            );
+            Some(first)
+        }
+    }
+}
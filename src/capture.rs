@@ -0,0 +1,432 @@
+//! Packed capture files of rtl_433 records, for captures long enough that
+//! json lines get unwieldy
+//!
+//! A packed capture starts with [`MAGIC`], followed by chunks of records.
+//! Each chunk starts with a header, tagged `C`, giving the length in bytes of
+//! the records following it, how many there are, and the earliest and latest
+//! of their timestamps. Each record is tagged `R`, and gives its length,
+//! timestamp, and compact rtl_433 json. Integers are little endian, and
+//! timestamps are milliseconds since the Unix epoch.
+//!
+//! The chunk headers index the capture, so that seeking to a time skips
+//! whole chunks. A chunk is only given its length once it's complete, so the
+//! records of a chunk cut short by a crash are still read, just not skipped.
+//!
+//! A capture can also be kept as the lines rtl_433 output, exactly as they
+//! were received, in a file of json lines per run in a directory. Lines that
+//! aren't json, or that the bridge doesn't decode, are kept too, so that a
+//! device the bridge doesn't support yet can be reported with real samples.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, TimeZone};
+use thiserror::Error;
+
+/// Identifies a packed capture, and the version of its format
+pub const MAGIC: &[u8; 8] = b"WRCAP01\n";
+
+const CHUNK_TAG: u8 = b'C';
+const RECORD_TAG: u8 = b'R';
+// Tag, record length and timestamp
+const RECORD_HEADER_LEN: u32 = 1 + 4 + 8;
+
+// Chunks are completed at whichever of these limits is reached first
+const CHUNK_RECORDS: u32 = 1024;
+const CHUNK_SPAN_MS: i64 = 60 * 60 * 1000;
+
+#[derive(Error, Debug)]
+pub enum CaptureError {
+    #[error("{0} is not a packed capture")]
+    NotACapture(String),
+    #[error("Corrupt packed capture, unexpected tag {tag:#04x} at offset {offset}")]
+    Corrupt { tag: u8, offset: u64 },
+}
+
+/// Returns true if the file at `path` is a packed capture, rather than json
+/// lines
+pub fn is_packed(path: &Path) -> Result<bool> {
+    let mut magic = [0; MAGIC.len()];
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open capture {}", path.display()))?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The time a record was received, if it can be told from its json
+pub fn timestamp_of(json: &serde_json::Value) -> Option<DateTime<Local>> {
+    if let Some(record) = crate::radio::decode(json) {
+        return Some(record.timestamp);
+    }
+    json.get("time")?
+        .as_str()
+        .and_then(|time| crate::replay::parse_timestamp(time).ok())
+}
+
+#[derive(Debug)]
+struct ChunkHeader {
+    len: u32,
+    count: u32,
+    earliest: i64,
+    latest: i64,
+}
+
+impl ChunkHeader {
+    fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&[CHUNK_TAG])?;
+        writer.write_all(&self.len.to_le_bytes())?;
+        writer.write_all(&self.count.to_le_bytes())?;
+        writer.write_all(&self.earliest.to_le_bytes())?;
+        writer.write_all(&self.latest.to_le_bytes())
+    }
+
+    // Reads the header following a chunk tag
+    fn read<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        Ok(ChunkHeader {
+            len: u32::from_le_bytes(read_array(reader)?),
+            count: u32::from_le_bytes(read_array(reader)?),
+            earliest: i64::from_le_bytes(read_array(reader)?),
+            latest: i64::from_le_bytes(read_array(reader)?),
+        })
+    }
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> std::io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Appends records to a packed capture. The chunk being written is completed
+/// when the writer is flushed or dropped.
+pub struct CaptureWriter {
+    file: BufWriter<File>,
+    // Offset and header of the chunk being written
+    chunk: Option<(u64, ChunkHeader)>,
+}
+
+impl CaptureWriter {
+    /// Opens a packed capture for appending, creating it if it doesn't exist
+    pub fn append(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open capture {}", path.display()))?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(MAGIC)?;
+        } else if !is_packed(path)? {
+            return Err(CaptureError::NotACapture(path.display().to_string()).into());
+        }
+        file.seek(SeekFrom::End(0))?;
+        Ok(CaptureWriter {
+            file: BufWriter::new(file),
+            chunk: None,
+        })
+    }
+
+    /// Appends a record received at `timestamp`
+    pub fn write(&mut self, timestamp: DateTime<Local>, json: &serde_json::Value) -> Result<()> {
+        let timestamp = timestamp.timestamp_millis();
+        let payload = serde_json::to_vec(json)?;
+        if self.chunk.is_none() {
+            let header = ChunkHeader {
+                len: 0,
+                count: 0,
+                earliest: timestamp,
+                latest: timestamp,
+            };
+            let offset = self.file.stream_position()?;
+            header.write(&mut self.file)?;
+            self.chunk = Some((offset, header));
+        }
+        self.file.write_all(&[RECORD_TAG])?;
+        self.file.write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.file.write_all(&timestamp.to_le_bytes())?;
+        self.file.write_all(&payload)?;
+        // Records are written as they're received, so that a capture isn't
+        // missing the last of them after a crash
+        self.file.flush()?;
+
+        let (_, header) = self.chunk.as_mut().expect("chunk vanished");
+        header.len += RECORD_HEADER_LEN + payload.len() as u32;
+        header.count += 1;
+        header.earliest = header.earliest.min(timestamp);
+        header.latest = header.latest.max(timestamp);
+        if header.count >= CHUNK_RECORDS || header.latest - header.earliest >= CHUNK_SPAN_MS {
+            self.complete_chunk()?;
+        }
+        Ok(())
+    }
+
+    /// Completes the chunk being written, and flushes it to disk
+    pub fn flush(&mut self) -> Result<()> {
+        self.complete_chunk()?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    // Fills in the length of the chunk being written, making it skippable
+    fn complete_chunk(&mut self) -> Result<()> {
+        if let Some((offset, header)) = self.chunk.take() {
+            self.file.seek(SeekFrom::Start(offset))?;
+            header.write(&mut self.file)?;
+            self.file.seek(SeekFrom::End(0))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CaptureWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            log::error!("Failed to complete capture: {:?}", e);
+        }
+    }
+}
+
+/// Appends the lines rtl_433 output, as they were received, to a file of
+/// json lines
+pub struct LineWriter {
+    file: File,
+}
+
+impl LineWriter {
+    /// Starts a file of lines in `directory`, named for the time it's
+    /// started, e.g. `rtl_433-20240115-120000.jsonl`
+    pub fn create(directory: &Path) -> Result<Self> {
+        let name = format!("rtl_433-{}.jsonl", Local::now().format("%Y%m%d-%H%M%S"));
+        let path = directory.join(name);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open capture {}", path.display()))?;
+        log::info!("Capturing rtl_433 output to {}", path.display());
+        Ok(LineWriter { file })
+    }
+
+    /// Appends a line as it was received
+    pub fn write(&mut self, line: &str) -> Result<()> {
+        let mut line = line.trim_end().to_owned();
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Where what the radios receive is captured
+pub enum Capture {
+    /// The json records, in a packed capture
+    Packed(CaptureWriter),
+    /// The lines rtl_433 output, as they were received
+    Lines(LineWriter),
+}
+
+impl Capture {
+    /// Opens the capture at `path`: a file of lines in it when it's a
+    /// directory, and otherwise a packed capture, created if it doesn't
+    /// exist
+    pub fn open(path: &Path) -> Result<Self> {
+        match path.is_dir() {
+            true => Ok(Capture::Lines(LineWriter::create(path)?)),
+            false => Ok(Capture::Packed(CaptureWriter::append(path)?)),
+        }
+    }
+
+    /// Appends a line received from a radio, before it's parsed, when
+    /// capturing lines
+    pub fn write_line(&mut self, line: &str) -> Result<()> {
+        match self {
+            Capture::Lines(writer) => writer.write(line),
+            Capture::Packed(_) => Ok(()),
+        }
+    }
+
+    /// Appends a json record received at `timestamp`, when capturing
+    /// records
+    pub fn write_record(
+        &mut self,
+        timestamp: DateTime<Local>,
+        json: &serde_json::Value,
+    ) -> Result<()> {
+        match self {
+            Capture::Packed(writer) => writer.write(timestamp, json),
+            Capture::Lines(_) => Ok(()),
+        }
+    }
+}
+
+/// Reads the json records of a packed capture, in the order they were
+/// written
+pub struct CaptureReader {
+    reader: BufReader<File>,
+}
+
+impl CaptureReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        if !is_packed(path)? {
+            return Err(CaptureError::NotACapture(path.display().to_string()).into());
+        }
+        let mut reader = BufReader::new(
+            File::open(path)
+                .with_context(|| format!("Failed to open capture {}", path.display()))?,
+        );
+        reader.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+        Ok(CaptureReader { reader })
+    }
+
+    /// Skips the chunks of records that are all from before `start`. Records
+    /// from before it may remain in the chunk it falls in.
+    pub fn seek(&mut self, start: DateTime<Local>) -> Result<()> {
+        let start = start.timestamp_millis();
+        loop {
+            let offset = self.reader.stream_position()?;
+            if self.reader.fill_buf()?.first() != Some(&CHUNK_TAG) {
+                return Ok(());
+            }
+            self.reader.consume(1);
+            let header = ChunkHeader::read(&mut self.reader)?;
+            if header.len == 0 || header.latest >= start {
+                self.reader.seek(SeekFrom::Start(offset))?;
+                return Ok(());
+            }
+            self.reader.seek_relative(i64::from(header.len))?;
+        }
+    }
+
+    // Reads the next record, or `None` at the end of the capture
+    fn read_record(&mut self) -> Result<Option<(i64, Vec<u8>)>> {
+        loop {
+            let offset = self.reader.stream_position()?;
+            let tag = match self.reader.fill_buf()?.first() {
+                Some(tag) => *tag,
+                None => return Ok(None),
+            };
+            self.reader.consume(1);
+            match tag {
+                CHUNK_TAG => {
+                    ChunkHeader::read(&mut self.reader)?;
+                }
+                RECORD_TAG => {
+                    let len = u32::from_le_bytes(read_array(&mut self.reader)?);
+                    let timestamp = i64::from_le_bytes(read_array(&mut self.reader)?);
+                    let mut payload = vec![0; len as usize];
+                    self.reader.read_exact(&mut payload)?;
+                    return Ok(Some((timestamp, payload)));
+                }
+                tag => return Err(CaptureError::Corrupt { tag, offset }.into()),
+            }
+        }
+    }
+
+    // Reads the next record and its timestamp, ending the capture at a
+    // record cut short by a crash
+    fn next_record(&mut self) -> Option<Result<(i64, serde_json::Value)>> {
+        match self.read_record() {
+            Ok(Some((timestamp, payload))) => Some(
+                serde_json::from_slice(&payload)
+                    .map(|json| (timestamp, json))
+                    .map_err(Into::into),
+            ),
+            Ok(None) => None,
+            // A record cut short by a crash ends the capture
+            Err(e)
+                if e.downcast_ref::<std::io::Error>()
+                    .map(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
+                    .unwrap_or(false) =>
+            {
+                log::warn!("Capture ends partway through a record");
+                None
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl Iterator for CaptureReader {
+    type Item = Result<serde_json::Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record()
+            .map(|record| record.map(|(_, json)| json))
+    }
+}
+
+/// Rewrites a packed capture with only the records `keep` accepts, given
+/// each record's timestamp and json. Returns the number of records removed.
+pub fn retain<F>(path: &Path, mut keep: F) -> Result<usize>
+where
+    F: FnMut(DateTime<Local>, &serde_json::Value) -> bool,
+{
+    let mut reader = CaptureReader::open(path)?;
+    let staged = path.with_extension("rewrite");
+    if staged.exists() {
+        std::fs::remove_file(&staged)?;
+    }
+    let mut writer = CaptureWriter::append(&staged)?;
+    let mut removed = 0;
+    while let Some(record) = reader.next_record() {
+        let (timestamp, json) = record?;
+        let timestamp = Local
+            .timestamp_millis_opt(timestamp)
+            .single()
+            .unwrap_or_else(crate::clock::now);
+        if keep(timestamp, &json) {
+            writer.write(timestamp, &json)?;
+        } else {
+            removed += 1;
+        }
+    }
+    writer.flush()?;
+    drop(writer);
+    std::fs::rename(&staged, path)
+        .with_context(|| format!("Failed to replace capture {}", path.display()))?;
+    Ok(removed)
+}
+
+/// Packs a capture of rtl_433 json records, one per line, appending them to
+/// the packed capture at `output`. Returns the number of records packed.
+pub fn pack(input: &Path, output: &Path) -> Result<usize> {
+    let lines = BufReader::new(
+        File::open(input).with_context(|| format!("Failed to open capture {}", input.display()))?,
+    )
+    .lines();
+    let mut writer = CaptureWriter::append(output)?;
+    let mut packed = 0;
+    let mut last = None;
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let json: serde_json::Value = match serde_json::from_str(&line) {
+            Ok(json) => json,
+            Err(e) => {
+                log::warn!("Skipping unparseable capture line: {:?}", e);
+                continue;
+            }
+        };
+        // Records without a time of their own are taken to be from the time
+        // of the record before them
+        let timestamp = match timestamp_of(&json).or(last) {
+            Some(timestamp) => timestamp,
+            None => {
+                log::warn!("Skipping capture line without a time: {}", line);
+                continue;
+            }
+        };
+        writer.write(timestamp, &json)?;
+        last = Some(timestamp);
+        packed += 1;
+    }
+    writer.flush()?;
+    Ok(packed)
+}
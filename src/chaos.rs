@@ -0,0 +1,55 @@
+//! Fault injection for soak testing how the bridge copes with misbehaving
+//! sinks and receivers
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// State of the xorshift generator behind `roll`; zero until first seeded
+static STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Rates, from 0 to 1, at which faults are injected. All are zero unless set
+/// with the hidden `--chaos-*` arguments.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChaosConfig {
+    /// Fraction of sink publishes that fail without reaching the broker
+    pub sink_failure_rate: f64,
+    /// Fraction of sink publishes preceded by dropping the broker connection
+    pub disconnect_rate: f64,
+    /// Fraction of lines from the receiver that are corrupted before parsing
+    pub malformed_rate: f64,
+}
+
+/// Returns true with probability `rate`. Not suitable for anything but
+/// deciding when to inject faults.
+pub fn roll(rate: f64) -> bool {
+    if rate <= 0.0 {
+        return false;
+    }
+    let step = |mut x: u64| {
+        if x == 0 {
+            x = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(0)
+                | 1;
+        }
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        Some(x)
+    };
+    let previous = STATE
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, step)
+        .unwrap_or_default();
+    let x = step(previous).unwrap_or_default();
+    ((x >> 11) as f64 / (1u64 << 53) as f64) < rate
+}
+
+/// Corrupts a line of receiver output by truncating it partway through
+pub fn mangle(line: &str) -> String {
+    let cut = line.len() / 2;
+    let cut = (0..=cut)
+        .rev()
+        .find(|&i| line.is_char_boundary(i))
+        .unwrap_or(0);
+    line[..cut].to_owned()
+}
@@ -0,0 +1,84 @@
+//! The time the bridge goes by, for stamping records and events and for
+//! judging when readings go stale, sensors go quiet, and days roll over
+//!
+//! The system clock is used unless another [`Clock`] is installed. Replays
+//! install a [`ManualClock`] that follows the replayed records, so that
+//! timers and rollovers fire as they did when the records were captured, and
+//! tests can install one to move time along deterministically. Pacing of
+//! background threads, and other waits on real time, use `Instant` instead.
+
+use std::sync::{Arc, Mutex, RwLock};
+
+use chrono::{DateTime, Local, Utc};
+
+/// A source of the current time
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Local>;
+}
+
+/// The system's clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+/// A clock that only moves when told to
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<DateTime<Local>>,
+}
+
+impl ManualClock {
+    pub fn new(start: DateTime<Local>) -> Self {
+        ManualClock {
+            now: Mutex::new(start),
+        }
+    }
+
+    /// Sets the time, which may move it backwards
+    pub fn set(&self, time: DateTime<Local>) {
+        *self.now.lock().expect("clock poisoned") = time;
+    }
+
+    /// Moves the time forward by `by`
+    pub fn advance(&self, by: chrono::Duration) {
+        let mut now = self.now.lock().expect("clock poisoned");
+        *now += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Local> {
+        *self.now.lock().expect("clock poisoned")
+    }
+}
+
+// The installed clock; the system clock when unset
+static CLOCK: RwLock<Option<Arc<dyn Clock>>> = RwLock::new(None);
+
+/// Makes `clock` the clock the whole process goes by
+pub fn install(clock: Arc<dyn Clock>) {
+    *CLOCK.write().expect("clock poisoned") = Some(clock);
+}
+
+/// Goes back to the system clock
+pub fn reset() {
+    *CLOCK.write().expect("clock poisoned") = None;
+}
+
+/// The current time, by the installed clock
+pub fn now() -> DateTime<Local> {
+    match *CLOCK.read().expect("clock poisoned") {
+        Some(ref clock) => clock.now(),
+        None => Local::now(),
+    }
+}
+
+/// The current time in UTC, by the installed clock
+pub fn now_utc() -> DateTime<Utc> {
+    now().with_timezone(&Utc)
+}
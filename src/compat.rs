@@ -0,0 +1,169 @@
+//! Compatibility with the rtl_433 release installed, since its flags have
+//! changed between releases
+//!
+//! The release is read from `rtl_433 -V` before the radios are launched, and
+//! the arguments they're launched with adapted to it: UTC timestamps are
+//! asked for as `-M time:utc` from 20.02, and as `-M utc` before, and the
+//! protocol of each record is only asked for from 19.08, which added
+//! `-M protocol`. Releases before 18.12, which predate the `-M` options
+//! altogether, are refused, as are decoder options given to `-R` after a
+//! `:` before 20.02. Builds whose version isn't a release, like those from
+//! git without tags, are taken to be the newest.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use thiserror::Error;
+
+use crate::units::UnitSystem;
+
+/// Oldest rtl_433 release supported
+pub const MINIMUM: Version = Version::new(18, 12);
+// Release adding `-M protocol`
+const META_PROTOCOL: Version = Version::new(19, 8);
+// Release adding `-M time:<options>`, and options after `-R <decoder>:`
+const META_TIME: Version = Version::new(20, 2);
+const DECODER_OPTIONS: Version = Version::new(20, 2);
+
+#[derive(Error, Debug)]
+pub enum CompatError {
+    #[error("rtl_433 {found} is too old, {} or newer is needed", MINIMUM)]
+    TooOld { found: Version },
+    #[error("rtl_433 {found} doesn't support {feature}, which needs {needed} or newer")]
+    Unsupported {
+        found: Version,
+        feature: &'static str,
+        needed: Version,
+    },
+}
+
+/// An rtl_433 release, numbered by its year and month, e.g. 23.11
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub year: u32,
+    pub month: u32,
+}
+
+impl Version {
+    pub const fn new(year: u32, month: u32) -> Self {
+        Version { year, month }
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{:02}", self.year, self.month)
+    }
+}
+
+/// Parses the release out of rtl_433's version line, e.g. `rtl_433 version
+/// 23.11 (2023-11-28) inputs file rtl_tcp RTL-SDR`, or `rtl_433 version
+/// 23.11-42-g1a2b3c4 ...` for a build from git after the release
+pub fn parse_version(output: &str) -> Option<Version> {
+    let version = output
+        .lines()
+        .find_map(|line| line.split_once("rtl_433 version "))?
+        .1;
+    let version = version.split(['-', ' ']).next()?;
+    let (year, month) = version.split_once('.')?;
+    Some(Version::new(year.parse().ok()?, month.parse().ok()?))
+}
+
+/// Reads the release of the rtl_433 at `binpath`, `None` if it isn't one
+pub fn probe(binpath: &Path) -> Result<Option<Version>> {
+    let output = std::process::Command::new(binpath)
+        .arg("-V")
+        .stdin(std::process::Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to read the version of {}", binpath.display()))?;
+    // rtl_433 prints its version to stderr
+    let mut text = String::from_utf8_lossy(&output.stderr).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stdout));
+    let version = parse_version(&text);
+    match version {
+        Some(version) => log::info!("rtl_433 version {}", version),
+        None => log::debug!(
+            "rtl_433 at {} isn't a release, taking it to be the newest",
+            binpath.display()
+        ),
+    }
+    Ok(version)
+}
+
+/// The arguments of the rtl_433 release radios are launched with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compat {
+    version: Option<Version>,
+}
+
+impl Compat {
+    /// Adapts to `version`, `None` being the newest, refusing releases that
+    /// are too old
+    pub fn new(version: Option<Version>) -> Result<Self, CompatError> {
+        match version {
+            Some(found) if found < MINIMUM => Err(CompatError::TooOld { found }),
+            version => Ok(Compat { version }),
+        }
+    }
+
+    /// Adapts to the rtl_433 at `binpath`
+    pub fn probe(binpath: &Path) -> Result<Self> {
+        Ok(Compat::new(probe(binpath)?)?)
+    }
+
+    fn supports(&self, since: Version) -> bool {
+        !matches!(self.version, Some(version) if version < since)
+    }
+
+    // Refuses a feature the release doesn't support
+    fn require(&self, since: Version, feature: &'static str) -> Result<(), CompatError> {
+        match self.version {
+            Some(found) if found < since => Err(CompatError::Unsupported {
+                found,
+                feature,
+                needed: since,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// The arguments a radio is launched with around its own, which must
+    /// have had their decoder names resolved: JSON records with UTC
+    /// timestamps, in metric units for the metric and SI systems and in
+    /// customary units otherwise, and each record's signal level and protocol
+    /// too when `detailed`
+    pub fn args(
+        &self,
+        radio_args: &[String],
+        units: Option<UnitSystem>,
+        detailed: bool,
+    ) -> Result<Vec<String>, CompatError> {
+        let mut decoders = radio_args
+            .iter()
+            .zip(radio_args.iter().skip(1))
+            .filter(|(flag, _)| *flag == "-R")
+            .map(|(_, value)| value);
+        if decoders.any(|value| value.contains(':')) {
+            self.require(DECODER_OPTIONS, "decoder options")?;
+        }
+        let mut args = match self.supports(META_TIME) {
+            true => vec!["-Mtime:utc".to_owned()],
+            false => vec!["-Mutc".to_owned()],
+        };
+        args.push("-Fjson".to_owned());
+        args.extend(radio_args.iter().cloned());
+        // rtl_433's "si" is metric, with temperatures in °C rather than K
+        args.push(match units {
+            Some(UnitSystem::Metric | UnitSystem::Si) => "-Csi".to_owned(),
+            Some(UnitSystem::Imperial) | None => "-Ccustomary".to_owned(),
+        });
+        if detailed {
+            args.push("-Mlevel".to_owned());
+            match self.supports(META_PROTOCOL) {
+                true => args.push("-Mprotocol".to_owned()),
+                false => log::debug!("rtl_433 too old to report the protocol of records"),
+            }
+        }
+        Ok(args)
+    }
+}
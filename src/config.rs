@@ -1,38 +1,128 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::convert::TryFrom;
 
 use anyhow::{Context, Result};
+#[cfg(feature = "keyring")]
 use clap::crate_name;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
-pub(crate) enum ConfigError {
+pub enum ConfigError {
     #[error("File read error")]
     ReadError(#[from] std::io::Error),
     #[error("Json parse error")]
     JsonError(#[from] serde_json::Error),
     #[error("Argument error: missing mqtt broker for mqtt credentials")]
     MqttMissingBroker,
+    #[error(
+        "Argument error: unsupported mqtt broker scheme in '{0}', expected tcp://, ws:// or wss://"
+    )]
+    BrokerScheme(String),
+    #[error("Argument error: mqtt quality of service '{0}' not 0, 1 or 2")]
+    MqttQos(String),
     #[error("Keyring access failure")]
     KeyringError(String),
+    #[error("Built without keyring support")]
+    KeyringUnsupported,
+    #[error("Argument error: history retention requires a history database")]
+    HistoryMissingPath,
+    #[error("Argument error: unknown integrity level '{0}'")]
+    IntegrityLevel(String),
+    #[error("Argument error: unknown region '{0}', expected US915, EU868 or AU")]
+    Region(String),
+    #[error("Argument error: location assignment '{0}' not of the form SENSOR_ID=LOCATION")]
+    LocationFormat(String),
+    #[error("Argument error: retain setting '{0}' not of the form SENSOR_ID=on|off")]
+    RetainFormat(String),
+    #[error("Argument error: probe name '{0}' not of the form DEVICE/PROBE=NAME")]
+    ProbeFormat(String),
+    #[error("Argument error: publish interval '{0}' not of the form SENSOR_ID=SECONDS")]
+    PublishIntervalFormat(String),
+    #[error("Argument error: unknown power profile '{0}'")]
+    PowerProfile(String),
+    #[error("Argument error: statistics window '{0}' not a number of seconds, minutes, hours or days, e.g. '5m'")]
+    StatsWindow(String),
+    #[error("Argument error: rain day start '{0}' not a time of day, e.g. '09:00'")]
+    RainDayStart(String),
+    #[error("Argument error: sampling rule '{0}' not '1/N' or a number of seconds, e.g. '60s'")]
+    SampleRule(String),
+    #[error("Argument error: sampling '{0}' not of the form MODEL=RULE")]
+    SampleFormat(String),
+    #[error("Argument error: CWOP callsign '{0}' not up to 9 letters, digits and dashes")]
+    CwopCallsign(String),
+    #[error("Argument error: CWOP station location '{0}' not a latitude and longitude in degrees")]
+    CwopLocation(String),
+    #[error("Argument error: Ecowitt channel {0} not from 1 to 8")]
+    EcowittChannel(u8),
+    #[error("Argument error: webhook for '{0}' has a threshold but no measurement")]
+    WebhookThreshold(String),
+    #[error("Argument error: usage statistics endpoint '{0}' not an http or https URL")]
+    UsageEndpoint(String),
+    #[error("Argument error: usage statistics interval must be at least a second")]
+    UsageInterval,
+    #[error("Argument error: update url '{0}' not an https URL")]
+    UpdateUrl(String),
+    #[error("Argument error: unknown configuration profile '{0}'")]
+    UnknownProfile(String),
+    #[error("Argument error: invalid profile schedule date '{0}', expected MM-DD")]
+    ProfileDate(String),
+    #[error("Argument error: configuration profile '{0}' is invalid: {1}")]
+    InvalidProfile(String, serde_json::Error),
+    #[error("Argument error: radio '{0}' not of the form NAME=ARGS")]
+    RadioFormat(String),
+    #[error("Argument error: unknown receiver '{0}'")]
+    Receiver(String),
+    #[error("Built without RTL-SDR support")]
+    RtlSdrUnsupported,
+    #[error("Argument error: unknown topic naming strategy '{0}'")]
+    TopicNaming(String),
+    #[error("Argument error: unknown payload format '{0}'")]
+    PayloadFormat(String),
+    #[error("Argument error: unknown log format '{0}'")]
+    LogFormat(String),
+    #[error("Argument error: fault injection rate '{0}' not between 0 and 1")]
+    ChaosRate(String),
+    #[error("Argument error: invalid sensor pattern '{pattern}': {reason}")]
+    SensorPattern { pattern: String, reason: String },
+    #[error("Argument error: pipeline stage '{0}' given more than once")]
+    PipelineDuplicate(String),
+    #[error("Argument error: pipeline stage '{0}' can't come after '{1}'; stages go filters, calibrators, derivers, aggregators, then sinks")]
+    PipelineOrder(String, String),
+    #[error("Argument error: pipeline stage 'sea-level' needs an altitude, or station_altitude to be set")]
+    PipelineAltitude,
+    #[error("Argument error: name '{1}' of sensor '{0}' is empty or contains an mqtt wildcard")]
+    SensorName(String, String),
+    #[error("Argument error: mqtt protocol version '{0}' not 4 (MQTT 3.1.1) or 5 (MQTT 5)")]
+    MqttProtocolVersion(String),
 }
 
+/// Account used for connecting to the mqtt broker, and where its password is
+/// kept
 #[derive(Serialize, Deserialize, Clone)]
-pub(crate) enum Credentials {
+pub enum Credentials {
+    /// A username whose password is kept on the keyring, under the username
+    /// and the broker it's for
     Keyring(String),
     ConfigFile(String, String),
 }
 
+/// What the keyring mqtt passwords are kept on is called, for messages
+#[cfg(windows)]
+pub const KEYRING_NAME: &str = "Windows Credential Manager";
+#[cfg(not(windows))]
+pub const KEYRING_NAME: &str = "session keyring";
+
 impl Credentials {
-    pub(crate) fn get(&self) -> Option<(String, String)> {
-        match (self.username(), self.password().ok().flatten()) {
+    /// The username and password to connect to `broker` with
+    pub fn get(&self, broker: &str) -> Option<(String, String)> {
+        match (self.username(), self.password(broker).ok().flatten()) {
             (Some(u), Some(p)) if !u.is_empty() && !p.is_empty() => Some((u, p)),
             _ => None,
         }
     }
 
-    pub(crate) fn username(&self) -> Option<String> {
+    pub fn username(&self) -> Option<String> {
         match self {
             Credentials::Keyring(u) if u.is_empty() => None,
             Credentials::Keyring(u) => Some(u.clone()),
@@ -41,21 +131,24 @@ impl Credentials {
         }
     }
 
-    pub(crate) fn password(&self) -> Result<Option<String>> {
+    /// The password to connect to `broker` with
+    pub fn password(&self, broker: &str) -> Result<Option<String>> {
         match self {
-            Credentials::Keyring(u) => Credentials::get_from_keyring(u).with_context(|| {
-                format!(
-                    "Failed retrieving secrets for user {} from session keyring",
-                    &u,
-                )
-            }),
+            Credentials::Keyring(u) => {
+                Credentials::get_from_keyring(broker, u).with_context(|| {
+                    format!(
+                        "Failed retrieving secrets for user {} of {} from {}",
+                        &u, broker, KEYRING_NAME,
+                    )
+                })
+            }
             Credentials::ConfigFile(_, p) if p.is_empty() => Ok(None),
             Credentials::ConfigFile(_, p) => Ok(Some(p.clone())),
         }
     }
 
     #[must_use = "Credentials may not be mutated in-place. Calling \"update_<field>()\" creates a copy with the updated value."]
-    pub(crate) fn update_username(&self, username: &str) -> Credentials {
+    pub fn update_username(&self, username: &str) -> Credentials {
         let mut dup = self.clone();
         let username = username.to_string();
         match dup {
@@ -70,14 +163,16 @@ impl Credentials {
     }
 
     #[must_use = "Credentials may not be mutated in-place. Calling \"update_<field>()\" creates a copy with the updated value."]
-    pub(crate) fn update_password(&self, password: &str) -> Result<Credentials> {
+    pub fn update_password(&self, broker: &str, password: &str) -> Result<Credentials> {
         let mut dup = self.clone();
         match &mut dup {
-            Credentials::Keyring(u) => {
-                Credentials::set_on_keyring(u, password).with_context(|| {
-                    format!("Failed updating secret for user {} on session keyring", &u)
-                })?
-            }
+            Credentials::Keyring(u) => Credentials::set_on_keyring(broker, u, password)
+                .with_context(|| {
+                    format!(
+                        "Failed updating secret for user {} of {} on {}",
+                        &u, broker, KEYRING_NAME
+                    )
+                })?,
             Credentials::ConfigFile(_, ref mut p) => {
                 if *p != password {
                     *p = password.to_string();
@@ -88,14 +183,14 @@ impl Credentials {
     }
 
     #[must_use = "Credentials may not be converted between variants in-place. Calling \"as_<type>\" creates a copy as another variant."]
-    pub(crate) fn as_keyring(&self) -> Result<Credentials> {
+    pub fn as_keyring(&self, broker: &str) -> Result<Credentials> {
         match self {
             Self::Keyring(_) => Ok(self.clone()),
             c => {
                 let username = c.username().unwrap_or_default();
-                let password = c.password().ok().flatten().unwrap_or_default();
+                let password = c.password(broker).ok().flatten().unwrap_or_default();
                 if !username.is_empty() && !password.is_empty() {
-                    Credentials::set_on_keyring(&username, &password)?;
+                    Credentials::set_on_keyring(broker, &username, &password)?;
                 }
                 Ok(Self::Keyring(username))
             }
@@ -103,42 +198,66 @@ impl Credentials {
     }
 
     #[must_use = "Credentials may not be converted between variants in-place. Calling \"as_<type>\" creates a copy as another variant."]
-    pub(crate) fn as_configfile(&self) -> Credentials {
+    pub fn as_configfile(&self, broker: &str) -> Credentials {
         match self {
             Self::ConfigFile(_, _) => self.clone(),
             c => {
                 let username = c.username().unwrap_or_default();
-                let password = c.password().ok().flatten().unwrap_or_default();
+                let password = c.password(broker).ok().flatten().unwrap_or_default();
                 Self::ConfigFile(username, password)
             }
         }
     }
 
-    fn get_from_keyring(username: &str) -> Result<Option<String>> {
-        let service = String::from(crate_name!());
-        let keyring = keyring::Entry::new(&service, username)?;
-        match keyring.get_password() {
+    // The keyring entry of `username`'s password for `broker`
+    #[cfg(feature = "keyring")]
+    fn keyring_entry(broker: &str, username: &str) -> keyring::Result<keyring::Entry> {
+        keyring::Entry::new(crate_name!(), &format!("{}@{}", username, broker))
+    }
+
+    #[cfg(feature = "keyring")]
+    fn get_from_keyring(broker: &str, username: &str) -> Result<Option<String>> {
+        let error = |e: keyring::Error| {
+            Err(ConfigError::KeyringError(e.to_string())).with_context(|| {
+                format!("Error contacting {} for user {}", KEYRING_NAME, &username)
+            })
+        };
+        match Credentials::keyring_entry(broker, username)?.get_password() {
+            Ok(p) => return Ok(Some(p)),
+            Err(keyring::error::Error::NoEntry) => {}
+            Err(e) => return error(e),
+        }
+        // Passwords used to be kept under the username alone, for whichever
+        // broker
+        match keyring::Entry::new(crate_name!(), username)?.get_password() {
             Ok(p) => Ok(Some(p)),
             Err(keyring::error::Error::NoEntry) => Ok(None),
-            Err(e) => Err(ConfigError::KeyringError(e.to_string())).with_context(|| {
-                format!("Error contacting session keyring for user {}", &username)
-            }),
+            Err(e) => error(e),
         }
     }
 
-    fn set_on_keyring(username: &str, password: &str) -> Result<()> {
-        let service = String::from(crate_name!());
-        let keyring = keyring::Entry::new(&service, username)?;
-        keyring
+    #[cfg(feature = "keyring")]
+    fn set_on_keyring(broker: &str, username: &str, password: &str) -> Result<()> {
+        Credentials::keyring_entry(broker, username)?
             .set_password(password)
             .map_err(|e| ConfigError::KeyringError(e.to_string()))
             .with_context(|| {
                 format!(
-                    "Failed updating secret for user {} on session keyring",
-                    &username
+                    "Failed updating secret for user {} on {}",
+                    &username, KEYRING_NAME
                 )
             })
     }
+
+    #[cfg(not(feature = "keyring"))]
+    fn get_from_keyring(_broker: &str, _username: &str) -> Result<Option<String>> {
+        Err(ConfigError::KeyringUnsupported.into())
+    }
+
+    #[cfg(not(feature = "keyring"))]
+    fn set_on_keyring(_broker: &str, _username: &str, _password: &str) -> Result<()> {
+        Err(ConfigError::KeyringUnsupported.into())
+    }
 }
 
 impl Default for Credentials {
@@ -168,35 +287,725 @@ impl std::cmp::PartialEq<Credentials> for Credentials {
             return false;
         }
 
-        if self.password().ok() != other.password().ok() {
-            return false;
+        // Keyring passwords are kept by username, so only those kept in the
+        // configuration file can differ
+        match (self, other) {
+            (Credentials::ConfigFile(_, p), Credentials::ConfigFile(_, q)) => p == q,
+            _ => true,
         }
-
-        true
     }
 }
 
+/// How the mqtt broker is reached, chosen by the scheme of its address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// Plain TCP, for addresses like `localhost:1883` or `tcp://localhost:1883`
+    Tcp,
+    /// WebSockets, for addresses like `ws://proxy:80/mqtt`
+    WebSocket,
+    /// WebSockets over TLS, for addresses like `wss://proxy:443/mqtt`
+    SecureWebSocket,
+}
+
+/// Connection settings for the mqtt broker
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub(crate) struct MqttConfig {
-    pub(crate) broker: String,
-    pub(crate) credentials: Option<Credentials>,
+pub struct MqttConfig {
+    /// Address of the broker, as `host:port` or a URI whose scheme selects
+    /// the [`Transport`]
+    pub broker: String,
+    /// Path of the WebSocket endpoint, for WebSocket broker addresses
+    /// without a path of their own
+    #[serde(default)]
+    pub ws_path: Option<String>,
+    pub credentials: Option<Credentials>,
+    /// Client id to connect with, by default derived from the instance id
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// Messages waiting to be published before further ones are dropped
+    #[serde(default)]
+    pub queue_capacity: Option<usize>,
+    /// Seconds a publish may take before the broker is given up on
+    #[serde(default)]
+    pub publish_timeout: Option<u64>,
+    /// Quality of service records are published with: 0 for at most once, 1
+    /// for at least once, or 2 for exactly once
+    #[serde(default)]
+    pub qos: Option<i32>,
+    /// Whether the broker discards the session on connecting; a persistent
+    /// session keeps subscriptions and queued messages across restarts
+    #[serde(default)]
+    pub clean_session: Option<bool>,
+    /// Seconds between keep-alive pings when nothing else is sent
+    #[serde(default)]
+    pub keep_alive: Option<u64>,
+    /// Prefix of the topics records are published to on this broker, in
+    /// place of `topic_prefix`
+    #[serde(default)]
+    pub topic_prefix: Option<String>,
+    /// Sensors whose records are published to this broker, as
+    /// [`SensorPattern`]s, or every sensor's when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sensors: Vec<String>,
+    /// Version of the mqtt protocol to connect with: 4 for MQTT 3.1.1, or 5
+    /// for MQTT 5, which gives readings an expiry, a content type and user
+    /// properties
+    #[serde(default)]
+    pub protocol_version: Option<u8>,
+    /// Seconds the broker holds on to a reading for subscribers before
+    /// discarding it as stale, with MQTT 5; 0 for as long as it likes
+    #[serde(default)]
+    pub message_expiry: Option<u32>,
+}
+
+// Reads the brokers as one broker's settings, a list of them, or null
+fn deserialize_brokers<'de, D>(deserializer: D) -> std::result::Result<Vec<MqttConfig>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Brokers {
+        One(Box<MqttConfig>),
+        Many(Vec<MqttConfig>),
+    }
+    Ok(match Option::<Brokers>::deserialize(deserializer)? {
+        Some(Brokers::One(mqtt)) => vec![*mqtt],
+        Some(Brokers::Many(brokers)) => brokers,
+        None => Vec::new(),
+    })
+}
+
+// Writes the brokers back as they're most simply read: a single broker's
+// settings on their own, and none as null
+fn serialize_brokers<S>(
+    brokers: &[MqttConfig],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match brokers {
+        [] => serializer.serialize_none(),
+        [mqtt] => mqtt.serialize(serializer),
+        brokers => brokers.serialize(serializer),
+    }
 }
 
 impl MqttConfig {
-    pub(crate) fn new<S: Into<String>>(broker: S) -> Self {
+    pub fn new<S: Into<String>>(broker: S) -> Self {
         MqttConfig {
             broker: broker.into(),
+            ws_path: None,
             credentials: None,
+            client_id: None,
+            queue_capacity: None,
+            publish_timeout: None,
+            qos: None,
+            clean_session: None,
+            keep_alive: None,
+            topic_prefix: None,
+            sensors: Vec::new(),
+            protocol_version: None,
+            message_expiry: None,
+        }
+    }
+
+    pub fn get_queue_capacity(&self) -> usize {
+        self.queue_capacity.unwrap_or(1024).max(1)
+    }
+
+    pub fn get_publish_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.publish_timeout.unwrap_or(30))
+    }
+
+    /// The [`SensorPattern`]s of the sensors published to this broker
+    pub fn sensor_patterns(&self) -> std::result::Result<Vec<SensorPattern>, ConfigError> {
+        self.sensors.iter().map(|pattern| pattern.parse()).collect()
+    }
+
+    /// The transport the broker is reached over, from its address's scheme
+    pub fn transport(&self) -> std::result::Result<Transport, ConfigError> {
+        match self.broker.split_once("://") {
+            None => Ok(Transport::Tcp),
+            Some(("tcp", _)) => Ok(Transport::Tcp),
+            Some(("ws", _)) => Ok(Transport::WebSocket),
+            Some(("wss", _)) => Ok(Transport::SecureWebSocket),
+            Some(_) => Err(ConfigError::BrokerScheme(self.broker.clone())),
+        }
+    }
+
+    /// The broker's `host:port`, without any scheme or path
+    pub fn host_port(&self) -> &str {
+        let address = self
+            .broker
+            .split_once("://")
+            .map(|(_, address)| address)
+            .unwrap_or(&self.broker);
+        address.split('/').next().unwrap_or(address)
+    }
+
+    /// The broker's address as a URI, with the scheme of its transport and
+    /// the configured WebSocket path if it has none of its own
+    pub fn server_uri(&self) -> std::result::Result<String, ConfigError> {
+        let scheme = match self.transport()? {
+            Transport::Tcp => return Ok(format!("tcp://{}", self.host_port())),
+            Transport::WebSocket => "ws",
+            Transport::SecureWebSocket => "wss",
+        };
+        let address = self.broker.split_once("://").map_or("", |(_, a)| a);
+        match (address.contains('/'), &self.ws_path) {
+            (false, Some(path)) => Ok(format!(
+                "{}://{}/{}",
+                scheme,
+                address,
+                path.trim_start_matches('/')
+            )),
+            _ => Ok(self.broker.clone()),
+        }
+    }
+
+    pub fn get_qos(&self) -> i32 {
+        self.qos.unwrap_or(2)
+    }
+
+    pub fn get_clean_session(&self) -> bool {
+        self.clean_session.unwrap_or(true)
+    }
+
+    pub fn get_keep_alive(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.keep_alive.unwrap_or(20).max(1))
+    }
+
+    pub fn get_protocol_version(&self) -> u8 {
+        self.protocol_version.unwrap_or(4)
+    }
+
+    /// Seconds before the broker discards a reading, if ever
+    pub fn get_message_expiry(&self) -> Option<u32> {
+        Some(self.message_expiry.unwrap_or(3600)).filter(|secs| *secs > 0)
+    }
+}
+
+/// Settings for the local history database
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HistoryConfig {
+    pub path: std::path::PathBuf,
+    /// Days to keep records for; records are kept forever when unset
+    #[serde(default)]
+    pub retention_days: Option<u64>,
+}
+
+impl HistoryConfig {
+    pub fn new<P: Into<std::path::PathBuf>>(path: P) -> Self {
+        HistoryConfig {
+            path: path.into(),
+            retention_days: None,
+        }
+    }
+}
+
+/// What records are received through
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Receiver {
+    /// An rtl_433 child process per configured radio
+    #[default]
+    #[serde(rename = "rtl_433")]
+    Rtl433,
+    /// An RTL-SDR dongle read directly, receiving only the Fine Offset
+    /// sensors the raw packet decoders support
+    #[serde(rename = "rtl-sdr")]
+    RtlSdr,
+}
+
+impl std::str::FromStr for Receiver {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "rtl_433" => Ok(Receiver::Rtl433),
+            "rtl-sdr" => Ok(Receiver::RtlSdr),
+            _ => Err(ConfigError::Receiver(s.to_owned())),
+        }
+    }
+}
+
+/// Strength of the integrity check a decoder applied to a packet, as reported
+/// in the "mic" field of rtl_433's output, weakest first
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Integrity {
+    #[default]
+    None,
+    Parity,
+    Checksum,
+    Crc,
+}
+
+impl std::str::FromStr for Integrity {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "NONE" => Ok(Integrity::None),
+            "PARITY" => Ok(Integrity::Parity),
+            "CHECKSUM" => Ok(Integrity::Checksum),
+            "CRC" => Ok(Integrity::Crc),
+            _ => Err(ConfigError::IntegrityLevel(s.to_owned())),
         }
     }
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
-pub(crate) struct Config {
-    pub(crate) output_level: Option<u8>,
-    pub(crate) rtl_433: Option<std::path::PathBuf>,
-    pub(crate) mqtt: Option<MqttConfig>,
-    pub(crate) sensor_ignores: HashSet<String>,
+pub struct IntegrityConfig {
+    /// Weakest integrity check accepted from sensors without an override
+    pub required: Integrity,
+    /// Per-sensor overrides of the required integrity check
+    #[serde(default)]
+    pub sensors: BTreeMap<String, Integrity>,
+}
+
+impl IntegrityConfig {
+    pub fn required_for(&self, sensor_id: &str) -> Integrity {
+        self.sensors
+            .get(sensor_id)
+            .copied()
+            .unwrap_or(self.required)
+    }
+
+    /// Whether a record's integrity check meets the requirement for its
+    /// sensor
+    pub fn accepts(&self, record: &crate::radio::Record) -> bool {
+        let required = self.required_for(&record.sensor_id);
+        if record.integrity() < required {
+            log::debug!(
+                "[{}] Dropping record with integrity {:?}, {:?} required",
+                record.sensor_id,
+                record.integrity(),
+                required
+            );
+            return false;
+        }
+        true
+    }
+}
+
+/// An ignore or allow list entry, compiled for matching against sensor ids
+#[derive(Clone, Debug)]
+pub enum SensorPattern {
+    /// A sensor id
+    Exact(String),
+    /// A prefix of sensor ids, ending in `/`, e.g. `AmbientWeather-WH31E/`
+    Prefix(String),
+    /// A glob in which `*` matches any run of characters and `?` any single
+    /// character, e.g. `IDM/4*`, or a regex prefixed with `re:` that matches
+    /// anywhere in the sensor id unless anchored, e.g. `re:^IDM/4\d+$`
+    Regex(regex::Regex),
+}
+
+impl std::str::FromStr for SensorPattern {
+    type Err = ConfigError;
+
+    fn from_str(pattern: &str) -> std::result::Result<Self, Self::Err> {
+        let regex = if let Some(regex) = pattern.strip_prefix("re:") {
+            regex.to_owned()
+        } else if pattern.ends_with('/') {
+            return Ok(Self::Prefix(pattern.to_owned()));
+        } else if pattern.contains(['*', '?']) {
+            let glob: String = pattern
+                .split_inclusive(['*', '?'])
+                .map(|part| match part.char_indices().last() {
+                    Some((i, '*')) => format!("{}.*", regex::escape(&part[..i])),
+                    Some((i, '?')) => format!("{}.", regex::escape(&part[..i])),
+                    _ => regex::escape(part),
+                })
+                .collect();
+            format!("^{}$", glob)
+        } else {
+            return Ok(Self::Exact(pattern.to_owned()));
+        };
+        regex::Regex::new(&regex)
+            .map(Self::Regex)
+            .map_err(|e| ConfigError::SensorPattern {
+                pattern: pattern.to_owned(),
+                reason: e.to_string(),
+            })
+    }
+}
+
+impl SensorPattern {
+    pub fn matches(&self, sensor_id: &str) -> bool {
+        match self {
+            Self::Exact(id) => id == sensor_id,
+            Self::Prefix(prefix) => sensor_id.starts_with(prefix.as_str()),
+            Self::Regex(regex) => regex.is_match(sensor_id),
+        }
+    }
+}
+
+/// The ignore and allow lists, compiled once rather than for every record
+#[derive(Clone, Debug, Default)]
+pub struct SensorFilter {
+    ignores: Vec<SensorPattern>,
+    allows: Vec<SensorPattern>,
+}
+
+impl SensorFilter {
+    pub fn new(
+        ignores: &HashSet<String>,
+        allows: &HashSet<String>,
+    ) -> std::result::Result<Self, ConfigError> {
+        let compile = |patterns: &HashSet<String>| {
+            patterns
+                .iter()
+                .map(|pattern| pattern.parse())
+                .collect::<std::result::Result<Vec<SensorPattern>, ConfigError>>()
+        };
+        Ok(SensorFilter {
+            ignores: compile(ignores)?,
+            allows: compile(allows)?,
+        })
+    }
+
+    /// Whether records from a sensor are dropped, because it's ignored or
+    /// because there's an allow list it isn't on
+    pub fn is_ignored(&self, sensor_id: &str) -> bool {
+        let matches = |patterns: &[SensorPattern]| patterns.iter().any(|p| p.matches(sensor_id));
+        matches(&self.ignores) || (!self.allows.is_empty() && !matches(&self.allows))
+    }
+}
+
+/// Which sensors' data and measurement topics the broker retains the latest
+/// message of, so that subscribers see it as soon as they connect rather
+/// than at the sensor's next transmission
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RetainConfig {
+    /// Whether topics are retained for sensors without an override
+    #[serde(default)]
+    pub all: bool,
+    /// Per-sensor overrides, by [`SensorPattern`]; sensor ids take
+    /// precedence over other patterns
+    #[serde(default)]
+    pub sensors: BTreeMap<String, bool>,
+}
+
+/// The retain settings, compiled once rather than for every record
+#[derive(Clone, Debug, Default)]
+pub struct RetainPolicy {
+    all: bool,
+    sensors: Vec<(SensorPattern, bool)>,
+}
+
+impl RetainPolicy {
+    pub fn new(retain: &RetainConfig) -> std::result::Result<Self, ConfigError> {
+        let mut sensors = retain
+            .sensors
+            .iter()
+            .map(|(pattern, on)| Ok((pattern.parse()?, *on)))
+            .collect::<std::result::Result<Vec<(SensorPattern, bool)>, ConfigError>>()?;
+        sensors.sort_by_key(|(pattern, _)| !matches!(pattern, SensorPattern::Exact(_)));
+        Ok(RetainPolicy {
+            all: retain.all,
+            sensors,
+        })
+    }
+
+    /// Whether the topics of a sensor are published retained
+    pub fn retains(&self, sensor_id: &str) -> bool {
+        self.sensors
+            .iter()
+            .find(|(pattern, _)| pattern.matches(sensor_id))
+            .map(|(_, on)| *on)
+            .unwrap_or(self.all)
+    }
+}
+
+/// What's known of a sensor beyond what it reports, e.g. that
+/// `AmbientWeather-WH31E/3` is the greenhouse's
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SensorInfo {
+    /// Name the sensor is published under in place of its id, as with
+    /// `aliases`, and referred to by in the API and log lines
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Location the sensor is grouped under, taking precedence over
+    /// `locations`
+    #[serde(default)]
+    pub location: Option<String>,
+    /// Labels published in the sensor's metadata and given by the API
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+}
+
+/// Application settings, persisted as json and overridden by command line
+/// arguments
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Config {
+    pub output_level: Option<u8>,
+    /// Whether records are received through rtl_433 or directly from an
+    /// RTL-SDR dongle
+    #[serde(default)]
+    pub receiver: Receiver,
+    pub rtl_433: Option<std::path::PathBuf>,
+    /// Where the station's sensors were sold, selecting the frequency and
+    /// decoders listened with unless radios are configured
+    #[serde(default)]
+    pub region: crate::region::Region,
+    /// rtl_433 arguments selecting the receiver, frequency and decoders of
+    /// each radio to run concurrently, by the name its records are tagged
+    /// with. A single radio listening for Fine Offset sensors on the
+    /// region's frequency is run when there are none.
+    #[serde(default)]
+    pub radios: BTreeMap<String, Vec<String>>,
+    /// Dongle settings when receiving directly from an RTL-SDR
+    #[cfg(feature = "rtlsdr")]
+    #[serde(default)]
+    pub rtlsdr: crate::rtlsdr::RtlSdrConfig,
+    /// Brokers records are published to, given as one broker's settings or
+    /// a list of them. Each is published the records of the sensors matching
+    /// its `sensors`; only the first is published the bridge's own topics.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_brokers",
+        serialize_with = "serialize_brokers"
+    )]
+    pub mqtt: Vec<MqttConfig>,
+    pub sensor_ignores: HashSet<String>,
+    /// Sensors to publish, if any are listed, dropping records from all
+    /// others; entries in both lists are [`SensorPattern`]s
+    #[serde(default)]
+    pub sensor_allows: HashSet<String>,
+    /// Sensors, or decoders, whose records are logged in detail whatever
+    /// the log level, as [`SensorPattern`]s
+    #[serde(default)]
+    pub trace: Vec<String>,
+    /// Sensors grouped by location; location names may be `/`-separated
+    /// hierarchies, e.g. `outdoor/greenhouse`
+    #[serde(default)]
+    pub locations: BTreeMap<String, HashSet<String>>,
+    /// Friendly names, locations and tags of sensors, by sensor id
+    #[serde(default)]
+    pub sensors: BTreeMap<String, SensorInfo>,
+    #[serde(default)]
+    pub integrity: IntegrityConfig,
+    /// Seconds without a record before a sensor is reported offline
+    #[serde(default)]
+    pub sensor_timeout: Option<u64>,
+    /// Seconds without a record before a sensor is considered lost, rather
+    /// than just stale
+    #[serde(default)]
+    pub sensor_lost_timeout: Option<u64>,
+    /// Seconds after startup during which sensors going quiet and
+    /// implausible readings aren't alerted on
+    #[serde(default)]
+    pub startup_grace: Option<u64>,
+    /// Seconds between summary log lines, which replace the info log line
+    /// per published record; there's a line per record when unset
+    #[serde(default)]
+    pub log_summary: Option<u64>,
+    /// Layout of the log lines: plain text, or a json object per line for
+    /// log collectors
+    #[serde(default)]
+    pub log_format: crate::logformat::LogFormat,
+    /// Seconds between publishes of telemetry, e.g. percentiles of the
+    /// latency from receiving records to publishing them; no telemetry is
+    /// published when unset
+    #[serde(default)]
+    pub telemetry_interval: Option<u64>,
+    /// Seconds within which identical records from a sensor are dropped as
+    /// repeats
+    #[serde(default)]
+    pub dedup_window: Option<u64>,
+    /// Seconds to hold records for confirmation before republishing them on
+    /// a `validated` topic; two-stage publishing is disabled when unset
+    #[serde(default)]
+    pub validation_delay: Option<u64>,
+    /// Repeat transmissions of a reading needed within the validation delay
+    /// for it to be republished as validated, 1 when unset
+    #[serde(default)]
+    pub validation_confirmations: Option<u32>,
+    /// Packed capture every record from rtl_433 is appended to, or a
+    /// directory every line rtl_433 outputs is kept in as is
+    #[serde(default)]
+    pub capture: Option<std::path::PathBuf>,
+    #[serde(default)]
+    pub history: Option<HistoryConfig>,
+    /// Files of newline-delimited json every record published is appended
+    /// to, a file per day or hour
+    #[serde(default)]
+    pub archive: Option<crate::archive::ArchiveConfig>,
+    /// Address the HTTP server for pulling sensor state listens on, e.g.
+    /// `127.0.0.1:8080`; there's no server when unset
+    #[serde(default)]
+    pub api_listen: Option<String>,
+    /// Address the Arrow IPC stream of normalized records listens on, e.g.
+    /// `127.0.0.1:8815`; there's no stream when unset
+    #[serde(default)]
+    pub arrow_listen: Option<String>,
+    /// Minutes of records a client joining the Arrow stream is sent first,
+    /// 60 when unset
+    #[serde(default)]
+    pub arrow_window: Option<u64>,
+    /// Topic level all published topics are nested under
+    #[serde(default)]
+    pub topic_prefix: Option<String>,
+    /// How sensors are named in published topics; a custom naming when
+    /// unset and there's a topic template, and by location otherwise
+    #[serde(default)]
+    pub topic_naming: Option<crate::topics::NamingStrategy>,
+    /// Layout of published topics for custom naming; see
+    /// [`crate::topics::TopicTemplate`]
+    #[serde(default)]
+    pub topic_template: Option<String>,
+    /// Layout of the json payload published for each record
+    #[serde(default)]
+    pub payload_format: crate::normalize::PayloadFormat,
+    /// Whether normalized payloads include the record as rtl_433 reported it
+    #[serde(default)]
+    pub payload_include_raw: bool,
+    /// Whether normalized payloads include how their derived measurements
+    /// were computed
+    #[serde(default)]
+    pub payload_include_lineage: bool,
+    /// System of units published values are presented in; when unset, each
+    /// measurement keeps the units it has always been published in
+    #[serde(default)]
+    pub units: Option<crate::units::UnitSystem>,
+    /// Altitude of the station above sea level, in meters, used to correct
+    /// barometric pressure to sea level; no correction is made when unset
+    #[serde(default)]
+    pub station_altitude: Option<f32>,
+    /// Local time of day rain days start at, e.g. `09:00`, for daily
+    /// rainfall; midnight when unset
+    #[serde(default)]
+    pub rain_day_start: Option<String>,
+    /// File the day's rainfall is kept in across restarts; it's kept in the
+    /// state directory when bridging, and not at all when replaying
+    #[serde(default)]
+    pub rain_state: Option<std::path::PathBuf>,
+    /// File when each sensor was last heard from is kept in across
+    /// restarts, so that sensors that go quiet while the bridge is down are
+    /// still reported; it's kept in the state directory when bridging, and
+    /// not at all when replaying
+    #[serde(default)]
+    pub sensor_state: Option<std::path::PathBuf>,
+    /// Predicting when sensors' batteries run out from the trend of their
+    /// battery levels
+    #[serde(default)]
+    pub battery: Option<crate::battery::BatteryConfig>,
+    /// Checking the system clock against the time radio-controlled clock
+    /// sensors broadcast
+    #[serde(default)]
+    pub radio_time: Option<crate::radiotime::RadioTimeConfig>,
+    /// Submitting anonymous statistics of the devices heard and how many of
+    /// their records decode; nothing is submitted when unset
+    #[serde(default)]
+    pub usage: Option<crate::usage::UsageConfig>,
+    /// What's done with the records none of the parsers make sense of
+    #[serde(default)]
+    pub unparsed: crate::unparsed::UnparsedConfig,
+    /// Whether records of devices no dedicated parser accepts are parsed
+    /// for the fields rtl_433's decoders commonly share
+    #[serde(default)]
+    pub generic_parser: bool,
+    /// File sensors' battery levels are kept in across restarts, so that
+    /// their trends aren't started over; it's kept in the state directory
+    /// when bridging, and not at all when replaying
+    #[serde(default)]
+    pub battery_state: Option<std::path::PathBuf>,
+    /// Size and cardinality limits records are dropped beyond
+    #[serde(default)]
+    pub limits: crate::guardrails::LimitsConfig,
+    /// Sampling of high-rate protocols, by model name or a pattern over
+    /// them; records sampled out are dropped before anything else
+    #[serde(default)]
+    pub sampling: BTreeMap<String, crate::sampling::SampleRule>,
+    /// Sensors whose packets are assembled into the bursts they split a
+    /// message across
+    #[serde(default)]
+    pub bursts: Vec<crate::burst::BurstRule>,
+    /// Corrections for the quirks of particular models, applied to their
+    /// records before anything else
+    #[serde(default)]
+    pub quirks: Vec<crate::quirks::Quirk>,
+    /// Names sensors are published under in place of their ids
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+    /// Names of the probes of multi-probe devices, by model or sensor id and
+    /// then by probe: the channel of devices reporting each probe on a
+    /// channel of its own, or the number in suffixed fields like
+    /// `temperature_2_C`
+    #[serde(default)]
+    pub probes: BTreeMap<String, BTreeMap<String, String>>,
+    /// Offsets added to measurements, by sensor id and then measurement
+    /// name, in the metric unit of each measurement. Raw payloads are
+    /// published as received, so only normalized payloads and derived values
+    /// are calibrated.
+    #[serde(default)]
+    pub calibration: BTreeMap<String, BTreeMap<String, f32>>,
+    /// Retaining the latest message of sensor and measurement topics
+    #[serde(default)]
+    pub retain: RetainConfig,
+    /// Windows the rolling statistics of each sensor's measurements are
+    /// published over, e.g. `5m` or `1h`
+    #[serde(default)]
+    pub stats_windows: Vec<String>,
+    /// Rules switching automation outputs, e.g. relays, on sensor readings
+    #[serde(default)]
+    pub automation: Vec<crate::automation::AutomationRule>,
+    /// Uploads of the current conditions to personal weather station
+    /// networks
+    #[serde(default)]
+    pub uploads: Vec<crate::pws::PwsUpload>,
+    /// Reporting the current conditions to the Citizen Weather Observer
+    /// Program
+    #[serde(default)]
+    pub cwop: Option<crate::cwop::CwopConfig>,
+    /// Uploads to custom servers in the format of an Ecowitt gateway
+    #[serde(default)]
+    pub ecowitt: Vec<crate::ecowitt::EcowittUpload>,
+    /// An Ecowitt gateway receiving the same sensors, whose readings are
+    /// compared with those decoded here
+    #[serde(default)]
+    pub gateway: Option<crate::gateway::GatewayConfig>,
+    /// Publishing signed digests of the records published, for consumers
+    /// to check that none went missing or were altered
+    #[serde(default)]
+    pub audit: Option<crate::audit::AuditConfig>,
+    /// Notifications POSTed to webhooks for records matching a rule
+    #[serde(default)]
+    pub webhooks: Vec<crate::webhook::Webhook>,
+    /// How often records are published
+    #[serde(default)]
+    pub throttle: crate::throttle::ThrottleConfig,
+    /// The stages records go through, in order; every stage, in the order
+    /// they've always been run in, when unset
+    #[serde(default)]
+    pub pipeline: Option<Vec<crate::stages::Stage>>,
+    /// Power profile, for stations running from a battery or solar panel
+    #[serde(default)]
+    pub power: crate::power::PowerConfig,
+    /// Named overlays of settings, merged over the rest of the
+    /// configuration while the profile is active; see [`crate::profiles`]
+    #[serde(default)]
+    pub profiles: BTreeMap<String, serde_json::Map<String, serde_json::Value>>,
+    /// Times of year each profile is active
+    #[serde(default)]
+    pub profile_schedule: Vec<crate::profiles::ProfileSchedule>,
+    /// Profile active regardless of the schedule
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Runtime configuration permitted over mqtt control topics
+    #[serde(default)]
+    pub control: crate::control::ControlConfig,
+    /// Checking for newer releases
+    #[serde(default)]
+    pub update: crate::update::UpdateConfig,
+    /// Stable id of this installation, kept in the state directory rather
+    /// than the configuration file
+    #[serde(skip)]
+    pub instance_id: Option<uuid::Uuid>,
+    /// Fault injection for soak tests, which is never persisted
+    #[serde(skip)]
+    pub chaos: crate::chaos::ChaosConfig,
 }
 
 impl TryFrom<&std::path::Path> for Config {
@@ -218,7 +1027,7 @@ impl TryFrom<&std::path::PathBuf> for Config {
 }
 
 impl Config {
-    pub(crate) fn update_from_args(&mut self, arg_matches: &clap::ArgMatches) -> Result<()> {
+    pub fn update_from_args(&mut self, arg_matches: &clap::ArgMatches) -> Result<()> {
         // We want to be a little bit careful that the absence of configuration
         // args isn't taken as a request to overwrite the configured values with
         // the default
@@ -237,20 +1046,29 @@ impl Config {
             self.rtl_433 = Some(rtl_433_path);
         }
 
+        if let Some(receiver) = arg_matches.value_of("receiver") {
+            self.receiver = receiver.parse()?;
+        }
+
+        #[cfg(feature = "mqtt")]
         if let Some(broker) = arg_matches.value_of("mqtt_broker") {
-            if let Some(ref mut mqtt) = &mut self.mqtt {
-                mqtt.broker = broker.to_owned();
-            } else {
-                self.mqtt = Some(MqttConfig::new(broker));
+            match self.mqtt.first_mut() {
+                Some(mqtt) => mqtt.broker = broker.to_owned(),
+                None => self.mqtt.push(MqttConfig::new(broker)),
             }
         }
 
-        if let Some(ref mut mqtt) = &mut self.mqtt {
+        // The keyring argument only exists when keyring support is built in
+        #[cfg(feature = "mqtt")]
+        let use_keyring =
+            cfg!(feature = "keyring") && arg_matches.is_present("mqtt_credentials_keyring");
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = self.mqtt.first_mut() {
             let cred = mqtt.credentials.clone().unwrap_or_default();
-            let mut new_cred = if arg_matches.is_present("mqtt_keyring_password") {
-                cred.as_keyring()?
-            } else if arg_matches.is_present("mqtt_config_password") {
-                cred.as_configfile()
+            let mut new_cred = if use_keyring {
+                cred.as_keyring(&mqtt.broker)?
+            } else if arg_matches.is_present("mqtt_credentials_config") {
+                cred.as_configfile(&mqtt.broker)
             } else {
                 cred
             };
@@ -258,7 +1076,56 @@ impl Config {
                 new_cred = new_cred.update_username(user);
             }
             mqtt.credentials.replace(new_cred);
-        } else if arg_matches.is_present("mqtt_user") || arg_matches.is_present("mqtt_password") {
+
+            if let Some(qos) = arg_matches.value_of("mqtt_qos") {
+                mqtt.qos = Some(
+                    qos.parse()
+                        .map_err(|_| ConfigError::MqttQos(qos.to_owned()))?,
+                );
+            }
+            if let Some(clean) = arg_matches.value_of("mqtt_clean_session") {
+                mqtt.clean_session = Some(clean == "true");
+            }
+            if let Some(secs) = arg_matches.value_of("mqtt_keep_alive") {
+                mqtt.keep_alive = Some(
+                    secs.parse()
+                        .with_context(|| format!("Invalid keep-alive interval '{}'", secs))?,
+                );
+            }
+            if let Some(client_id) = arg_matches.value_of("mqtt_client_id") {
+                mqtt.client_id = Some(client_id.to_owned());
+            }
+            if let Some(path) = arg_matches.value_of("mqtt_ws_path") {
+                mqtt.ws_path = Some(path.to_owned());
+            }
+            if let Some(version) = arg_matches.value_of("mqtt_protocol_version") {
+                mqtt.protocol_version = Some(
+                    version
+                        .parse()
+                        .map_err(|_| ConfigError::MqttProtocolVersion(version.to_owned()))?,
+                );
+            }
+            if let Some(secs) = arg_matches.value_of("mqtt_message_expiry") {
+                mqtt.message_expiry = Some(
+                    secs.parse()
+                        .with_context(|| format!("Invalid message expiry '{}'", secs))?,
+                );
+            }
+            mqtt.transport()?;
+            if let Some(qos) = mqtt.qos.filter(|qos| !(0..=2).contains(qos)) {
+                return Err(ConfigError::MqttQos(qos.to_string()).into());
+            }
+        } else if arg_matches.is_present("mqtt_user")
+            || use_keyring
+            || arg_matches.is_present("mqtt_credentials_config")
+            || arg_matches.is_present("mqtt_qos")
+            || arg_matches.is_present("mqtt_clean_session")
+            || arg_matches.is_present("mqtt_keep_alive")
+            || arg_matches.is_present("mqtt_client_id")
+            || arg_matches.is_present("mqtt_ws_path")
+            || arg_matches.is_present("mqtt_protocol_version")
+            || arg_matches.is_present("mqtt_message_expiry")
+        {
             return Err(ConfigError::MqttMissingBroker.into());
         }
 
@@ -270,10 +1137,684 @@ impl Config {
                 .map(|s| s.to_owned()),
         );
 
+        self.sensor_allows.extend(
+            arg_matches
+                .values_of("only")
+                .iter_mut()
+                .flatten()
+                .map(|s| s.to_owned()),
+        );
+
+        self.trace.extend(
+            arg_matches
+                .values_of("trace")
+                .iter_mut()
+                .flatten()
+                .map(|s| s.to_owned()),
+        );
+
+        if let Some(timeout) = arg_matches.value_of("sensor_timeout") {
+            self.sensor_timeout = Some(
+                timeout
+                    .parse()
+                    .with_context(|| format!("Invalid sensor timeout '{}'", timeout))?,
+            );
+        }
+
+        if let Some(timeout) = arg_matches.value_of("sensor_lost_timeout") {
+            self.sensor_lost_timeout = Some(
+                timeout
+                    .parse()
+                    .with_context(|| format!("Invalid sensor lost timeout '{}'", timeout))?,
+            );
+        }
+
+        if let Some(grace) = arg_matches.value_of("startup_grace") {
+            self.startup_grace = Some(
+                grace
+                    .parse()
+                    .with_context(|| format!("Invalid startup grace period '{}'", grace))?,
+            );
+        }
+
+        if let Some(interval) = arg_matches.value_of("log_summary") {
+            self.log_summary = Some(
+                interval
+                    .parse()
+                    .with_context(|| format!("Invalid summary interval '{}'", interval))?,
+            );
+        }
+
+        if arg_matches.is_present("publish_unparsed") {
+            self.unparsed.publish = true;
+            if let Some(topic) = arg_matches.value_of("publish_unparsed") {
+                self.unparsed.topic = Some(topic.to_owned());
+            }
+        }
+
+        if arg_matches.is_present("generic_parser") {
+            self.generic_parser = true;
+        }
+
+        if let Some(format) = arg_matches.value_of("log_format") {
+            self.log_format = format.parse()?;
+        }
+
+        if let Some(interval) = arg_matches.value_of("telemetry") {
+            self.telemetry_interval = Some(
+                interval
+                    .parse()
+                    .with_context(|| format!("Invalid telemetry interval '{}'", interval))?,
+            );
+        }
+
+        if let Some(window) = arg_matches.value_of("dedup_window") {
+            self.dedup_window = Some(
+                window
+                    .parse()
+                    .with_context(|| format!("Invalid dedup window '{}'", window))?,
+            );
+        }
+
+        if let Some(delay) = arg_matches.value_of("validation_delay") {
+            self.validation_delay = Some(
+                delay
+                    .parse()
+                    .with_context(|| format!("Invalid validation delay '{}'", delay))?,
+            );
+        }
+
+        if let Some(count) = arg_matches.value_of("validation_confirmations") {
+            self.validation_confirmations = Some(
+                count
+                    .parse()
+                    .with_context(|| format!("Invalid validation confirmations '{}'", count))?,
+            );
+        }
+
+        if let Some(path) = arg_matches.value_of("capture") {
+            self.capture = Some(std::path::PathBuf::from(path));
+        }
+
+        if let Some(path) = arg_matches.value_of("archive") {
+            if let Some(ref mut archive) = &mut self.archive {
+                archive.directory = std::path::PathBuf::from(path);
+            } else {
+                self.archive = Some(crate::archive::ArchiveConfig::new(path.into()));
+            }
+        }
+
+        if let Some(path) = arg_matches.value_of("history_db") {
+            if let Some(ref mut history) = &mut self.history {
+                history.path = std::path::PathBuf::from(path);
+            } else {
+                self.history = Some(HistoryConfig::new(path));
+            }
+        }
+
+        if let Some(days) = arg_matches.value_of("history_retention") {
+            let days = days
+                .parse()
+                .with_context(|| format!("Invalid history retention '{}'", days))?;
+            match &mut self.history {
+                Some(history) => history.retention_days = Some(days),
+                None => return Err(ConfigError::HistoryMissingPath.into()),
+            }
+        }
+
+        if let Some(profile) = arg_matches.value_of("profile") {
+            self.profile = Some(profile.to_owned());
+        }
+
+        if let Some(listen) = arg_matches.value_of("api_listen") {
+            self.api_listen = Some(listen.to_owned());
+        }
+
+        if let Some(listen) = arg_matches.value_of("arrow_listen") {
+            self.arrow_listen = Some(listen.to_owned());
+        }
+
+        if let Some(prefix) = arg_matches.value_of("topic_prefix") {
+            self.topic_prefix = Some(prefix.to_owned());
+        }
+
+        if let Some(naming) = arg_matches.value_of("topic_naming") {
+            self.topic_naming = Some(naming.parse()?);
+        }
+
+        if let Some(template) = arg_matches.value_of("topic_template") {
+            self.topic_template = Some(template.to_owned());
+        }
+
+        if let Some(level) = arg_matches.value_of("required_integrity") {
+            self.integrity.required = level.parse()?;
+        }
+
+        if let Some(region) = arg_matches.value_of("region") {
+            self.region = region.parse()?;
+        }
+
+        for radio in arg_matches.values_of("radio").iter_mut().flatten() {
+            let (name, args) = radio
+                .split_once('=')
+                .filter(|(name, args)| !name.is_empty() && !args.trim().is_empty())
+                .ok_or_else(|| ConfigError::RadioFormat(radio.to_owned()))?;
+            self.radios.insert(
+                name.to_owned(),
+                args.split_whitespace().map(str::to_owned).collect(),
+            );
+        }
+
+        for assignment in arg_matches.values_of("location").iter_mut().flatten() {
+            let (sensor_id, location) = assignment
+                .split_once('=')
+                .filter(|(s, l)| !s.is_empty() && !l.is_empty())
+                .ok_or_else(|| ConfigError::LocationFormat(assignment.to_owned()))?;
+            for sensors in self.locations.values_mut() {
+                sensors.remove(sensor_id);
+            }
+            self.locations
+                .entry(location.trim_matches('/').to_owned())
+                .or_default()
+                .insert(sensor_id.to_owned());
+        }
+        self.locations.retain(|_, sensors| !sensors.is_empty());
+
+        for name in arg_matches.values_of("probe").iter_mut().flatten() {
+            let (device, probe, name) = name
+                .split_once('=')
+                .and_then(|(probe, name)| {
+                    let (device, probe) = probe.rsplit_once('/')?;
+                    Some((device, probe, name))
+                })
+                .filter(|(d, p, n)| !d.is_empty() && !p.is_empty() && !n.is_empty())
+                .ok_or_else(|| ConfigError::ProbeFormat(name.to_owned()))?;
+            self.probes
+                .entry(device.to_owned())
+                .or_default()
+                .insert(probe.to_owned(), name.to_owned());
+        }
+
+        if arg_matches.is_present("retain") {
+            self.retain.all = true;
+        }
+
+        for setting in arg_matches.values_of("retain_sensor").iter_mut().flatten() {
+            let (sensor_id, on) = match setting.rsplit_once('=') {
+                Some((sensor_id, "on")) if !sensor_id.is_empty() => (sensor_id, true),
+                Some((sensor_id, "off")) if !sensor_id.is_empty() => (sensor_id, false),
+                _ => return Err(ConfigError::RetainFormat(setting.to_owned()).into()),
+            };
+            self.retain.sensors.insert(sensor_id.to_owned(), on);
+        }
+
+        if let Some(interval) = arg_matches.value_of("min_publish_interval") {
+            self.throttle.min_interval = Some(
+                interval
+                    .parse()
+                    .with_context(|| format!("Invalid publish interval '{}'", interval))?,
+            );
+        }
+
+        for setting in arg_matches
+            .values_of("min_publish_interval_sensor")
+            .iter_mut()
+            .flatten()
+        {
+            let (sensor_id, interval) = setting
+                .rsplit_once('=')
+                .filter(|(s, _)| !s.is_empty())
+                .and_then(|(s, i)| Some((s, i.parse().ok()?)))
+                .ok_or_else(|| ConfigError::PublishIntervalFormat(setting.to_owned()))?;
+            self.throttle.sensors.insert(sensor_id.to_owned(), interval);
+        }
+
+        if let Some(interval) = arg_matches.value_of("batch") {
+            self.throttle.batch_interval = Some(
+                interval
+                    .parse()
+                    .with_context(|| format!("Invalid batch interval '{}'", interval))?,
+            );
+        }
+
+        for setting in arg_matches.values_of("sample").iter_mut().flatten() {
+            let (model, rule) = setting
+                .rsplit_once('=')
+                .filter(|(m, _)| !m.is_empty())
+                .ok_or_else(|| ConfigError::SampleFormat(setting.to_owned()))?;
+            self.sampling.insert(model.to_owned(), rule.parse()?);
+        }
+
+        if let Some(start) = arg_matches.value_of("rain_day_start") {
+            self.rain_day_start = Some(start.to_owned());
+        }
+
+        if let Some(windows) = arg_matches.values_of("stats_window") {
+            self.stats_windows = windows.map(str::to_owned).collect();
+        }
+
+        if let Some(profile) = arg_matches.value_of("power_profile") {
+            self.power.profile = profile.parse()?;
+        }
+
+        if let Some(format) = arg_matches.value_of("payload_format") {
+            self.payload_format = format.parse()?;
+        }
+
+        if arg_matches.is_present("payload_include_raw") {
+            self.payload_include_raw = true;
+        }
+
+        if arg_matches.is_present("payload_include_lineage") {
+            self.payload_include_lineage = true;
+        }
+
+        if let Some(units) = arg_matches.value_of("units") {
+            self.units = Some(units.parse()?);
+        }
+
+        if let Some(altitude) = arg_matches.value_of("station_altitude") {
+            self.station_altitude = Some(
+                altitude
+                    .parse()
+                    .with_context(|| format!("Invalid station altitude '{}'", altitude))?,
+            );
+        }
+
+        if let Some(max) = arg_matches.value_of("max_payload_bytes") {
+            self.limits.max_payload_bytes = Some(
+                max.parse()
+                    .with_context(|| format!("Invalid payload size limit '{}'", max))?,
+            );
+        }
+
+        if let Some(max) = arg_matches.value_of("max_sensors") {
+            self.limits.max_sensors = Some(
+                max.parse()
+                    .with_context(|| format!("Invalid sensor limit '{}'", max))?,
+            );
+        }
+
+        if let Some(max) = arg_matches.value_of("max_measurement_names") {
+            self.limits.max_measurement_names = Some(
+                max.parse()
+                    .with_context(|| format!("Invalid measurement name limit '{}'", max))?,
+            );
+        }
+
+        for operation in arg_matches.values_of("allow_control").iter_mut().flatten() {
+            self.control.allow.insert(operation.parse()?);
+        }
+
+        if arg_matches.is_present("check_updates") {
+            self.update.check = true;
+        }
+
+        if let Some(url) = arg_matches.value_of("update_url") {
+            self.update.url = Some(url.to_owned());
+        }
+
+        let chaos_rate = |name: &str| -> Result<Option<f64>> {
+            match arg_matches.value_of(name) {
+                Some(rate) => match rate.parse::<f64>() {
+                    Ok(r) if (0.0..=1.0).contains(&r) => Ok(Some(r)),
+                    _ => Err(ConfigError::ChaosRate(rate.to_owned()).into()),
+                },
+                None => Ok(None),
+            }
+        };
+        if let Some(rate) = chaos_rate("chaos_sink_failure_rate")? {
+            self.chaos.sink_failure_rate = rate;
+        }
+        if let Some(rate) = chaos_rate("chaos_disconnect_rate")? {
+            self.chaos.disconnect_rate = rate;
+        }
+        if let Some(rate) = chaos_rate("chaos_malformed_rate")? {
+            self.chaos.malformed_rate = rate;
+        }
+
         Ok(())
     }
 
-    pub(crate) fn get_log_level(&self) -> log::LevelFilter {
+    /// Checks the settings that are only parsed once the bridge starts, so
+    /// that mistakes in them can be caught without starting it
+    pub fn validate(&self) -> Result<()> {
+        match self.problems().into_iter().next() {
+            Some(problem) => Err(problem),
+            None => Ok(()),
+        }
+    }
+
+    /// Every mistake in the settings that are only parsed once the bridge
+    /// starts, rather than only the first
+    pub fn problems(&self) -> Vec<anyhow::Error> {
+        let mut problems = Vec::new();
+        let mut check = |result: Result<()>| problems.extend(result.err());
+        check(self.sensor_filter().map(drop));
+        check(self.retain_policy().map(drop));
+        check(self.topics().map(drop));
+        check(self.throttle().map(drop));
+        check(self.sampler().map(drop));
+        check(self.get_stats_windows().map(drop));
+        check(self.get_rain_day_start().map(drop));
+        check(self.stages().map(drop));
+        for (sensor_id, info) in &self.sensors {
+            if let Some(ref name) = info.name {
+                if name.is_empty() || name.contains(['+', '#']) {
+                    check(Err(ConfigError::SensorName(
+                        sensor_id.clone(),
+                        name.clone(),
+                    )
+                    .into()));
+                }
+            }
+        }
+        for mqtt in &self.mqtt {
+            check(mqtt.transport().map(drop).map_err(Into::into));
+            if !matches!(mqtt.get_protocol_version(), 4 | 5) {
+                check(Err(ConfigError::MqttProtocolVersion(
+                    mqtt.get_protocol_version().to_string(),
+                )
+                .into()));
+            }
+            for pattern in &mqtt.sensors {
+                check(
+                    pattern
+                        .parse::<SensorPattern>()
+                        .map(drop)
+                        .map_err(Into::into),
+                );
+            }
+        }
+        check(
+            crate::profiles::Profiles::new(self)
+                .map(drop)
+                .map_err(Into::into),
+        );
+        check(
+            crate::automation::Automation::new(&self.automation)
+                .map(drop)
+                .map_err(Into::into),
+        );
+        check(
+            crate::quirks::Quirks::new(&self.quirks)
+                .map(drop)
+                .map_err(Into::into),
+        );
+        check(
+            crate::burst::Assembler::new(&self.bursts)
+                .map(drop)
+                .map_err(Into::into),
+        );
+        check(
+            crate::webhook::Notifier::new(&self.webhooks)
+                .map(drop)
+                .map_err(Into::into),
+        );
+        if let Some(ref gateway) = self.gateway {
+            check(
+                crate::gateway::Comparer::new(gateway.clone())
+                    .map(drop)
+                    .map_err(Into::into),
+            );
+        }
+        if let Some(ref cwop) = self.cwop {
+            check(cwop.validate().map_err(Into::into));
+        }
+        if let Some(ref usage) = self.usage {
+            check(usage.validate().map_err(Into::into));
+        }
+        check(self.update.validate().map_err(Into::into));
+        let patterns = self
+            .uploads
+            .iter()
+            .flat_map(|u| &u.sensors)
+            .chain(
+                self.ecowitt
+                    .iter()
+                    .flat_map(|u| u.sensors.iter().chain(u.channels.values())),
+            )
+            .chain(&self.trace);
+        for pattern in patterns {
+            check(
+                pattern
+                    .parse::<SensorPattern>()
+                    .map(drop)
+                    .map_err(Into::into),
+            );
+        }
+        for upload in &self.ecowitt {
+            if let Some(channel) = upload.channels.keys().find(|c| !(1..=8).contains(*c)) {
+                check(Err(ConfigError::EcowittChannel(*channel).into()));
+            }
+        }
+        problems
+    }
+
+    /// The ignore and allow lists, compiled for matching
+    pub fn sensor_filter(&self) -> Result<SensorFilter> {
+        Ok(SensorFilter::new(
+            &self.sensor_ignores,
+            &self.sensor_allows,
+        )?)
+    }
+
+    /// The retain settings, compiled for matching
+    pub fn retain_policy(&self) -> Result<RetainPolicy> {
+        Ok(RetainPolicy::new(&self.retain)?)
+    }
+
+    pub fn get_rain_day_start(&self) -> Result<chrono::NaiveTime> {
+        match self.rain_day_start {
+            Some(ref start) => chrono::NaiveTime::parse_from_str(start, "%H:%M")
+                .map_err(|_| ConfigError::RainDayStart(start.to_owned()).into()),
+            None => Ok(chrono::NaiveTime::MIN),
+        }
+    }
+
+    pub fn get_stats_windows(&self) -> Result<Vec<crate::stats::StatsWindow>> {
+        Ok(self
+            .stats_windows
+            .iter()
+            .map(|window| window.parse())
+            .collect::<std::result::Result<_, _>>()?)
+    }
+
+    /// The stages records go through, as listed or by default
+    pub fn stages(&self) -> Result<Vec<crate::stages::Stage>> {
+        match self.pipeline {
+            Some(ref stages) => {
+                crate::stages::validate(stages, self)?;
+                Ok(stages.clone())
+            }
+            None => Ok(crate::stages::default_stages(self)),
+        }
+    }
+
+    pub fn sampler(&self) -> Result<crate::sampling::Sampler> {
+        Ok(crate::sampling::Sampler::new(&self.sampling)?)
+    }
+
+    pub fn throttle(&self) -> Result<crate::throttle::Throttle> {
+        Ok(crate::throttle::Throttle::new(&self.throttle)?)
+    }
+
+    pub fn location_of(&self, sensor_id: &str) -> Option<&str> {
+        if let Some(location) = self
+            .sensors
+            .get(sensor_id)
+            .and_then(|s| s.location.as_ref())
+        {
+            return Some(location.trim_matches('/'));
+        }
+        self.locations
+            .iter()
+            .find(|(_, sensors)| sensors.contains(sensor_id))
+            .map(|(location, _)| location.as_str())
+    }
+
+    /// The name a sensor goes by: its alias, or else the name it's given in
+    /// `sensors`
+    pub fn name_of(&self, sensor_id: &str) -> Option<&str> {
+        self.aliases
+            .get(sensor_id)
+            .or_else(|| self.sensors.get(sensor_id)?.name.as_ref())
+            .map(String::as_str)
+    }
+
+    /// Everything configured of a sensor, in one place
+    pub fn sensor_info(&self, sensor_id: &str) -> SensorInfo {
+        SensorInfo {
+            name: self.name_of(sensor_id).map(str::to_owned),
+            location: self.location_of(sensor_id).map(str::to_owned),
+            tags: self
+                .sensors
+                .get(sensor_id)
+                .map(|s| s.tags.clone())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Everything configured of every sensor that's named, located or
+    /// tagged, by sensor id
+    pub fn known_sensors(&self) -> BTreeMap<String, SensorInfo> {
+        self.aliases
+            .keys()
+            .chain(self.locations.values().flatten())
+            .chain(self.sensors.keys())
+            .map(|sensor_id| (sensor_id.clone(), self.sensor_info(sensor_id)))
+            .collect()
+    }
+
+    /// How sensors with a name are referred to in log lines, by sensor id:
+    /// by their name, followed by their id
+    pub fn sensor_labels(&self) -> BTreeMap<String, String> {
+        self.known_sensors()
+            .into_iter()
+            .filter_map(|(sensor_id, info)| {
+                let label = format!("{} ({})", info.name?, sensor_id);
+                Some((sensor_id, label))
+            })
+            .collect()
+    }
+
+    pub fn get_topic_naming(&self) -> crate::topics::NamingStrategy {
+        match (self.topic_naming, &self.topic_template) {
+            (Some(naming), _) => naming,
+            (None, Some(_)) => crate::topics::NamingStrategy::Custom,
+            (None, None) => crate::topics::NamingStrategy::default(),
+        }
+    }
+
+    /// The configured layout of the topics published to the first broker
+    pub fn topics(&self) -> Result<crate::topics::TopicTemplate> {
+        self.topics_on(self.mqtt.first())
+    }
+
+    /// The configured layout of the topics published to a broker, under its
+    /// own topic prefix if it has one
+    pub fn topics_on(&self, mqtt: Option<&MqttConfig>) -> Result<crate::topics::TopicTemplate> {
+        let prefix = mqtt.and_then(|mqtt| mqtt.topic_prefix.as_deref());
+        crate::topics::TopicTemplate::new(
+            prefix.or(self.topic_prefix.as_deref()),
+            self.get_topic_naming(),
+            self.topic_template.as_deref(),
+        )
+        .map(|topics| topics.aliases(self.all_aliases()))
+        .with_context(|| "Invalid topic template")
+    }
+
+    /// The bridge's status topic on the first broker
+    pub fn status_topic(&self) -> String {
+        self.status_topic_on(self.mqtt.first())
+    }
+
+    /// The bridge's status topic on a broker, under its own topic prefix if
+    /// it has one
+    pub fn status_topic_on(&self, mqtt: Option<&MqttConfig>) -> String {
+        let prefix = mqtt.and_then(|mqtt| mqtt.topic_prefix.as_deref());
+        crate::availability::status_topic(prefix.or(self.topic_prefix.as_deref()))
+    }
+
+    // The configured aliases, along with those naming the channels of
+    // multi-probe devices after their probes
+    fn all_aliases(&self) -> BTreeMap<String, String> {
+        let mut aliases = BTreeMap::new();
+        for (device, probes) in &self.probes {
+            for (probe, name) in probes {
+                aliases.insert(
+                    format!("{}/{}", device, probe),
+                    format!("{}/{}", device, name),
+                );
+            }
+        }
+        for (sensor_id, info) in &self.sensors {
+            if let Some(ref name) = info.name {
+                aliases.insert(sensor_id.clone(), name.clone());
+            }
+        }
+        aliases.extend(self.aliases.clone());
+        aliases
+    }
+
+    /// The configured name of a probe of the sensor with the given id and
+    /// model, if any, preferring names given for the sensor over those for
+    /// its model
+    pub fn probe_name(&self, sensor_id: &str, model: Option<&str>, probe: &str) -> Option<&str> {
+        std::iter::once(sensor_id)
+            .chain(model)
+            .filter_map(|device| self.probes.get(device)?.get(probe))
+            .map(String::as_str)
+            .next()
+    }
+
+    pub fn get_sensor_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.sensor_timeout.unwrap_or(900))
+    }
+
+    pub fn get_arrow_window(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.arrow_window.unwrap_or(60) * 60)
+    }
+
+    pub fn get_validation_confirmations(&self) -> u32 {
+        self.validation_confirmations.unwrap_or(1)
+    }
+
+    pub fn get_dedup_window(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(
+            self.dedup_window
+                .unwrap_or(crate::dedup::DEFAULT_WINDOW_SECS),
+        )
+    }
+
+    pub fn get_sensor_lost_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.sensor_lost_timeout.unwrap_or(24 * 60 * 60))
+    }
+
+    pub fn get_startup_grace(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.startup_grace.unwrap_or(0))
+    }
+
+    pub fn get_log_summary(&self) -> Option<std::time::Duration> {
+        self.log_summary
+            .map(|secs| std::time::Duration::from_secs(secs.max(1)))
+    }
+
+    pub fn get_telemetry_interval(&self) -> Option<std::time::Duration> {
+        self.telemetry_interval
+            .map(|secs| std::time::Duration::from_secs(secs.max(1)))
+    }
+
+    /// Level published records are logged at, which is demoted to debug when
+    /// they're summarized instead
+    pub fn get_record_log_level(&self) -> log::Level {
+        match self.log_summary {
+            Some(_) => log::Level::Debug,
+            None => log::Level::Info,
+        }
+    }
+
+    pub fn get_log_level(&self) -> log::LevelFilter {
         match self.output_level.unwrap_or(1) {
             0 => log::LevelFilter::Off,
             1 => log::LevelFilter::Error,
@@ -284,3 +1825,70 @@ impl Config {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(pattern: &str) -> SensorPattern {
+        pattern.parse().unwrap()
+    }
+
+    #[test]
+    fn exact_pattern_matches_only_the_sensor_id() {
+        let exact = pattern("AmbientWeather-WH31E/2");
+        assert!(matches!(exact, SensorPattern::Exact(_)));
+        assert!(exact.matches("AmbientWeather-WH31E/2"));
+        assert!(!exact.matches("AmbientWeather-WH31E/21"));
+        assert!(!exact.matches("AmbientWeather-WH31E/"));
+    }
+
+    #[test]
+    fn prefix_pattern_matches_every_sensor_of_the_model() {
+        let prefix = pattern("AmbientWeather-WH31E/");
+        assert!(matches!(prefix, SensorPattern::Prefix(_)));
+        assert!(prefix.matches("AmbientWeather-WH31E/2"));
+        assert!(prefix.matches("AmbientWeather-WH31E/177"));
+        assert!(!prefix.matches("AmbientWeather-WH31B/2"));
+    }
+
+    #[test]
+    fn glob_pattern_matches_the_whole_sensor_id() {
+        let star = pattern("IDM/4*");
+        assert!(star.matches("IDM/4"));
+        assert!(star.matches("IDM/41234"));
+        assert!(!star.matches("IDM/5123"));
+        assert!(!star.matches("SCM/IDM/4"));
+
+        let single = pattern("Fineoffset-WH?5/1");
+        assert!(single.matches("Fineoffset-WH45/1"));
+        assert!(single.matches("Fineoffset-WH65/1"));
+        assert!(!single.matches("Fineoffset-WH5/1"));
+
+        // Everything else in a glob is matched literally
+        let literal = pattern("PM2.5-*");
+        assert!(literal.matches("PM2.5-3"));
+        assert!(!literal.matches("PM2x5-3"));
+    }
+
+    #[test]
+    fn regex_pattern_matches_anywhere_unless_anchored() {
+        let unanchored = pattern(r"re:WH(31|32)");
+        assert!(unanchored.matches("AmbientWeather-WH31E/2"));
+        assert!(unanchored.matches("Fineoffset-WH32B/7"));
+        assert!(!unanchored.matches("Fineoffset-WH40/7"));
+
+        let anchored = pattern(r"re:^IDM/4\d+$");
+        assert!(anchored.matches("IDM/41234"));
+        assert!(!anchored.matches("IDM/4"));
+        assert!(!anchored.matches("IDM/41234/2"));
+    }
+
+    #[test]
+    fn invalid_regex_pattern_is_an_error() {
+        let err = "re:WH(31".parse::<SensorPattern>().unwrap_err();
+        assert!(
+            matches!(err, ConfigError::SensorPattern { ref pattern, .. } if pattern == "re:WH(31")
+        );
+    }
+}
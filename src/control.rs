@@ -0,0 +1,396 @@
+//! Retained mqtt control topics that adjust the bridge's behavior at runtime
+//!
+//! Sensors are put into maintenance, pausing everything published about
+//! them while e.g. their batteries are swapped, by publishing retained to
+//! `weatherradio/control/<sensor_id>/maintenance`. The payload is how long
+//! maintenance lasts: a number of seconds, an RFC 3339 end time, or `on`
+//! for an hour. An empty payload or `off` ends it early. When maintenance
+//! expires, the retained control message is cleared, so that it doesn't
+//! take effect again after a restart.
+//!
+//! The other operations each have to be permitted in [`ControlConfig`], and
+//! are applied by the pipeline as they arrive, between records:
+//!
+//! * `<sensor_id>/ignore`: `on` ignores the sensor, `off` stops ignoring it
+//! * `<sensor_id>/alias`: publishes the sensor under the payload rather than
+//!   its id, or under its id again if the payload is empty
+//! * `<sensor_id>/calibrate/<measurement>`: adds the payload to the named
+//!   measurement, in its metric unit, or stops calibrating it if empty
+//! * `status`: publishes the runtime configuration to
+//!   `weatherradio/status/dump`
+//! * `power`: switches to the power profile named by the payload, `low` or
+//!   `normal`
+//! * `profile`: activates the [configuration profile](crate::profiles)
+//!   named by the payload, or goes back to the profile schedule if empty
+//! * `trace`: [traces](crate::trace) the sensors and decoders matching the
+//!   comma-separated patterns of the payload, or none if empty
+//!
+//! Retained commands are applied again after a restart, except for `status`.
+//! Who may publish to the control topics is left to the broker's ACLs.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use clap::crate_name;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::sink::{Message, MqttSink};
+
+const MAINTENANCE_SUFFIX: &str = "/maintenance";
+const IGNORE_SUFFIX: &str = "/ignore";
+const ALIAS_SUFFIX: &str = "/alias";
+const CALIBRATE_LEVEL: &str = "/calibrate/";
+const STATUS_TOPIC: &str = "status";
+const POWER_TOPIC: &str = "power";
+const PROFILE_TOPIC: &str = "profile";
+const TRACE_TOPIC: &str = "trace";
+// How long maintenance lasts when it's turned on without a duration
+const DEFAULT_MAINTENANCE: Duration = Duration::from_secs(60 * 60);
+// How often expired maintenance windows are checked for
+const EXPIRY_PERIOD: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum ControlError {
+    #[error("Invalid maintenance duration '{0}', expected seconds, an RFC 3339 end time, 'on', or 'off'")]
+    MaintenanceDuration(String),
+    #[error("Invalid switch '{0}', expected 'on' or 'off'")]
+    Switch(String),
+    #[error("Invalid calibration offset '{0}'")]
+    CalibrationOffset(String),
+    #[error("Invalid power profile '{0}', expected 'low' or 'normal'")]
+    PowerProfile(String),
+    #[error("Unknown control operation '{0}'")]
+    UnknownOperation(String),
+}
+
+/// Operations that may be permitted over the control topics
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Ignore,
+    Alias,
+    Calibrate,
+    Status,
+    Power,
+    Profile,
+    Trace,
+}
+
+impl std::str::FromStr for Operation {
+    type Err = ControlError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(Self::Ignore),
+            "alias" => Ok(Self::Alias),
+            "calibrate" => Ok(Self::Calibrate),
+            "status" => Ok(Self::Status),
+            "power" => Ok(Self::Power),
+            "profile" => Ok(Self::Profile),
+            "trace" => Ok(Self::Trace),
+            _ => Err(ControlError::UnknownOperation(s.to_owned())),
+        }
+    }
+}
+
+/// Which operations are accepted over the control topics; maintenance is
+/// always accepted, everything else only when allowed
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ControlConfig {
+    #[serde(default)]
+    pub allow: BTreeSet<Operation>,
+}
+
+/// A runtime configuration change received over the control topics, for the
+/// pipeline to apply
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Ignore {
+        sensor_id: String,
+        ignore: bool,
+    },
+    Alias {
+        sensor_id: String,
+        alias: Option<String>,
+    },
+    Calibrate {
+        sensor_id: String,
+        measurement: String,
+        offset: Option<f32>,
+    },
+    StatusDump,
+    Power(crate::power::PowerProfile),
+    Profile(Option<String>),
+    Trace(Vec<String>),
+}
+
+impl Command {
+    /// The operation that has to be allowed for the command to be applied
+    pub fn operation(&self) -> Operation {
+        match self {
+            Self::Ignore { .. } => Operation::Ignore,
+            Self::Alias { .. } => Operation::Alias,
+            Self::Calibrate { .. } => Operation::Calibrate,
+            Self::StatusDump => Operation::Status,
+            Self::Power(_) => Operation::Power,
+            Self::Profile(_) => Operation::Profile,
+            Self::Trace(_) => Operation::Trace,
+        }
+    }
+}
+
+/// Topic level all control topics are nested under
+pub fn control_topic() -> String {
+    format!("{}/control", crate_name!())
+}
+
+/// Topic the runtime configuration is published to when requested
+pub fn status_dump_topic(status_topic: &str) -> String {
+    format!("{}/dump", status_topic)
+}
+
+/// Control topic that puts a sensor into maintenance
+pub fn maintenance_topic(sensor_id: &str) -> String {
+    format!("{}/{}{}", control_topic(), sensor_id, MAINTENANCE_SUFFIX)
+}
+
+// Parses a maintenance payload into when maintenance ends, or `None` if it
+// is being turned off
+fn parse_maintenance(payload: &str) -> Result<Option<DateTime<Local>>, ControlError> {
+    let payload = payload.trim();
+    let now = crate::clock::now();
+    let until = match payload.to_lowercase().as_str() {
+        "" | "off" | "false" | "0" => return Ok(None),
+        "on" | "true" => now + chrono::Duration::from_std(DEFAULT_MAINTENANCE).unwrap_or_default(),
+        _ => match (
+            payload.parse::<i64>(),
+            DateTime::parse_from_rfc3339(payload),
+        ) {
+            (Ok(secs), _) if secs > 0 => now + chrono::Duration::seconds(secs),
+            (_, Ok(until)) => until.with_timezone(&Local),
+            _ => return Err(ControlError::MaintenanceDuration(payload.to_owned())),
+        },
+    };
+    Ok(Some(until))
+}
+
+fn parse_switch(payload: &str) -> Result<bool, ControlError> {
+    match payload.trim().to_lowercase().as_str() {
+        "" | "off" | "false" | "0" => Ok(false),
+        "on" | "true" | "1" => Ok(true),
+        _ => Err(ControlError::Switch(payload.to_owned())),
+    }
+}
+
+// Parses a message on a control topic other than maintenance, with the topic
+// given relative to the control topic level
+fn parse_command(topic: &str, payload: &str) -> Result<Option<Command>, ControlError> {
+    let payload = payload.trim();
+    if topic == STATUS_TOPIC {
+        return Ok(Some(Command::StatusDump));
+    }
+    if topic == POWER_TOPIC {
+        let profile = payload
+            .to_lowercase()
+            .parse()
+            .map_err(|_| ControlError::PowerProfile(payload.to_owned()))?;
+        return Ok(Some(Command::Power(profile)));
+    }
+    if topic == PROFILE_TOPIC {
+        return Ok(Some(Command::Profile(
+            Some(payload.to_owned()).filter(|p| !p.is_empty()),
+        )));
+    }
+    if topic == TRACE_TOPIC {
+        return Ok(Some(Command::Trace(crate::trace::parse_patterns(payload))));
+    }
+    if let Some(sensor_id) = topic.strip_suffix(IGNORE_SUFFIX) {
+        return Ok(Some(Command::Ignore {
+            sensor_id: sensor_id.to_owned(),
+            ignore: parse_switch(payload)?,
+        }));
+    }
+    if let Some(sensor_id) = topic.strip_suffix(ALIAS_SUFFIX) {
+        return Ok(Some(Command::Alias {
+            sensor_id: sensor_id.to_owned(),
+            alias: Some(payload.to_owned()).filter(|a| !a.is_empty()),
+        }));
+    }
+    if let Some((sensor_id, measurement)) = topic.rsplit_once(CALIBRATE_LEVEL) {
+        let offset = match payload {
+            "" => None,
+            offset => Some(
+                offset
+                    .parse()
+                    .map_err(|_| ControlError::CalibrationOffset(offset.to_owned()))?,
+            ),
+        };
+        return Ok(Some(Command::Calibrate {
+            sensor_id: sensor_id.to_owned(),
+            measurement: measurement.to_owned(),
+            offset,
+        }));
+    }
+    Ok(None)
+}
+
+/// Sensors currently in maintenance, and when their maintenance ends
+#[derive(Clone, Debug, Default)]
+pub struct Maintenance {
+    sensors: Arc<Mutex<HashMap<String, DateTime<Local>>>>,
+}
+
+impl Maintenance {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether publishing for a sensor is currently paused
+    pub fn is_active(&self, sensor_id: &str) -> bool {
+        let sensors = self.sensors.lock().expect("maintenance state poisoned");
+        sensors
+            .get(sensor_id)
+            .map(|until| *until > crate::clock::now())
+            .unwrap_or(false)
+    }
+
+    /// Puts a sensor into maintenance until the given time, or takes it out
+    /// of maintenance
+    pub fn set(&self, sensor_id: &str, until: Option<DateTime<Local>>) {
+        let mut sensors = self.sensors.lock().expect("maintenance state poisoned");
+        match until {
+            Some(until) => {
+                log::info!("Sensor {} in maintenance until {}", sensor_id, until);
+                sensors.insert(sensor_id.to_owned(), until);
+            }
+            None => {
+                if sensors.remove(sensor_id).is_some() {
+                    log::info!("Sensor {} out of maintenance", sensor_id);
+                }
+            }
+        }
+    }
+
+    /// When each sensor in maintenance leaves it
+    pub fn snapshot(&self) -> BTreeMap<String, DateTime<Local>> {
+        let sensors = self.sensors.lock().expect("maintenance state poisoned");
+        sensors
+            .iter()
+            .map(|(sensor_id, until)| (sensor_id.clone(), *until))
+            .collect()
+    }
+
+    /// Removes sensors whose maintenance has ended, returning their ids
+    fn expire(&self) -> Vec<String> {
+        let mut sensors = self.sensors.lock().expect("maintenance state poisoned");
+        let now = crate::clock::now();
+        let expired: Vec<String> = sensors
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(sensor_id, _)| sensor_id.clone())
+            .collect();
+        for sensor_id in &expired {
+            log::info!("Sensor {} maintenance expired", sensor_id);
+            sensors.remove(sensor_id);
+        }
+        expired
+    }
+
+    /// Applies a maintenance message, returning false if the topic isn't a
+    /// maintenance topic
+    fn handle(&self, topic: &str, payload: &str) -> bool {
+        let sensor_id = match topic.strip_suffix(MAINTENANCE_SUFFIX) {
+            Some(sensor_id) => sensor_id,
+            None => return false,
+        };
+        match parse_maintenance(payload) {
+            Ok(until) => self.set(sensor_id, until),
+            Err(e) => log::warn!("[{}] {}", sensor_id, e),
+        }
+        true
+    }
+}
+
+// Applies maintenance messages, and forwards permitted commands to the
+// pipeline
+fn dispatch(
+    message: &Message,
+    maintenance: &Maintenance,
+    conf: &ControlConfig,
+    commands: &Sender<Command>,
+) {
+    let topic = match message
+        .topic()
+        .strip_prefix(&format!("{}/", control_topic()))
+    {
+        Some(topic) => topic,
+        None => return,
+    };
+    let payload = message.payload_str();
+    if maintenance.handle(topic, &payload) {
+        return;
+    }
+    let command = match parse_command(topic, &payload) {
+        Ok(Some(command)) => command,
+        Ok(None) => {
+            log::debug!("Ignoring unknown control topic {}", message.topic());
+            return;
+        }
+        Err(e) => {
+            log::warn!("{}: {}", message.topic(), e);
+            return;
+        }
+    };
+    if !conf.allow.contains(&command.operation()) {
+        log::warn!(
+            "Rejecting {:?} control operation on {}, which isn't allowed",
+            command.operation(),
+            message.topic()
+        );
+        return;
+    }
+    // A retained request would otherwise dump the status on every restart
+    if command == Command::StatusDump && message.retained() {
+        return;
+    }
+    log::info!("Control command received: {:?}", command);
+    // The pipeline only goes away when shutting down
+    let _ = commands.send(command);
+}
+
+/// Subscribes to the control topics, and starts a background thread that
+/// applies maintenance messages, expires maintenance windows, and forwards
+/// permitted commands to the returned receiver
+pub fn spawn_listener(
+    sink: &MqttSink,
+    maintenance: Maintenance,
+    conf: ControlConfig,
+) -> anyhow::Result<Receiver<Command>> {
+    let messages = sink.subscribe(&format!("{}/#", control_topic()))?;
+    let sink = sink.clone();
+    let (commands, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || loop {
+        match messages.recv_timeout(EXPIRY_PERIOD) {
+            Ok(Some(message)) => dispatch(&message, &maintenance, &conf, &commands),
+            // The sink subscribes again whenever it connects again, so this
+            // only comes once it's disconnected for good
+            Ok(None) => {
+                log::debug!("Disconnected from broker, no longer listening for control messages");
+                return;
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+        for sensor_id in maintenance.expire() {
+            let topic = maintenance_topic(&sensor_id);
+            if let Err(e) = sink.publish_retained(&topic, "", 1) {
+                log::error!("Failed to clear {}: {:?}", topic, e);
+            }
+        }
+    });
+    Ok(receiver)
+}
@@ -0,0 +1,110 @@
+//! Counters of what the bridge has done since it started, published for
+//! monitoring its health from the broker's side, without a metrics stack
+//!
+//! With `--telemetry`, the counters are published every telemetry interval
+//! to `weatherradio/$stats`, along with the bridge's uptime. They only ever
+//! go up, so rates are had by comparing two publishes. rtl_433 isn't
+//! restarted by the bridge, which exits when rtl_433 does for its service
+//! manager to start them both afresh, so `rtl_433_exits` counts at most one
+//! exit per run of a radio.
+
+use std::collections::BTreeMap;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use clap::crate_name;
+use serde::Serialize;
+
+use crate::events::Event;
+use crate::sink::MqttSink;
+
+/// Topic the counters are published to
+pub fn stats_topic() -> String {
+    format!("{}/$stats", crate_name!())
+}
+
+/// What the bridge has done since it started
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Counters {
+    /// Records received from rtl_433
+    pub records_received: u64,
+    /// Records published to the broker
+    pub records_published: u64,
+    /// Records dropped as repeats of one already published
+    pub dedup_drops: u64,
+    /// Lines of rtl_433 output that weren't json, and records none of the
+    /// parsers made sense of
+    pub parse_errors: u64,
+    /// Records decoded, by the parser that decoded them
+    pub parsers: BTreeMap<String, u64>,
+    /// rtl_433 processes that exited
+    pub rtl_433_exits: u64,
+}
+
+// Everything counted since the bridge started
+static COUNTERS: Mutex<Counters> = Mutex::new(Counters {
+    records_received: 0,
+    records_published: 0,
+    dedup_drops: 0,
+    parse_errors: 0,
+    parsers: BTreeMap::new(),
+    rtl_433_exits: 0,
+});
+
+/// Updates the counters
+pub fn count(update: impl FnOnce(&mut Counters)) {
+    update(&mut COUNTERS.lock().expect("counters poisoned"));
+}
+
+/// The counters as they are now
+pub fn snapshot() -> Counters {
+    COUNTERS.lock().expect("counters poisoned").clone()
+}
+
+/// What's published to the stats topic
+#[derive(Clone, Debug, Serialize)]
+struct Stats {
+    uptime_secs: u64,
+    #[serde(flatten)]
+    counters: Counters,
+}
+
+/// Starts a background thread that publishes the counters to `sink` every
+/// `interval`, and once more when the radio stops
+pub fn spawn_reporter(
+    sink: MqttSink,
+    events: Receiver<Event>,
+    interval: Duration,
+) -> JoinHandle<()> {
+    let up_since = Instant::now();
+    std::thread::spawn(move || {
+        let mut started = Instant::now();
+        loop {
+            let stopped = match events.recv_timeout(interval.saturating_sub(started.elapsed())) {
+                Ok(Event::RadioStopped) | Err(RecvTimeoutError::Disconnected) => true,
+                Ok(_) | Err(RecvTimeoutError::Timeout) => false,
+            };
+            if !stopped && started.elapsed() < interval {
+                continue;
+            }
+            started = Instant::now();
+            let stats = Stats {
+                uptime_secs: up_since.elapsed().as_secs(),
+                counters: snapshot(),
+            };
+            let topic = stats_topic();
+            let result = serde_json::to_vec(&stats)
+                .map_err(anyhow::Error::from)
+                .and_then(|payload| sink.publish(&topic, payload));
+            match result {
+                Ok(()) => log::debug!("mqtt <== {}({:?})", topic, stats),
+                Err(e) => log::error!("Failed to publish bridge stats: {:?}", e),
+            }
+            if stopped {
+                return;
+            }
+        }
+    })
+}
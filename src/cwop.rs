@@ -0,0 +1,230 @@
+//! Sending the station's current conditions to the Citizen Weather Observer
+//! Program (CWOP) as APRS weather reports, through an APRS-IS server
+//!
+//! Conditions are gathered as for uploads to weather networks (see
+//! [`crate::pws`]), and reported every interval, five minutes unless
+//! configured otherwise as CWOP asks, over a fresh connection each time.
+//! Stations without an amateur radio license use their CWOP id as the
+//! callsign, with the default passcode of -1.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::ConfigError;
+use crate::errors::{ErrorCode, ErrorEvent};
+use crate::events::{Event, EventBus};
+use crate::pws::Readings;
+
+/// APRS-IS server CWOP reports are sent to, unless configured otherwise
+pub const DEFAULT_SERVER: &str = "cwop.aprs.net:14580";
+
+/// Seconds between reports, unless configured otherwise
+pub const DEFAULT_REPORT_INTERVAL: u64 = 300;
+
+// Seconds to wait on the server before giving up on a report
+const TIMEOUT_SECS: u64 = 30;
+
+// hPa per inHg
+const HPA_PER_INHG: f64 = 33.8639;
+
+fn default_passcode() -> i32 {
+    -1
+}
+
+fn default_interval() -> u64 {
+    DEFAULT_REPORT_INTERVAL
+}
+
+fn default_server() -> String {
+    DEFAULT_SERVER.to_owned()
+}
+
+/// The station's CWOP registration
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CwopConfig {
+    /// Amateur radio callsign, or CWOP id, e.g. `CW1234`
+    pub callsign: String,
+    /// APRS-IS passcode, which only licensed amateurs have
+    #[serde(default = "default_passcode")]
+    pub passcode: i32,
+    /// Latitude of the station, in degrees north
+    pub latitude: f64,
+    /// Longitude of the station, in degrees east
+    pub longitude: f64,
+    /// Seconds between reports
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// APRS-IS server, as `HOST:PORT`
+    #[serde(default = "default_server")]
+    pub server: String,
+    /// Sensors whose readings are reported, as
+    /// [`SensorPattern`](crate::config::SensorPattern)s; all of them when
+    /// empty
+    #[serde(default)]
+    pub sensors: Vec<String>,
+}
+
+// Keeps passcodes out of debug logging
+impl std::fmt::Debug for CwopConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("CwopConfig")
+            .field("callsign", &self.callsign)
+            .field("passcode", &"<redacted>")
+            .field("latitude", &self.latitude)
+            .field("longitude", &self.longitude)
+            .field("interval", &self.interval)
+            .field("server", &self.server)
+            .field("sensors", &self.sensors)
+            .finish()
+    }
+}
+
+impl CwopConfig {
+    /// Checks the callsign and location
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let callsign = &self.callsign;
+        if callsign.is_empty()
+            || callsign.len() > 9
+            || !callsign
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return Err(ConfigError::CwopCallsign(callsign.clone()));
+        }
+        if !(-90.0..=90.0).contains(&self.latitude) || !(-180.0..=180.0).contains(&self.longitude) {
+            return Err(ConfigError::CwopLocation(format!(
+                "{}, {}",
+                self.latitude, self.longitude
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Formats degrees as APRS does, e.g. `4903.50N` or `07201.75W`
+fn position(degrees: f64, width: usize, positive: char, negative: char) -> String {
+    let hemisphere = if degrees < 0.0 { negative } else { positive };
+    let degrees = degrees.abs();
+    // Rounded to hundredths of a minute up front, so that 59.999' carries
+    let hundredths = (degrees * 6000.0).round() as u64;
+    format!(
+        "{:0width$}{:02}.{:02}{}",
+        hundredths / 6000,
+        hundredths % 6000 / 100,
+        hundredths % 100,
+        hemisphere,
+        width = width
+    )
+}
+
+/// The APRS weather report of the readings taken at `time`, e.g.
+/// `CW1234>APRS,TCPIP*:@151200z4903.50N/07201.75W_180/004g008t070r000P010h50b10132weatherradio`
+pub fn report(conf: &CwopConfig, time: DateTime<Local>, readings: &Readings) -> String {
+    // Wind and temperature are always reported, as dots when unknown
+    let field = |name: &str, digits: usize| match readings.get(name) {
+        Some(value) => format!("{:0digits$}", value.round() as i64, digits = digits),
+        None => ".".repeat(digits),
+    };
+    let mut report = format!(
+        "{}>APRS,TCPIP*:@{}{}/{}_{}/{}g{}t{}",
+        conf.callsign.to_ascii_uppercase(),
+        time.with_timezone(&Utc).format("%d%H%Mz"),
+        position(conf.latitude, 2, 'N', 'S'),
+        position(conf.longitude, 3, 'E', 'W'),
+        field("winddir", 3),
+        field("windspeedmph", 3),
+        field("windgustmph", 3),
+        field("tempf", 3),
+    );
+    // The rest are left out when unknown
+    if let Some(rain) = readings.get("rainin") {
+        report.push_str(&format!("r{:03}", ((rain * 100.0).round() as i64).min(999)));
+    }
+    if let Some(rain) = readings.get("dailyrainin") {
+        report.push_str(&format!("P{:03}", ((rain * 100.0).round() as i64).min(999)));
+    }
+    if let Some(humidity) = readings.get("humidity") {
+        report.push_str(&format!("h{:02}", humidity.round() as i64 % 100));
+    }
+    if let Some(pressure) = readings.get("baromin") {
+        report.push_str(&format!(
+            "b{:05}",
+            (pressure * HPA_PER_INHG * 10.0).round() as i64
+        ));
+    }
+    if let Some(radiation) = readings.get("solarradiation") {
+        let radiation = radiation.round() as i64;
+        match radiation {
+            r if r < 1000 => report.push_str(&format!("L{:03}", r)),
+            r => report.push_str(&format!("l{:03}", (r - 1000).min(999))),
+        }
+    }
+    report.push_str(clap::crate_name!());
+    report
+}
+
+/// Logs in to the APRS-IS server and sends a report
+pub fn send(conf: &CwopConfig, report: &str) -> Result<()> {
+    let timeout = Duration::from_secs(TIMEOUT_SECS);
+    let address = conf
+        .server
+        .to_socket_addrs()
+        .with_context(|| format!("Failed to resolve {}", conf.server))?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("No address for {}", conf.server))?;
+    let mut stream = TcpStream::connect_timeout(&address, timeout)
+        .with_context(|| format!("Failed to connect to {}", conf.server))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut lines = BufReader::new(stream.try_clone()?).lines();
+    // The server greets with a comment, and answers the login with another
+    let greeting = lines.next().transpose()?;
+    log::trace!("APRS-IS <== {:?}", greeting);
+    write!(
+        stream,
+        "user {} pass {} vers {} {}\r\n",
+        conf.callsign.to_ascii_uppercase(),
+        conf.passcode,
+        clap::crate_name!(),
+        clap::crate_version!()
+    )?;
+    let login = lines.next().transpose()?.unwrap_or_default();
+    log::trace!("APRS-IS <== {}", login);
+    if !login.contains("logresp") {
+        anyhow::bail!("login refused: {}", login);
+    }
+    write!(stream, "{}\r\n", report)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Starts a background thread reporting the conditions in the records on
+/// `events` to CWOP every interval, until the radio stops
+pub fn spawn_reporter(
+    conf: CwopConfig,
+    events: std::sync::mpsc::Receiver<Event>,
+    errors: EventBus,
+) -> Result<JoinHandle<()>, ConfigError> {
+    conf.validate()?;
+    let sensors = conf.sensors.clone();
+    let interval = Duration::from_secs(conf.interval);
+    crate::pws::spawn_gatherer(&sensors, interval, events, move |time, readings| {
+        let report = report(&conf, time, readings);
+        match send(&conf, &report) {
+            Ok(()) => log::debug!("APRS-IS ==> {}", report),
+            Err(e) => {
+                log::error!("Failed to report to CWOP as {}: {:#}", conf.callsign, e);
+                errors.publish(Event::Error(
+                    ErrorEvent::new(ErrorCode::Upload, format!("{:#}", e))
+                        .context("station_id", &conf.callsign),
+                ));
+            }
+        }
+    })
+}
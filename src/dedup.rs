@@ -0,0 +1,112 @@
+//! Suppression of the repeated transmissions sensors send of each reading
+
+use std::collections::HashMap;
+
+use crate::radio::Record;
+
+/// Window within which identical records from a sensor are dropped, unless
+/// configured otherwise
+pub const DEFAULT_WINDOW_SECS: u64 = 5;
+
+/// Remembers the last record from each sensor, so that repeats of it within
+/// the window can be dropped even when other sensors' records arrive in
+/// between
+#[derive(Debug)]
+pub struct DedupCache {
+    window: chrono::Duration,
+    last: HashMap<String, Record>,
+}
+
+impl DedupCache {
+    pub fn new(window: std::time::Duration) -> Self {
+        DedupCache {
+            window: chrono::Duration::from_std(window).unwrap_or_else(|_| chrono::Duration::zero()),
+            last: HashMap::new(),
+        }
+    }
+
+    /// Whether the record repeats the content of its sensor's previous
+    /// record, within the window. Records that aren't duplicates are
+    /// remembered for comparison with those that follow.
+    pub fn is_duplicate(&mut self, record: &Record) -> bool {
+        if let Some(previous) = self.last.get(&record.sensor_id) {
+            let elapsed = record.timestamp - previous.timestamp;
+            if elapsed <= self.window && same_content(previous, record) {
+                return true;
+            }
+        }
+        self.last.insert(record.sensor_id.clone(), record.clone());
+        false
+    }
+}
+
+// Compares records ignoring their timestamps, since repeats can straddle a
+// second boundary
+fn same_content(a: &Record, b: &Record) -> bool {
+    match (&a.record_json, &b.record_json) {
+        (serde_json::Value::Object(a), serde_json::Value::Object(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(k, v)| k == "time" || b.get(k).map(|bv| bv == v).unwrap_or(false))
+        }
+        (a, b) => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn record_at(sensor_id: &str, secs: u32, temperature_c: f64) -> Record {
+        let timestamp = chrono::Local
+            .with_ymd_and_hms(2024, 1, 15, 10, 0, secs)
+            .unwrap();
+        Record {
+            timestamp,
+            sensor_id: sensor_id.to_owned(),
+            record_json: serde_json::json!({
+                "time": timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
+                "model": "Fineoffset-WH31E",
+                "temperature_C": temperature_c,
+            }),
+            measurements: vec![],
+            radio: None,
+        }
+    }
+
+    #[test]
+    fn repeats_within_the_window_are_dropped() {
+        let mut cache = DedupCache::new(std::time::Duration::from_secs(5));
+        assert!(!cache.is_duplicate(&record_at("a", 0, 21.5)));
+        assert!(cache.is_duplicate(&record_at("a", 1, 21.5)));
+        assert!(cache.is_duplicate(&record_at("a", 5, 21.5)));
+    }
+
+    #[test]
+    fn repeats_after_the_window_are_kept() {
+        let mut cache = DedupCache::new(std::time::Duration::from_secs(5));
+        assert!(!cache.is_duplicate(&record_at("a", 0, 21.5)));
+        assert!(!cache.is_duplicate(&record_at("a", 6, 21.5)));
+        // The window runs from the last record kept
+        assert!(cache.is_duplicate(&record_at("a", 10, 21.5)));
+    }
+
+    #[test]
+    fn changed_readings_are_kept() {
+        let mut cache = DedupCache::new(std::time::Duration::from_secs(5));
+        assert!(!cache.is_duplicate(&record_at("a", 0, 21.5)));
+        assert!(!cache.is_duplicate(&record_at("a", 1, 21.6)));
+        assert!(cache.is_duplicate(&record_at("a", 2, 21.6)));
+    }
+
+    #[test]
+    fn sensors_are_windowed_apart() {
+        let mut cache = DedupCache::new(std::time::Duration::from_secs(5));
+        assert!(!cache.is_duplicate(&record_at("a", 0, 21.5)));
+        assert!(!cache.is_duplicate(&record_at("b", 1, 21.5)));
+        assert!(cache.is_duplicate(&record_at("a", 2, 21.5)));
+        assert!(cache.is_duplicate(&record_at("b", 3, 21.5)));
+    }
+}
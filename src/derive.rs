@@ -0,0 +1,67 @@
+//! Extension point for computing additional measurements from the ones
+//! sensors report
+
+use serde::Serialize;
+
+use crate::radio::{Measurement, Record};
+
+/// How a derived value was computed, so that it can be told apart from
+/// values sensors report, and recomputed when its formula changes
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Lineage {
+    /// Names of the measurements the value was computed from, as in topics
+    pub sources: Vec<String>,
+    /// Name of the formula the value was computed with
+    pub formula: String,
+    /// Version of the formula, which changes whenever the formula's results
+    /// would
+    pub version: u32,
+}
+
+impl Lineage {
+    pub fn new(sources: &[&str], formula: &str, version: u32) -> Self {
+        Lineage {
+            sources: sources.iter().map(|s| (*s).to_owned()).collect(),
+            formula: formula.to_owned(),
+            version,
+        }
+    }
+}
+
+/// A value computed from sensor measurements by a [`DerivedCalculator`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct DerivedValue {
+    /// Name of the derived measurement, used in its topic
+    pub name: String,
+    pub value: f32,
+    /// Abbreviation of the unit the value is in, if any
+    pub unit: String,
+    /// How the value was computed, if it was computed from measurements
+    pub lineage: Option<Lineage>,
+}
+
+/// Computes new measurements for a sensor from its recent records.
+///
+/// Calculators are registered with
+/// [`PipelineBuilder::calculator`](crate::pipeline::PipelineBuilder::calculator),
+/// and are run against every record that makes it through filtering and
+/// deduplication. Whatever they return is appended to the record's
+/// measurements, and published to the measurement's own topic.
+pub trait DerivedCalculator: Send {
+    /// Computes measurements for the sensor that sent the most recent record
+    /// in `history`. `history` holds that sensor's recent records, oldest
+    /// first, and is never empty.
+    fn calculate(&mut self, history: &[Record]) -> Vec<Measurement>;
+}
+
+impl DerivedCalculator for crate::rain::RainTracker {
+    fn calculate(&mut self, history: &[Record]) -> Vec<Measurement> {
+        let mut record = match history.last() {
+            Some(record) => record.clone(),
+            None => return Vec::new(),
+        };
+        let reported = record.measurements.len();
+        self.process(&mut record);
+        record.measurements.split_off(reported)
+    }
+}
@@ -0,0 +1,189 @@
+//! Comparison of two captures of rtl_433 output, e.g. from receivers with
+//! different antennas, gain settings, or rtl_433 versions
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Result;
+
+use crate::radio::Record;
+
+/// How far apart the timestamps of two records may be for them to be
+/// considered the same transmission, unless overridden
+pub const DEFAULT_TOLERANCE_SECS: i64 = 5;
+
+/// Mean difference between a measurement as received in the two captures
+#[derive(Clone, Debug, Default)]
+pub struct Offset {
+    /// Sum of right minus left values
+    total: f64,
+    /// Number of records both captures had the measurement in
+    pub count: usize,
+    pub unit: String,
+}
+
+impl Offset {
+    pub fn mean(&self) -> f64 {
+        self.total / self.count.max(1) as f64
+    }
+}
+
+/// Comparison of the records from one sensor in the two captures
+#[derive(Clone, Debug, Default)]
+pub struct SensorDiff {
+    pub sensor_id: String,
+    /// Records received in the left capture
+    pub left: usize,
+    /// Records received in the right capture
+    pub right: usize,
+    /// Records received in both captures
+    pub matched: usize,
+    /// Mean of how much later the right capture timestamped each matched
+    /// record than the left one, in seconds
+    pub time_offset: f64,
+    /// Mean difference of each measurement in matched records, by name
+    pub offsets: BTreeMap<String, Offset>,
+}
+
+/// Systematic differences between two captures
+#[derive(Clone, Debug, Default)]
+pub struct DiffReport {
+    /// Sensors only heard in the left capture
+    pub left_only: Vec<String>,
+    /// Sensors only heard in the right capture
+    pub right_only: Vec<String>,
+    /// Sensors heard in both captures
+    pub sensors: Vec<SensorDiff>,
+}
+
+/// Reads every record from a capture, ignoring integrity requirements
+pub fn load(path: &std::path::Path) -> Result<Vec<Record>> {
+    let replay = crate::replay::Replay::open(
+        path,
+        &crate::config::Config::default(),
+        crate::replay::ReplayTiming::default(),
+    )?;
+    Ok(replay.collect())
+}
+
+fn by_sensor(records: Vec<Record>) -> BTreeMap<String, Vec<Record>> {
+    let mut sensors: BTreeMap<String, Vec<Record>> = BTreeMap::new();
+    for record in records {
+        sensors
+            .entry(record.sensor_id.clone())
+            .or_default()
+            .push(record);
+    }
+    for records in sensors.values_mut() {
+        records.sort_by_key(|r| r.timestamp);
+        // rtl_433 reports each repeat of a transmission, which would
+        // otherwise count as packets the other capture lost
+        records.dedup_by(|a, b| a.record_json == b.record_json);
+    }
+    sensors
+}
+
+fn compare_sensor(
+    sensor_id: &str,
+    left: &[Record],
+    right: &[Record],
+    tolerance: chrono::Duration,
+) -> SensorDiff {
+    let mut diff = SensorDiff {
+        sensor_id: sensor_id.to_owned(),
+        left: left.len(),
+        right: right.len(),
+        ..Default::default()
+    };
+    let mut time_offset = 0.0;
+    let (mut i, mut j) = (0, 0);
+    while i < left.len() && j < right.len() {
+        let (l, r) = (&left[i], &right[j]);
+        let gap = r.timestamp - l.timestamp;
+        if gap > tolerance {
+            i += 1;
+            continue;
+        }
+        if -gap > tolerance {
+            j += 1;
+            continue;
+        }
+        diff.matched += 1;
+        time_offset += gap.num_milliseconds() as f64 / 1000.0;
+        for lm in &l.measurements {
+            let name = lm.name();
+            let rm = match r.measurements.iter().find(|m| m.name() == name) {
+                Some(rm) => rm,
+                None => continue,
+            };
+            if let (Some(lv), Some(rv)) = (lm.numeric(), rm.numeric()) {
+                let offset = diff.offsets.entry(name).or_default();
+                offset.total += rv - lv;
+                offset.count += 1;
+                offset.unit = lm.unit();
+            }
+        }
+        i += 1;
+        j += 1;
+    }
+    diff.time_offset = time_offset / diff.matched.max(1) as f64;
+    diff
+}
+
+/// Aligns the records of two captures by sensor and time, and summarizes
+/// how they differ
+pub fn compare(left: Vec<Record>, right: Vec<Record>, tolerance: chrono::Duration) -> DiffReport {
+    let left = by_sensor(left);
+    let right = by_sensor(right);
+    let sensor_ids: BTreeSet<&String> = left.keys().chain(right.keys()).collect();
+    let mut report = DiffReport::default();
+    for sensor_id in sensor_ids {
+        match (left.get(sensor_id), right.get(sensor_id)) {
+            (Some(l), Some(r)) => report
+                .sensors
+                .push(compare_sensor(sensor_id, l, r, tolerance)),
+            (Some(_), None) => report.left_only.push(sensor_id.clone()),
+            (None, _) => report.right_only.push(sensor_id.clone()),
+        }
+    }
+    report
+}
+
+impl std::fmt::Display for DiffReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for sensor_id in &self.left_only {
+            writeln!(f, "{}: only in left capture", sensor_id)?;
+        }
+        for sensor_id in &self.right_only {
+            writeln!(f, "{}: only in right capture", sensor_id)?;
+        }
+        for sensor in &self.sensors {
+            writeln!(
+                f,
+                "{}: {} left, {} right, {} matched ({} missed by left, {} missed by right), timestamps {:+.1} s",
+                sensor.sensor_id,
+                sensor.left,
+                sensor.right,
+                sensor.matched,
+                sensor.right - sensor.matched,
+                sensor.left - sensor.matched,
+                sensor.time_offset
+            )?;
+            for (name, offset) in &sensor.offsets {
+                let mean = format!(
+                    "{:+.*} {}",
+                    crate::units::PRECISION,
+                    offset.mean(),
+                    offset.unit
+                );
+                writeln!(
+                    f,
+                    "    {}: {} over {} records",
+                    name,
+                    mean.trim_end(),
+                    offset.count
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,295 @@
+//! Emulating an Ecowitt gateway's uploads to a custom server, so that
+//! software written for a GW1000, e.g. weewx-interceptor or Home Assistant's
+//! Ecowitt integration, can take the station's readings without mqtt
+//!
+//! Every interval, the latest readings are POSTed form encoded, in the
+//! imperial units and field names a gateway uses. Readings of the outdoor
+//! sensors fill the gateway's outdoor fields, and sensors assigned an Ecowitt
+//! channel fill that channel's temperature and humidity, as WH31s would.
+//! Readings that have gone stale are left out. Uploads are made with `curl`,
+//! and a failed upload is reported on the event bus and tried again the next
+//! interval.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{ConfigError, SensorPattern};
+use crate::errors::{ErrorCode, ErrorEvent};
+use crate::events::{Event, EventBus};
+use crate::radio::{Measurement, Record};
+use crate::region::Region;
+use crate::units::UnitSystem;
+
+/// Seconds between uploads, unless configured otherwise, as a gateway
+/// defaults to
+pub const DEFAULT_UPLOAD_INTERVAL: u64 = 60;
+
+/// Gateway model reported, unless configured otherwise
+pub const DEFAULT_MODEL: &str = "GW1000";
+
+// Seconds to wait on an upload before giving up
+const UPLOAD_TIMEOUT_SECS: &str = "30";
+
+// Lux per W/m² of sunlight, as for uploads to weather networks
+const LUX_PER_WATT_M2: f64 = 126.7;
+
+fn default_interval() -> u64 {
+    DEFAULT_UPLOAD_INTERVAL
+}
+
+fn default_model() -> String {
+    DEFAULT_MODEL.to_owned()
+}
+
+/// A custom server the station's readings are uploaded to as a gateway's
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EcowittUpload {
+    /// Where readings are POSTed, e.g. a Home Assistant webhook
+    pub url: String,
+    /// Key identifying the gateway, which the receiving software tells
+    /// stations apart by; the bridge's instance id when unset
+    #[serde(default)]
+    pub passkey: Option<String>,
+    /// Seconds between uploads
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// Gateway model reported
+    #[serde(default = "default_model")]
+    pub model: String,
+    /// Frequency band reported, e.g. `868M`; the region's when unset
+    #[serde(default)]
+    pub frequency: Option<String>,
+    /// Sensors whose readings fill the outdoor fields, as [`SensorPattern`]s;
+    /// all of those without a channel when empty
+    #[serde(default)]
+    pub sensors: Vec<String>,
+    /// Sensors whose temperature and humidity fill each Ecowitt channel,
+    /// from 1 to 8, as [`SensorPattern`]s
+    #[serde(default)]
+    pub channels: BTreeMap<u8, String>,
+}
+
+// The gateway field an outdoor sensor's measurement is uploaded in, and its
+// value in the units a gateway uses
+fn field(measurement: &Measurement) -> Option<(&'static str, f64)> {
+    let name = match measurement {
+        Measurement::Temperature(_) => "tempf",
+        Measurement::RelativeHumidity(_) => "humidity",
+        Measurement::WindSpeed(_) => "windspeedmph",
+        Measurement::WindGust(_) => "windgustmph",
+        Measurement::WindDirection(_) => "winddir",
+        Measurement::Rainfall(_) => "totalrainin",
+        Measurement::RainRate(_) => "rainratein",
+        Measurement::DailyRainfall(_) => "dailyrainin",
+        Measurement::Pressure(_) => "baromabsin",
+        Measurement::SeaLevelPressure(_) => "baromrelin",
+        Measurement::UvIndex(_) => "uv",
+        Measurement::Pm2_5(_) => "pm25_ch1",
+        Measurement::Co2(_) => "co2",
+        Measurement::Lux(lux) => {
+            return Some(("solarradiation", f64::from(*lux) / LUX_PER_WATT_M2))
+        }
+        _ => return None,
+    };
+    Some((name, measurement.numeric_in(Some(UnitSystem::Imperial))?))
+}
+
+// The gateway field a channel sensor's measurement is uploaded in
+fn channel_field(channel: u8, measurement: &Measurement) -> Option<(String, f64)> {
+    let name = match measurement {
+        Measurement::Temperature(_) => format!("temp{}f", channel),
+        Measurement::RelativeHumidity(_) => format!("humidity{}", channel),
+        _ => return None,
+    };
+    Some((name, measurement.numeric_in(Some(UnitSystem::Imperial))?))
+}
+
+/// The latest readings, by gateway field
+#[derive(Debug, Default)]
+pub struct Fields {
+    readings: BTreeMap<String, (DateTime<Local>, f64)>,
+}
+
+impl Fields {
+    /// Takes the readings of an outdoor sensor's record, or of a channel
+    /// sensor's when `channel` is given
+    pub fn observe(&mut self, record: &Record, channel: Option<u8>) {
+        for measurement in &record.measurements {
+            let reading = match channel {
+                Some(channel) => channel_field(channel, measurement),
+                None => field(measurement).map(|(name, value)| (name.to_owned(), value)),
+            };
+            if let Some((name, value)) = reading {
+                self.readings.insert(name, (record.timestamp, value));
+            }
+        }
+    }
+
+    /// The readings that aren't stale at `now`, along with when the latest
+    /// was taken, or `None` if there are none
+    pub fn current(&self, now: DateTime<Local>) -> Option<(DateTime<Local>, BTreeMap<&str, f64>)> {
+        let since = now - chrono::Duration::minutes(crate::pws::STALE_AFTER_MINS);
+        let fresh: BTreeMap<&str, f64> = self
+            .readings
+            .iter()
+            .filter(|(_, (t, _))| *t >= since)
+            .map(|(name, (_, value))| (name.as_str(), *value))
+            .collect();
+        let latest = self
+            .readings
+            .values()
+            .map(|(t, _)| *t)
+            .filter(|t| *t >= since)
+            .max()?;
+        Some((latest, fresh))
+    }
+}
+
+/// The form a gateway on the frequency `band` would upload the readings
+/// taken at `time` in
+pub fn form(
+    upload: &EcowittUpload,
+    passkey: &str,
+    band: &str,
+    time: DateTime<Local>,
+    readings: &BTreeMap<&str, f64>,
+) -> String {
+    let mut form = vec![
+        ("PASSKEY".to_owned(), passkey.to_owned()),
+        (
+            "stationtype".to_owned(),
+            format!("{}_V{}", clap::crate_name!(), clap::crate_version!()),
+        ),
+        (
+            "dateutc".to_owned(),
+            time.with_timezone(&Utc)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+        ),
+    ];
+    form.extend(
+        readings
+            .iter()
+            .map(|(name, value)| ((*name).to_owned(), format!("{:.2}", value))),
+    );
+    form.push(("freq".to_owned(), band.to_owned()));
+    form.push(("model".to_owned(), upload.model.clone()));
+    let form: Vec<String> = form
+        .iter()
+        .map(|(name, value)| format!("{}={}", crate::pws::encode(name), crate::pws::encode(value)))
+        .collect();
+    form.join("&")
+}
+
+/// POSTs a form to the custom server
+pub fn submit(url: &str, form: &str) -> Result<()> {
+    let mut curl = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error"])
+        .args(["--max-time", UPLOAD_TIMEOUT_SECS])
+        .args(["--data-binary", "@-"])
+        .arg(url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| "Unable to run curl")?;
+    if let Some(mut stdin) = curl.stdin.take() {
+        stdin
+            .write_all(form.as_bytes())
+            .with_context(|| "Failed to pass the form to curl")?;
+    }
+    let output = curl.wait_with_output()?;
+    if !output.status.success() {
+        anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
+
+/// Starts a background thread uploading the readings in the records on
+/// `events` every interval, until the radio stops. `instance_id` is the
+/// passkey of uploads that don't configure their own, and `region` gives the
+/// band of those that don't configure theirs.
+pub fn spawn_uploader(
+    upload: EcowittUpload,
+    instance_id: Option<uuid::Uuid>,
+    region: Region,
+    events: Receiver<Event>,
+    errors: EventBus,
+) -> Result<JoinHandle<()>, ConfigError> {
+    let sensors = upload
+        .sensors
+        .iter()
+        .map(|pattern| pattern.parse())
+        .collect::<Result<Vec<SensorPattern>, ConfigError>>()?;
+    let channels = upload
+        .channels
+        .iter()
+        .map(|(channel, pattern)| match channel {
+            1..=8 => Ok((*channel, pattern.parse()?)),
+            _ => Err(ConfigError::EcowittChannel(*channel)),
+        })
+        .collect::<Result<Vec<(u8, SensorPattern)>, ConfigError>>()?;
+    let passkey = match (&upload.passkey, instance_id) {
+        (Some(passkey), _) => passkey.clone(),
+        (None, Some(id)) => id.simple().to_string().to_ascii_uppercase(),
+        (None, None) => clap::crate_name!().to_ascii_uppercase(),
+    };
+    let band = upload
+        .frequency
+        .clone()
+        .unwrap_or_else(|| region.band().to_owned());
+    let interval = Duration::from_secs(upload.interval.max(1));
+    Ok(std::thread::spawn(move || {
+        let mut fields = Fields::default();
+        let mut started = Instant::now();
+        loop {
+            match events.recv_timeout(interval.saturating_sub(started.elapsed())) {
+                Ok(Event::Record(record)) => {
+                    let channel = channels
+                        .iter()
+                        .find(|(_, pattern)| pattern.matches(&record.sensor_id))
+                        .map(|(channel, _)| *channel);
+                    let outdoor = match channel {
+                        Some(_) => false,
+                        None if sensors.is_empty() => true,
+                        None => sensors.iter().any(|s| s.matches(&record.sensor_id)),
+                    };
+                    if channel.is_some() || outdoor {
+                        fields.observe(&record, channel);
+                    }
+                }
+                Ok(Event::RadioStopped) | Err(RecvTimeoutError::Disconnected) => return,
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            }
+            if started.elapsed() < interval {
+                continue;
+            }
+            started = Instant::now();
+            let (time, readings) = match fields.current(crate::clock::now()) {
+                Some(current) => current,
+                None => continue,
+            };
+            match submit(
+                &upload.url,
+                &form(&upload, &passkey, &band, time, &readings),
+            ) {
+                Ok(()) => log::debug!("Uploaded {} readings to {}", readings.len(), upload.url),
+                Err(e) => {
+                    log::error!("Failed to upload to {}: {:#}", upload.url, e);
+                    errors.publish(Event::Error(
+                        ErrorEvent::new(ErrorCode::Upload, format!("{:#}", e))
+                            .context("url", &upload.url),
+                    ));
+                }
+            }
+        }
+    }))
+}
@@ -0,0 +1,177 @@
+//! Structured error events, published to a topic of their own so that a
+//! gateway's problems can be alerted on without reading its logs
+//!
+//! Events are rate limited per code: beyond [`BURST`] events of a code within
+//! [`PERIOD`], further ones are only counted, and the count is reported with
+//! the next event of that code that is published.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc::Receiver;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use clap::crate_name;
+use serde::Serialize;
+
+use crate::events::Event;
+use crate::sink::MqttSink;
+
+/// Events of a code published within [`PERIOD`] before further ones are
+/// suppressed
+pub const BURST: u32 = 5;
+/// Period over which events of a code are rate limited
+pub const PERIOD: Duration = Duration::from_secs(60);
+
+/// Topic error events are published to
+pub fn errors_topic() -> String {
+    format!("{}/errors", crate_name!())
+}
+
+/// What kind of problem an error event reports
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    /// rtl_433 output that isn't json
+    MalformedOutput,
+    /// Reading from a radio failed
+    RadioRead,
+    /// A radio stopped delivering records
+    RadioStopped,
+    /// rtl_433 reported a failure of its dongle on stderr
+    RadioFault,
+    /// A raw packet couldn't be decoded
+    Decode,
+    /// Publishing to the sink failed
+    Sink,
+    /// A record couldn't be stored in the history database
+    History,
+    /// A record couldn't be appended to the capture
+    Capture,
+    /// A record couldn't be appended to the archive
+    Archive,
+    /// An automation output couldn't be switched
+    Automation,
+    /// Conditions couldn't be uploaded to a weather network or CWOP
+    Upload,
+    /// A notification couldn't be POSTed to a webhook
+    Webhook,
+    /// The Ecowitt gateway compared against couldn't be read
+    Gateway,
+    /// The system clock couldn't be stepped to the radio time
+    Clock,
+    /// Usage statistics couldn't be submitted
+    Usage,
+}
+
+/// A problem in the bridge, as published on the errors topic
+#[derive(Clone, Debug, Serialize)]
+pub struct ErrorEvent {
+    pub time: DateTime<Local>,
+    pub code: ErrorCode,
+    pub message: String,
+    /// Where the problem happened, e.g. the radio or sink involved
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub context: BTreeMap<String, String>,
+    /// Events of the same code suppressed by the rate limit since the last
+    /// one published
+    #[serde(skip_serializing_if = "is_zero")]
+    pub suppressed: u64,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+impl ErrorEvent {
+    pub fn new<M: Into<String>>(code: ErrorCode, message: M) -> Self {
+        ErrorEvent {
+            time: crate::clock::now(),
+            code,
+            message: message.into(),
+            context: BTreeMap::new(),
+            suppressed: 0,
+        }
+    }
+
+    /// Adds a piece of context, e.g. the name of the radio involved
+    pub fn context<V: ToString>(mut self, key: &str, value: V) -> Self {
+        self.context.insert(key.to_owned(), value.to_string());
+        self
+    }
+}
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    published: u32,
+    suppressed: u64,
+}
+
+/// Limits error events to [`BURST`] per code within each [`PERIOD`]
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    windows: HashMap<ErrorCode, Window>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether an event may be published, noting on it how many before it
+    /// were suppressed if so
+    pub fn admit(&mut self, event: &mut ErrorEvent) -> bool {
+        let window = self.windows.entry(event.code).or_insert(Window {
+            started: Instant::now(),
+            published: 0,
+            suppressed: 0,
+        });
+        if window.started.elapsed() >= PERIOD {
+            window.started = Instant::now();
+            window.published = 0;
+        }
+        if window.published >= BURST {
+            window.suppressed += 1;
+            return false;
+        }
+        window.published += 1;
+        event.suppressed = std::mem::take(&mut window.suppressed);
+        true
+    }
+}
+
+/// Starts a background thread that publishes the error events on the event
+/// bus to the errors topic, rate limited, until the radio stops
+pub fn spawn_reporter(sink: MqttSink, events: Receiver<Event>) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut limiter = RateLimiter::new();
+        let topic = errors_topic();
+        for event in events {
+            let mut error = match event {
+                Event::Error(error) => error,
+                Event::SinkError { sink, error } => {
+                    ErrorEvent::new(ErrorCode::Sink, error).context("sink", sink)
+                }
+                Event::RadioStopped => return,
+                _ => continue,
+            };
+            if !limiter.admit(&mut error) {
+                continue;
+            }
+            let payload = match serde_json::to_vec(&error) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    log::error!("Failed to serialize error event: {:?}", e);
+                    continue;
+                }
+            };
+            // The sink failing is among the errors reported, so failing to
+            // report it isn't worth more than a debug line
+            match sink.publish(&topic, payload) {
+                Ok(()) => log::debug!("mqtt <== {}({:?})", topic, error),
+                Err(e) => log::debug!("Failed to publish error event: {:?}", e),
+            }
+        }
+    })
+}
@@ -0,0 +1,69 @@
+//! Internal publish/subscribe bus connecting the pipeline to the components
+//! that react to what happens in it
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use crate::lifecycle::Transition;
+use crate::radio::Record;
+
+/// Something that happened in the bridge that other components may want to
+/// react to
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// A record made it through filtering, and derived measurements have
+    /// been added to it
+    Record(Record),
+    /// A record was dropped for not meeting the integrity requirements
+    IntegrityFailure { sensor_id: String },
+    /// None of the parsers made sense of a record from rtl_433, given as
+    /// rtl_433 reported it
+    Unparsed(serde_json::Value),
+    /// A sensor was heard from for the first time, or after going offline
+    SensorOnline { sensor_id: String, topic: String },
+    /// A sensor hasn't been heard from within the sensor timeout
+    SensorOffline { topic: String },
+    /// A sensor moved to a new lifecycle state
+    SensorLifecycle(Transition),
+    /// Publishing to a sink failed
+    SinkError { sink: String, error: String },
+    /// Something went wrong that remote monitoring should hear about
+    Error(crate::errors::ErrorEvent),
+    /// The power profile changed, or was set at startup
+    PowerProfile(crate::power::PowerProfile),
+    /// The radio started delivering records
+    RadioStarted,
+    /// The radio stopped delivering records, and the pipeline is shutting
+    /// down. No further events follow.
+    RadioStopped,
+}
+
+/// Fans out every published event to all current subscribers. Cloning the
+/// bus produces another handle to the same set of subscribers.
+#[derive(Clone, Debug, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a receiver for all events published from now on. Dropping
+    /// the receiver unsubscribes.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = channel();
+        self.subscribers
+            .lock()
+            .expect("event bus poisoned")
+            .push(tx);
+        rx
+    }
+
+    /// Delivers an event to every subscriber
+    pub fn publish(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().expect("event bus poisoned");
+        subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
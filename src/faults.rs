@@ -0,0 +1,87 @@
+//! Failures rtl_433, and the SDR libraries under it, report on its stderr
+//!
+//! rtl_433 keeps running through some failures of the dongle under it,
+//! printing a complaint and no more records, so its stderr is read for the
+//! signatures of known failures. Each one recognized is logged as a warning
+//! and reported on the event bus, where it's counted and marks the bridge
+//! unhealthy for the API. A fatal one, after which the dongle won't deliver
+//! records again without being reopened, stops its rtl_433, so that the
+//! bridge exits with an error for its service manager to restart it.
+
+use serde::Serialize;
+
+/// A failure recognized in rtl_433's stderr
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Fault {
+    /// The dongle couldn't be claimed, usually as another program or the
+    /// kernel's DVB driver has it
+    UsbClaim,
+    /// No dongle was found, or it couldn't be opened
+    NoDevice,
+    /// Reading from the dongle stalled, as when it's unplugged or browns out
+    AsyncStalled,
+    /// The dongle's USB transfers failed, as when it's unplugged
+    UsbTransfer,
+    /// Samples were dropped, as when the host can't keep up with the sample
+    /// rate
+    SampleDrop,
+}
+
+// Substrings of the lines reporting each fault, in lowercase
+const SIGNATURES: &[(&str, Fault)] = &[
+    ("usb_claim_interface error", Fault::UsbClaim),
+    ("failed to open rtlsdr device", Fault::NoDevice),
+    ("no supported devices found", Fault::NoDevice),
+    ("async read stalled", Fault::AsyncStalled),
+    ("cb transfer status", Fault::UsbTransfer),
+    ("samples lost", Fault::SampleDrop),
+    ("lost at least", Fault::SampleDrop),
+    ("overflow", Fault::SampleDrop),
+];
+
+impl Fault {
+    /// The fault's name, as reported and used to label its counter
+    pub fn name(self) -> &'static str {
+        match self {
+            Fault::UsbClaim => "usb-claim",
+            Fault::NoDevice => "no-device",
+            Fault::AsyncStalled => "async-stalled",
+            Fault::UsbTransfer => "usb-transfer",
+            Fault::SampleDrop => "sample-drop",
+        }
+    }
+
+    /// Whether the dongle won't deliver records again without rtl_433 being
+    /// restarted
+    pub fn is_fatal(self) -> bool {
+        self != Fault::SampleDrop
+    }
+}
+
+impl std::fmt::Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for Fault {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SIGNATURES
+            .iter()
+            .map(|(_, fault)| *fault)
+            .find(|fault| fault.name() == s)
+            .ok_or_else(|| format!("Unknown fault '{}'", s))
+    }
+}
+
+/// The fault a line of rtl_433's stderr reports, if any
+pub fn recognize(line: &str) -> Option<Fault> {
+    let line = line.to_ascii_lowercase();
+    SIGNATURES
+        .iter()
+        .find(|(signature, _)| line.contains(signature))
+        .map(|(_, fault)| *fault)
+}
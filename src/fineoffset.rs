@@ -0,0 +1,451 @@
+//! Decoding of raw Fine Offset packets, for payloads captured without
+//! rtl_433's own decoders, e.g. with `rtl_433 -A` or an SDR recording
+//!
+//! Decoded packets are turned into json with the fields rtl_433 would have
+//! reported, so they go through the regular parsers from there. Raw packet
+//! decoding is only built with the `raw-decoders` feature.
+
+#[cfg(feature = "raw-decoders")]
+use anyhow::Result;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PacketError {
+    #[error("Packet is not valid hex")]
+    InvalidHex,
+    #[error("Packet is {0} bytes, expected {1}")]
+    Length(usize, usize),
+    #[error("Unsupported Fine Offset family code {0:#04x}")]
+    UnsupportedFamily(u8),
+    #[error("Packet CRC mismatch, computed {computed:#04x} but packet has {packet:#04x}")]
+    Crc { computed: u8, packet: u8 },
+    #[error("Packet checksum mismatch, computed {computed:#04x} but packet has {packet:#04x}")]
+    Checksum { computed: u8, packet: u8 },
+}
+
+/// Sync word preceding the payload, which captures often include
+#[cfg(feature = "raw-decoders")]
+pub const SYNC: [u8; 2] = [0x2d, 0xd4];
+
+#[cfg(feature = "raw-decoders")]
+const RCC_FAMILY: u8 = 0x52;
+#[cfg(feature = "raw-decoders")]
+const RCC_LEN: usize = 11;
+#[cfg(feature = "raw-decoders")]
+const WH45_FAMILY: u8 = 0x45;
+#[cfg(feature = "raw-decoders")]
+const WH45_LEN: usize = 15;
+#[cfg(feature = "raw-decoders")]
+const WS80_FAMILY: u8 = 0x80;
+#[cfg(feature = "raw-decoders")]
+const WS80_LEN: usize = 18;
+#[cfg(feature = "raw-decoders")]
+const WS90_FAMILY: u8 = 0x90;
+#[cfg(feature = "raw-decoders")]
+const WS90_LEN: usize = 32;
+
+/// Length of the longest packet decoded, after the sync word
+#[cfg(feature = "raw-decoders")]
+pub const MAX_PACKET_LEN: usize = WS90_LEN;
+
+// Verifies the CRC-8 (polynomial 0x31) of a packet in its second to last
+// byte, and the sum of its bytes in its last
+#[cfg(feature = "raw-decoders")]
+fn verify(b: &[u8]) -> Result<(), PacketError> {
+    let (crc_at, sum_at) = (b.len() - 2, b.len() - 1);
+    let mut crc = crc_any::CRCu8::create_crc(0x31, 8, 0x00, 0x00, false);
+    crc.digest(&b[..crc_at]);
+    let computed = crc.get_crc();
+    if computed != b[crc_at] {
+        return Err(PacketError::Crc {
+            computed,
+            packet: b[crc_at],
+        });
+    }
+    let computed = b[..sum_at].iter().fold(0u8, |sum, x| sum.wrapping_add(*x));
+    if computed != b[sum_at] {
+        return Err(PacketError::Checksum {
+            computed,
+            packet: b[sum_at],
+        });
+    }
+    Ok(())
+}
+
+// Payload layout of the WH45 air quality sensor, after the sync word:
+//  0  1  2  3  4  5  6  7  8  9 10 11 12 13 14
+// YY II II II 0T TT HH BD DD BD DD CC CC RR SS
+// - Y: family code 0x45
+// - I: 24 bit device id
+// - T: 11 bit temperature, in 0.1 °C offset by 40 °C
+// - H: relative humidity, in %
+// - B: 2 bits each, together the 4 bit battery level in bars from 0 to 5
+// - D: 14 bit PM2.5 and PM10, in 0.1 µg/m³
+// - C: CO2, in ppm
+// - R: CRC-8 of bytes 0-12, polynomial 0x31
+// - S: sum of bytes 0-13
+#[cfg(feature = "raw-decoders")]
+fn decode_wh45(b: &[u8]) -> Result<serde_json::Value, PacketError> {
+    if b.len() < WH45_LEN {
+        return Err(PacketError::Length(b.len(), WH45_LEN));
+    }
+    verify(&b[..WH45_LEN])?;
+
+    let id = u32::from(b[1]) << 16 | u32::from(b[2]) << 8 | u32::from(b[3]);
+    let temp_raw = i32::from(b[4] & 0x07) << 8 | i32::from(b[5]);
+    let pm2_5_raw = u16::from(b[7] & 0x3f) << 8 | u16::from(b[8]);
+    let pm10_raw = u16::from(b[9] & 0x3f) << 8 | u16::from(b[10]);
+    let co2 = u16::from(b[11]) << 8 | u16::from(b[12]);
+    let battery_bars = (b[7] & 0xc0) >> 4 | (b[9] & 0xc0) >> 6;
+    Ok(serde_json::json!({
+        "model": "Fineoffset-WH45",
+        "id": id,
+        "battery_ok": u8::from(battery_bars > 1),
+        "temperature_C": f64::from(temp_raw - 400) * 0.1,
+        "humidity": b[6],
+        "pm2_5_ug_m3": f64::from(pm2_5_raw) * 0.1,
+        "pm10_ug_m3": f64::from(pm10_raw) * 0.1,
+        "co2_ppm": co2,
+        "mic": "CRC",
+    }))
+}
+
+// Payload layout of the radio-controlled clock (RCC) broadcast of the WH31B,
+// after the sync word:
+//  0  1  2  3  4  5  6  7  8  9 10
+// YY II FF AA MM DD HH NN SS RR CC
+// - Y: family code 0x52
+// - I: device id
+// - F: flags: 0x80 is set while daylight saving time is in effect, and the
+//   other bits aren't known
+// - A, M, D, H, N, S: year within the century, month, day, hour, minute and
+//   second, in BCD, the time being local to the transmitter the clock
+//   receives. The bits above each field's highest digit aren't known.
+// - R: CRC-8 of bytes 0-8, polynomial 0x31
+// - C: sum of bytes 0-9
+// The bits that aren't known are reported as they are, the flags as
+// "flags", and those above the fields as "spare_bits", in hex from the month
+// to the second, so that captures can be searched for their meaning.
+// E.g. either side of the clocks going back at 03:00 CEST on 27 October 2024:
+// 2dd4 52 20 80 24 10 27 02 59 59 e9 ea  02:59:59 CEST, dst 1
+// 2dd4 52 20 00 24 10 27 02 00 00 87 56  02:00:00 CET, dst 0
+#[cfg(feature = "raw-decoders")]
+fn decode_rcc(b: &[u8]) -> Result<serde_json::Value, PacketError> {
+    if b.len() < RCC_LEN {
+        return Err(PacketError::Length(b.len(), RCC_LEN));
+    }
+    verify(&b[..RCC_LEN])?;
+
+    let bcd = |byte: u8, tens: u8| u32::from((byte & tens) >> 4) * 10 + u32::from(byte & 0x0f);
+    let clock = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        2000 + bcd(b[3], 0xf0),
+        bcd(b[4], 0x10),
+        bcd(b[5], 0x30),
+        bcd(b[6], 0x30),
+        bcd(b[7], 0x70),
+        bcd(b[8], 0x70),
+    );
+    let spare_bits = format!(
+        "{:02x}{:02x}{:02x}{:02x}{:02x}",
+        b[4] & 0xe0,
+        b[5] & 0xc0,
+        b[6] & 0xc0,
+        b[7] & 0x80,
+        b[8] & 0x80
+    );
+    Ok(serde_json::json!({
+        "model": "AmbientWeather-WH31B",
+        "id": b[1],
+        "radio_clock": clock,
+        "dst": u8::from(b[2] & 0x80 != 0),
+        "flags": b[2],
+        "spare_bits": spare_bits,
+        "mic": "CRC",
+    }))
+}
+
+// Payload layout shared by the WS80 and WS90 all-in-one stations, after the
+// sync word:
+//  0  1  2  3  4  5  6  7  8  9 10 11 12 13
+// YY II II II LL LL BB FF TT HH WW DD GG VV
+// - Y: family code
+// - I: 24 bit device id
+// - L: light, in units of 10 lux
+// - B: battery voltage, in units of 20 mV
+// - F: flags, holding the high bits of the values after it: 0x03 of the
+//   temperature, 0x10 of the wind speed, 0x20 of the wind direction, and
+//   0x40 of the gust
+// - T: 10 bit temperature, in 0.1 °C offset by 40 °C
+// - H: relative humidity, in %
+// - W: 9 bit wind speed, in 0.1 m/s
+// - D: 9 bit wind direction, in degrees
+// - G: 9 bit gust, in 0.1 m/s
+// - V: UV index, in tenths
+// Values with every bit set are readings the station doesn't have, and are
+// left out.
+#[cfg(feature = "raw-decoders")]
+fn decode_ws_common(b: &[u8], model: &str) -> serde_json::Value {
+    let id = u32::from(b[1]) << 16 | u32::from(b[2]) << 8 | u32::from(b[3]);
+    let light_raw = u16::from(b[4]) << 8 | u16::from(b[5]);
+    let battery_mv = u32::from(b[6]) * 20;
+    // 1.4 V is flat and 3.0 V is full, as rtl_433 reports it
+    let battery_level = (f64::from(battery_mv.saturating_sub(1400)) / 1600.0).min(1.0);
+    let temp_raw = u16::from(b[7] & 0x03) << 8 | u16::from(b[8]);
+    let wind_avg = u16::from(b[7] & 0x10) << 4 | u16::from(b[10]);
+    let wind_dir = u16::from(b[7] & 0x20) << 3 | u16::from(b[11]);
+    let wind_max = u16::from(b[7] & 0x40) << 2 | u16::from(b[12]);
+    let mut json = serde_json::json!({
+        "model": model,
+        "id": id,
+        "battery_ok": battery_level,
+        "battery_mV": battery_mv,
+        "mic": "CRC",
+    });
+    if temp_raw != 0x3ff {
+        json["temperature_C"] = (f64::from(i32::from(temp_raw) - 400) * 0.1).into();
+    }
+    if b[9] != 0xff {
+        json["humidity"] = b[9].into();
+    }
+    if wind_dir != 0x1ff {
+        json["wind_dir_deg"] = wind_dir.into();
+    }
+    if wind_avg != 0x1ff {
+        json["wind_avg_m_s"] = (f64::from(wind_avg) * 0.1).into();
+    }
+    if wind_max != 0x1ff {
+        json["wind_max_m_s"] = (f64::from(wind_max) * 0.1).into();
+    }
+    if b[13] != 0xff {
+        json["uvi"] = (f64::from(b[13]) * 0.1).into();
+    }
+    if light_raw != 0xffff {
+        json["light_lux"] = (u32::from(light_raw) * 10).into();
+    }
+    json
+}
+
+// The WS80 has the common layout, followed by:
+// 14 15 16 17
+// UU UU RR SS
+// - U: unknown
+// - R: CRC-8 of bytes 0-15, polynomial 0x31
+// - S: sum of bytes 0-16
+#[cfg(feature = "raw-decoders")]
+fn decode_ws80(b: &[u8]) -> Result<serde_json::Value, PacketError> {
+    if b.len() < WS80_LEN {
+        return Err(PacketError::Length(b.len(), WS80_LEN));
+    }
+    verify(&b[..WS80_LEN])?;
+    Ok(decode_ws_common(b, "Fineoffset-WS80"))
+}
+
+// The WS90 has the common layout, followed by:
+// 14 15 16 17 18 19 20 21 .. 29 30 31
+// UU UU UU UU UU RR RR CC .. FW XX SS
+// - U: unknown
+// - R: rain total, in 0.1 mm
+// - C: supercap voltage, in 0.1 V, in the low 6 bits
+// - F: firmware version
+// - X: CRC-8 of bytes 0-29, polynomial 0x31
+// - S: sum of bytes 0-30
+#[cfg(feature = "raw-decoders")]
+fn decode_ws90(b: &[u8]) -> Result<serde_json::Value, PacketError> {
+    if b.len() < WS90_LEN {
+        return Err(PacketError::Length(b.len(), WS90_LEN));
+    }
+    verify(&b[..WS90_LEN])?;
+    let mut json = decode_ws_common(b, "Fineoffset-WS90");
+    let rain_raw = u16::from(b[19]) << 8 | u16::from(b[20]);
+    json["rain_mm"] = (f64::from(rain_raw) * 0.1).into();
+    json["supercap_V"] = (f64::from(b[21] & 0x3f) * 0.1).into();
+    json["firmware"] = b[29].into();
+    Ok(json)
+}
+
+#[cfg(feature = "raw-decoders")]
+fn parse_hex(hex: &str) -> Result<Vec<u8>, PacketError> {
+    let hex: String = hex.chars().filter(|c| !c.is_whitespace()).collect();
+    if !hex.len().is_multiple_of(2) {
+        return Err(PacketError::InvalidHex);
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| PacketError::InvalidHex))
+        .collect()
+}
+
+/// Decodes a hex encoded Fine Offset packet, with or without its sync word,
+/// into a record received at `timestamp`
+#[cfg(feature = "raw-decoders")]
+pub fn decode(
+    hex: &str,
+    timestamp: chrono::DateTime<chrono::Local>,
+) -> Result<crate::radio::Record> {
+    let json = packet_json(&parse_hex(hex)?, timestamp)?;
+    crate::ambientweather::try_parse(&json)
+}
+
+/// Decodes a Fine Offset packet, with or without its sync word, into the
+/// json rtl_433 would have reported for it at `timestamp`
+#[cfg(feature = "raw-decoders")]
+pub fn packet_json(
+    bytes: &[u8],
+    timestamp: chrono::DateTime<chrono::Local>,
+) -> Result<serde_json::Value> {
+    let mut json = decode_payload(bytes)?;
+    json["time"] = timestamp.format("%Y-%m-%d %H:%M:%S").to_string().into();
+    Ok(json)
+}
+
+/// Decodes the raw packet in a record rtl_433 didn't decode itself, e.g. the
+/// output of a flex decoder, into the json rtl_433 would have reported for
+/// it. The packet is taken from the record's "data" field, or that of its
+/// first row, and the record's time is kept.
+#[cfg(feature = "raw-decoders")]
+pub fn raw_data_json(record: &serde_json::Value) -> Result<serde_json::Value> {
+    let data = record
+        .get("data")
+        .or_else(|| record.get("rows")?.get(0)?.get("data"))
+        .and_then(serde_json::Value::as_str)
+        .ok_or(PacketError::InvalidHex)?;
+    let mut json = decode_payload(&parse_hex(data)?)?;
+    json["time"] = match record.get("time") {
+        Some(time) => time.clone(),
+        None => crate::clock::now()
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+            .into(),
+    };
+    Ok(json)
+}
+
+#[cfg(feature = "raw-decoders")]
+fn decode_payload(bytes: &[u8]) -> Result<serde_json::Value, PacketError> {
+    let payload = bytes.strip_prefix(&SYNC[..]).unwrap_or(bytes);
+    match payload.first() {
+        Some(&RCC_FAMILY) => decode_rcc(payload),
+        Some(&WH45_FAMILY) => decode_wh45(payload),
+        Some(&WS80_FAMILY) => decode_ws80(payload),
+        Some(&WS90_FAMILY) => decode_ws90(payload),
+        Some(&family) => Err(PacketError::UnsupportedFamily(family)),
+        None => Err(PacketError::Length(0, WH45_LEN)),
+    }
+}
+
+#[cfg(all(test, feature = "raw-decoders"))]
+mod tests {
+    use super::*;
+
+    fn decode_hex(hex: &str) -> Result<serde_json::Value, PacketError> {
+        decode_payload(&parse_hex(hex)?)
+    }
+
+    fn assert_close(json: &serde_json::Value, field: &str, expected: f64) {
+        let actual = json[field]
+            .as_f64()
+            .unwrap_or_else(|| panic!("no {} in {}", field, json));
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{} is {}, expected {}",
+            field,
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn wh45() {
+        let json = decode_hex("2dd4 4500234502712d407b40c902643cb3").unwrap();
+        assert_eq!(json["model"], "Fineoffset-WH45");
+        assert_eq!(json["id"], 0x002345);
+        assert_close(&json, "temperature_C", 22.5);
+        assert_eq!(json["humidity"], 45);
+        assert_close(&json, "pm2_5_ug_m3", 12.3);
+        assert_close(&json, "pm10_ug_m3", 20.1);
+        assert_eq!(json["co2_ppm"], 612);
+        assert_eq!(json["battery_ok"], 1);
+    }
+
+    #[test]
+    fn ws80() {
+        let json = decode_hex("8008123a04d29622473e190e2920083f40de").unwrap();
+        assert_eq!(json["model"], "Fineoffset-WS80");
+        assert_eq!(json["id"], 0x08123a);
+        assert_eq!(json["light_lux"], 12340);
+        assert_eq!(json["battery_mV"], 3000);
+        assert_close(&json, "battery_ok", 1.0);
+        assert_close(&json, "temperature_C", 18.3);
+        assert_eq!(json["humidity"], 62);
+        assert_close(&json, "wind_avg_m_s", 2.5);
+        assert_eq!(json["wind_dir_deg"], 270);
+        assert_close(&json, "wind_max_m_s", 4.1);
+        assert_close(&json, "uvi", 3.2);
+    }
+
+    #[test]
+    fn ws90() {
+        let json =
+            decode_hex("2dd4 900b0f1a015991016d58002d0c003fffffff0030393600000000000000865f6e")
+                .unwrap();
+        assert_eq!(json["model"], "Fineoffset-WS90");
+        assert_eq!(json["id"], 0x0b0f1a);
+        assert_eq!(json["light_lux"], 3450);
+        assert_eq!(json["battery_mV"], 2900);
+        assert_close(&json, "battery_ok", 0.9375);
+        assert_close(&json, "temperature_C", -3.5);
+        assert_eq!(json["humidity"], 88);
+        assert_close(&json, "wind_avg_m_s", 0.0);
+        assert_eq!(json["wind_dir_deg"], 45);
+        assert_close(&json, "wind_max_m_s", 1.2);
+        assert_close(&json, "uvi", 0.0);
+        assert_close(&json, "rain_mm", 1234.5);
+        assert_close(&json, "supercap_V", 5.4);
+        assert_eq!(json["firmware"], 134);
+    }
+
+    #[test]
+    fn missing_readings_are_left_out() {
+        // A WS80 without its light and UV sensor, which reports them with
+        // every bit set
+        let json = decode_hex("8008123affff9622473e190e29ff083f9237").unwrap();
+        assert!(json.get("light_lux").is_none());
+        assert!(json.get("uvi").is_none());
+        assert_close(&json, "temperature_C", 18.3);
+    }
+
+    #[test]
+    fn rcc_either_side_of_the_clocks_going_back() {
+        let json = decode_hex("2dd4 522080241027025959e9ea").unwrap();
+        assert_eq!(json["radio_clock"], "2024-10-27T02:59:59");
+        assert_eq!(json["dst"], 1);
+        let json = decode_hex("2dd4 5220002410270200008756").unwrap();
+        assert_eq!(json["radio_clock"], "2024-10-27T02:00:00");
+        assert_eq!(json["dst"], 0);
+    }
+
+    #[test]
+    fn corrupt_packets_are_rejected() {
+        assert!(matches!(
+            decode_hex("4500234502722d407b40c902643cb3"),
+            Err(PacketError::Crc { packet: 0x3c, .. })
+        ));
+        assert!(matches!(
+            decode_hex("4500234502712d407b40c902643cb4"),
+            Err(PacketError::Checksum {
+                computed: 0xb3,
+                packet: 0xb4
+            })
+        ));
+        assert!(matches!(
+            decode_hex("4500234502712d407b40c9"),
+            Err(PacketError::Length(11, WH45_LEN))
+        ));
+        assert!(matches!(
+            decode_hex("31ff"),
+            Err(PacketError::UnsupportedFamily(0x31))
+        ));
+        assert!(matches!(decode_hex("45x"), Err(PacketError::InvalidHex)));
+    }
+}
@@ -0,0 +1,299 @@
+//! Report of the gaps in each sensor's records in the history database, for
+//! troubleshooting spotty dashboards
+//!
+//! A sensor's expected cadence is the median interval between its records,
+//! and any longer silence in which at least one of its transmissions went
+//! missing is a gap. Each gap is put down to its likeliest cause, from the
+//! events stored alongside the records: the bridge not running, the radio
+//! failing, no sensor at all being heard, or just this one sensor going
+//! quiet, e.g. from a flat battery or interference. Periods in which
+//! publishing to the broker failed are listed too, since the records made it
+//! into the history but not to dashboards.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use thiserror::Error;
+
+use crate::config::Config;
+use crate::history::{HistoryEvent, HistoryStore, BRIDGE_STARTED, BRIDGE_STOPPED};
+
+// Sink errors at most this far apart belong to the same outage, in minutes
+const OUTAGE_JOIN_MINS: i64 = 5;
+
+#[derive(Error, Debug)]
+pub enum GapsError {
+    #[error("Invalid period '{0}', expected a number of minutes, hours or days like '7d'")]
+    Since(String),
+    #[error("No history database configured, see --history-db")]
+    NoHistory,
+}
+
+/// Parses how far back to look, e.g. `12h` or `7d`
+pub fn parse_since(since: &str) -> Result<chrono::Duration, GapsError> {
+    let invalid = || GapsError::Since(since.to_owned());
+    let split = since
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let count: i64 = since[..split].parse().map_err(|_| invalid())?;
+    match &since[split..] {
+        _ if count <= 0 => Err(invalid()),
+        "m" => Ok(chrono::Duration::minutes(count)),
+        "h" => Ok(chrono::Duration::hours(count)),
+        "d" => Ok(chrono::Duration::days(count)),
+        _ => Err(invalid()),
+    }
+}
+
+/// What most likely caused a gap
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cause {
+    /// The bridge was stopped, or crashed and was started again
+    BridgeDown,
+    /// A radio failed, with the error reported
+    RadioFailure(String),
+    /// None of the other sensors were heard from either
+    NothingHeard,
+    /// Other sensors were heard from, so it's down to the sensor
+    SensorSilent,
+}
+
+impl std::fmt::Display for Cause {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::BridgeDown => write!(f, "bridge not running"),
+            Self::RadioFailure(error) => write!(f, "radio failed: {}", error),
+            Self::NothingHeard => write!(f, "no sensors heard, check the receiver"),
+            Self::SensorSilent => {
+                write!(f, "only this sensor silent, check its battery and range")
+            }
+        }
+    }
+}
+
+/// A silence in a sensor's records
+#[derive(Clone, Debug)]
+pub struct Gap {
+    pub from: DateTime<Local>,
+    /// When the sensor was heard from again, or when the bridge stopped or
+    /// the report was made if it hasn't been
+    pub until: DateTime<Local>,
+    /// Whether the sensor is still silent
+    pub ongoing: bool,
+    /// Transmissions expected in the gap that weren't received
+    pub missed: i64,
+    pub cause: Cause,
+}
+
+/// A period in which publishing to the broker failed
+#[derive(Clone, Debug)]
+pub struct Outage {
+    pub from: DateTime<Local>,
+    pub until: DateTime<Local>,
+    pub errors: usize,
+    pub last_error: String,
+}
+
+/// The gaps of every sensor with records since `since`
+#[derive(Debug)]
+pub struct GapReport {
+    pub since: DateTime<Local>,
+    /// Each sensor's cadence and gaps, by sensor id
+    pub sensors: BTreeMap<String, (chrono::Duration, Vec<Gap>)>,
+    pub outages: Vec<Outage>,
+}
+
+fn format_duration(d: chrono::Duration) -> String {
+    match d.num_seconds() {
+        s if s < 120 => format!("{}s", s),
+        s if s < 2 * 3600 => format!("{}m", s / 60),
+        s if s < 2 * 86400 => format!("{}h {}m", s / 3600, s / 60 % 60),
+        s => format!("{}d {}h", s / 86400, s / 3600 % 24),
+    }
+}
+
+impl std::fmt::Display for GapReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let time = |t: DateTime<Local>| t.format("%Y-%m-%d %H:%M:%S").to_string();
+        let gaps: usize = self.sensors.values().map(|(_, gaps)| gaps.len()).sum();
+        writeln!(
+            f,
+            "{} gaps in the records of {} sensors since {}",
+            gaps,
+            self.sensors.len(),
+            time(self.since)
+        )?;
+        for (sensor_id, (cadence, gaps)) in self.sensors.iter().filter(|(_, (_, g))| !g.is_empty())
+        {
+            writeln!(f, "{} (every {})", sensor_id, format_duration(*cadence))?;
+            for gap in gaps {
+                let until = match gap.ongoing {
+                    true => "now".to_owned(),
+                    false => time(gap.until),
+                };
+                let length = gap.until - gap.from;
+                writeln!(
+                    f,
+                    "  {} to {} ({}, ~{} missed): {}",
+                    time(gap.from),
+                    until,
+                    format_duration(length),
+                    gap.missed,
+                    gap.cause
+                )?;
+            }
+        }
+        if !self.outages.is_empty() {
+            writeln!(f, "Broker outages, missed by dashboards but not history")?;
+            for outage in &self.outages {
+                writeln!(
+                    f,
+                    "  {} to {} ({} errors): {}",
+                    time(outage.from),
+                    time(outage.until),
+                    outage.errors,
+                    outage.last_error
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The median of the positive intervals between `times`, if there are enough
+// of them to go by
+fn cadence(times: &[DateTime<Local>]) -> Option<chrono::Duration> {
+    let mut intervals: Vec<chrono::Duration> = times
+        .windows(2)
+        .map(|pair| pair[1] - pair[0])
+        .filter(|d| *d > chrono::Duration::zero())
+        .collect();
+    intervals.sort_unstable();
+    match intervals.len() {
+        n if n >= 2 => Some(intervals[n / 2]),
+        _ => None,
+    }
+}
+
+// Puts a gap in `sensor_id`'s records down to its likeliest cause
+fn cause(
+    sensor_id: &str,
+    from: DateTime<Local>,
+    until: DateTime<Local>,
+    events: &[HistoryEvent],
+    timestamps: &BTreeMap<String, Vec<DateTime<Local>>>,
+) -> Cause {
+    let during: Vec<&HistoryEvent> = events
+        .iter()
+        .filter(|e| e.time >= from && e.time < until)
+        .collect();
+    if during
+        .iter()
+        .any(|e| e.kind == BRIDGE_STOPPED || e.kind == BRIDGE_STARTED)
+    {
+        return Cause::BridgeDown;
+    }
+    if let Some(e) = during
+        .iter()
+        .find(|e| ["radio-read", "radio-stopped", "radio-fault"].contains(&e.kind.as_str()))
+    {
+        return Cause::RadioFailure(e.detail.clone());
+    }
+    let others_heard = timestamps
+        .iter()
+        .filter(|(other, _)| *other != sensor_id)
+        .any(|(_, times)| times.iter().any(|t| *t > from && *t < until));
+    match others_heard {
+        true => Cause::SensorSilent,
+        false => Cause::NothingHeard,
+    }
+}
+
+// Groups sink errors into outages
+fn outages(events: &[HistoryEvent]) -> Vec<Outage> {
+    let mut outages: Vec<Outage> = Vec::new();
+    for event in events.iter().filter(|e| e.kind == "sink") {
+        match outages.last_mut() {
+            Some(outage)
+                if event.time - outage.until <= chrono::Duration::minutes(OUTAGE_JOIN_MINS) =>
+            {
+                outage.until = event.time;
+                outage.errors += 1;
+                outage.last_error = event.detail.clone();
+            }
+            _ => outages.push(Outage {
+                from: event.time,
+                until: event.time,
+                errors: 1,
+                last_error: event.detail.clone(),
+            }),
+        }
+    }
+    outages
+}
+
+/// Finds the gaps in each sensor's records since `since`
+pub fn analyze(store: &HistoryStore, since: DateTime<Local>) -> Result<GapReport> {
+    let now = crate::clock::now();
+    let events = store.events(since)?;
+    let mut timestamps: BTreeMap<String, Vec<DateTime<Local>>> = BTreeMap::new();
+    for (sensor_id, time) in store.timestamps(since)? {
+        timestamps.entry(sensor_id).or_default().push(time);
+    }
+    let mut sensors = BTreeMap::new();
+    for (sensor_id, times) in &timestamps {
+        let usual = match cadence(times) {
+            Some(usual) => usual,
+            None => continue,
+        };
+        let missed = |length: chrono::Duration| {
+            let ratio = length.num_milliseconds() as f64 / usual.num_milliseconds() as f64;
+            ratio.round() as i64 - 1
+        };
+        let mut gaps = Vec::new();
+        // The silence after the last record lasts until the bridge was
+        // stopped, if it has been since
+        let last = times.last().map(|last| {
+            let stopped = events
+                .iter()
+                .find(|e| e.kind == BRIDGE_STOPPED && e.time >= *last)
+                .map(|e| e.time);
+            (*last, stopped.unwrap_or(now), stopped.is_none())
+        });
+        let silences = times
+            .windows(2)
+            .map(|pair| (pair[0], pair[1], false))
+            .chain(last);
+        for (from, until, ongoing) in silences {
+            let missed = missed(until - from);
+            if missed < 1 {
+                continue;
+            }
+            gaps.push(Gap {
+                from,
+                until,
+                ongoing,
+                missed,
+                cause: cause(sensor_id, from, until, &events, &timestamps),
+            });
+        }
+        sensors.insert(sensor_id.clone(), (usual, gaps));
+    }
+    Ok(GapReport {
+        since,
+        sensors,
+        outages: outages(&events),
+    })
+}
+
+/// Finds the gaps in the configured history database since `since` ago
+pub fn report(conf: &Config, since: chrono::Duration) -> Result<GapReport> {
+    let history = conf
+        .history
+        .as_ref()
+        .filter(|h| h.path.exists())
+        .ok_or(GapsError::NoHistory)?;
+    let store = HistoryStore::open(&history.path, None)?;
+    analyze(&store, crate::clock::now() - since)
+}
@@ -0,0 +1,459 @@
+//! Cross-checking the readings decoded here against those of an Ecowitt
+//! gateway receiving the same sensors, e.g. a GW1000, GW1100 or Froggit
+//! DP1500, for validating the decoders against the vendor's firmware and
+//! spotting calibration offsets set on one side but not the other
+//!
+//! Every interval, the gateway's live data is read over its local API, the
+//! binary protocol on TCP port 45000 that the WS View app uses, and each
+//! outdoor and channel reading is compared with the latest reading of the
+//! same measurement by the sensors mapped to it, as for Ecowitt uploads.
+//! Readings the radio hasn't heard within the interval aren't compared.
+//! Comparisons are made in the metric units of normalized payloads, after
+//! the calibration configured here, and are published to
+//! `weatherradio/gateway` with their differences and the mean difference
+//! since the bridge started. A difference beyond the measurement's
+//! tolerance is logged when it starts and when it ends.
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use clap::crate_name;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{ConfigError, SensorPattern};
+use crate::errors::{ErrorCode, ErrorEvent};
+use crate::events::{Event, EventBus};
+use crate::radio::Record;
+use crate::sink::MqttSink;
+use crate::units::UnitSystem;
+
+/// Seconds between comparisons, unless configured otherwise
+pub const DEFAULT_INTERVAL: u64 = 60;
+
+/// Port of the gateway's local API
+pub const API_PORT: u16 = 45000;
+
+// Seconds to wait on the gateway before giving up
+const TIMEOUT_SECS: u64 = 5;
+
+// Command reading the live data
+const CMD_LIVEDATA: u8 = 0x27;
+
+// Differences tolerated by default, by measurement, allowing for each side
+// rounding to its own resolution
+const TOLERANCES: &[(&str, f64)] = &[
+    ("temperature_c", 0.15),
+    ("humidity", 1.5),
+    ("pressure_hpa", 0.15),
+    ("wind_dir_deg", 1.5),
+    ("wind_speed_km_h", 0.5),
+    ("wind_gust_km_h", 0.5),
+    ("rain_rate_mm_h", 0.15),
+    ("light_lux", 15.0),
+    ("uv_index", 0.5),
+];
+
+/// Channel, for channel sensors, and normalized measurement name a reading
+/// is compared by
+pub type Key = (Option<u8>, String);
+
+fn default_interval() -> u64 {
+    DEFAULT_INTERVAL
+}
+
+/// Topic comparisons with the gateway are published to
+pub fn gateway_topic() -> String {
+    format!("{}/gateway", crate_name!())
+}
+
+/// An Ecowitt gateway the readings are compared against
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GatewayConfig {
+    /// Address of the gateway, as `host` or `host:port`
+    pub address: String,
+    /// Seconds between comparisons
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// Sensors compared with the gateway's outdoor readings, as
+    /// [`SensorPattern`]s; all of those without a channel when empty
+    #[serde(default)]
+    pub sensors: Vec<String>,
+    /// Sensors compared with each Ecowitt channel's temperature and
+    /// humidity, from 1 to 8, as [`SensorPattern`]s
+    #[serde(default)]
+    pub channels: BTreeMap<u8, String>,
+    /// Differences tolerated, by normalized measurement name, over the
+    /// defaults
+    #[serde(default)]
+    pub tolerances: BTreeMap<String, f64>,
+}
+
+impl GatewayConfig {
+    fn tolerance(&self, measurement: &str) -> f64 {
+        self.tolerances
+            .get(measurement)
+            .copied()
+            .unwrap_or_else(|| {
+                TOLERANCES
+                    .iter()
+                    .find(|(name, _)| *name == measurement)
+                    .map(|(_, tolerance)| *tolerance)
+                    .unwrap_or_default()
+            })
+    }
+}
+
+// Length of the value of each live data item, by id
+fn item_len(id: u8) -> Option<usize> {
+    let len = match id {
+        0x06 | 0x07 | 0x17 | 0x22..=0x29 | 0x58..=0x5b | 0x60 | 0x72..=0x7b => 1,
+        0x01..=0x05 | 0x08..=0x11 | 0x16 | 0x19..=0x21 | 0x2a | 0x4d..=0x53 => 2,
+        // Soil temperature and moisture alternate, from a temperature
+        0x2b..=0x4a if id & 1 == 1 => 2,
+        0x2b..=0x4a => 1,
+        0x63..=0x6a => 3,
+        0x12..=0x15 | 0x61 | 0x62 | 0x6c => 4,
+        0x18 => 6,
+        0x4c | 0x70 => 16,
+        _ => return None,
+    };
+    Some(len)
+}
+
+// The channel and normalized measurement an item is compared as, and its
+// value in the measurement's metric unit
+fn item(id: u8, value: &[u8]) -> Option<(Option<u8>, &'static str, f64)> {
+    let signed = || f64::from(i16::from_be_bytes([value[0], value[1]]));
+    let unsigned = || f64::from(u16::from_be_bytes([value[0], value[1]]));
+    let reading = match id {
+        0x02 => (None, "temperature_c", signed() / 10.0),
+        0x07 => (None, "humidity", f64::from(value[0])),
+        0x08 => (None, "pressure_hpa", unsigned() / 10.0),
+        0x0a => (None, "wind_dir_deg", unsigned()),
+        // In tenths of m/s
+        0x0b => (None, "wind_speed_km_h", unsigned() * 0.36),
+        0x0c => (None, "wind_gust_km_h", unsigned() * 0.36),
+        0x0e => (None, "rain_rate_mm_h", unsigned() / 10.0),
+        0x15 => {
+            let light = u32::from_be_bytes([value[0], value[1], value[2], value[3]]);
+            (None, "light_lux", f64::from(light) / 10.0)
+        }
+        0x17 => (None, "uv_index", f64::from(value[0])),
+        0x1a..=0x21 => (Some(id - 0x19), "temperature_c", signed() / 10.0),
+        0x22..=0x29 => (Some(id - 0x21), "humidity", f64::from(value[0])),
+        _ => return None,
+    };
+    Some(reading)
+}
+
+/// Decodes the data of a live data response into the readings compared, by
+/// channel and normalized measurement. Decoding stops at the first item it
+/// doesn't know the length of, which gateways place after the readings
+/// compared.
+pub fn decode(data: &[u8]) -> BTreeMap<Key, f64> {
+    let mut readings = BTreeMap::new();
+    let mut i = 0;
+    while i < data.len() {
+        let id = data[i];
+        let len = match item_len(id) {
+            Some(len) if i + 1 + len <= data.len() => len,
+            _ => {
+                log::debug!("Stopped decoding gateway live data at item {:#04x}", id);
+                break;
+            }
+        };
+        if let Some((channel, measurement, value)) = item(id, &data[i + 1..i + 1 + len]) {
+            let value = (value * 1000.0).round() / 1000.0;
+            readings.insert((channel, measurement.to_owned()), value);
+        }
+        i += 1 + len;
+    }
+    readings
+}
+
+/// Reads the live data from the gateway at `address`, returning the data of
+/// the response
+pub fn live_data(address: &str) -> Result<Vec<u8>> {
+    let timeout = Duration::from_secs(TIMEOUT_SECS);
+    let address = match address.contains(':') {
+        true => address.to_owned(),
+        false => format!("{}:{}", address, API_PORT),
+    };
+    let socket = address
+        .to_socket_addrs()
+        .with_context(|| format!("Unable to resolve {}", address))?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("No address for {}", address))?;
+    let mut stream = TcpStream::connect_timeout(&socket, timeout)
+        .with_context(|| format!("Unable to connect to {}", address))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    // The size counts the command, itself and the checksum
+    stream.write_all(&[0xff, 0xff, CMD_LIVEDATA, 3, CMD_LIVEDATA + 3])?;
+    let mut header = [0u8; 5];
+    stream.read_exact(&mut header)?;
+    if header[..3] != [0xff, 0xff, CMD_LIVEDATA] {
+        anyhow::bail!("Unexpected response from {}: {:02x?}", address, header);
+    }
+    let size = usize::from(u16::from_be_bytes([header[3], header[4]]));
+    let mut rest = vec![0u8; size.saturating_sub(3)];
+    stream.read_exact(&mut rest)?;
+    let checksum = rest.pop().unwrap_or_default();
+    let sum = header[2..]
+        .iter()
+        .chain(&rest)
+        .fold(0u8, |sum, b| sum.wrapping_add(*b));
+    if sum != checksum {
+        anyhow::bail!("Bad checksum in response from {}", address);
+    }
+    Ok(rest)
+}
+
+/// A reading compared with the gateway's
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Comparison {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+    /// Normalized name of the measurement
+    pub measurement: String,
+    /// Sensor the radio's reading is from
+    pub sensor: String,
+    pub radio: f64,
+    pub gateway: f64,
+    /// The radio's reading less the gateway's
+    pub difference: f64,
+    /// Mean difference over every comparison of the measurement since the
+    /// bridge started
+    pub mean_difference: f64,
+    pub samples: u64,
+    pub within_tolerance: bool,
+}
+
+/// The comparisons made with a poll of the gateway, as published
+#[derive(Clone, Debug, Serialize)]
+pub struct GatewayReport {
+    pub time: DateTime<Local>,
+    pub comparisons: Vec<Comparison>,
+}
+
+/// The radio's latest readings, and the differences from the gateway's seen
+/// so far
+#[derive(Debug)]
+pub struct Comparer {
+    conf: GatewayConfig,
+    sensors: Vec<SensorPattern>,
+    channels: Vec<(u8, SensorPattern)>,
+    // Latest readings by channel and measurement, with when they were taken
+    // and the sensor taking them
+    latest: BTreeMap<Key, (DateTime<Local>, String, f64)>,
+    // Sum and count of the differences by channel and measurement
+    differences: BTreeMap<Key, (f64, u64)>,
+    discrepant: HashSet<Key>,
+}
+
+impl Comparer {
+    pub fn new(conf: GatewayConfig) -> Result<Self, ConfigError> {
+        let sensors = conf
+            .sensors
+            .iter()
+            .map(|pattern| pattern.parse())
+            .collect::<Result<Vec<SensorPattern>, ConfigError>>()?;
+        let channels = conf
+            .channels
+            .iter()
+            .map(|(channel, pattern)| match channel {
+                1..=8 => Ok((*channel, pattern.parse()?)),
+                _ => Err(ConfigError::EcowittChannel(*channel)),
+            })
+            .collect::<Result<Vec<(u8, SensorPattern)>, ConfigError>>()?;
+        Ok(Comparer {
+            conf,
+            sensors,
+            channels,
+            latest: BTreeMap::new(),
+            differences: BTreeMap::new(),
+            discrepant: HashSet::new(),
+        })
+    }
+
+    /// Takes the readings of a record from a sensor mapped to the gateway
+    pub fn observe(&mut self, record: &Record) {
+        let channel = self
+            .channels
+            .iter()
+            .find(|(_, pattern)| pattern.matches(&record.sensor_id))
+            .map(|(channel, _)| *channel);
+        let outdoor = match channel {
+            Some(_) => false,
+            None if self.sensors.is_empty() => true,
+            None => self.sensors.iter().any(|s| s.matches(&record.sensor_id)),
+        };
+        if channel.is_none() && !outdoor {
+            return;
+        }
+        for measurement in &record.measurements {
+            let name = match crate::normalize::key(measurement) {
+                Some(name) => name,
+                None => continue,
+            };
+            if let Some(value) = measurement.numeric_in(Some(UnitSystem::Metric)) {
+                // As rounded in normalized payloads
+                let value = (value * 1000.0).round() / 1000.0;
+                self.latest.insert(
+                    (channel, name),
+                    (record.timestamp, record.sensor_id.clone(), value),
+                );
+            }
+        }
+    }
+
+    /// Compares the gateway's readings with the radio's taken since `since`
+    pub fn compare(
+        &mut self,
+        gateway: &BTreeMap<Key, f64>,
+        since: DateTime<Local>,
+    ) -> Vec<Comparison> {
+        let mut comparisons = Vec::new();
+        for (key, gateway) in gateway {
+            let (sensor, radio) = match self.latest.get(key) {
+                Some((time, sensor, radio)) if *time >= since => (sensor.clone(), *radio),
+                _ => continue,
+            };
+            let (channel, measurement) = key;
+            let mut difference = radio - gateway;
+            if measurement == "wind_dir_deg" {
+                difference = (difference + 540.0).rem_euclid(360.0) - 180.0;
+            }
+            // Adding zero turns a -0 into 0
+            let difference = (difference * 1000.0).round() / 1000.0 + 0.0;
+            let (sum, samples) = self.differences.entry(key.clone()).or_default();
+            *sum += difference;
+            *samples += 1;
+            let within_tolerance = difference.abs() <= self.conf.tolerance(measurement) + 1e-9;
+            let described = match channel {
+                Some(channel) => format!("{} of channel {}", measurement, channel),
+                None => measurement.clone(),
+            };
+            match (within_tolerance, self.discrepant.contains(key)) {
+                (false, false) => {
+                    log::warn!(
+                        "[{}] {} differs from the gateway's by {} ({} here, {} there)",
+                        sensor,
+                        described,
+                        difference,
+                        radio,
+                        gateway
+                    );
+                    self.discrepant.insert(key.clone());
+                }
+                (true, true) => {
+                    log::info!("[{}] {} agrees with the gateway's again", sensor, described);
+                    self.discrepant.remove(key);
+                }
+                _ => {}
+            }
+            comparisons.push(Comparison {
+                channel: *channel,
+                measurement: measurement.clone(),
+                sensor,
+                radio,
+                gateway: *gateway,
+                difference,
+                mean_difference: (*sum / *samples as f64 * 1000.0).round() / 1000.0,
+                samples: *samples,
+                within_tolerance,
+            });
+        }
+        comparisons
+    }
+}
+
+// Polls the gateway and publishes the comparisons with the radio's readings
+// taken since `since`
+fn poll(
+    comparer: &mut Comparer,
+    sink: Option<&MqttSink>,
+    since: DateTime<Local>,
+    errors: &EventBus,
+) {
+    let address = comparer.conf.address.clone();
+    let gateway = match live_data(&address) {
+        Ok(data) => decode(&data),
+        Err(e) => {
+            log::error!(
+                "Failed to read the live data of gateway {}: {:#}",
+                address,
+                e
+            );
+            errors.publish(Event::Error(
+                ErrorEvent::new(ErrorCode::Gateway, format!("{:#}", e))
+                    .context("gateway", &address),
+            ));
+            return;
+        }
+    };
+    let report = GatewayReport {
+        time: crate::clock::now(),
+        comparisons: comparer.compare(&gateway, since),
+    };
+    log::debug!(
+        "Compared {} readings with gateway {}",
+        report.comparisons.len(),
+        address
+    );
+    let sink = match sink {
+        Some(sink) if !report.comparisons.is_empty() => sink,
+        _ => return,
+    };
+    let topic = gateway_topic();
+    let payload = match serde_json::to_vec(&report) {
+        Ok(payload) => payload,
+        Err(e) => {
+            log::error!("Failed to serialize gateway comparison: {:?}", e);
+            return;
+        }
+    };
+    match sink.publish(&topic, payload) {
+        Ok(()) => log::debug!("mqtt <== {}({:?})", topic, report),
+        Err(e) => log::error!("Failed to publish gateway comparison: {:#}", e),
+    }
+}
+
+/// Starts a background thread comparing the readings in the records on
+/// `events` with the gateway's every interval, until the radio stops,
+/// publishing the comparisons to `sink` if there is one and reporting
+/// failures to read the gateway on `errors`
+pub fn spawn_comparer(
+    conf: GatewayConfig,
+    sink: Option<MqttSink>,
+    events: Receiver<Event>,
+    errors: EventBus,
+) -> Result<JoinHandle<()>, ConfigError> {
+    let interval = Duration::from_secs(conf.interval.max(1));
+    let mut comparer = Comparer::new(conf)?;
+    Ok(std::thread::spawn(move || {
+        let mut started = Instant::now();
+        loop {
+            match events.recv_timeout(interval.saturating_sub(started.elapsed())) {
+                Ok(Event::Record(record)) => comparer.observe(&record),
+                Ok(Event::RadioStopped) | Err(RecvTimeoutError::Disconnected) => return,
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            }
+            if started.elapsed() < interval {
+                continue;
+            }
+            started = Instant::now();
+            // rtl_433 only gives the time to the second
+            let since = crate::clock::now()
+                - chrono::Duration::from_std(interval).unwrap_or_default()
+                - chrono::Duration::seconds(1);
+            poll(&mut comparer, sink.as_ref(), since, &errors);
+        }
+    }))
+}
@@ -0,0 +1,164 @@
+//! A fallback parser for devices of any model, mapping the fields rtl_433's
+//! decoders commonly share into measurements
+//!
+//! It makes what it can of records the dedicated parsers turn down, e.g.
+//! those identified by a string id rather than a number, or stamped in
+//! another of rtl_433's time formats (`-M time:iso`, `-M time:unix`), or not
+//! at all, and is tried last, losing to any other parser that accepts the
+//! record. Since it accepts anything with a model, it's only enabled with
+//! `generic_parser` in the configuration file or `--generic-parser`.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+use chrono::{Local, TimeZone};
+use thiserror::Error;
+
+use uom::si::{f32::Length, length};
+use uom::si::{f32::Pressure, pressure};
+use uom::si::{f32::ThermodynamicTemperature, thermodynamic_temperature};
+use uom::si::{u16::Velocity, velocity};
+
+use crate::radio::{Measurement, Record};
+
+#[derive(Error, Debug)]
+pub enum GenericError {
+    #[error("Generic parser not enabled")]
+    Disabled,
+    #[error("Record root not dictionary")]
+    NotDictionary,
+    #[error("Record missing model")]
+    MissingModel,
+    #[error("Record missing sensor id")]
+    MissingSensorId,
+    #[error("Unrecognized record timestamp '{0}'")]
+    TimestampFormat(String),
+}
+
+// Whether the parser accepts records
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables or disables the parser
+pub fn enable(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether the parser is enabled
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// Formats of rtl_433's `time` field, other than a unix timestamp
+const TIME_FORMATS: &[&str] = &[
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S%.f",
+];
+
+// When a record was received, by its `time` field, or now without one
+fn timestamp(time: Option<&serde_json::Value>) -> Result<chrono::DateTime<Local>> {
+    let time = match time {
+        Some(serde_json::Value::String(time)) => time,
+        Some(serde_json::Value::Number(seconds)) => {
+            return seconds
+                .as_f64()
+                .and_then(|seconds| Local.timestamp_opt(seconds as i64, 0).single())
+                .ok_or_else(|| GenericError::TimestampFormat(seconds.to_string()).into())
+        }
+        _ => return Ok(crate::clock::now()),
+    };
+    if let Ok(seconds) = time.parse::<f64>() {
+        return timestamp(Some(&serde_json::json!(seconds)));
+    }
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(time) {
+        return Ok(time.with_timezone(&Local));
+    }
+    TIME_FORMATS
+        .iter()
+        .find_map(|format| chrono::NaiveDateTime::parse_from_str(time, format).ok())
+        .and_then(|time| Local.from_local_datetime(&time).earliest())
+        .ok_or_else(|| GenericError::TimestampFormat(time.clone()).into())
+}
+
+// A number field as an f32
+fn number(m: &serde_json::Map<String, serde_json::Value>, field: &str) -> Option<f32> {
+    m.get(field)
+        .and_then(|value| value.as_f64())
+        .map(|value| value as f32)
+}
+
+/// Parses a json record from rtl_433 of any model, if the parser is enabled
+pub fn try_parse(json: &serde_json::Value) -> Result<Record> {
+    if !is_enabled() {
+        return Err(GenericError::Disabled.into());
+    }
+    let m = match json {
+        serde_json::Value::Object(m) => m,
+        _ => return Err(GenericError::NotDictionary.into()),
+    };
+    let model = m
+        .get("model")
+        .and_then(|model| model.as_str())
+        .ok_or(GenericError::MissingModel)?;
+    // Ids are numbers or strings, depending on the decoder
+    let id = match m.get("id") {
+        Some(serde_json::Value::Number(id)) => Some(id.to_string()),
+        Some(serde_json::Value::String(id)) if !id.is_empty() => Some(id.clone()),
+        _ => None,
+    };
+    let channel = m.get("channel").and_then(|channel| channel.as_u64());
+    let sensor_id = match (id, channel) {
+        (_, Some(channel)) => format!("{}/{}", model, channel),
+        (Some(id), None) => format!("{}/{}", model, id),
+        (None, None) => return Err(GenericError::MissingSensorId.into()),
+    };
+    let timestamp = timestamp(m.get("time"))?;
+
+    let mut measurements = Vec::new();
+    if let Some(battery) = number(m, "battery_ok") {
+        measurements.push(Measurement::BatteryOk(battery > 0.0));
+    }
+    if let Some(temp_c) = number(m, "temperature_C") {
+        measurements.push(Measurement::Temperature(ThermodynamicTemperature::new::<
+            thermodynamic_temperature::degree_celsius,
+        >(temp_c)));
+    } else if let Some(temp_f) = number(m, "temperature_F") {
+        measurements.push(Measurement::Temperature(ThermodynamicTemperature::new::<
+            thermodynamic_temperature::degree_fahrenheit,
+        >(temp_f)));
+    }
+    if let Some(humidity) = number(m, "humidity") {
+        measurements.push(Measurement::RelativeHumidity(
+            humidity.round().clamp(0.0, 255.0) as u8,
+        ));
+    }
+    for (unit, to_m_s) in [("m_s", 1.0), ("km_h", 1.0 / 3.6), ("mi_h", 0.44704)] {
+        if let Some(speed) = number(m, &format!("wind_avg_{}", unit)) {
+            let speed = (speed * to_m_s).round().max(0.0) as u16;
+            measurements.push(Measurement::WindSpeed(Velocity::new::<
+                velocity::meter_per_second,
+            >(speed)));
+            break;
+        }
+    }
+    if let Some(rain_mm) = number(m, "rain_mm") {
+        measurements.push(Measurement::Rainfall(Length::new::<length::millimeter>(
+            rain_mm,
+        )));
+    } else if let Some(rain_in) = number(m, "rain_in") {
+        measurements.push(Measurement::Rainfall(Length::new::<length::inch>(rain_in)));
+    }
+    if let Some(hpa) = number(m, "pressure_hPa") {
+        measurements.push(Measurement::Pressure(
+            Pressure::new::<pressure::hectopascal>(hpa),
+        ));
+    }
+    Ok(Record {
+        timestamp,
+        sensor_id,
+        record_json: json.clone(),
+        measurements,
+        radio: None,
+    })
+}
@@ -0,0 +1,30 @@
+//! Grace period after startup, while the bridge hears from its sensors again,
+//! during which sensors going quiet and implausible readings aren't alerted
+//! on
+
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug)]
+pub struct StartupGrace {
+    until: Instant,
+}
+
+impl StartupGrace {
+    /// A grace period lasting `period` from now
+    pub fn new(period: Duration) -> Self {
+        StartupGrace {
+            until: Instant::now() + period,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        Instant::now() < self.until
+    }
+}
+
+impl Default for StartupGrace {
+    /// No grace period at all
+    fn default() -> Self {
+        Self::new(Duration::ZERO)
+    }
+}
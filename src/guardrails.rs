@@ -0,0 +1,134 @@
+//! Limits on record size and cardinality, protecting downstream time series
+//! databases from the gadgets of noisy neighbors
+
+use std::collections::{BTreeMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::radio::Record;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum LimitError {
+    #[error("[{sensor_id}] Record of {size} bytes exceeds the {max} byte payload limit")]
+    PayloadSize {
+        sensor_id: String,
+        size: usize,
+        max: usize,
+    },
+    #[error("[{sensor_id}] New sensor exceeds the limit of {max} distinct sensors")]
+    Sensors { sensor_id: String, max: usize },
+    #[error("[{sensor_id}] New measurement '{name}' exceeds the limit of {max} distinct measurement names")]
+    MeasurementNames {
+        sensor_id: String,
+        name: String,
+        max: usize,
+    },
+}
+
+impl LimitError {
+    /// Short name of the limit that was exceeded, for counting drops
+    pub fn limit(&self) -> &'static str {
+        match self {
+            Self::PayloadSize { .. } => "payload_size",
+            Self::Sensors { .. } => "sensors",
+            Self::MeasurementNames { .. } => "measurement_names",
+        }
+    }
+}
+
+/// Limits beyond which records are dropped; each is unlimited when unset
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LimitsConfig {
+    /// Largest json record accepted, in bytes
+    #[serde(default)]
+    pub max_payload_bytes: Option<usize>,
+    /// Most distinct sensors accepted; records from sensors beyond the first
+    /// this many are dropped
+    #[serde(default)]
+    pub max_sensors: Option<usize>,
+    /// Most distinct measurement names accepted across all sensors
+    #[serde(default)]
+    pub max_measurement_names: Option<usize>,
+}
+
+/// Enforces [`LimitsConfig`], keeping count of the records dropped
+#[derive(Debug, Default)]
+pub struct Guardrails {
+    limits: LimitsConfig,
+    sensors: HashSet<String>,
+    names: HashSet<String>,
+    dropped: BTreeMap<&'static str, u64>,
+    reported: HashSet<(&'static str, String)>,
+}
+
+impl Guardrails {
+    pub fn new(limits: LimitsConfig) -> Self {
+        Guardrails {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// Checks a record against the limits, admitting its sensor and
+    /// measurement names if it is within them. Records that aren't are
+    /// counted, and reported once per sensor and limit.
+    pub fn check(&mut self, record: &Record) -> Result<(), LimitError> {
+        let result = self.admit(record);
+        if let Err(ref e) = result {
+            *self.dropped.entry(e.limit()).or_default() += 1;
+            if self.reported.insert((e.limit(), record.sensor_id.clone())) {
+                log::warn!("{}; dropping its records", e);
+            } else {
+                log::debug!("{}", e);
+            }
+        }
+        result
+    }
+
+    fn admit(&mut self, record: &Record) -> Result<(), LimitError> {
+        if let Some(max) = self.limits.max_payload_bytes {
+            let size = record.record_json.to_string().len();
+            if size > max {
+                return Err(LimitError::PayloadSize {
+                    sensor_id: record.sensor_id.clone(),
+                    size,
+                    max,
+                });
+            }
+        }
+        if let Some(max) = self.limits.max_sensors {
+            if !self.sensors.contains(&record.sensor_id) && self.sensors.len() >= max {
+                return Err(LimitError::Sensors {
+                    sensor_id: record.sensor_id.clone(),
+                    max,
+                });
+            }
+        }
+        let mut names: Vec<String> = record
+            .measurements
+            .iter()
+            .map(|m| m.name())
+            .filter(|name| !self.names.contains(name))
+            .collect();
+        names.sort();
+        names.dedup();
+        if let Some(max) = self.limits.max_measurement_names {
+            if let Some(name) = names.get(max.saturating_sub(self.names.len())) {
+                return Err(LimitError::MeasurementNames {
+                    sensor_id: record.sensor_id.clone(),
+                    name: name.clone(),
+                    max,
+                });
+            }
+        }
+        self.sensors.insert(record.sensor_id.clone());
+        self.names.extend(names);
+        Ok(())
+    }
+
+    /// Number of records dropped so far, by the limit they exceeded
+    pub fn dropped(&self) -> &BTreeMap<&'static str, u64> {
+        &self.dropped
+    }
+}
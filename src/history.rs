@@ -0,0 +1,288 @@
+//! Local SQLite store of every record received
+
+use std::sync::mpsc::Receiver;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+
+use crate::errors::{ErrorCode, ErrorEvent};
+use crate::events::{Event, EventBus};
+use crate::radio::Record;
+
+// How often old records are pruned, when a retention period is configured
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS records (
+        id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        sensor_id TEXT NOT NULL,
+        raw_json TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS records_sensor_time ON records (sensor_id, timestamp);
+    CREATE INDEX IF NOT EXISTS records_time ON records (timestamp);
+    CREATE TABLE IF NOT EXISTS measurements (
+        record_id INTEGER NOT NULL REFERENCES records (id) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        value REAL,
+        unit TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS measurements_record ON measurements (record_id);
+    CREATE TABLE IF NOT EXISTS events (
+        timestamp TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        detail TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS events_time ON events (timestamp);
+";
+
+/// Kinds of events stored alongside the records
+pub const BRIDGE_STARTED: &str = "bridge-started";
+pub const BRIDGE_STOPPED: &str = "bridge-stopped";
+
+/// Something that happened to the bridge, as stored in the history
+#[derive(Clone, Debug)]
+pub struct HistoryEvent {
+    pub time: DateTime<Local>,
+    /// [`BRIDGE_STARTED`], [`BRIDGE_STOPPED`], or the code of an error event
+    pub kind: String,
+    pub detail: String,
+}
+
+fn parse_timestamp(timestamp: &str) -> Result<DateTime<Local>> {
+    Ok(DateTime::parse_from_rfc3339(timestamp)
+        .with_context(|| format!("Invalid timestamp '{}' in history", timestamp))?
+        .with_timezone(&Local))
+}
+
+/// Writes every record to a SQLite database, creating its schema as needed,
+/// and optionally prunes records older than a retention period
+pub struct HistoryStore {
+    conn: rusqlite::Connection,
+    retention: Option<chrono::Duration>,
+    last_prune: Option<Instant>,
+}
+
+impl HistoryStore {
+    /// Opens (or creates) the database at `path`
+    pub fn open(path: &std::path::Path, retention_days: Option<u64>) -> Result<Self> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let conn = rusqlite::Connection::open(path)
+            .with_context(|| format!("Failed to open history database {}", path.display()))?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        conn.execute_batch(SCHEMA)
+            .with_context(|| format!("Failed to create schema in {}", path.display()))?;
+        let mut store = HistoryStore {
+            conn,
+            retention: retention_days.map(|days| chrono::Duration::days(days as i64)),
+            last_prune: None,
+        };
+        store.prune()?;
+        Ok(store)
+    }
+
+    /// Stores a record and all of its measurements
+    pub fn insert(&mut self, record: &Record) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO records (timestamp, sensor_id, raw_json) VALUES (?1, ?2, ?3)",
+            rusqlite::params![
+                record.timestamp.with_timezone(&chrono::Utc).to_rfc3339(),
+                record.sensor_id,
+                record.record_json.to_string()
+            ],
+        )?;
+        let record_id = tx.last_insert_rowid();
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO measurements (record_id, name, value, unit) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for measurement in &record.measurements {
+                stmt.execute(rusqlite::params![
+                    record_id,
+                    measurement.name(),
+                    measurement.numeric(),
+                    measurement.unit()
+                ])?;
+            }
+        }
+        tx.commit()?;
+
+        if self
+            .last_prune
+            .map(|t| t.elapsed() >= PRUNE_INTERVAL)
+            .unwrap_or(true)
+        {
+            self.prune()?;
+        }
+        Ok(())
+    }
+
+    /// Deletes records older than the retention period
+    pub fn prune(&mut self) -> Result<()> {
+        self.last_prune = Some(Instant::now());
+        let retention = match self.retention {
+            Some(retention) => retention,
+            None => return Ok(()),
+        };
+        let cutoff = (crate::clock::now_utc() - retention).to_rfc3339();
+        let pruned = self
+            .conn
+            .execute("DELETE FROM records WHERE timestamp < ?1", [&cutoff])?;
+        self.conn
+            .execute("DELETE FROM events WHERE timestamp < ?1", [&cutoff])?;
+        if pruned > 0 {
+            log::info!("Pruned {} records from history", pruned);
+        }
+        Ok(())
+    }
+
+    /// Stores something that happened to the bridge, e.g. the radio starting
+    /// or the broker failing, for troubleshooting gaps in the records
+    pub fn insert_event(&mut self, kind: &str, detail: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO events (timestamp, kind, detail) VALUES (?1, ?2, ?3)",
+            rusqlite::params![crate::clock::now_utc().to_rfc3339(), kind, detail],
+        )?;
+        Ok(())
+    }
+
+    /// When each sensor's records since `since` were received, by sensor and
+    /// then in order
+    pub fn timestamps(&self, since: DateTime<Local>) -> Result<Vec<(String, DateTime<Local>)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT sensor_id, timestamp FROM records WHERE timestamp >= ?1
+             ORDER BY sensor_id, timestamp",
+        )?;
+        let rows = stmt.query_map([since.with_timezone(&chrono::Utc).to_rfc3339()], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        let mut timestamps = Vec::new();
+        for row in rows {
+            let (sensor_id, timestamp) = row?;
+            timestamps.push((sensor_id, parse_timestamp(&timestamp)?));
+        }
+        Ok(timestamps)
+    }
+
+    /// The events stored since `since`, in order
+    pub fn events(&self, since: DateTime<Local>) -> Result<Vec<HistoryEvent>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT timestamp, kind, detail FROM events WHERE timestamp >= ?1 ORDER BY timestamp",
+        )?;
+        let rows = stmt.query_map([since.with_timezone(&chrono::Utc).to_rfc3339()], |row| {
+            Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        let mut events = Vec::new();
+        for row in rows {
+            let (timestamp, kind, detail) = row?;
+            events.push(HistoryEvent {
+                time: parse_timestamp(&timestamp)?,
+                kind,
+                detail,
+            });
+        }
+        Ok(events)
+    }
+
+    /// The most recent record stored for a sensor, as rtl_433 reported it
+    pub fn latest(&self, sensor_id: &str) -> Result<Option<serde_json::Value>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT raw_json FROM records WHERE sensor_id = ?1 ORDER BY timestamp DESC LIMIT 1",
+        )?;
+        let mut rows = stmt.query([sensor_id])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(serde_json::from_str(&row.get::<_, String>(0)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Names of every measurement stored for a sensor
+    pub fn measurement_names(&self, sensor_id: &str) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT DISTINCT m.name FROM measurements m JOIN records r ON m.record_id = r.id
+             WHERE r.sensor_id = ?1 ORDER BY m.name",
+        )?;
+        let names = stmt
+            .query_map([sensor_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(names)
+    }
+
+    /// Deletes a sensor's records, or only those older than `before`,
+    /// returning how many were deleted
+    pub fn purge(
+        &mut self,
+        sensor_id: &str,
+        before: Option<chrono::DateTime<chrono::Local>>,
+    ) -> Result<usize> {
+        let purged = match before {
+            Some(before) => self.conn.execute(
+                "DELETE FROM records WHERE sensor_id = ?1 AND timestamp < ?2",
+                rusqlite::params![sensor_id, before.with_timezone(&chrono::Utc).to_rfc3339()],
+            )?,
+            None => self
+                .conn
+                .execute("DELETE FROM records WHERE sensor_id = ?1", [sensor_id])?,
+        };
+        // Reclaim the space, so that the deleted records can't be recovered
+        // from the file
+        self.conn.execute_batch("VACUUM;")?;
+        Ok(purged)
+    }
+}
+
+// Stores an event, if it's one that explains gaps in the records
+fn store_event(store: &mut HistoryStore, event: &Event) -> Result<()> {
+    match event {
+        Event::RadioStarted => store.insert_event(BRIDGE_STARTED, ""),
+        Event::RadioStopped => store.insert_event(BRIDGE_STOPPED, ""),
+        Event::SinkError { sink, error } => {
+            store.insert_event("sink", &format!("{}: {}", sink, error))
+        }
+        Event::Error(e) => {
+            let code = serde_json::to_value(e.code)?;
+            store.insert_event(code.as_str().unwrap_or_default(), &e.message)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Starts a background thread storing every record published on the event
+/// bus, along with the radio starting and stopping and errors, until the
+/// radio stops, reporting failures on `errors`
+pub fn spawn_recorder(
+    mut store: HistoryStore,
+    events: Receiver<Event>,
+    errors: EventBus,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        for event in events {
+            match event {
+                // The history is most valuable when the sink isn't, so don't
+                // let its failures take the sink down with it
+                Event::Record(record) => {
+                    if let Err(e) = store.insert(&record) {
+                        log::error!("Failed to store record in history: {:?}", e);
+                        errors.publish(Event::Error(
+                            ErrorEvent::new(ErrorCode::History, format!("{:#}", e))
+                                .context("sensor_id", &record.sensor_id),
+                        ));
+                    }
+                }
+                event => {
+                    if let Err(e) = store_event(&mut store, &event) {
+                        log::error!("Failed to store event in history: {:?}", e);
+                    }
+                    if let Event::RadioStopped = event {
+                        break;
+                    }
+                }
+            }
+        }
+    })
+}
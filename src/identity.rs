@@ -0,0 +1,82 @@
+//! Stable identity of this installation
+//!
+//! A random id is generated on first run and kept in the state directory, so
+//! that the bridge is recognizable across restarts, and so that several
+//! bridges sharing a broker don't collide on their mqtt client ids.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::crate_name;
+use thiserror::Error;
+use uuid::Uuid;
+
+#[derive(Error, Debug)]
+pub enum IdentityError {
+    #[error("Invalid instance id in {path}: {reason}")]
+    Invalid { path: String, reason: String },
+}
+
+// MQTT 3.1 brokers only accept client ids of up to 23 characters
+const CLIENT_ID_LEN: usize = 23;
+
+/// Where the instance id is kept, under the user's state directory, or
+/// local data directory on platforms without one
+pub fn default_path() -> Option<PathBuf> {
+    state_path("instance_id")
+}
+
+/// Where a file of the bridge's state is kept, alongside the instance id
+pub fn state_path(name: &str) -> Option<PathBuf> {
+    dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .map(|dir| dir.join(crate_name!()).join(name))
+}
+
+/// Reads the instance id saved at `path`, generating and saving one if there
+/// is none yet. An id that can't be saved is still returned, but only lasts
+/// as long as the process.
+pub fn load_or_create(path: &Path) -> Result<Uuid> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => {
+            return Uuid::parse_str(contents.trim()).map_err(|e| {
+                IdentityError::Invalid {
+                    path: path.display().to_string(),
+                    reason: e.to_string(),
+                }
+                .into()
+            })
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Failed to read instance id from {}", path.display()))
+        }
+    }
+    let id = Uuid::new_v4();
+    if let Err(e) = save(path, id) {
+        log::warn!(
+            "Failed to save instance id to {}, it will change on restart: {:#}",
+            path.display(),
+            e
+        );
+    } else {
+        log::info!("Generated instance id {}", id);
+    }
+    Ok(id)
+}
+
+fn save(path: &Path, id: Uuid) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, format!("{}\n", id))?;
+    Ok(())
+}
+
+/// The mqtt client id for an instance, short enough for any broker
+pub fn client_id(id: Uuid) -> String {
+    let mut client_id = format!("{}-{}", crate_name!(), id.simple());
+    client_id.truncate(CLIENT_ID_LEN);
+    client_id
+}
@@ -6,7 +6,7 @@ use thiserror::Error;
 use uom::si::{energy, f32::Energy};
 
 #[derive(Error, Debug)]
-pub(crate) enum MeasurementError {
+pub enum MeasurementError {
     #[error("Record root not dictionary")]
     NotDictionary,
     #[error("Record missing timestamp")]
@@ -63,7 +63,8 @@ pub(crate) enum MeasurementError {
 //      "MeterType" : "Electric",
 //      "mic" : "CRC"
 // }
-pub(crate) fn try_parse(json: &serde_json::Value) -> Result<crate::radio::Record> {
+/// Parses a json record from rtl_433 as an IDM/NETIDM utility meter
+pub fn try_parse(json: &serde_json::Value) -> Result<crate::radio::Record> {
     if let serde_json::Value::Object(m) = json {
         let timestamp: chrono::DateTime<chrono::Local> =
             if let Some(serde_json::Value::String(time)) = m.get("time") {
@@ -104,6 +105,7 @@ pub(crate) fn try_parse(json: &serde_json::Value) -> Result<crate::radio::Record
             sensor_id,
             record_json: json.clone(),
             measurements,
+            radio: None,
         })
     } else {
         Err(MeasurementError::NotDictionary.into())
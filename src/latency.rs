@@ -0,0 +1,128 @@
+//! Latency from a sensor transmitting a record to its publish completing, for
+//! spotting a backed up pipeline or a slow broker before dashboards visibly
+//! lag
+//!
+//! Latency is measured from the record's timestamp, which rtl_433 only gives
+//! to the second, so it's only meaningful at that resolution.
+
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use clap::crate_name;
+use serde::Serialize;
+
+use crate::events::Event;
+use crate::sink::MqttSink;
+
+// Latencies kept between reports; a sink far enough behind to exceed this is
+// well past needing a precise answer
+const MAX_SAMPLES: usize = 10_000;
+
+/// Topic latency percentiles are published to
+pub fn telemetry_topic() -> String {
+    format!("{}/telemetry/latency", crate_name!())
+}
+
+/// Percentiles of the latencies recorded over a period, in milliseconds
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct LatencyReport {
+    pub count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p50_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p90_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p99_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_ms: Option<u64>,
+}
+
+impl LatencyReport {
+    fn new(mut samples: Vec<u64>) -> Self {
+        samples.sort_unstable();
+        let percentile = |p: usize| {
+            let rank = (samples.len() * p).div_ceil(100).max(1);
+            samples.get(rank - 1).copied()
+        };
+        LatencyReport {
+            count: samples.len(),
+            p50_ms: percentile(50),
+            p90_ms: percentile(90),
+            p99_ms: percentile(99),
+            max_ms: samples.last().copied(),
+        }
+    }
+}
+
+/// Collects the latencies of published records between reports. Cloning the
+/// tracker produces another handle to the same latencies.
+#[derive(Clone, Debug, Default)]
+pub struct LatencyTracker {
+    samples: Arc<Mutex<Vec<u64>>>,
+}
+
+impl LatencyTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the publish of a record received at `received` completing now
+    pub fn record(&self, received: DateTime<Local>) {
+        let latency = (crate::clock::now() - received).num_milliseconds().max(0) as u64;
+        let mut samples = self.samples.lock().expect("latencies poisoned");
+        if samples.len() < MAX_SAMPLES {
+            samples.push(latency);
+        }
+    }
+
+    /// Percentiles of the latencies recorded since the last report
+    pub fn peek(&self) -> LatencyReport {
+        LatencyReport::new(self.samples.lock().expect("latencies poisoned").clone())
+    }
+
+    /// Percentiles of the latencies recorded since the last report, starting
+    /// a new one
+    pub fn report(&self) -> LatencyReport {
+        LatencyReport::new(std::mem::take(
+            &mut *self.samples.lock().expect("latencies poisoned"),
+        ))
+    }
+}
+
+/// Starts a background thread that publishes the percentiles of the
+/// latencies of `sink` every `interval`, until the radio stops
+pub fn spawn_reporter(
+    sink: MqttSink,
+    events: Receiver<Event>,
+    interval: Duration,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut started = Instant::now();
+        loop {
+            match events.recv_timeout(interval.saturating_sub(started.elapsed())) {
+                Ok(Event::RadioStopped) | Err(RecvTimeoutError::Disconnected) => return,
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            }
+            if started.elapsed() < interval {
+                continue;
+            }
+            started = Instant::now();
+            let report = sink.latency().report();
+            let topic = telemetry_topic();
+            let payload = match serde_json::to_vec(&report) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    log::error!("Failed to serialize latency report: {:?}", e);
+                    continue;
+                }
+            };
+            match sink.publish(&topic, payload) {
+                Ok(()) => log::debug!("mqtt <== {}({:?})", topic, report),
+                Err(e) => log::error!("Failed to publish latency report: {:?}", e),
+            }
+        }
+    })
+}
@@ -0,0 +1,84 @@
+//! Decoders and record pipeline for bridging weather and utility sensor
+//! broadcasts, as received by [rtl_433](https://github.com/merbanan/rtl_433),
+//! to an mqtt broker.
+//!
+//! The `weatherradio` binary is a thin command line shell over this library:
+//! it builds a [`config::Config`], reads [`radio::Record`]s from a
+//! [`radio::Sensor`], and feeds them through a [`pipeline::Pipeline`]
+//! publishing to a [`sink::MqttSink`].
+
+pub mod acl;
+pub mod ambientweather;
+pub mod api;
+pub mod archive;
+pub mod arrow;
+pub mod audit;
+pub mod automation;
+pub mod availability;
+pub mod battery;
+pub mod burst;
+pub mod capture;
+pub mod chaos;
+pub mod clock;
+pub mod compat;
+pub mod config;
+pub mod control;
+pub mod counters;
+pub mod cwop;
+pub mod dedup;
+pub mod derive;
+pub mod diff;
+pub mod ecowitt;
+pub mod errors;
+pub mod events;
+pub mod faults;
+pub mod fineoffset;
+pub mod gaps;
+pub mod gateway;
+pub mod generic;
+pub mod grace;
+pub mod guardrails;
+pub mod history;
+pub mod identity;
+pub mod idm;
+pub mod latency;
+pub mod lifecycle;
+pub mod logformat;
+pub mod meta;
+pub mod mirror;
+pub mod normalize;
+pub mod pipeline;
+pub mod power;
+pub mod preflight;
+pub mod pressure;
+pub mod profiles;
+pub mod protocols;
+pub mod purge;
+pub mod pws;
+pub mod quality;
+pub mod query;
+pub mod quirks;
+pub mod radio;
+pub mod radiotime;
+pub mod rain;
+pub mod region;
+pub mod replay;
+#[cfg(feature = "rtlsdr")]
+pub mod rtlsdr;
+pub mod sampling;
+pub mod scm;
+pub mod setup;
+pub mod sink;
+pub mod stages;
+pub mod stats;
+pub mod summary;
+pub mod throttle;
+pub mod top;
+pub mod topics;
+pub mod trace;
+pub mod units;
+pub mod unparsed;
+pub mod update;
+pub mod usage;
+pub mod validate;
+pub mod webhook;
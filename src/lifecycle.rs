@@ -0,0 +1,340 @@
+//! Per-sensor lifecycle tracking, from first discovery through to being lost
+//! or replaced by a new sensor
+//!
+//! When given a state file, the tracker keeps when each sensor was last
+//! heard from in it, so that a sensor that dies while the bridge is down,
+//! or just before a restart, still goes stale and gets alerted on, rather
+//! than being forgotten.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::control::Maintenance;
+use crate::events::{Event, EventBus};
+use crate::grace::StartupGrace;
+use crate::radio::Record;
+use crate::sink::MqttSink;
+
+/// Where a sensor is in its lifecycle
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SensorState {
+    /// Heard from for the first time
+    Discovered,
+    /// Reporting regularly
+    Active,
+    /// Not heard from within the sensor timeout
+    Stale,
+    /// Not heard from within the lost timeout
+    Lost,
+    /// A new sensor of the same model has shown up in the same slot while
+    /// this one was silent, as happens when a battery swap rerolls its id
+    Replaced,
+}
+
+/// A sensor moving from one lifecycle state to another
+#[derive(Clone, Debug, Serialize)]
+pub struct Transition {
+    pub sensor_id: String,
+    /// The sensor's topic, which the transition is published under
+    #[serde(skip)]
+    pub topic: String,
+    pub previous: Option<SensorState>,
+    pub state: SensorState,
+    pub timestamp: DateTime<Local>,
+    /// The sensor that took over this one's slot, when it was replaced
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replaced_by: Option<String>,
+}
+
+impl Transition {
+    /// Retained topic the transition is published to
+    pub fn lifecycle_topic(&self) -> String {
+        format!("{}/lifecycle", self.topic)
+    }
+
+    /// Publishes the transition to `sink`, if there is one, and on the event
+    /// bus
+    pub fn publish(self, sink: Option<&MqttSink>, events: &EventBus) -> anyhow::Result<()> {
+        let level = match self.state {
+            SensorState::Stale | SensorState::Lost => log::Level::Warn,
+            _ => log::Level::Info,
+        };
+        log::log!(
+            level,
+            "Sensor {} is now {:?} (was {:?})",
+            self.sensor_id,
+            self.state,
+            self.previous
+        );
+        let result = match sink {
+            Some(sink) => {
+                sink.publish_retained(&self.lifecycle_topic(), serde_json::to_vec(&self)?, 1)
+            }
+            None => Ok(()),
+        };
+        events.publish(Event::SensorLifecycle(self));
+        result
+    }
+}
+
+// Sensors that report the same model and channel from the same location are
+// assumed to be the same physical sensor, even if their ids differ
+type Slot = (Option<String>, Option<String>, Option<String>);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SensorEntry {
+    topic: String,
+    slot: Slot,
+    state: SensorState,
+    last_seen: DateTime<Local>,
+}
+
+fn load(path: &Path) -> HashMap<String, SensorEntry> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return HashMap::new(),
+        Err(e) => {
+            log::warn!(
+                "Failed to read sensor state from {}: {:?}",
+                path.display(),
+                e
+            );
+            return HashMap::new();
+        }
+    };
+    match serde_json::from_slice::<HashMap<String, SensorEntry>>(&contents) {
+        // Replaced sensors are gone for good
+        Ok(mut sensors) => {
+            sensors.retain(|_, entry| entry.state != SensorState::Replaced);
+            sensors
+        }
+        Err(e) => {
+            log::warn!(
+                "Ignoring unreadable sensor state in {}: {:?}",
+                path.display(),
+                e
+            );
+            HashMap::new()
+        }
+    }
+}
+
+/// Tracks the lifecycle state of every sensor heard from, moving them along
+/// as records arrive and as timers run out
+#[derive(Clone, Debug)]
+pub struct LifecycleTracker {
+    sensors: Arc<Mutex<HashMap<String, SensorEntry>>>,
+    stale_timeout: Duration,
+    lost_timeout: Duration,
+    path: Option<PathBuf>,
+}
+
+impl LifecycleTracker {
+    pub fn new(stale_timeout: Duration, lost_timeout: Duration) -> Self {
+        LifecycleTracker {
+            sensors: Arc::new(Mutex::new(HashMap::new())),
+            stale_timeout,
+            lost_timeout: lost_timeout.max(stale_timeout),
+            path: None,
+        }
+    }
+
+    /// Keeps the sensors' states in the state file at `path`, taking up
+    /// those of the sensors heard from before the last restart
+    pub fn state_file(mut self, path: PathBuf) -> Self {
+        let restored = load(&path);
+        if !restored.is_empty() {
+            log::debug!("Restored the state of {} sensors", restored.len());
+        }
+        *self.sensors.lock().expect("lifecycle state poisoned") = restored;
+        self.path = Some(path);
+        self
+    }
+
+    /// The sensors restored from the state file that were still being heard
+    /// from, and their topics and when they last were
+    pub fn active(&self) -> Vec<(String, String, DateTime<Local>)> {
+        let sensors = self.sensors.lock().expect("lifecycle state poisoned");
+        sensors
+            .iter()
+            .filter(|(_, e)| matches!(e.state, SensorState::Discovered | SensorState::Active))
+            .map(|(sensor_id, e)| (sensor_id.clone(), e.topic.clone(), e.last_seen))
+            .collect()
+    }
+
+    /// Every sensor tracked, with its topic, state and when it was last
+    /// heard from, by sensor id
+    pub fn sensors(&self) -> Vec<(String, String, SensorState, DateTime<Local>)> {
+        let sensors = self.sensors.lock().expect("lifecycle state poisoned");
+        let mut sensors: Vec<_> = sensors
+            .iter()
+            .map(|(sensor_id, e)| (sensor_id.clone(), e.topic.clone(), e.state, e.last_seen))
+            .collect();
+        sensors.sort_by(|a, b| a.0.cmp(&b.0));
+        sensors
+    }
+
+    /// Saves the sensors' states to the state file, if there is one
+    pub fn save(&self) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+        let result = {
+            let sensors = self.sensors.lock().expect("lifecycle state poisoned");
+            serde_json::to_vec(&*sensors)
+        };
+        let result = path
+            .parent()
+            .map(std::fs::create_dir_all)
+            .transpose()
+            .and_then(|_| std::fs::write(path, result?));
+        if let Err(e) = result {
+            log::warn!("Failed to save sensor state to {}: {:?}", path.display(), e);
+        }
+    }
+
+    /// Records that a sensor was heard from, returning the transitions that
+    /// caused
+    pub fn observe(&self, record: &Record, topic: &str, location: Option<&str>) -> Vec<Transition> {
+        let field = |name: &str| match record.record_json.get(name) {
+            Some(serde_json::Value::String(s)) => Some(s.clone()),
+            Some(serde_json::Value::Number(n)) => Some(n.to_string()),
+            _ => None,
+        };
+        let slot = (
+            field("model"),
+            field("channel"),
+            location.map(str::to_owned),
+        );
+        let transition = |sensor_id: &str, topic: &str, previous, state| Transition {
+            sensor_id: sensor_id.to_owned(),
+            topic: topic.to_owned(),
+            previous,
+            state,
+            timestamp: record.timestamp,
+            replaced_by: None,
+        };
+
+        let mut sensors = self.sensors.lock().expect("lifecycle state poisoned");
+        let mut transitions = Vec::new();
+        match sensors.get_mut(&record.sensor_id) {
+            Some(entry) => {
+                entry.last_seen = crate::clock::now();
+                if entry.state != SensorState::Active {
+                    transitions.push(transition(
+                        &record.sensor_id,
+                        &entry.topic,
+                        Some(entry.state),
+                        SensorState::Active,
+                    ));
+                    entry.state = SensorState::Active;
+                }
+            }
+            None => {
+                // A silent sensor in the same slot has most likely had its
+                // id changed by a battery swap
+                for (sensor_id, entry) in sensors.iter_mut() {
+                    let silent = matches!(entry.state, SensorState::Stale | SensorState::Lost);
+                    if silent && entry.slot == slot {
+                        let mut replaced = transition(
+                            sensor_id,
+                            &entry.topic,
+                            Some(entry.state),
+                            SensorState::Replaced,
+                        );
+                        replaced.replaced_by = Some(record.sensor_id.clone());
+                        transitions.push(replaced);
+                        entry.state = SensorState::Replaced;
+                    }
+                }
+                transitions.push(transition(
+                    &record.sensor_id,
+                    topic,
+                    None,
+                    SensorState::Discovered,
+                ));
+                sensors.insert(
+                    record.sensor_id.clone(),
+                    SensorEntry {
+                        topic: topic.to_owned(),
+                        slot,
+                        state: SensorState::Discovered,
+                        last_seen: crate::clock::now(),
+                    },
+                );
+            }
+        }
+        transitions
+    }
+
+    /// Moves sensors whose timers have run out to stale or lost, returning
+    /// the transitions that caused
+    fn expire(&self) -> Vec<Transition> {
+        let mut sensors = self.sensors.lock().expect("lifecycle state poisoned");
+        let mut transitions = Vec::new();
+        let now = crate::clock::now();
+        for (sensor_id, entry) in sensors.iter_mut() {
+            let silence = (now - entry.last_seen).to_std().unwrap_or_default();
+            let state = match entry.state {
+                SensorState::Discovered | SensorState::Active if silence > self.stale_timeout => {
+                    SensorState::Stale
+                }
+                SensorState::Stale if silence > self.lost_timeout => SensorState::Lost,
+                _ => continue,
+            };
+            transitions.push(Transition {
+                sensor_id: sensor_id.clone(),
+                topic: entry.topic.clone(),
+                previous: Some(entry.state),
+                state,
+                timestamp: now,
+                replaced_by: None,
+            });
+            entry.state = state;
+        }
+        transitions
+    }
+
+    /// Starts a background thread that periodically moves silent sensors to
+    /// stale or lost, and publishes the transitions, once the startup grace
+    /// period is over
+    pub fn spawn_timer(
+        &self,
+        sink: Option<MqttSink>,
+        events: EventBus,
+        maintenance: Maintenance,
+        grace: StartupGrace,
+    ) {
+        let tracker = self.clone();
+        let period = std::cmp::min(tracker.stale_timeout, Duration::from_secs(30));
+        std::thread::spawn(move || loop {
+            std::thread::sleep(period);
+            tracker.save();
+            if grace.is_active() {
+                continue;
+            }
+            for transition in tracker.expire() {
+                let sensor_id = transition.sensor_id.clone();
+                // Sensors in maintenance are expected to go quiet, so their
+                // transitions aren't announced to the sink
+                let target = sink.as_ref().filter(|_| !maintenance.is_active(&sensor_id));
+                if let Err(e) = transition.publish(target, &events) {
+                    log::error!("Failed to publish lifecycle for {}: {:?}", sensor_id, e);
+                    if let Some(ref sink) = sink {
+                        events.publish(Event::SinkError {
+                            sink: sink.broker().to_owned(),
+                            error: format!("{:#}", e),
+                        });
+                    }
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,107 @@
+//! Layouts of the log lines, for reading by people or by log collectors
+//!
+//! The `json` layout writes each line as a json object, with the time in
+//! RFC 3339, the level, the module logging it and the message, so that a
+//! collector like Loki or journald's json export can index them without
+//! parsing text. Lines about a record carry its sensor id as a `sensor_id`
+//! field, and lines about a publish the topic published to as a `topic`
+//! field, taken from the line's key-values where it's logged with them, and
+//! otherwise from the message: the sensor id lines about a record are
+//! prefixed with in brackets, and the topic of `mqtt <== <topic>(...)`.
+
+use serde::{Deserialize, Serialize};
+
+/// Layout of each log line
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Plain text, for reading on a terminal
+    #[default]
+    Text,
+    /// A json object per line, for log collectors
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = crate::config::ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(crate::config::ConfigError::LogFormat(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct LogLine<'a> {
+    timestamp: String,
+    level: &'a str,
+    module: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sensor_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+}
+
+// Collects the fields logged as key-values
+#[derive(Default)]
+struct Fields {
+    sensor_id: Option<String>,
+    topic: Option<String>,
+}
+
+impl<'kvs> log::kv::VisitSource<'kvs> for Fields {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        match key.as_str() {
+            "sensor_id" => self.sensor_id = Some(value.to_string()),
+            "topic" => self.topic = Some(value.to_string()),
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+// The sensor id a line about a record starts with, as `[<sensor id>] ...`
+fn sensor_id(message: &str) -> Option<&str> {
+    let (sensor_id, _) = message.strip_prefix('[')?.split_once("] ")?;
+    Some(sensor_id).filter(|sensor_id| !sensor_id.is_empty())
+}
+
+// The topic of a line about a publish, as `mqtt <== <topic>(<payload>)` or
+// `[<sensor id>] Publishing <topic>(<payload>)`
+fn topic(message: &str) -> Option<&str> {
+    let (_, rest) = message
+        .split_once(" <== ")
+        .or_else(|| message.split_once("] Publishing "))?;
+    let topic = rest.split(['(', ' ']).next()?;
+    Some(topic).filter(|topic| !topic.is_empty())
+}
+
+/// Writes a log line as a json object, for [`flexi_logger::Logger::format`]
+pub fn json_format(
+    w: &mut dyn std::io::Write,
+    now: &mut flexi_logger::DeferredNow,
+    record: &log::Record,
+) -> Result<(), std::io::Error> {
+    let message = record.args().to_string();
+    let mut fields = Fields::default();
+    record.key_values().visit(&mut fields).ok();
+    let line = LogLine {
+        timestamp: now.format_rfc3339(),
+        level: record.level().as_str(),
+        module: record.module_path().unwrap_or("<unnamed>"),
+        message: &message,
+        sensor_id: fields
+            .sensor_id
+            .or_else(|| sensor_id(&message).map(str::to_owned)),
+        topic: fields.topic.or_else(|| topic(&message).map(str::to_owned)),
+    };
+    serde_json::to_writer(&mut *w, &line).map_err(std::io::Error::from)
+}
@@ -6,15 +6,52 @@ use clap::{app_from_crate, crate_name, crate_version};
 use flexi_logger::{default_format, detailed_format, Logger};
 use thiserror::Error;
 
-mod ambientweather;
-mod config;
-mod idm;
-mod radio;
+#[cfg(feature = "raw-decoders")]
+use weatherradio::fineoffset;
+use weatherradio::{
+    acl, capture, clock, config, diff, events, gaps, generic, identity, lifecycle, logformat,
+    pipeline, preflight, purge, query, radio, replay, setup, sink, stages, top, trace, units,
+    update, usage,
+};
 
 #[derive(Error, Debug)]
 pub(crate) enum AppError {
     #[error("Application configuration directory not found")]
     AppDirNotFound,
+    #[error("State directory not found, see sensor_state in the configuration file")]
+    StateDirNotFound,
+    #[cfg(not(feature = "raw-decoders"))]
+    #[error(
+        "No such file '{0}', and raw packets can only be decoded with the raw-decoders feature"
+    )]
+    RawDecodersUnsupported(String),
+    #[error("Not a record of a supported device")]
+    UnsupportedRecord,
+}
+
+/// Decodes an rtl_433 json record, or a raw Fine Offset packet in hex
+fn decode_line(line: &str) -> Result<radio::Record> {
+    let line = line.trim();
+    if line.starts_with('{') {
+        let json: serde_json::Value =
+            serde_json::from_str(line).with_context(|| "Record is not valid json")?;
+        return radio::decode(&json).ok_or_else(|| AppError::UnsupportedRecord.into());
+    }
+    #[cfg(feature = "raw-decoders")]
+    return fineoffset::decode(line, clock::now());
+    #[cfg(not(feature = "raw-decoders"))]
+    Err(AppError::RawDecodersUnsupported(line.to_owned()).into())
+}
+
+/// Prints a decoded record's measurements, and optionally its rtl_433 json
+fn print_record(record: &radio::Record, json: bool) {
+    println!("{} {}", record.timestamp.to_rfc3339(), record.sensor_id);
+    if json {
+        println!("  {}", record.record_json);
+    }
+    for measurement in &record.measurements {
+        println!("  {}", measurement);
+    }
 }
 
 fn main() -> Result<()> {
@@ -26,7 +63,7 @@ fn main() -> Result<()> {
 
     let gen_cfg_help = format!("Generates a json-formatted configuration file at {}, populated by the current invocation arguments, and defaults where arguments were omitted, and then exits the program", json_config_path.display());
 
-    let matches = app_from_crate!("")
+    let app = app_from_crate!("")
         .arg(
             clap::Arg::new("quiet")
                 .short('q')
@@ -43,6 +80,14 @@ fn main() -> Result<()> {
                 .global(true)
                 .help("Enable debug-level output"),
         )
+        .arg(
+            clap::Arg::new("receiver")
+                .long("receiver")
+                .takes_value(true)
+                .value_name("RECEIVER")
+                .possible_values(["rtl_433", "rtl-sdr"])
+                .help("Receive through rtl_433, or directly from an RTL-SDR dongle for Fine Offset sensors only (default rtl_433)"),
+        )
         .arg(
             clap::Arg::new("rtl_433_bin")
                 .short('r')
@@ -51,6 +96,25 @@ fn main() -> Result<()> {
                 .value_name("PROGRAM")
                 .help("Path to the rtl_433 binary"),
         )
+        .arg(
+            clap::Arg::new("region")
+                .long("region")
+                .takes_value(true)
+                .value_name("REGION")
+                .possible_values(["us915", "eu868", "au"])
+                .ignore_case(true)
+                .help("Where the sensors were sold, selecting the frequency and decoders listened with unless radios are given (default us915)"),
+        )
+        .arg(
+            clap::Arg::new("radio")
+                .long("radio")
+                .multiple_occurrences(true)
+                .takes_value(true)
+                .value_name("NAME=ARGS")
+                .help("Run an rtl_433 with these receiver arguments alongside any other radios, tagging its records with NAME, e.g. 'meters=-d 1 -f 912M -R 149', where -R also takes a decoder's name; can be repeated"),
+        );
+    #[cfg(feature = "mqtt")]
+    let app = app
         .arg(
             clap::Arg::new("mqtt_broker")
                 .short('b')
@@ -58,9 +122,16 @@ fn main() -> Result<()> {
                 .takes_value(true)
                 .value_name("BROKER")
                 .help(
-                    "Network identifier of the mqtt broker to publish to, e.g. 'localhost:1883'",
+                    "Network identifier of the mqtt broker to publish to, e.g. 'localhost:1883', or 'wss://proxy/mqtt' for WebSockets",
                 ),
         )
+        .arg(
+            clap::Arg::new("mqtt_ws_path")
+                .long("mqtt-ws-path")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Path of the broker's WebSocket endpoint, for ws:// and wss:// brokers given without one, e.g. 'mqtt'"),
+        )
         .arg(
             clap::Arg::new("mqtt_user")
                 .short('u')
@@ -70,17 +141,65 @@ fn main() -> Result<()> {
                 .help("Account user for connecting to the mqtt broker"),
         )
         .arg(
-            clap::Arg::new("mqtt_credentials_keyring")
-                .short('k')
-                .long("mqtt-credentials-keyring")
-                .help("mqtt broker account password stored on session keyring, prompt on startup if no password set"),
+            clap::Arg::new("mqtt_qos")
+                .long("mqtt-qos")
+                .takes_value(true)
+                .value_name("QOS")
+                .possible_values(["0", "1", "2"])
+                .help("Quality of service to publish records with: 0 at most once, 1 at least once, 2 exactly once (default 2)"),
+        )
+        .arg(
+            clap::Arg::new("mqtt_clean_session")
+                .long("mqtt-clean-session")
+                .takes_value(true)
+                .value_name("BOOL")
+                .possible_values(["true", "false"])
+                .help("Whether the broker discards the session on connecting, rather than keeping it across restarts (default true)"),
+        )
+        .arg(
+            clap::Arg::new("mqtt_keep_alive")
+                .long("mqtt-keep-alive")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("Interval between keep-alive pings to the broker (default 20)"),
+        )
+        .arg(
+            clap::Arg::new("mqtt_protocol_version")
+                .long("mqtt-protocol-version")
+                .takes_value(true)
+                .value_name("VERSION")
+                .possible_values(["4", "5"])
+                .help("Version of the mqtt protocol to connect with: 4 for MQTT 3.1.1, 5 for MQTT 5 (default 4)"),
+        )
+        .arg(
+            clap::Arg::new("mqtt_message_expiry")
+                .long("mqtt-message-expiry")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("Seconds the broker holds on to readings before discarding them as stale, with MQTT 5, or 0 for no expiry (default 3600)"),
+        )
+        .arg(
+            clap::Arg::new("mqtt_client_id")
+                .long("mqtt-client-id")
+                .takes_value(true)
+                .value_name("ID")
+                .help("Client id to connect to the broker with (default derived from the instance id)"),
         )
         .arg(
             clap::Arg::new("mqtt_credentials_config")
                 .short('f')
                 .long("mqtt-credentials-config")
                 .help("mqtt broker account password stored in config file, prompt on startup if no password set"),
-        )
+        );
+    #[cfg(all(feature = "mqtt", feature = "keyring"))]
+    let app = app
+        .arg(
+            clap::Arg::new("mqtt_credentials_keyring")
+                .short('k')
+                .long("mqtt-credentials-keyring")
+                .help("mqtt broker account password stored on session keyring (Credential Manager on Windows), prompt on startup if no password set"),
+        );
+    let app = app
         .arg(
             clap::Arg::new("ignore")
                 .short('i')
@@ -88,7 +207,370 @@ fn main() -> Result<()> {
                 .multiple_occurrences(true)
                 .takes_value(true)
                 .value_name("SENSOR_ID")
-                .help("Ignore the specified sensor topic; can be repeated"),
+                .help("Ignore the specified sensor topic, which may be a glob like 'Acurite-*', a prefix like 'Acurite-Tower/', or a regex like 're:^IDM/4\\d+$'; can be repeated"),
+        )
+        .arg(
+            clap::Arg::new("only")
+                .long("only")
+                .multiple_occurrences(true)
+                .takes_value(true)
+                .value_name("SENSOR_ID")
+                .help("Ignore every sensor but the specified one, which may be a glob, prefix, or regex as for --ignore; can be repeated"),
+        )
+        .arg(
+            clap::Arg::new("trace")
+                .long("trace")
+                .multiple_occurrences(true)
+                .takes_value(true)
+                .value_name("SENSOR_ID")
+                .help("Log the records of the specified sensor in detail, whatever the log level; may be a glob, prefix, or regex as for --ignore, matching sensor ids or decoder names, and can be repeated"),
+        )
+        .arg(
+            clap::Arg::new("sensor_timeout")
+                .long("sensor-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("Report a sensor as offline after this long without hearing from it (default 900)"),
+        )
+        .arg(
+            clap::Arg::new("sensor_lost_timeout")
+                .long("sensor-lost-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("Report a sensor as lost after this long without hearing from it (default 86400)"),
+        )
+        .arg(
+            clap::Arg::new("startup_grace")
+                .long("startup-grace")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("Hold back reports of quiet sensors and implausible readings for this long after startup (default 0)"),
+        )
+        .arg(
+            clap::Arg::new("log_summary")
+                .long("log-summary")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .default_missing_value("60")
+                .value_name("SECONDS")
+                .help("Log a summary every SECONDS (default 60) in place of a line per published record"),
+        )
+        .arg(
+            clap::Arg::new("generic_parser")
+                .long("generic-parser")
+                .help("Parse the temperature, humidity, wind, rain, pressure and battery fields of records of any model no dedicated parser accepts"),
+        )
+        .arg(
+            clap::Arg::new("publish_unparsed")
+                .long("publish-unparsed")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .value_name("TOPIC")
+                .help("Publish the records none of the parsers make sense of, at most one of each model a minute, to TOPIC (default weatherradio/unparsed)"),
+        )
+        .arg(
+            clap::Arg::new("log_format")
+                .long("log-format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(["text", "json"])
+                .help("Log plain text, or a json object per line for log collectors (default text)"),
+        )
+        .arg(
+            clap::Arg::new("telemetry")
+                .long("telemetry")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .default_missing_value("60")
+                .value_name("SECONDS")
+                .help("Publish telemetry, such as publish latency percentiles, every SECONDS (default 60)"),
+        )
+        .arg(
+            clap::Arg::new("dedup_window")
+                .long("dedup-window")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("Drop records identical to the sensor's previous one within this long (default 5)"),
+        )
+        .arg(
+            clap::Arg::new("validation_delay")
+                .long("validation-delay")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("Additionally republish records on a 'validated' topic after holding them this long for confirmation and outlier checks"),
+        )
+        .arg(
+            clap::Arg::new("validation_confirmations")
+                .long("validation-confirmations")
+                .takes_value(true)
+                .value_name("COUNT")
+                .help("Repeat transmissions of a reading needed within the validation delay to republish it as validated (default 1)"),
+        )
+        .arg(
+            clap::Arg::new("capture")
+                .long("capture")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Append every record from rtl_433 to a packed capture at this location, or, given a directory, every line rtl_433 outputs as is to a file in it, for replaying later"),
+        )
+        .arg(
+            clap::Arg::new("archive")
+                .long("archive")
+                .takes_value(true)
+                .value_name("DIRECTORY")
+                .help("Append every record published, as json, to a file per day in this directory"),
+        )
+        .arg(
+            clap::Arg::new("history_db")
+                .long("history-db")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Store every record in a SQLite database at this location"),
+        )
+        .arg(
+            clap::Arg::new("history_retention")
+                .long("history-retention")
+                .takes_value(true)
+                .value_name("DAYS")
+                .help("Prune records older than this from the history database"),
+        )
+        .arg(
+            clap::Arg::new("profile")
+                .long("profile")
+                .takes_value(true)
+                .value_name("NAME")
+                .help("Activate this configuration profile, regardless of the profile schedule"),
+        )
+        .arg(
+            clap::Arg::new("api_listen")
+                .long("api")
+                .takes_value(true)
+                .value_name("ADDRESS")
+                .help("Serve the sensors' current state over HTTP on this address, e.g. '127.0.0.1:8080'"),
+        )
+        .arg(
+            clap::Arg::new("arrow_listen")
+                .long("arrow")
+                .takes_value(true)
+                .value_name("ADDRESS")
+                .help("Stream normalized records as Apache Arrow IPC on this address, e.g. '127.0.0.1:8815'"),
+        )
+        .arg(
+            clap::Arg::new("topic_prefix")
+                .long("topic-prefix")
+                .takes_value(true)
+                .value_name("PREFIX")
+                .help("Topic level to nest all published topics under, e.g. 'weather'"),
+        )
+        .arg(
+            clap::Arg::new("topic_naming")
+                .long("topic-naming")
+                .takes_value(true)
+                .value_name("STRATEGY")
+                .possible_values(["location", "alias", "model-channel", "uuid", "custom"])
+                .help("How sensors are named in published topics; custom uses --topic-template (default location, or custom with a template)"),
+        )
+        .arg(
+            clap::Arg::new("topic_template")
+                .long("topic-template")
+                .takes_value(true)
+                .value_name("TEMPLATE")
+                .help("Layout of published topics for custom naming, using placeholders {sensor_id}, {model}, {id}, {channel}, {location}, {uuid} and {measurement}, with alternatives like {channel|id}"),
+        )
+        .arg(
+            clap::Arg::new("required_integrity")
+                .long("required-integrity")
+                .takes_value(true)
+                .value_name("LEVEL")
+                .possible_values(["none", "parity", "checksum", "crc"])
+                .help("Weakest packet integrity check to accept from sensors without a per-sensor override"),
+        )
+        .arg(
+            clap::Arg::new("location")
+                .short('l')
+                .long("location")
+                .multiple_occurrences(true)
+                .takes_value(true)
+                .value_name("SENSOR_ID=LOCATION")
+                .help("Group the specified sensor under a location, e.g. 'outdoor/greenhouse'; can be repeated"),
+        )
+        .arg(
+            clap::Arg::new("probe")
+                .long("probe")
+                .multiple_occurrences(true)
+                .takes_value(true)
+                .value_name("DEVICE/PROBE=NAME")
+                .help("Name a probe of a multi-probe device, by model or sensor id and its channel or probe number, e.g. 'Fineoffset-WN34/3=pool'; can be repeated"),
+        )
+        .arg(
+            clap::Arg::new("retain")
+                .long("retain")
+                .help("Have the broker retain the latest message of every sensor and measurement topic, for subscribers connecting between transmissions"),
+        )
+        .arg(
+            clap::Arg::new("retain_sensor")
+                .long("retain-sensor")
+                .multiple_occurrences(true)
+                .takes_value(true)
+                .value_name("SENSOR_ID=on|off")
+                .help("Retain, or don't retain, the topics of the specified sensor, which may be a glob, prefix, or regex as for --ignore, overriding --retain; can be repeated"),
+        )
+        .arg(
+            clap::Arg::new("min_publish_interval")
+                .long("min-publish-interval")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("Publish at most one record per sensor this often; records in between are still recorded"),
+        )
+        .arg(
+            clap::Arg::new("min_publish_interval_sensor")
+                .long("min-publish-interval-sensor")
+                .multiple_occurrences(true)
+                .takes_value(true)
+                .value_name("SENSOR_ID=SECONDS")
+                .help("Publish at most one record this often for the specified sensor, which may be a glob, prefix, or regex as for --ignore, overriding --min-publish-interval; can be repeated"),
+        )
+        .arg(
+            clap::Arg::new("batch")
+                .long("batch")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("Publish records together as a json array to weatherradio/batch every SECONDS, rather than each to its sensor's topic"),
+        )
+        .arg(
+            clap::Arg::new("sample")
+                .long("sample")
+                .multiple_occurrences(true)
+                .takes_value(true)
+                .value_name("MODEL=RULE")
+                .help("Keep only some records of a model, which may be a glob, prefix, or regex as for --ignore: '1/N' keeps one in N of each sensor's records, e.g. '60s' the first each minute; can be repeated"),
+        )
+        .arg(
+            clap::Arg::new("rain_day_start")
+                .long("rain-day-start")
+                .takes_value(true)
+                .value_name("HH:MM")
+                .help("Local time the daily rainfall starts over at (default 00:00)"),
+        )
+        .arg(
+            clap::Arg::new("stats_window")
+                .long("stats-window")
+                .multiple_occurrences(true)
+                .takes_value(true)
+                .value_name("WINDOW")
+                .help("Publish the rolling min, max and mean of each sensor's measurements over this window, e.g. '5m' or '1h', to its stats topic; can be repeated"),
+        )
+        .arg(
+            clap::Arg::new("power_profile")
+                .long("power-profile")
+                .takes_value(true)
+                .value_name("PROFILE")
+                .possible_values(["normal", "low"])
+                .help("Publish less often and duty cycle the receivers in the low profile, for stations running from a solar panel (default normal)"),
+        )
+        .arg(
+            clap::Arg::new("payload_format")
+                .long("payload-format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(["raw", "normalized"])
+                .help("Publish records as rtl_433 reported them, or in a canonical layout common to all devices (default raw)"),
+        )
+        .arg(
+            clap::Arg::new("payload_include_raw")
+                .long("payload-include-raw")
+                .help("Include the record as rtl_433 reported it in normalized payloads"),
+        )
+        .arg(
+            clap::Arg::new("payload_include_lineage")
+                .long("payload-include-lineage")
+                .help("Include the measurements and formula each derived value was computed from in normalized payloads"),
+        )
+        .arg(
+            clap::Arg::new("units")
+                .long("units")
+                .takes_value(true)
+                .value_name("SYSTEM")
+                .possible_values(["metric", "imperial", "si"])
+                .help("System of units to publish values in (default each measurement's historical units)"),
+        )
+        .arg(
+            clap::Arg::new("station_altitude")
+                .long("station-altitude")
+                .takes_value(true)
+                .value_name("METERS")
+                .allow_hyphen_values(true)
+                .help("Altitude of the station, for correcting barometric pressure to sea level"),
+        )
+        .arg(
+            clap::Arg::new("max_payload_bytes")
+                .long("max-payload-bytes")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("Drop records whose json is larger than this"),
+        )
+        .arg(
+            clap::Arg::new("max_sensors")
+                .long("max-sensors")
+                .takes_value(true)
+                .value_name("COUNT")
+                .help("Drop records from sensors beyond the first this many heard from"),
+        )
+        .arg(
+            clap::Arg::new("max_measurement_names")
+                .long("max-measurement-names")
+                .takes_value(true)
+                .value_name("COUNT")
+                .help("Drop records introducing measurement names beyond the first this many"),
+        )
+        .arg(
+            clap::Arg::new("allow_control")
+                .long("allow-control")
+                .multiple_occurrences(true)
+                .takes_value(true)
+                .value_name("OPERATION")
+                .possible_values(["ignore", "alias", "calibrate", "status", "power", "profile", "trace"])
+                .help("Accept this operation on the mqtt control topics; can be repeated"),
+        )
+        .arg(
+            clap::Arg::new("check_updates")
+                .long("check-updates")
+                .help("Check for a newer release at startup, logging and publishing it if there is one"),
+        )
+        .arg(
+            clap::Arg::new("update_url")
+                .long("update-url")
+                .takes_value(true)
+                .value_name("URL")
+                .global(true)
+                .help("Look up the latest release here rather than on GitHub, in the same json layout"),
+        )
+        .arg(
+            clap::Arg::new("chaos_sink_failure_rate")
+                .long("chaos-sink-failure-rate")
+                .takes_value(true)
+                .value_name("RATE")
+                .hide(true)
+                .help("Fail this fraction of sink publishes, for soak testing"),
+        )
+        .arg(
+            clap::Arg::new("chaos_disconnect_rate")
+                .long("chaos-disconnect-rate")
+                .takes_value(true)
+                .value_name("RATE")
+                .hide(true)
+                .help("Drop the broker connection before this fraction of sink publishes, for soak testing"),
+        )
+        .arg(
+            clap::Arg::new("chaos_malformed_rate")
+                .long("chaos-malformed-rate")
+                .takes_value(true)
+                .value_name("RATE")
+                .hide(true)
+                .help("Corrupt this fraction of lines read from rtl_433, for soak testing"),
         )
         .arg(
             clap::Arg::new("generate_config")
@@ -96,8 +578,355 @@ fn main() -> Result<()> {
                 .long("generate-config")
                 .help(gen_cfg_help.as_str())
         )
+        .arg(
+            clap::Arg::new("check_config")
+                .long("check-config")
+                .help("Checks the configuration and the environment it points at, as config validate does, and then exits the program"),
+        )
+        .subcommand(
+            clap::Command::new("run")
+                .about("Listens to the radio and publishes what it hears, as when no subcommand is given"),
+        )
+        .subcommand(
+            clap::Command::new("config")
+                .about("Generates, shows or validates the configuration, or shows the pipeline it sets up")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("generate")
+                        .about("Writes the configuration file, populated by the arguments given and defaults where they were omitted, as --generate-config does"),
+                )
+                .subcommand(
+                    clap::Command::new("show")
+                        .about("Prints the configuration in effect, from the configuration file and the arguments given over it"),
+                )
+                .subcommand(
+                    clap::Command::new("validate")
+                        .about("Checks the configuration in effect, and that rtl_433, the broker and the keyring it points at can be reached, reporting every problem that would stop the bridge from starting; exits with 1 for problems in the settings, and 2 for problems only in the environment"),
+                )
+                .subcommand(
+                    clap::Command::new("pipeline")
+                        .about("Prints the stages records go through with the configuration in effect, in order from the radio to the sinks"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("setup")
+                .about("Asks for the rtl_433 binary, the frequency region and the mqtt broker, checking each answer, and writes the configuration file"),
+        )
+        .subcommand(
+            clap::Command::new("sensors")
+                .about("Lists the sensors heard from")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("list")
+                        .about("Lists every sensor heard from, with its state and when it was last heard from, as kept across restarts"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("decode")
+                .alias("decode-fineoffset")
+                .about("Decodes rtl_433 json records, or raw Fine Offset packets captured without rtl_433's decoders, printing their measurements")
+                .arg(
+                    clap::Arg::new("input")
+                        .value_name("HEX|JSON|FILE")
+                        .help("Packet bytes in hex, with or without the 2dd4 sync word, an rtl_433 json record, or a file of them, one per line or packed; read line by line from stdin when omitted or '-'"),
+                )
+                .arg(
+                    clap::Arg::new("json")
+                        .long("json")
+                        .help("Also print the rtl_433 json each record was decoded from, or that a packet decodes to"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("convert-units")
+                .about("Converts a value between units, with the same precision used for published values")
+                .arg(
+                    clap::Arg::new("value")
+                        .required(true)
+                        .value_name("VALUE")
+                        .allow_hyphen_values(true)
+                        .help("Value with unit suffix, e.g. '74.3F', '12mm', '5km/h'"),
+                )
+                .arg(
+                    clap::Arg::new("unit")
+                        .required(true)
+                        .value_name("UNIT")
+                        .help("Unit to convert to, e.g. 'C', 'in', 'mph'"),
+                ),
+        );
+    let matches = app
+        .subcommand(
+            clap::Command::new("acl")
+                .about("Prints broker ACL entries granting the mqtt account access to exactly the topics published and subscribed to with this configuration")
+                .arg(
+                    clap::Arg::new("format")
+                        .long("format")
+                        .takes_value(true)
+                        .value_name("BROKER")
+                        .possible_values(["mosquitto", "emqx"])
+                        .help("Broker whose ACL syntax to print (default mosquitto)"),
+                )
+                .arg(
+                    clap::Arg::new("user")
+                        .long("user")
+                        .takes_value(true)
+                        .value_name("USERNAME")
+                        .help("Account to grant access to, if not the configured mqtt username"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("diff")
+                .about("Compares two captures of rtl_433 json output, reporting missing sensors, packet loss, and measurement offsets")
+                .arg(
+                    clap::Arg::new("left")
+                        .required(true)
+                        .value_name("LEFT")
+                        .help("File of rtl_433 json records, one per line or packed"),
+                )
+                .arg(
+                    clap::Arg::new("right")
+                        .required(true)
+                        .value_name("RIGHT")
+                        .help("File of rtl_433 json records to compare against LEFT, one per line or packed"),
+                )
+                .arg(
+                    clap::Arg::new("tolerance")
+                        .long("tolerance")
+                        .takes_value(true)
+                        .value_name("SECONDS")
+                        .help("How far apart timestamps may be for records to be the same transmission (default 5)"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("pack")
+                .about("Packs captured rtl_433 json output into the compact format written by --capture")
+                .arg(
+                    clap::Arg::new("input")
+                        .required(true)
+                        .value_name("INPUT")
+                        .help("File of rtl_433 json records, one per line"),
+                )
+                .arg(
+                    clap::Arg::new("output")
+                        .required(true)
+                        .value_name("OUTPUT")
+                        .help("Packed capture to append the records to, created if it doesn't exist"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("purge")
+                .about("Deletes a sensor's records from the history database and capture, and clears its retained topics on the broker")
+                .arg(
+                    clap::Arg::new("sensor")
+                        .long("sensor")
+                        .required(true)
+                        .takes_value(true)
+                        .value_name("SENSOR_ID")
+                        .help("Sensor whose records to delete, e.g. 'IDM/12345678'"),
+                )
+                .arg(
+                    clap::Arg::new("before")
+                        .long("before")
+                        .takes_value(true)
+                        .value_name("DATE")
+                        .help("Only delete records from before this date or timestamp, leaving its topics alone"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("gaps")
+                .about("Reports the gaps in each sensor's records in the history database, with their likely causes")
+                .arg(
+                    clap::Arg::new("since")
+                        .long("since")
+                        .takes_value(true)
+                        .value_name("PERIOD")
+                        .default_value("7d")
+                        .help("How far back to look, in minutes, hours or days, e.g. '12h'"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("query")
+                .about("Runs a read-only SQL query over the history database, e.g. for ad hoc analysis")
+                .arg(
+                    clap::Arg::new("sql")
+                        .required(true)
+                        .value_name("SQL")
+                        .help("Query to run, e.g. \"SELECT sensor_id, count(*) FROM records GROUP BY sensor_id\""),
+                )
+                .arg(
+                    clap::Arg::new("format")
+                        .long("format")
+                        .takes_value(true)
+                        .value_name("FORMAT")
+                        .possible_values(["table", "csv", "json"])
+                        .default_value("table")
+                        .help("How to print the results"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("self-update")
+                .about("Replaces this executable with the latest release, for installs of the static binary"),
+        )
+        .subcommand(
+            clap::Command::new("top")
+                .about("Shows the sensors of a bridge running elsewhere with --api, redrawn live, e.g. to inspect a headless receiver from a laptop")
+                .arg(
+                    clap::Arg::new("connect")
+                        .long("connect")
+                        .required(true)
+                        .takes_value(true)
+                        .value_name("HOST:PORT")
+                        .help("Address the bridge serves its API on, as given to its --api"),
+                )
+                .arg(
+                    clap::Arg::new("interval")
+                        .long("interval")
+                        .takes_value(true)
+                        .value_name("SECONDS")
+                        .default_value("2")
+                        .help("How often to redraw"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("usage")
+                .about("Prints the anonymous usage statistics of the devices in a capture, as they would be submitted with usage set in the configuration file, without sending them anywhere")
+                .arg(
+                    clap::Arg::new("file")
+                        .required(true)
+                        .value_name("FILE")
+                        .help("File of rtl_433 json records, one per line or packed"),
+                )
+                .arg(
+                    clap::Arg::new("json")
+                        .long("json")
+                        .help("Print the statistics as the json submitted"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("replay")
+                .about("Processes captured rtl_433 json output instead of listening to the radio")
+                .arg(
+                    clap::Arg::new("file")
+                        .required(true)
+                        .value_name("FILE")
+                        .help("File of rtl_433 json records, one per line or packed"),
+                )
+                .arg(
+                    clap::Arg::new("speed")
+                        .long("speed")
+                        .takes_value(true)
+                        .value_name("FACTOR")
+                        .help("Replay with the gaps between records divided by this factor, e.g. '60x' (default no delay)"),
+                )
+                .arg(
+                    clap::Arg::new("realtime_from")
+                        .long("realtime-from")
+                        .takes_value(true)
+                        .value_name("TIMESTAMP")
+                        .help("Replay records before this time without delay, and later ones with their timestamps shifted to now"),
+                )
+                .arg(
+                    clap::Arg::new("start")
+                        .long("start")
+                        .takes_value(true)
+                        .value_name("TIMESTAMP")
+                        .help("Skip records from before this time"),
+                ),
+        )
         .get_matches();
 
+    if let Some(convert) = matches.subcommand_matches("convert-units") {
+        let value = convert.value_of("value").unwrap_or_default();
+        let unit = convert.value_of("unit").unwrap_or_default();
+        println!("{}", units::convert(value, unit)?);
+        return Ok(());
+    }
+
+    if let Some(pack) = matches.subcommand_matches("pack") {
+        let input = std::path::Path::new(pack.value_of("input").unwrap_or_default());
+        let output = std::path::Path::new(pack.value_of("output").unwrap_or_default());
+        let packed = capture::pack(input, output)?;
+        println!("Packed {} records into {}", packed, output.display());
+        return Ok(());
+    }
+
+    if let Some(compare) = matches.subcommand_matches("diff") {
+        let tolerance = match compare.value_of("tolerance") {
+            Some(t) => t
+                .parse()
+                .with_context(|| format!("Invalid tolerance '{}'", t))?,
+            None => diff::DEFAULT_TOLERANCE_SECS,
+        };
+        let left = diff::load(std::path::Path::new(
+            compare.value_of("left").unwrap_or_default(),
+        ))?;
+        let right = diff::load(std::path::Path::new(
+            compare.value_of("right").unwrap_or_default(),
+        ))?;
+        let report = diff::compare(left, right, chrono::Duration::seconds(tolerance));
+        print!("{}", report);
+        return Ok(());
+    }
+
+    if let Some(top) = matches.subcommand_matches("top") {
+        let interval = top.value_of("interval").unwrap_or_default();
+        let interval = interval
+            .parse()
+            .with_context(|| format!("Invalid redraw interval '{}'", interval))?;
+        return top::run(
+            top.value_of("connect").unwrap_or_default(),
+            std::time::Duration::from_secs(interval),
+        );
+    }
+
+    if let Some(usage) = matches.subcommand_matches("usage") {
+        let records = diff::load(std::path::Path::new(
+            usage.value_of("file").unwrap_or_default(),
+        ))?;
+        // The capture's span stands in for the interval the records were
+        // counted over
+        let seconds = match (
+            records.iter().map(|r| r.timestamp).min(),
+            records.iter().map(|r| r.timestamp).max(),
+        ) {
+            (Some(first), Some(last)) => (last - first).num_seconds().max(0) as u64,
+            _ => 0,
+        };
+        let report = usage::report(seconds);
+        match usage.is_present("json") {
+            true => println!("{}", serde_json::to_string_pretty(&report)?),
+            false => print!("{}", report),
+        }
+        return Ok(());
+    }
+
+    if let Some(decode) = matches.subcommand_matches("decode") {
+        generic::enable(matches.is_present("generic_parser"));
+        let json = decode.is_present("json");
+        match decode.value_of("input").filter(|input| *input != "-") {
+            Some(input) if std::path::Path::new(input).exists() => {
+                for record in diff::load(std::path::Path::new(input))? {
+                    print_record(&record, json);
+                }
+            }
+            Some(input) => print_record(&decode_line(input)?, json),
+            // Lines that don't decode are reported and skipped, as when
+            // pasting a session's worth of captures
+            None => {
+                for line in std::io::stdin().lines() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match decode_line(&line) {
+                        Ok(record) => print_record(&record, json),
+                        Err(e) => eprintln!("{}: {:#}", line.trim(), e),
+                    }
+                }
+            }
+        }
+        return Ok(());
+    }
+
     let mut conf = if json_config_path.exists() {
         config::Config::try_from(&json_config_path).with_context(|| {
             format!(
@@ -109,38 +938,204 @@ fn main() -> Result<()> {
         config::Config::default()
     };
     conf.update_from_args(&matches)?;
+    generic::enable(conf.generic_parser);
+    // Mistakes in the settings are caught before anything starts, except
+    // when checking the configuration, which reports all of them, and when
+    // setting it up afresh
+    let validate = matches
+        .subcommand_matches("config")
+        .and_then(|config| config.subcommand_matches("validate"));
+    let check_config = matches.is_present("check_config") || validate.is_some();
+    if !check_config && matches.subcommand_matches("setup").is_none() {
+        conf.validate()?;
+    }
 
     let crate_log_level = conf.get_log_level();
     let general_log_level = match crate_log_level {
         log::LevelFilter::Trace | log::LevelFilter::Debug => log::LevelFilter::Error,
         _ => log::LevelFilter::Off,
     };
+    // Traced sensors are logged whatever the log level
     let spec = format!(
-        "{}, {} = {}",
+        "{}, {} = {}, {} = info",
         general_log_level,
         crate_name!(),
-        crate_log_level
+        crate_log_level,
+        trace::TARGET
     );
-    Logger::try_with_str(&spec)?
-        .format(detailed_format)
-        .format_for_stderr(default_format)
+    let logger = match conf.log_format {
+        logformat::LogFormat::Text => Logger::try_with_str(&spec)?
+            .format(detailed_format)
+            .format_for_stderr(default_format),
+        logformat::LogFormat::Json => Logger::try_with_str(&spec)?.format(logformat::json_format),
+    };
+    logger
         .start()
         .with_context(|| "Failed to start FlexiLogger logging backend")?;
 
     log::info!("{} version {}", crate_name!(), crate_version!());
 
+    log::debug!("receiver: {:?}", conf.receiver);
     log::debug!("rtl-433: {:?}", conf.rtl_433);
+    log::debug!("region: {:?}", conf.region);
+    log::debug!("radios: {:?}", conf.radios);
     log::debug!("mqtt: {:?}", conf.mqtt);
     log::debug!("sensors to ignore: {:?}", conf.sensor_ignores);
+    log::debug!("sensors to allow: {:?}", conf.sensor_allows);
+    log::debug!("sensor locations: {:?}", conf.locations);
+    log::debug!("sensors: {:?}", conf.sensors);
+    log::debug!("integrity requirements: {:?}", conf.integrity);
+    log::debug!("capture: {:?}", conf.capture);
+    log::debug!("archive: {:?}", conf.archive);
+    log::debug!("history: {:?}", conf.history);
+    log::debug!("api_listen: {:?}", conf.api_listen);
+    log::debug!("arrow_listen: {:?}", conf.arrow_listen);
+    log::debug!("arrow_window: {:?}", conf.get_arrow_window());
+    log::debug!("profiles: {:?}", conf.profiles.keys().collect::<Vec<_>>());
+    log::debug!("profile schedule: {:?}", conf.profile_schedule);
+    log::debug!("profile: {:?}", conf.profile);
+    log::debug!("startup grace period: {:?}", conf.startup_grace);
+    log::debug!("log summary interval: {:?}", conf.log_summary);
+    log::debug!("log format: {:?}", conf.log_format);
+    log::debug!("telemetry interval: {:?}", conf.telemetry_interval);
+    log::debug!("limits: {:?}", conf.limits);
+    log::debug!("sampling: {:?}", conf.sampling);
+    log::debug!("quirks: {:?}", conf.quirks);
+    log::debug!("bursts: {:?}", conf.bursts);
+    log::debug!("sensor aliases: {:?}", conf.aliases);
+    log::debug!("probe names: {:?}", conf.probes);
+    log::debug!("topic naming: {:?}", conf.get_topic_naming());
+    log::debug!("retain: {:?}", conf.retain);
+    log::debug!("throttle: {:?}", conf.throttle);
+    log::debug!("radio time: {:?}", conf.radio_time);
+    log::debug!("usage statistics: {:?}", conf.usage);
+    log::debug!("unparsed records: {:?}", conf.unparsed);
+    log::debug!("generic parser: {:?}", conf.generic_parser);
+    log::debug!("trace: {:?}", conf.trace);
+    log::debug!("pipeline: {:?}", conf.pipeline);
+    log::debug!("power: {:?}", conf.power);
+    log::debug!("automation: {:?}", conf.automation);
+    log::debug!("uploads: {:?}", conf.uploads);
+    log::debug!("cwop: {:?}", conf.cwop);
+    log::debug!("ecowitt uploads: {:?}", conf.ecowitt);
+    log::debug!("gateway: {:?}", conf.gateway);
+    log::debug!("audit: {:?}", conf.audit);
+    log::debug!("webhooks: {:?}", conf.webhooks);
+    log::debug!("statistics windows: {:?}", conf.stats_windows);
+    log::debug!("rain day start: {:?}", conf.rain_day_start);
+    log::debug!("battery prediction: {:?}", conf.battery);
+    log::debug!("calibration: {:?}", conf.calibration);
+    log::debug!("remote control: {:?}", conf.control);
+    log::debug!("updates: {:?}", conf.update);
+    log::debug!("fault injection: {:?}", conf.chaos);
+
+    if let Some(acl) = matches.subcommand_matches("acl") {
+        let format = acl
+            .value_of("format")
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default();
+        let username = acl
+            .value_of("user")
+            .map(str::to_owned)
+            .or_else(|| conf.mqtt.first()?.credentials.as_ref()?.username())
+            .ok_or(acl::AclError::MissingUsername)?;
+        print!("{}", acl::render(format, &username, &acl::topics(&conf)?));
+        return Ok(());
+    }
+
+    if let Some(config) = matches.subcommand_matches("config") {
+        if config.subcommand_matches("show").is_some() {
+            println!("{}", serde_json::to_string_pretty(&conf)?);
+            return Ok(());
+        }
+        if config.subcommand_matches("pipeline").is_some() {
+            print!("{}", stages::graph(&conf.stages()?, &conf));
+            return Ok(());
+        }
+    }
+    if check_config {
+        let problems = preflight::check(&conf);
+        for problem in &problems {
+            eprintln!("error: {}", problem);
+        }
+        match problems.iter().map(|problem| problem.kind).min() {
+            Some(kind) => {
+                eprintln!("{} problem(s) found", problems.len());
+                std::process::exit(kind.exit_code());
+            }
+            None if json_config_path.exists() => {
+                println!("{} is valid", json_config_path.display())
+            }
+            None => println!("No configuration file, and the arguments given are valid"),
+        }
+        return Ok(());
+    }
 
-    if let Some(ref mut mqtt) = conf.mqtt {
+    if let Some(sensors) = matches.subcommand_matches("sensors") {
+        if sensors.subcommand_matches("list").is_some() {
+            let path = conf
+                .sensor_state
+                .clone()
+                .or_else(|| identity::state_path("sensors.json"))
+                .ok_or(AppError::StateDirNotFound)?;
+            let sensors = lifecycle::LifecycleTracker::new(
+                conf.get_sensor_timeout(),
+                conf.get_sensor_lost_timeout(),
+            )
+            .state_file(path)
+            .sensors();
+            let width = sensors.iter().map(|s| s.0.len()).max().unwrap_or_default();
+            for (sensor_id, topic, state, last_seen) in &sensors {
+                println!(
+                    "{:width$}  {:10}  {}  {}",
+                    sensor_id,
+                    format!("{:?}", state),
+                    last_seen.format("%Y-%m-%d %H:%M:%S"),
+                    topic,
+                    width = width
+                );
+            }
+            if sensors.is_empty() {
+                println!("No sensors heard from yet");
+            }
+            return Ok(());
+        }
+    }
+
+    if matches.subcommand_matches("self-update").is_some() {
+        match update::self_update(&conf.update)? {
+            Some(version) => println!("Updated to {}", version),
+            None => println!("{} is up to date", crate_version!()),
+        }
+        return Ok(());
+    }
+
+    if matches.subcommand_matches("setup").is_some() {
+        let stdin = std::io::stdin();
+        let mut setup = setup::Setup::new(stdin.lock(), std::io::stdout());
+        let conf = setup.run(conf)?;
+        if setup.confirm_write(&json_config_path)? {
+            write_config(&conf, &json_config_path)?;
+            println!(
+                "Wrote {}; start the bridge with weatherradio run",
+                json_config_path.display()
+            );
+        }
+        return Ok(());
+    }
+
+    #[cfg(feature = "mqtt")]
+    for mqtt in conf.mqtt.iter_mut() {
         if let Some(cred) = &mqtt.credentials {
-            if let Ok(None) = cred.password() {
+            if let Ok(None) = cred.password(&mqtt.broker) {
                 mqtt.credentials = Some(
                     cred.update_password(
+                        &mqtt.broker,
                         rpassword::prompt_password(format!(
-                            "mqtt password for {}: ",
-                            cred.username().unwrap_or_default()
+                            "mqtt password for {} on {}: ",
+                            cred.username().unwrap_or_default(),
+                            mqtt.broker
                         ))?
                         .as_str(),
                     )?,
@@ -149,75 +1144,240 @@ fn main() -> Result<()> {
         }
     }
 
-    if matches.is_present("generate_config") {
-        std::fs::create_dir_all(json_config_path.parent().expect("Configuration file directory could not be determined from the provided configuration file path"))?;
-        let mut config_file = std::io::BufWriter::new(
-            std::fs::File::create(&json_config_path).with_context(|| {
-                format!(
-                    "Failed to create configuration file at {}",
-                    json_config_path.display()
-                )
-            })?,
-        );
-        let json_out = serde_json::to_string(&conf)?;
-        config_file.write_all(json_out.as_bytes())?;
-        config_file.flush()?;
+    let generate = matches
+        .subcommand_matches("config")
+        .and_then(|config| config.subcommand_matches("generate"));
+    if matches.is_present("generate_config") || generate.is_some() {
+        write_config(&conf, &json_config_path)?;
         return Ok(());
     }
 
-    let session_opt = if let Some(mqtt) = &conf.mqtt {
-        log::debug!("Establishing connection to mqtt broker {}", mqtt.broker);
-        let broker_uri = format!("tcp://{}", mqtt.broker);
-        let mqtt_session = paho_mqtt::Client::new(broker_uri.as_str())
-            .with_context(|| format!("Failed to establish connection to broker {}", broker_uri))?;
-        let mut mqtt_opts = paho_mqtt::ConnectOptionsBuilder::new();
-        mqtt_opts
-            .keep_alive_interval(std::time::Duration::from_secs(20))
-            .clean_session(true);
-        if let Some(cred) = &mqtt.credentials {
-            if let Some((u, p)) = cred.get() {
-                mqtt_opts.user_name(u);
-                mqtt_opts.password(p);
-            }
+    if let Some(purge) = matches.subcommand_matches("purge") {
+        let sensor_id = purge.value_of("sensor").unwrap_or_default();
+        let before = purge
+            .value_of("before")
+            .map(purge::parse_before)
+            .transpose()?;
+        print!("{}", purge::purge(&conf, sensor_id, before)?);
+        return Ok(());
+    }
+
+    if let Some(gaps) = matches.subcommand_matches("gaps") {
+        let since = gaps::parse_since(gaps.value_of("since").unwrap_or_default())?;
+        print!("{}", gaps::report(&conf, since)?);
+        return Ok(());
+    }
+
+    if let Some(sql) = matches.subcommand_matches("query") {
+        let format = sql
+            .value_of("format")
+            .unwrap_or_default()
+            .parse()
+            .map_err(anyhow::Error::msg)?;
+        let result = query::query(&conf, sql.value_of("sql").unwrap_or_default())?;
+        print!("{}", result.format(format)?);
+        return Ok(());
+    }
+
+    let instance_id = match identity::default_path() {
+        Some(path) => identity::load_or_create(&path)?,
+        None => {
+            log::warn!("State directory not found, instance id will change on restart");
+            uuid::Uuid::new_v4()
         }
-        mqtt_session.connect(mqtt_opts.finalize())?;
-        log::info!("Connected to mqtt broker {}", mqtt.broker);
-        Some(mqtt_session)
-    } else {
-        None
     };
-
-    log::debug!("Opening rtl_433...");
-    let weather = radio::Sensor::<radio::RTL433>::new(&conf)?;
-    // Dedup records
-    let mut last: Option<crate::radio::Record> = None;
-    for record in weather.filter(|r| !conf.sensor_ignores.contains(&r.sensor_id)) {
-        if last.as_ref().map(|l| l == &record).unwrap_or(false) {
-            log::trace!("Duplicate record.");
-            continue;
-        }
-        log::trace!("[RECORD] {} {}", record.timestamp, record.sensor_id);
-        if let Some(ref session) = session_opt {
-            let msg = paho_mqtt::Message::new(
-                &record.sensor_id,
-                serde_json::to_vec(&record.record_json)?,
-                2,
+    log::debug!("instance id: {}", instance_id);
+    conf.instance_id = Some(instance_id);
+    for mqtt in conf.mqtt.iter_mut() {
+        mqtt.client_id
+            .get_or_insert_with(|| identity::client_id(instance_id));
+    }
+    let replaying = matches.subcommand_matches("replay").is_some();
+    // Replaying old records would otherwise clobber the day's rainfall
+    if !replaying {
+        if conf.rain_state.is_none() {
+            conf.rain_state = identity::state_path("rain.json");
+        }
+        if conf.sensor_state.is_none() {
+            conf.sensor_state = identity::state_path("sensors.json");
+        }
+        if conf.battery_state.is_none() {
+            conf.battery_state = identity::state_path("battery.json");
+        }
+    }
+    if replaying {
+        // Nor should weather networks be sent conditions long past
+        if !conf.uploads.is_empty() || conf.cwop.is_some() || !conf.ecowitt.is_empty() {
+            log::info!("Not uploading replayed records to weather networks");
+            conf.uploads.clear();
+            conf.cwop = None;
+            conf.ecowitt.clear();
+        }
+        // Nor notifications of readings long past
+        if !conf.webhooks.is_empty() {
+            log::info!("Not notifying webhooks of replayed records");
+            conf.webhooks.clear();
+        }
+        // Nor should the clock be set to a time long past
+        if let Some(step) = conf.radio_time.as_mut().and_then(|r| r.step_command.take()) {
+            log::info!(
+                "Not stepping the clock to replayed radio time with {}",
+                step.display()
             );
-            session.publish(msg)?;
-            log::info!("mqtt <== {}({})", record.sensor_id, record.record_json);
-        }
-        /*
-        for measurement in &record.measurements {
-            log::info!("[{}]:{} {}", record.timestamp, record.sensor_id, measurement);
-            if let Some(ref session) = session_opt {
-                let topic = format!("{}/{}", record.sensor_id, measurement.name());
-                let msg = paho_mqtt::Message::new(&topic, measurement.value(), 2);
-                session.publish(msg)?;
-                log::info!("mqtt <== {}({})", topic, measurement.value());
-            }
         }
-        */
-        last = Some(record);
+        // Nor archived among the records heard now
+        if conf.archive.take().is_some() {
+            log::info!("Not archiving replayed records");
+        }
+        // Nor counted as devices heard now
+        if conf.usage.take().is_some() {
+            log::info!("Not submitting usage statistics of replayed records");
+        }
+        // Nor compared with what a gateway hears now
+        if conf.gateway.take().is_some() {
+            log::info!("Not comparing replayed records with the gateway");
+        }
     }
+
+    // Reading from the radio and publishing to the sink run as tasks on the
+    // runtime, while records are processed on this thread
+    let runtime =
+        tokio::runtime::Runtime::new().with_context(|| "Failed to start the async runtime")?;
+    let result = {
+        let _runtime = runtime.enter();
+        trace::set(&conf.trace)?;
+        #[cfg(unix)]
+        reload_trace_on_signal(json_config_path, &matches)?;
+        bridge(conf, &matches)
+    };
+    // Don't wait on a publish to an unresponsive broker that has already
+    // been given up on
+    runtime.shutdown_background();
+    result
+}
+
+/// Takes the sensors to trace from the configuration file again, along with
+/// those given as arguments, on every SIGUSR1. Must be called within a tokio
+/// runtime.
+#[cfg(unix)]
+fn reload_trace_on_signal(path: std::path::PathBuf, matches: &clap::ArgMatches) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let args: Vec<String> = matches
+        .values_of("trace")
+        .iter_mut()
+        .flatten()
+        .map(str::to_owned)
+        .collect();
+    let mut signals =
+        signal(SignalKind::user_defined1()).with_context(|| "Failed to listen for SIGUSR1")?;
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            log::info!("Reloading the sensors to trace from {}", path.display());
+            let patterns = match config::Config::try_from(&path) {
+                Ok(conf) => conf.trace.into_iter().chain(args.iter().cloned()),
+                Err(e) => {
+                    log::warn!("Failed to reload {}: {:#}", path.display(), e);
+                    continue;
+                }
+            };
+            if let Err(e) = trace::set(&patterns.collect::<Vec<_>>()) {
+                log::warn!("{}", e);
+            }
+        }
+    });
     Ok(())
 }
+
+/// Writes the configuration file
+fn write_config(conf: &config::Config, path: &std::path::Path) -> Result<()> {
+    std::fs::create_dir_all(path.parent().expect("Configuration file directory could not be determined from the provided configuration file path"))?;
+    let mut config_file =
+        std::io::BufWriter::new(std::fs::File::create(path).with_context(|| {
+            format!("Failed to create configuration file at {}", path.display())
+        })?);
+    let json_out = serde_json::to_string(conf)?;
+    config_file.write_all(json_out.as_bytes())?;
+    config_file.flush()?;
+    Ok(())
+}
+
+/// Feeds records from the radio, or a replayed capture, through the pipeline
+/// until it runs out. Must be called within a tokio runtime.
+fn bridge(conf: config::Config, matches: &clap::ArgMatches) -> Result<()> {
+    // A broker that's down is connected to once it's back, rather than
+    // keeping the bridge from starting
+    let sink = conf
+        .mqtt
+        .first()
+        .map(|mqtt| sink::MqttSink::connect(mqtt, &conf.status_topic_on(Some(mqtt))))
+        .map(|sink| sink.chaos(conf.chaos));
+    update::announce(conf.update.clone(), sink.clone());
+
+    if let Some(replay) = matches.subcommand_matches("replay") {
+        let timing = replay::ReplayTiming {
+            speed: replay
+                .value_of("speed")
+                .map(replay::parse_speed)
+                .transpose()?,
+            realtime_from: replay
+                .value_of("realtime_from")
+                .map(replay::parse_timestamp)
+                .transpose()?,
+            start: replay
+                .value_of("start")
+                .map(replay::parse_timestamp)
+                .transpose()?,
+        };
+        let path = std::path::Path::new(replay.value_of("file").unwrap_or_default());
+        log::debug!("Replaying {}...", path.display());
+        // Timers and rollovers go by the replayed records' time
+        let clock = std::sync::Arc::new(clock::ManualClock::new(chrono::Local::now()));
+        clock::install(clock.clone());
+        let records = replay::Replay::open(path, &conf, timing)?.follow(clock);
+        let mut pipeline = pipeline::Pipeline::new(conf, sink)?;
+        pipeline.run(records)?;
+        return pipeline.finish();
+    }
+
+    let events = events::EventBus::new();
+    if conf.receiver == config::Receiver::RtlSdr {
+        #[cfg(not(feature = "rtlsdr"))]
+        return Err(config::ConfigError::RtlSdrUnsupported.into());
+        #[cfg(feature = "rtlsdr")]
+        {
+            log::debug!("Opening RTL-SDR...");
+            let weather = radio::Sensor::<radio::RtlSdrNative>::new(&conf, &events)?;
+            let mut pipeline = build_pipeline(conf, sink, events)?;
+            pipeline.run(weather)?;
+            return pipeline.finish();
+        }
+    }
+
+    log::debug!("Opening rtl_433...");
+    let weather = radio::Sensor::<radio::RTL433>::new(&conf, &events)?;
+    let failure = weather.failure_handle();
+    let mut pipeline = build_pipeline(conf, sink, events)?;
+    pipeline.run(weather)?;
+    pipeline.finish()?;
+    // Exit with an error, so that the service manager restarts the bridge
+    let failure = failure.lock().expect("failure poisoned").clone();
+    match failure {
+        Some(failure) => Err(anyhow::Error::msg(failure)),
+        None => Ok(()),
+    }
+}
+
+// The pipeline with its default calculators, publishing its events on the
+// bus the radio reports its problems on
+fn build_pipeline(
+    conf: config::Config,
+    sink: Option<sink::MqttSink>,
+    events: events::EventBus,
+) -> Result<pipeline::Pipeline> {
+    let builder = pipeline::PipelineBuilder::new(conf).event_bus(events);
+    match sink {
+        Some(sink) => builder.sink(sink).build(),
+        None => builder.build(),
+    }
+}
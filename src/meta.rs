@@ -0,0 +1,143 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde::Serialize;
+
+pub const META_SUFFIX: &str = "$meta";
+
+/// Fields of rtl_433 records describing the sensor itself rather than what
+/// it measures, e.g. the WS90's firmware version
+pub const FEATURE_FIELDS: &[&str] = &["firmware", "flags"];
+
+/// Self-description of a sensor, published retained alongside its data topic
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SensorMeta {
+    pub sensor_id: String,
+    pub model: Option<String>,
+    /// The sensor's configured name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The alias the sensor is published under, configured or set over the
+    /// control topic
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    pub location: Option<String>,
+    /// The sensor's configured tags
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    pub tags: BTreeSet<String>,
+    pub units: BTreeMap<String, String>,
+    /// Offsets added to the sensor's measurements, by measurement, in their
+    /// metric units
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub calibration: BTreeMap<String, f32>,
+    /// The sensor's [`FEATURE_FIELDS`], as last reported
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub features: BTreeMap<String, serde_json::Value>,
+    /// The sensor's [quality score](crate::quality), rounded to 5
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<u8>,
+}
+
+impl SensorMeta {
+    pub fn from_record(
+        record: &crate::radio::Record,
+        conf: &crate::config::Config,
+        units: Option<crate::units::UnitSystem>,
+    ) -> Self {
+        let info = conf.sensor_info(&record.sensor_id);
+        let model = if let Some(serde_json::Value::String(model)) = record.record_json.get("model")
+        {
+            Some(model.clone())
+        } else {
+            None
+        };
+        let units = record
+            .measurements
+            .iter()
+            .map(|m| (m.name_in(units), m.unit_in(units)))
+            .collect();
+        let features = FEATURE_FIELDS
+            .iter()
+            .filter_map(|&field| Some((field.to_owned(), record.record_json.get(field)?.clone())))
+            .collect();
+        SensorMeta {
+            sensor_id: record.sensor_id.clone(),
+            model,
+            name: info.name,
+            alias: conf.aliases.get(&record.sensor_id).cloned(),
+            location: info.location,
+            tags: info.tags,
+            units,
+            calibration: conf
+                .calibration
+                .get(&record.sensor_id)
+                .cloned()
+                .unwrap_or_default(),
+            features,
+            quality: None,
+        }
+    }
+}
+
+/// Tracks the last metadata published for each sensor, so that the retained
+/// `$meta` topic is only republished when its content changes, e.g. when the
+/// sensor's alias or calibration is changed over the control topics or by a
+/// configuration profile. The tracker starts out empty, so every sensor's
+/// metadata is refreshed after a restart picks up configuration changes.
+#[derive(Debug, Default)]
+pub struct MetaTracker {
+    published: HashMap<String, SensorMeta>,
+    units: Option<crate::units::UnitSystem>,
+}
+
+impl MetaTracker {
+    /// Creates a tracker describing measurements in the given system of units
+    pub fn new(units: Option<crate::units::UnitSystem>) -> Self {
+        MetaTracker {
+            published: HashMap::new(),
+            units,
+        }
+    }
+
+    /// Returns the updated metadata for the record's sensor, if it differs
+    /// from what was last published
+    pub fn update(
+        &mut self,
+        record: &crate::radio::Record,
+        conf: &crate::config::Config,
+        quality: Option<u8>,
+    ) -> Option<SensorMeta> {
+        let mut meta = SensorMeta::from_record(record, conf, self.units);
+        meta.quality = quality.map(|score| (score + 2) / 5 * 5);
+        if let Some(prev) = self.published.get(&record.sensor_id) {
+            // Sensors don't always report every measurement in every packet,
+            // so accumulate everything we've seen from them
+            let mut units = prev.units.clone();
+            units.extend(meta.units);
+            meta.units = units;
+            if meta.model.is_none() {
+                meta.model = prev.model.clone();
+            }
+            for (field, value) in &meta.features {
+                match prev.features.get(field) {
+                    Some(prev_value) if prev_value != value => log::info!(
+                        "[{}] {} changed from {} to {}",
+                        record.sensor_id,
+                        field,
+                        prev_value,
+                        value
+                    ),
+                    _ => {}
+                }
+            }
+            let mut features = prev.features.clone();
+            features.extend(meta.features);
+            meta.features = features;
+            if prev == &meta {
+                return None;
+            }
+        }
+        self.published
+            .insert(record.sensor_id.clone(), meta.clone());
+        Some(meta)
+    }
+}
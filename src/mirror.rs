@@ -0,0 +1,142 @@
+//! Publishing records to further mqtt brokers besides the first, e.g. to a
+//! cloud broker alongside a local Mosquitto
+//!
+//! Each broker listed in `mqtt` after the first is published the records of
+//! the sensors matching its `sensors` patterns, or of every sensor, to their
+//! data topics and those of their derived measurements, under its own
+//! `topic_prefix` if it has one. Everything else, like the bridge's status,
+//! telemetry and control topics, is only published to the first broker.
+//!
+//! The pipeline's `mqtt` stage hands each broker the records it publishes,
+//! once they've been through the same maintenance, throttling, batching and
+//! retain decisions as on the first broker, and rendered with the same
+//! configuration, which commands over the control topics change for every
+//! broker at once.
+//!
+//! Every broker has a connection of its own, so that a broker that's down
+//! or slow holds up neither the pipeline nor the other brokers. As with the
+//! first broker, a broker that can't be connected to, or that's lost, is
+//! reported on the event bus and connected to again with backoff, holding
+//! its records until it's back.
+
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::config::{Config, MqttConfig, SensorPattern};
+use crate::events::EventBus;
+use crate::radio::{Measurement, Record};
+use crate::sink::MqttSink;
+use crate::throttle::Batcher;
+use crate::topics::TopicTemplate;
+
+/// A broker records are mirrored to
+pub struct Mirror {
+    topics: TopicTemplate,
+    sensors: Vec<SensorPattern>,
+    sink: MqttSink,
+    batcher: Option<Batcher>,
+}
+
+impl Mirror {
+    /// Connects to the broker in the background, reporting failures on
+    /// `errors`. Must be called within a tokio runtime.
+    pub fn connect(conf: &Config, mqtt: &MqttConfig, errors: EventBus) -> Result<Self> {
+        Ok(Mirror {
+            topics: conf.topics_on(Some(mqtt))?,
+            sensors: mqtt.sensor_patterns()?,
+            sink: MqttSink::connect(mqtt, &conf.status_topic_on(Some(mqtt))).events(errors),
+            batcher: None,
+        })
+    }
+
+    pub fn broker(&self) -> &str {
+        self.sink.broker()
+    }
+
+    /// Whether the sensor's records are published to the broker, as
+    /// matching its `sensors`
+    pub fn accepts(&self, sensor_id: &str) -> bool {
+        self.sensors.is_empty()
+            || self
+                .sensors
+                .iter()
+                .any(|pattern| pattern.matches(sensor_id))
+    }
+
+    /// Names the sensor in the broker's topics after `alias`, or after its
+    /// sensor id again when `None`
+    pub fn set_alias(&mut self, sensor_id: &str, alias: Option<&str>) {
+        self.topics.set_alias(sensor_id, alias);
+    }
+
+    /// Batches the broker's records every `interval`, or stops batching them
+    /// when `None`
+    pub fn batch(&mut self, interval: Option<Duration>) {
+        match (interval, self.batcher.take()) {
+            (Some(interval), None) => {
+                self.batcher = Some(Batcher::spawn(self.sink.clone(), interval))
+            }
+            (Some(_), batcher) => self.batcher = batcher,
+            (None, Some(batcher)) => batcher.finish(),
+            (None, None) => {}
+        }
+    }
+
+    /// Publishes a record admitted by the pipeline with its `payload`, and
+    /// its derived measurements
+    pub fn publish(
+        &self,
+        conf: &Config,
+        record: &Record,
+        location: Option<&str>,
+        payload: &serde_json::Value,
+        retained: bool,
+    ) -> Result<()> {
+        let sink = &self.sink;
+        let topic = self.topics.render(record, location, None);
+        match self.batcher {
+            Some(ref batcher) => batcher.add(&topic, payload.clone(), record.timestamp),
+            None => sink.publish_reading(&topic, serde_json::to_vec(payload)?, record, retained)?,
+        }
+        log::debug!("{} <== {}({})", sink.broker(), topic, payload);
+        // As on the first broker, derived measurements and probes are
+        // published to topics of their own
+        let own_topic =
+            |m: &&Measurement| m.is_derived() || matches!(m, Measurement::ProbeTemperature(..));
+        for measurement in record.measurements.iter().filter(own_topic) {
+            let topic =
+                self.topics
+                    .render(record, location, Some(&measurement.name_in(conf.units)));
+            let value = measurement.value_in(conf.units);
+            if retained {
+                sink.publish_retained(&topic, value.as_str(), sink.qos())?;
+            } else {
+                sink.publish(&topic, value.as_str())?;
+            }
+            log::debug!("{} <== {}({})", sink.broker(), topic, value);
+        }
+        Ok(())
+    }
+
+    /// Publishes the last batch, and disconnects from the broker
+    pub fn disconnect(self) {
+        if let Some(batcher) = self.batcher {
+            batcher.finish();
+        }
+        let broker = self.sink.broker().to_owned();
+        if let Err(e) = self.sink.disconnect() {
+            log::warn!("Failed to disconnect from {}: {:#}", broker, e);
+        }
+    }
+}
+
+/// Connects to each broker after the first, reporting failures on `errors`.
+/// Must be called within a tokio runtime.
+pub fn connect_mirrors(conf: &Config, errors: &EventBus) -> Result<Vec<Mirror>> {
+    conf.mqtt
+        .iter()
+        .skip(1)
+        .map(|mqtt| Mirror::connect(conf, mqtt, errors.clone()))
+        .collect()
+}
@@ -0,0 +1,153 @@
+//! Canonical json payloads, with the same layout and field names whatever
+//! device a record came from
+
+use serde::{Deserialize, Serialize};
+
+use crate::radio::{Measurement, Record};
+use crate::units::UnitSystem;
+
+/// Layout of the json payload published for each record
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadFormat {
+    /// The record exactly as rtl_433 reported it
+    #[default]
+    Raw,
+    /// The canonical layout produced by [`normalize`]
+    Normalized,
+}
+
+impl std::str::FromStr for PayloadFormat {
+    type Err = crate::config::ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Self::Raw),
+            "normalized" => Ok(Self::Normalized),
+            _ => Err(crate::config::ConfigError::PayloadFormat(s.to_owned())),
+        }
+    }
+}
+
+// Converts a CamelCase measurement name to snake_case
+fn snake_case(name: &str) -> String {
+    let mut key = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            key.push('_');
+        }
+        key.extend(c.to_lowercase());
+    }
+    key
+}
+
+/// Canonical field name of a measurement, suffixed with the metric unit its
+/// value is given in
+pub fn key(measurement: &Measurement) -> Option<String> {
+    let key = match measurement {
+        Measurement::TotalEnergyConsumption(_) => "energy_kwh",
+        Measurement::DifferentialEnergyConsumption(_, _) => "energy_delta_kwh",
+        Measurement::VolumeConsumption(_) => "volume_m3",
+        Measurement::BatteryOk(_) => "battery_ok",
+        Measurement::Temperature(_) => "temperature_c",
+        Measurement::RelativeHumidity(_) => "humidity",
+        Measurement::BatteryLevelRaw(_) => "battery_level",
+        Measurement::Clock(_) => "clock",
+        Measurement::Rainfall(_) => "rain_mm",
+        Measurement::RainfallDelta(_) => "rain_delta_mm",
+        Measurement::DailyRainfall(_) => "rain_daily_mm",
+        Measurement::RainRate(_) => "rain_rate_mm_h",
+        Measurement::Lux(_) => "light_lux",
+        Measurement::UvIndex(_) => "uv_index",
+        Measurement::WindSpeed(_) => "wind_speed_km_h",
+        Measurement::WindGust(_) => "wind_gust_km_h",
+        Measurement::WindDirection(_) => "wind_dir_deg",
+        Measurement::Pm2_5(_) => "pm2_5_ug_m3",
+        Measurement::Pm10(_) => "pm10_ug_m3",
+        Measurement::Co2(_) => "co2_ppm",
+        Measurement::Pressure(_) => "pressure_hpa",
+        Measurement::SeaLevelPressure(_) => "sea_level_pressure_hpa",
+        Measurement::Voltage(_) => "voltage_v",
+        Measurement::ProbeTemperature(probe, _) => {
+            return Some(format!("{}_temperature_c", snake_case(probe)))
+        }
+        Measurement::Derived(d) => return Some(snake_case(&d.name)),
+        Measurement::None => return None,
+    };
+    Some(key.to_owned())
+}
+
+// Field name suffix of a unit's abbreviation, e.g. `km_h` for km/h
+fn unit_suffix(unit: &str) -> String {
+    unit.replace('°', "")
+        .replace('/', "_")
+        .replace('³', "3")
+        .to_lowercase()
+}
+
+/// Canonical field name of a measurement, suffixed with the unit its value
+/// is given in for the system of units
+pub fn key_in(measurement: &Measurement, system: UnitSystem) -> Option<String> {
+    let key = key(measurement)?;
+    let metric = unit_suffix(&measurement.unit_in(Some(UnitSystem::Metric)));
+    let unit = unit_suffix(&measurement.unit_in(Some(system)));
+    if unit == metric {
+        return Some(key);
+    }
+    Some(match key.strip_suffix(&format!("_{}", metric)) {
+        Some(name) => format!("{}_{}", name, unit),
+        None => format!("{}_{}", key, unit),
+    })
+}
+
+/// Builds the canonical payload for a record, with values in the system of
+/// units, optionally including the record as rtl_433 reported it under
+/// `raw`, and how its derived measurements were computed under `lineage`
+pub fn normalize(
+    record: &Record,
+    units: UnitSystem,
+    include_raw: bool,
+    include_lineage: bool,
+) -> serde_json::Value {
+    let mut measurements = serde_json::Map::new();
+    let mut lineage = serde_json::Map::new();
+    for measurement in &record.measurements {
+        let key = match key_in(measurement, units) {
+            Some(key) => key,
+            None => continue,
+        };
+        if let Some(l) = measurement.lineage().filter(|_| include_lineage) {
+            lineage.insert(key.clone(), serde_json::json!(l));
+        }
+        let value = match measurement {
+            Measurement::BatteryOk(ok) => serde_json::Value::Bool(*ok),
+            Measurement::Clock(_) => measurement.value().into(),
+            // Values are held as f32, so round away the noise their unit
+            // conversions leave behind
+            m => match m.numeric_in(Some(units)) {
+                Some(n) => ((n * 1000.0).round() / 1000.0).into(),
+                None => continue,
+            },
+        };
+        measurements.insert(key, value);
+    }
+    let field = |name: &str| record.record_json.get(name).cloned().unwrap_or_default();
+    let mut payload = serde_json::json!({
+        "sensor": record.sensor_id,
+        "model": field("model"),
+        "id": field("id"),
+        "channel": field("channel"),
+        "ts": record.timestamp.to_rfc3339(),
+        "measurements": measurements,
+    });
+    if let Some(ref radio) = record.radio {
+        payload["radio"] = radio.as_str().into();
+    }
+    if include_raw {
+        payload["raw"] = record.record_json.clone();
+    }
+    if !lineage.is_empty() {
+        payload["lineage"] = lineage.into();
+    }
+    payload
+}
@@ -0,0 +1,1173 @@
+//! The processing applied to each record between the radio and the sinks
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::Result;
+use uom::si::{f32::Length, length};
+
+use crate::api::{self, ApiState};
+use crate::archive;
+use crate::arrow;
+use crate::audit::{AuditChain, Auditor};
+use crate::automation::Automation;
+use crate::availability::{self, AvailabilityMonitor};
+use crate::battery::BatteryPredictor;
+use crate::burst::Assembler;
+use crate::config::{Config, ConfigError, RetainPolicy, SensorFilter, SensorPattern};
+use crate::control::{self, Command, Maintenance};
+use crate::counters;
+use crate::cwop;
+use crate::dedup::DedupCache;
+use crate::derive::DerivedCalculator;
+use crate::ecowitt;
+use crate::errors;
+use crate::events::{Event, EventBus};
+use crate::gateway;
+use crate::grace::StartupGrace;
+use crate::guardrails::Guardrails;
+use crate::history::{self, HistoryStore};
+use crate::latency;
+use crate::lifecycle::LifecycleTracker;
+use crate::meta::{self, MetaTracker};
+use crate::mirror::{self, Mirror};
+use crate::normalize::{self, PayloadFormat};
+use crate::power::PowerProfile;
+use crate::pressure::SeaLevelCorrection;
+use crate::profiles::Profiles;
+use crate::pws;
+use crate::quality::{self, QualityTracker};
+use crate::quirks::Quirks;
+use crate::radio::{Measurement, Record};
+use crate::radiotime;
+use crate::rain::RainTracker;
+use crate::sampling::Sampler;
+use crate::sink::MqttSink;
+use crate::stages::Stage;
+use crate::stats::Aggregator;
+use crate::summary;
+use crate::throttle::{Batcher, Throttle};
+use crate::topics::TopicTemplate;
+use crate::trace;
+use crate::units::UnitSystem;
+use crate::unparsed;
+use crate::usage;
+use crate::validate::Validator;
+use crate::webhook;
+
+/// Number of recent records kept per sensor for derived calculators, unless
+/// overridden with [`PipelineBuilder::history_len`]
+pub const DEFAULT_HISTORY_LEN: usize = 16;
+
+// How long the pipeline waits for a record before checking for commands and
+// scheduled profile switches
+const IDLE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Assembles a [`Pipeline`], with any custom calculators to run on records
+pub struct PipelineBuilder {
+    conf: Config,
+    sink: Option<MqttSink>,
+    calculators: Vec<Box<dyn DerivedCalculator>>,
+    history_len: usize,
+    events: EventBus,
+}
+
+impl PipelineBuilder {
+    pub fn new(conf: Config) -> Self {
+        PipelineBuilder {
+            conf,
+            sink: None,
+            calculators: Vec::new(),
+            history_len: DEFAULT_HISTORY_LEN,
+            events: EventBus::new(),
+        }
+    }
+
+    /// Publishes processed records to `sink`
+    pub fn sink(mut self, sink: MqttSink) -> Self {
+        self.sink = Some(sink);
+        self
+    }
+
+    /// Registers a calculator run on every processed record, after the
+    /// configured derivers and those already registered
+    pub fn calculator<C: DerivedCalculator + 'static>(mut self, calculator: C) -> Self {
+        self.calculators.push(Box::new(calculator));
+        self
+    }
+
+    /// Number of recent records kept per sensor for calculators
+    pub fn history_len(mut self, history_len: usize) -> Self {
+        self.history_len = history_len.max(1);
+        self
+    }
+
+    /// Publishes the pipeline's events on `events`, rather than on a bus of
+    /// its own
+    pub fn event_bus(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Creates the pipeline, starting any background publishers the
+    /// configuration calls for
+    pub fn build(self) -> Result<Pipeline> {
+        let mut profiles = Profiles::new(&self.conf)?;
+        let conf = match profiles.update(&self.conf, crate::clock::now())? {
+            Some(conf) => conf,
+            None => self.conf,
+        };
+        let events = self.events;
+        let sink = self.sink.map(|sink| sink.events(events.clone()));
+        let chain = conf.audit.as_ref().map(|_| AuditChain::new());
+        let sink = match chain {
+            Some(ref chain) => sink.map(|sink| sink.audit(chain.clone())),
+            None => sink,
+        };
+        let auditor = match (&sink, chain, &conf.audit) {
+            (Some(sink), Some(chain), Some(audit)) => {
+                Some(Auditor::spawn(sink.clone(), chain, audit.clone()))
+            }
+            _ => None,
+        };
+        let topics = conf.topics()?;
+        let filter = conf.sensor_filter()?;
+        let published = match conf.mqtt.first() {
+            Some(mqtt) => mqtt.sensor_patterns()?,
+            None => Vec::new(),
+        };
+        let retain = conf.retain_policy()?;
+        let throttle = conf.throttle()?;
+        let automation = Automation::new(&conf.automation)?;
+        let quirks = Quirks::new(&conf.quirks)?;
+        let bursts = Assembler::new(&conf.bursts)?;
+        let sampler = conf.sampler()?;
+        let stages = conf.stages()?;
+        let mut calculators: Vec<Box<dyn DerivedCalculator>> = Vec::new();
+        for stage in &stages {
+            match stage {
+                Stage::Rain { .. } => calculators.push(Box::new(
+                    RainTracker::new().daily(stage.rain_day_start(&conf)?, conf.rain_state.clone()),
+                )),
+                Stage::SeaLevel { .. } => {
+                    let altitude = stage.altitude(&conf).ok_or(ConfigError::PipelineAltitude)?;
+                    let altitude = Length::new::<length::meter>(altitude);
+                    calculators.push(Box::new(SeaLevelCorrection::new(altitude)));
+                }
+                Stage::Battery => calculators.push(Box::new(BatteryPredictor::new(
+                    conf.battery.clone().unwrap_or_default(),
+                    conf.battery_state.clone(),
+                ))),
+                _ => {}
+            }
+        }
+        calculators.extend(self.calculators);
+        let windows = match stages.iter().find(|s| matches!(s, Stage::Stats { .. })) {
+            Some(stage) => stage.stats_windows(&conf)?,
+            None => Vec::new(),
+        };
+        let stats = Aggregator::new(windows);
+        let batcher = match (&sink, conf.throttle.get_batch_interval()) {
+            (Some(sink), Some(interval)) => Some(Batcher::spawn(sink.clone(), interval)),
+            _ => None,
+        };
+        let recorder = match conf.history {
+            Some(ref h) => {
+                let store = HistoryStore::open(&h.path, h.retention_days)?;
+                Some(history::spawn_recorder(
+                    store,
+                    events.subscribe(),
+                    events.clone(),
+                ))
+            }
+            None => None,
+        };
+        let archiver = match conf.archive {
+            Some(ref archive) => Some(archive::spawn_archiver(
+                archive.clone(),
+                events.subscribe(),
+                events.clone(),
+            )?),
+            None => None,
+        };
+        // Brokers after the first are published to alongside it, whether or
+        // not it's up
+        let mut mirrors = mirror::connect_mirrors(&conf, &events)?;
+        for mirror in mirrors.iter_mut() {
+            mirror.batch(conf.throttle.get_batch_interval());
+        }
+        let mut uploaders = conf
+            .uploads
+            .iter()
+            .map(|upload| pws::spawn_uploader(upload.clone(), events.subscribe(), events.clone()))
+            .collect::<Result<Vec<JoinHandle<()>>, ConfigError>>()?;
+        for upload in &conf.ecowitt {
+            uploaders.push(ecowitt::spawn_uploader(
+                upload.clone(),
+                conf.instance_id,
+                conf.region,
+                events.subscribe(),
+                events.clone(),
+            )?);
+        }
+        if let Some(ref cwop) = conf.cwop {
+            uploaders.push(cwop::spawn_reporter(
+                cwop.clone(),
+                events.subscribe(),
+                events.clone(),
+            )?);
+        }
+        let notifier = match conf.webhooks.is_empty() {
+            true => None,
+            false => Some(webhook::spawn_notifier(
+                &conf.webhooks,
+                events.subscribe(),
+                events.clone(),
+            )?),
+        };
+        let comparer = match conf.gateway {
+            Some(ref gateway) => Some(gateway::spawn_comparer(
+                gateway.clone(),
+                sink.clone(),
+                events.subscribe(),
+                events.clone(),
+            )?),
+            None => None,
+        };
+        let radio_time = conf.radio_time.as_ref().map(|radio_time| {
+            radiotime::spawn_monitor(
+                radio_time.clone(),
+                sink.clone(),
+                events.subscribe(),
+                events.clone(),
+            )
+        });
+        let unparsed =
+            unparsed::spawn_reporter(conf.unparsed.clone(), sink.clone(), events.subscribe());
+        let usage = conf
+            .usage
+            .as_ref()
+            .map(|usage| usage::spawn_reporter(usage.clone(), events.subscribe(), events.clone()));
+        let summary = conf.get_log_summary().map(|interval| {
+            summary::spawn_logger(events.subscribe(), interval, conf.sensor_labels())
+        });
+        let telemetry = match (&sink, conf.get_telemetry_interval()) {
+            (Some(sink), Some(interval)) => Some(latency::spawn_reporter(
+                sink.clone(),
+                events.subscribe(),
+                interval,
+            )),
+            _ => None,
+        };
+        let counters = match (&sink, conf.get_telemetry_interval()) {
+            (Some(sink), Some(interval)) => Some(counters::spawn_reporter(
+                sink.clone(),
+                events.subscribe(),
+                interval,
+            )),
+            _ => None,
+        };
+        let quality = QualityTracker::new();
+        let quality_tracker = quality::spawn_tracker(
+            quality.clone(),
+            events.subscribe(),
+            sink.clone(),
+            conf.get_telemetry_interval(),
+        );
+        let api = match conf.api_listen {
+            Some(ref listen) => {
+                let state = ApiState::new(
+                    quality.clone(),
+                    conf.known_sensors(),
+                    conf.payload_include_raw,
+                    conf.payload_include_lineage,
+                    conf.get_sensor_timeout(),
+                );
+                Some(api::spawn_server(listen, state, events.subscribe())?)
+            }
+            None => None,
+        };
+        let arrow = match conf.arrow_listen {
+            Some(ref listen) => Some(arrow::spawn_server(
+                listen,
+                conf.get_arrow_window(),
+                events.subscribe(),
+            )?),
+            None => None,
+        };
+        let errors = sink
+            .as_ref()
+            .map(|sink| errors::spawn_reporter(sink.clone(), events.subscribe()));
+        let maintenance = Maintenance::new();
+        let commands = match sink {
+            Some(ref sink) => Some(control::spawn_listener(
+                sink,
+                maintenance.clone(),
+                conf.control.clone(),
+            )?),
+            None => None,
+        };
+        let grace = StartupGrace::new(conf.get_startup_grace());
+        let availability_monitor = AvailabilityMonitor::new(conf.get_sensor_timeout());
+        availability_monitor.spawn_watchdog(
+            sink.clone(),
+            events.clone(),
+            maintenance.clone(),
+            grace,
+        );
+        let mut lifecycle =
+            LifecycleTracker::new(conf.get_sensor_timeout(), conf.get_sensor_lost_timeout());
+        if let Some(ref path) = conf.sensor_state {
+            lifecycle = lifecycle.state_file(path.clone());
+            for (sensor_id, sensor_topic, last_seen) in lifecycle.active() {
+                availability_monitor.restore(&sensor_id, &sensor_topic, last_seen);
+            }
+        }
+        lifecycle.spawn_timer(sink.clone(), events.clone(), maintenance.clone(), grace);
+        // Without a broker to publish them to, records aren't held back for
+        // validation at all
+        let validator = sink.as_ref().and(conf.validation_delay).map(|delay| {
+            Validator::new(std::time::Duration::from_secs(delay))
+                .confirmations(conf.get_validation_confirmations())
+                .grace(grace)
+                .log_level(conf.get_record_log_level())
+        });
+        if let (Some(sink), Some(validator)) = (&sink, &validator) {
+            validator.spawn_publisher(sink.clone());
+        }
+        let guardrails = Guardrails::new(conf.limits.clone());
+        let meta_tracker = MetaTracker::new(conf.units);
+        let dedup = DedupCache::new(conf.get_dedup_window());
+        let profile = conf.power.profile;
+        let mut pipeline = Pipeline {
+            conf,
+            topics,
+            filter,
+            published,
+            retain,
+            throttle,
+            batcher,
+            auditor,
+            automation,
+            quirks,
+            bursts,
+            sampler,
+            stats,
+            sink,
+            dedup,
+            history: HashMap::new(),
+            history_len: self.history_len,
+            calculators,
+            stages,
+            events,
+            recorder,
+            archiver,
+            mirrors,
+            uploaders,
+            notifier,
+            comparer,
+            radio_time,
+            unparsed,
+            usage,
+            summary,
+            telemetry,
+            counters,
+            errors,
+            quality,
+            quality_tracker,
+            api,
+            arrow,
+            meta_tracker,
+            availability_monitor,
+            lifecycle,
+            guardrails,
+            validator,
+            maintenance,
+            commands,
+            profiles,
+        };
+        pipeline.set_power_profile(profile);
+        Ok(pipeline)
+    }
+}
+
+/// Filters, deduplicates, and enriches records, and publishes them to the
+/// configured sink
+pub struct Pipeline {
+    conf: Config,
+    topics: TopicTemplate,
+    filter: SensorFilter,
+    // Sensors published to the first broker, or every sensor when empty
+    published: Vec<SensorPattern>,
+    retain: RetainPolicy,
+    throttle: Throttle,
+    batcher: Option<Batcher>,
+    auditor: Option<Auditor>,
+    automation: Automation,
+    quirks: Quirks,
+    bursts: Assembler,
+    sampler: Sampler,
+    stats: Aggregator,
+    sink: Option<MqttSink>,
+    dedup: DedupCache,
+    history: HashMap<String, VecDeque<Record>>,
+    history_len: usize,
+    calculators: Vec<Box<dyn DerivedCalculator>>,
+    stages: Vec<Stage>,
+    events: EventBus,
+    recorder: Option<JoinHandle<()>>,
+    archiver: Option<JoinHandle<()>>,
+    mirrors: Vec<Mirror>,
+    uploaders: Vec<JoinHandle<()>>,
+    notifier: Option<JoinHandle<()>>,
+    comparer: Option<JoinHandle<()>>,
+    radio_time: Option<JoinHandle<()>>,
+    unparsed: JoinHandle<()>,
+    usage: Option<JoinHandle<()>>,
+    summary: Option<JoinHandle<()>>,
+    telemetry: Option<JoinHandle<()>>,
+    counters: Option<JoinHandle<()>>,
+    errors: Option<JoinHandle<()>>,
+    quality: QualityTracker,
+    quality_tracker: JoinHandle<()>,
+    api: Option<JoinHandle<()>>,
+    arrow: Option<JoinHandle<()>>,
+    meta_tracker: MetaTracker,
+    availability_monitor: AvailabilityMonitor,
+    lifecycle: LifecycleTracker,
+    guardrails: Guardrails,
+    validator: Option<Validator>,
+    maintenance: Maintenance,
+    commands: Option<std::sync::mpsc::Receiver<Command>>,
+    profiles: Profiles,
+}
+
+impl Pipeline {
+    /// Creates a pipeline with the default calculators, publishing to `sink`
+    pub fn new(conf: Config, sink: Option<MqttSink>) -> Result<Self> {
+        let builder = PipelineBuilder::new(conf);
+        match sink {
+            Some(sink) => builder.sink(sink).build(),
+            None => builder.build(),
+        }
+    }
+
+    /// The bus the pipeline publishes its events on, for components that
+    /// want to subscribe to them
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Processes every record from `records`, until they run out, applying
+    /// the commands received over the control topics and the profile
+    /// schedule as they come due
+    pub fn run<I>(&mut self, records: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Record>,
+        I::IntoIter: Send + 'static,
+    {
+        self.events.publish(Event::RadioStarted);
+        // Records are read on a thread of their own, so that a quiet or
+        // sleeping radio doesn't hold up commands or profile switches. The
+        // next record is only read once the last is processed, as a replay's
+        // clock moves on as it's read.
+        let (wanted, want) = std::sync::mpsc::sync_channel::<()>(1);
+        let (sender, received) = std::sync::mpsc::sync_channel(0);
+        let mut records = records.into_iter();
+        std::thread::spawn(move || {
+            while want.recv().is_ok() {
+                match records.next() {
+                    Some(record) => {
+                        if sender.send(record).is_err() {
+                            return;
+                        }
+                    }
+                    None => return,
+                }
+            }
+        });
+        let _ = wanted.send(());
+        loop {
+            match received.recv_timeout(IDLE_PERIOD) {
+                Ok(record) => {
+                    self.process(record)?;
+                    let _ = wanted.send(());
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.apply_commands()?;
+                    self.update_profile();
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+    }
+
+    /// Processes a single record
+    pub fn process(&mut self, record: Record) -> Result<()> {
+        self.apply_commands()?;
+        self.update_profile();
+        if trace::is_traced(&record) {
+            log::info!(target: trace::TARGET, "[{}] Received {}", record.sensor_id, record.record_json);
+        }
+        self.process_from(record, 0)
+    }
+
+    // Runs a record through the stages from the `first`, and on to the sinks
+    fn process_from(&mut self, mut record: Record, first: usize) -> Result<()> {
+        let traced = trace::is_traced(&record);
+        // Filters and calibrators are run in the configured order, which
+        // puts every filter before the calibrators
+        let mut validated = self.stages[..first].contains(&Stage::Dedup);
+        let mut burst = None;
+        for (i, stage) in self.stages.iter().enumerate().skip(first) {
+            // Bursts go on through the rest of the stages once assembled
+            if *stage == Stage::Burst {
+                burst = Some(i + 1);
+                break;
+            }
+            let kept = match stage {
+                Stage::SensorFilter => !self.filter.is_ignored(&record.sensor_id),
+                Stage::Sample => {
+                    let kept = self.sampler.keep(&record);
+                    if !kept {
+                        log::trace!("[{}] Sampled out", record.sensor_id);
+                    }
+                    kept
+                }
+                Stage::Quirks => {
+                    self.quirks.apply(&mut record);
+                    true
+                }
+                Stage::Guardrails => self.guardrails.check(&record).is_ok(),
+                Stage::Dedup => {
+                    // Repeated transmissions are what confirm a reading to
+                    // the validator, so it sees them before they're dropped
+                    self.observe_validated(&record);
+                    validated = true;
+                    let duplicate = self.dedup.is_duplicate(&record);
+                    if duplicate {
+                        log::trace!("Duplicate record.");
+                        counters::count(|counters| counters.dedup_drops += 1);
+                    }
+                    !duplicate
+                }
+                Stage::ProbeNames => {
+                    self.name_probes(&mut record);
+                    true
+                }
+                Stage::Calibrate => {
+                    self.calibrate(&mut record);
+                    true
+                }
+                _ => true,
+            };
+            if !kept {
+                if traced {
+                    log::info!(target: trace::TARGET, "[{}] Dropped by {}", record.sensor_id, stage.name());
+                }
+                return Ok(());
+            }
+        }
+        if let Some(next) = burst {
+            let sensor_id = record.sensor_id.clone();
+            let mut result = Ok(());
+            for assembled in self.bursts.assemble(record) {
+                result = result.and(self.process_from(assembled, next));
+            }
+            if traced && self.bursts.is_pending(&sensor_id) {
+                log::info!(target: trace::TARGET, "[{}] Held for the rest of its burst", sensor_id);
+            }
+            return result;
+        }
+        if !validated {
+            self.observe_validated(&record);
+        }
+        let location = self.conf.location_of(&record.sensor_id).map(str::to_owned);
+        let location = location.as_deref();
+        let sensor_topic = self.topics.render(&record, location, None);
+        // Records from sensors in maintenance still feed history and the
+        // event bus, but nothing about them is published or alerted on
+        let paused = self.maintenance.is_active(&record.sensor_id);
+        let published = self.is_published(&record.sensor_id);
+        let history = self.history.entry(record.sensor_id.clone()).or_default();
+        if history.len() >= self.history_len {
+            history.pop_front();
+        }
+        history.push_back(record.clone());
+        let history = history.make_contiguous();
+        for calculator in self.calculators.iter_mut() {
+            record.measurements.extend(calculator.calculate(history));
+        }
+        if traced {
+            let measurements: Vec<String> = record
+                .measurements
+                .iter()
+                .map(|m| {
+                    format!(
+                        "{}={}",
+                        m.name_in(self.conf.units),
+                        m.value_in(self.conf.units)
+                    )
+                })
+                .collect();
+            log::info!(target: trace::TARGET, "[{}] Measurements {}", record.sensor_id, measurements.join(", "));
+        }
+        self.stats.observe(&record);
+        log::trace!("[RECORD] {} {}", record.timestamp, record.sensor_id);
+        if self.stages.contains(&Stage::Events) {
+            self.events.publish(Event::Record(record.clone()));
+        }
+        // Availability and lifecycle are announced on the first broker's
+        // topics, so they're only tracked for the sensors published there
+        let newly_seen = published
+            && self
+                .availability_monitor
+                .seen(&record.sensor_id, &sensor_topic);
+        if newly_seen {
+            self.events.publish(Event::SensorOnline {
+                sensor_id: record.sensor_id.clone(),
+                topic: sensor_topic.clone(),
+            });
+        }
+        let transitions = if published {
+            self.lifecycle.observe(&record, &sensor_topic, location)
+        } else {
+            Vec::new()
+        };
+        if paused {
+            log::debug!("Sensor {} in maintenance, not publishing", record.sensor_id);
+            if traced {
+                log::info!(target: trace::TARGET, "[{}] In maintenance, not publishing", record.sensor_id);
+            }
+        }
+        if !published {
+            log::trace!(
+                "Sensor {} not published to the first broker",
+                record.sensor_id
+            );
+        }
+        let sink = self.sink.clone().filter(|_| !paused && published);
+        let mirrored = !paused
+            && self
+                .mirrors
+                .iter()
+                .any(|mirror| mirror.accepts(&record.sensor_id));
+        // Throttled records still feed history and the event bus, they're
+        // just not published
+        let publishing = (sink.is_some() || mirrored) && self.stages.contains(&Stage::Mqtt);
+        let throttled = publishing && !self.throttle.admit(&record);
+        if throttled {
+            log::trace!("Sensor {} throttled, not publishing", record.sensor_id);
+            if traced {
+                log::info!(target: trace::TARGET, "[{}] Throttled, not publishing", record.sensor_id);
+            }
+        }
+        let mut result = match sink.as_ref().filter(|_| publishing && !throttled) {
+            Some(_) => self.publish(&record, location, &sensor_topic, newly_seen),
+            None => Ok(()),
+        };
+        if mirrored && publishing && !throttled {
+            self.mirror(&record, location);
+        }
+        for transition in transitions {
+            result = result.and(transition.publish(sink.as_ref(), &self.events));
+        }
+        if !paused {
+            self.automation
+                .evaluate(&record, self.sink.as_ref(), &self.events);
+        }
+        if let (Err(e), Some(sink)) = (&result, &self.sink) {
+            self.events.publish(Event::SinkError {
+                sink: sink.broker().to_owned(),
+                error: format!("{:#}", e),
+            });
+        }
+        result
+    }
+
+    /// Applies the commands received over the control topics since the last
+    /// record, or since they were last checked for
+    fn apply_commands(&mut self) -> Result<()> {
+        let commands: Vec<Command> = match self.commands {
+            Some(ref commands) => commands.try_iter().collect(),
+            None => return Ok(()),
+        };
+        for command in commands {
+            match command {
+                Command::Ignore { sensor_id, ignore } => {
+                    if !ignore {
+                        self.conf.sensor_ignores.remove(&sensor_id);
+                        self.filter = self.conf.sensor_filter()?;
+                    } else if self.conf.sensor_ignores.insert(sensor_id.clone()) {
+                        // The sensor id may be a pattern, which a typo can
+                        // leave invalid
+                        match self.conf.sensor_filter() {
+                            Ok(filter) => self.filter = filter,
+                            Err(e) => {
+                                log::warn!("{:#}", e);
+                                self.conf.sensor_ignores.remove(&sensor_id);
+                            }
+                        }
+                    }
+                }
+                Command::Alias { sensor_id, alias } => {
+                    self.topics.set_alias(&sensor_id, alias.as_deref());
+                    for mirror in self.mirrors.iter_mut() {
+                        mirror.set_alias(&sensor_id, alias.as_deref());
+                    }
+                    match alias {
+                        Some(alias) => self.conf.aliases.insert(sensor_id.clone(), alias),
+                        None => self.conf.aliases.remove(&sensor_id),
+                    };
+                    self.republish_meta(Some(&sensor_id))?;
+                }
+                Command::Calibrate {
+                    sensor_id,
+                    measurement,
+                    offset,
+                } => {
+                    let offsets = self.conf.calibration.entry(sensor_id.clone()).or_default();
+                    match offset {
+                        Some(offset) => offsets.insert(measurement, offset),
+                        None => offsets.remove(&measurement),
+                    };
+                    self.conf
+                        .calibration
+                        .retain(|_, offsets| !offsets.is_empty());
+                    self.republish_meta(Some(&sensor_id))?;
+                }
+                Command::StatusDump => self.publish_status()?,
+                Command::Power(profile) => self.set_power_profile(profile),
+                Command::Profile(profile) => {
+                    if let Err(e) = self.profiles.choose(profile) {
+                        log::warn!("{:#}", e);
+                    }
+                }
+                Command::Trace(patterns) => match trace::set(&patterns) {
+                    Ok(()) => self.conf.trace = patterns,
+                    Err(e) => log::warn!("{}", e),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Switches to the configuration profile that should be active, if it
+    /// isn't already, keeping the current settings if the profile's can't be
+    /// applied
+    fn update_profile(&mut self) {
+        let conf = match self.profiles.update(&self.conf, crate::clock::now()) {
+            Ok(Some(conf)) => conf,
+            Ok(None) => return,
+            Err(e) => {
+                log::error!("Failed to apply configuration profile: {:#}", e);
+                return;
+            }
+        };
+        if let Err(e) = self.reconfigure(conf) {
+            log::error!("Failed to apply configuration profile: {:#}", e);
+            return;
+        }
+        // The profile may have renamed, relocated or recalibrated sensors
+        if let Err(e) = self.republish_meta(None) {
+            log::error!("Failed to republish sensor metadata: {:#}", e);
+        }
+    }
+
+    /// Takes on the settings of `conf` that are applied to each record
+    fn reconfigure(&mut self, conf: Config) -> Result<()> {
+        let filter = conf.sensor_filter()?;
+        let retain = conf.retain_policy()?;
+        let throttle = conf.throttle()?;
+        let automation = Automation::new(&conf.automation)?;
+        let quirks = Quirks::new(&conf.quirks)?;
+        let sampler = conf.sampler()?;
+        self.filter = filter;
+        self.retain = retain;
+        self.throttle = throttle;
+        self.automation = automation;
+        self.quirks = quirks;
+        self.sampler = sampler;
+        self.guardrails = Guardrails::new(conf.limits.clone());
+        // The power profile is switched to afterwards, which puts its floor
+        // on the new throttle
+        let profile = conf.power.profile;
+        let current = self.conf.power.profile;
+        self.conf = conf;
+        self.conf.power.profile = current;
+        self.set_power_profile(profile);
+        Ok(())
+    }
+
+    /// Publishes less often in the low-power profile, and has the receivers
+    /// follow the profile through the event bus
+    fn set_power_profile(&mut self, profile: PowerProfile) {
+        if profile != self.conf.power.profile {
+            log::info!("Switching to {:?} power profile", profile);
+        }
+        self.conf.power.profile = profile;
+        let low_power = profile == PowerProfile::Low;
+        self.throttle
+            .set_floor(Some(self.conf.power.get_min_publish_interval()).filter(|_| low_power));
+        // Batching that's configured outright stays on in either profile
+        if self.conf.throttle.get_batch_interval().is_none() {
+            match (low_power, self.batcher.take(), &self.sink) {
+                (true, None, Some(sink)) => {
+                    let interval = self.conf.power.get_batch_interval();
+                    self.batcher = Some(Batcher::spawn(sink.clone(), interval));
+                }
+                (true, batcher, _) => self.batcher = batcher,
+                (false, Some(batcher), _) => batcher.finish(),
+                (false, None, _) => {}
+            }
+            let interval = Some(self.conf.power.get_batch_interval()).filter(|_| low_power);
+            for mirror in self.mirrors.iter_mut() {
+                mirror.batch(interval);
+            }
+        }
+        self.events.publish(Event::PowerProfile(profile));
+    }
+
+    /// Republishes the `$meta` of the sensor `sensor_id`, or of every sensor
+    /// heard from, where the configuration changes what it describes, from
+    /// the sensor's latest record
+    fn republish_meta(&mut self, sensor_id: Option<&str>) -> Result<()> {
+        let sink = match self.sink {
+            Some(ref sink) if self.stages.contains(&Stage::Mqtt) => sink,
+            _ => return Ok(()),
+        };
+        for (id, records) in &self.history {
+            if sensor_id.is_some_and(|sensor_id| sensor_id != id)
+                || self.maintenance.is_active(id)
+                || !self.is_published(id)
+            {
+                continue;
+            }
+            let record = match records.back() {
+                Some(record) => record,
+                None => continue,
+            };
+            let quality = self.quality.score(id).map(|q| q.score);
+            if let Some(sensor_meta) = self.meta_tracker.update(record, &self.conf, quality) {
+                let location = self.conf.location_of(id);
+                let sensor_topic = self.topics.render(record, location, None);
+                let topic = format!("{}/{}", sensor_topic, meta::META_SUFFIX);
+                sink.publish_retained(&topic, serde_json::to_vec(&sensor_meta)?, sink.qos())?;
+                log::debug!("mqtt <== {} (retained)", topic);
+            }
+        }
+        Ok(())
+    }
+
+    /// Publishes the configuration that can be changed at runtime, and the
+    /// sensors heard from so far
+    fn publish_status(&self) -> Result<()> {
+        if let Some(ref sink) = self.sink {
+            let status = serde_json::json!({
+                "version": clap::crate_version!(),
+                "instance_id": self.conf.instance_id,
+                "sensors": self.history.keys().collect::<std::collections::BTreeSet<_>>(),
+                "sensor_ignores": self.conf.sensor_ignores,
+                "sensor_allows": self.conf.sensor_allows,
+                "trace": self.conf.trace,
+                "aliases": self.conf.aliases,
+                "calibration": self.conf.calibration,
+                "locations": self.conf.locations,
+                "sensor_info": self.conf.sensors,
+                "maintenance": self.maintenance.snapshot(),
+                "control": self.conf.control,
+                "sampled_out": self.sampler.dropped(),
+                "power": self.conf.power.profile,
+                "profile": self.profiles.active(),
+                "quality": self.quality.report(),
+                "latency": sink.latency().peek(),
+            });
+            let topic = control::status_dump_topic(sink.status_topic());
+            sink.publish(&topic, serde_json::to_vec(&status)?)?;
+            log::info!("mqtt <== {}({})", topic, status);
+        }
+        Ok(())
+    }
+
+    /// Whether the sensor's records are published to the first broker, as
+    /// matching its `sensors`
+    fn is_published(&self, sensor_id: &str) -> bool {
+        self.published.is_empty()
+            || self
+                .published
+                .iter()
+                .any(|pattern| pattern.matches(sensor_id))
+    }
+
+    /// Hands a record to the validator, unless its sensor is in maintenance
+    /// or isn't published
+    fn observe_validated(&self, record: &Record) {
+        let validator = match self.validator {
+            Some(ref validator) => validator,
+            None => return,
+        };
+        if !self.maintenance.is_active(&record.sensor_id) && self.is_published(&record.sensor_id) {
+            let location = self.conf.location_of(&record.sensor_id);
+            validator.observe(&self.topics.render(record, location, None), record);
+        }
+    }
+
+    /// Labels a record's probe measurements with the probes' configured names
+    fn name_probes(&self, record: &mut Record) {
+        let model = record.record_json.get("model").and_then(|m| m.as_str());
+        for measurement in record.measurements.iter_mut() {
+            if let Measurement::ProbeTemperature(probe, t) = measurement {
+                let number = probe.trim_start_matches("Probe");
+                if let Some(name) = self.conf.probe_name(&record.sensor_id, model, number) {
+                    *measurement = Measurement::ProbeTemperature(name.to_owned(), *t);
+                }
+            }
+        }
+    }
+
+    /// Applies the configured calibration offsets to a record's measurements
+    fn calibrate(&self, record: &mut Record) {
+        let offsets = match self.conf.calibration.get(&record.sensor_id) {
+            Some(offsets) => offsets,
+            None => return,
+        };
+        for measurement in record.measurements.iter_mut() {
+            let name = measurement.name();
+            if let Some(offset) = offsets.get(&name) {
+                match measurement.calibrated(*offset) {
+                    Some(calibrated) => *measurement = calibrated,
+                    None => log::debug!("[{}] {} can't be calibrated", record.sensor_id, name),
+                }
+            }
+        }
+    }
+
+    /// Publishes a processed record, its derived measurements, and any change
+    /// in its sensor's availability or metadata to the sink
+    fn publish(
+        &mut self,
+        record: &Record,
+        location: Option<&str>,
+        sensor_topic: &str,
+        newly_seen: bool,
+    ) -> Result<()> {
+        if let Some(ref sink) = self.sink {
+            let payload = self.payload(record);
+            let retained = self.retain.retains(&record.sensor_id);
+            let level = self.conf.get_record_log_level();
+            let traced = trace::is_traced(record);
+            if traced {
+                log::info!(target: trace::TARGET, "[{}] Publishing {}({})", record.sensor_id, sensor_topic, payload);
+            }
+            if let Some(ref batcher) = self.batcher {
+                log::log!(level, sensor_id = record.sensor_id.as_str(); "batch <== {}({})", sensor_topic, payload);
+                batcher.add(sensor_topic, payload, record.timestamp);
+                counters::count(|counters| counters.records_published += 1);
+            } else {
+                sink.publish_reading(
+                    sensor_topic,
+                    serde_json::to_vec(&payload)?,
+                    record,
+                    retained,
+                )?;
+                counters::count(|counters| counters.records_published += 1);
+                log::log!(level, sensor_id = record.sensor_id.as_str(); "mqtt <== {}({})", sensor_topic, payload);
+            }
+            // Derived measurements aren't part of the radio's json record, so
+            // they get published to their own topics, as do probes so that
+            // each can be subscribed to by name
+            let own_topic =
+                |m: &&Measurement| m.is_derived() || matches!(m, Measurement::ProbeTemperature(..));
+            for measurement in record.measurements.iter().filter(own_topic) {
+                let topic = self.topics.render(
+                    record,
+                    location,
+                    Some(&measurement.name_in(self.conf.units)),
+                );
+                let value = measurement.value_in(self.conf.units);
+                if retained {
+                    sink.publish_retained(&topic, value.as_str(), sink.qos())?;
+                } else {
+                    sink.publish(&topic, value.as_str())?;
+                }
+                log::log!(level, sensor_id = record.sensor_id.as_str(); "mqtt <== {}({})", topic, value);
+                if traced {
+                    log::info!(target: trace::TARGET, "[{}] Publishing {}({})", record.sensor_id, topic, value);
+                }
+            }
+            for (window, summaries) in self.stats.summarize(&record.sensor_id, record.timestamp) {
+                let topic = window.topic(sensor_topic);
+                let payload = serde_json::to_vec(&summaries)?;
+                if retained {
+                    sink.publish_retained(&topic, payload, sink.qos())?;
+                } else {
+                    sink.publish(&topic, payload)?;
+                }
+                log::debug!("mqtt <== {}({} measurements)", topic, summaries.len());
+            }
+            if newly_seen {
+                let topic = availability::sensor_topic(sensor_topic);
+                sink.publish_retained(&topic, availability::ONLINE, 1)?;
+                log::debug!("mqtt <== {}({})", topic, availability::ONLINE);
+            }
+            if let Some(sensor_meta) = self.meta_tracker.update(
+                record,
+                &self.conf,
+                self.quality.score(&record.sensor_id).map(|q| q.score),
+            ) {
+                let topic = format!("{}/{}", sensor_topic, meta::META_SUFFIX);
+                sink.publish_retained(&topic, serde_json::to_vec(&sensor_meta)?, sink.qos())?;
+                log::debug!("mqtt <== {} (retained)", topic);
+            }
+        }
+        Ok(())
+    }
+
+    /// Publishes a processed record and its derived measurements to the
+    /// brokers after the first that take its sensor's records
+    fn mirror(&self, record: &Record, location: Option<&str>) {
+        let payload = self.payload(record);
+        let retained = self.retain.retains(&record.sensor_id);
+        for mirror in self
+            .mirrors
+            .iter()
+            .filter(|mirror| mirror.accepts(&record.sensor_id))
+        {
+            if let Err(e) = mirror.publish(&self.conf, record, location, &payload, retained) {
+                log::warn!("Mirroring to {} failed: {:#}", mirror.broker(), e);
+                self.events.publish(Event::SinkError {
+                    sink: mirror.broker().to_owned(),
+                    error: format!("{:#}", e),
+                });
+            }
+        }
+    }
+
+    /// The payload a record is published with, in the configured format
+    fn payload(&self, record: &Record) -> serde_json::Value {
+        match self.conf.payload_format {
+            PayloadFormat::Raw => record.record_json.clone(),
+            PayloadFormat::Normalized => normalize::normalize(
+                record,
+                self.conf.units.unwrap_or(UnitSystem::Metric),
+                self.conf.payload_include_raw,
+                self.conf.payload_include_lineage,
+            ),
+        }
+    }
+
+    /// Shuts down the pipeline, waiting for subscribers like the history
+    /// recorder to catch up, and disconnecting from the sink
+    pub fn finish(mut self) -> Result<()> {
+        if let Some(burst) = self.stages.iter().position(|s| *s == Stage::Burst) {
+            for assembled in self.bursts.flush() {
+                if let Err(e) = self.process_from(assembled, burst + 1) {
+                    log::warn!(
+                        "Failed to publish a burst held as the bridge stopped: {:#}",
+                        e
+                    );
+                }
+            }
+        }
+        if self.bursts.partial() > 0 {
+            log::info!("{} bursts were partial", self.bursts.partial());
+        }
+        self.events.publish(Event::RadioStopped);
+        self.lifecycle.save();
+        for (limit, count) in self.guardrails.dropped() {
+            log::warn!("Dropped {} records exceeding the {} limit", count, limit);
+        }
+        for (model, count) in self.sampler.dropped() {
+            log::info!("Sampled out {} {} records", count, model);
+        }
+        if let Some(batcher) = self.batcher {
+            batcher.finish();
+        }
+        if let Some(auditor) = self.auditor {
+            auditor.finish();
+        }
+        if let Some(recorder) = self.recorder {
+            if recorder.join().is_err() {
+                log::error!("History recorder panicked");
+            }
+        }
+        if let Some(archiver) = self.archiver {
+            if archiver.join().is_err() {
+                log::error!("Archiver panicked");
+            }
+        }
+        for mirror in self.mirrors {
+            mirror.disconnect();
+        }
+        for uploader in self.uploaders {
+            if uploader.join().is_err() {
+                log::error!("Weather network uploader panicked");
+            }
+        }
+        if let Some(notifier) = self.notifier {
+            if notifier.join().is_err() {
+                log::error!("Webhook notifier panicked");
+            }
+        }
+        if let Some(comparer) = self.comparer {
+            if comparer.join().is_err() {
+                log::error!("Gateway comparer panicked");
+            }
+        }
+        if let Some(radio_time) = self.radio_time {
+            if radio_time.join().is_err() {
+                log::error!("Radio time monitor panicked");
+            }
+        }
+        if self.unparsed.join().is_err() {
+            log::error!("Unparsed record reporter panicked");
+        }
+        if let Some(usage) = self.usage {
+            if usage.join().is_err() {
+                log::error!("Usage statistics reporter panicked");
+            }
+        }
+        if let Some(summary) = self.summary {
+            if summary.join().is_err() {
+                log::error!("Summary logger panicked");
+            }
+        }
+        if let Some(telemetry) = self.telemetry {
+            if telemetry.join().is_err() {
+                log::error!("Latency reporter panicked");
+            }
+        }
+        if let Some(counters) = self.counters {
+            if counters.join().is_err() {
+                log::error!("Bridge stats reporter panicked");
+            }
+        }
+        if self.quality_tracker.join().is_err() {
+            log::error!("Quality tracker panicked");
+        }
+        if let Some(api) = self.api {
+            if api.join().is_err() {
+                log::error!("API state tracker panicked");
+            }
+        }
+        if let Some(arrow) = self.arrow {
+            if arrow.join().is_err() {
+                log::error!("Arrow stream server panicked");
+            }
+        }
+        for (sensor_id, quality) in self.quality.report() {
+            log::debug!("[{}] Quality score: {:?}", sensor_id, quality);
+        }
+        if let Some(errors) = self.errors {
+            if errors.join().is_err() {
+                log::error!("Error reporter panicked");
+            }
+        }
+        if let Some(sink) = self.sink {
+            sink.disconnect()?;
+        }
+        Ok(())
+    }
+}
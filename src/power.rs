@@ -0,0 +1,192 @@
+//! Power profiles, for off-grid stations running from a small solar panel
+//!
+//! The low-power profile publishes less often, batching records and holding
+//! each sensor to a longer interval between publishes, and duty cycles the
+//! receivers: they listen for a while, then sleep. rtl_433 radios are
+//! suspended while they sleep, and the RTL-SDR dongle isn't read.
+//!
+//! The profile is chosen with `power` in the configuration file, or at
+//! runtime over the `power` control topic once that's allowed.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::ConfigError;
+use crate::events::{Event, EventBus};
+
+/// How hard the bridge works to save power
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerProfile {
+    /// Records are published as they're received, and receivers always
+    /// listen
+    #[default]
+    Normal,
+    /// Records are throttled and batched, and receivers are duty cycled
+    Low,
+}
+
+impl std::str::FromStr for PowerProfile {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(Self::Normal),
+            "low" => Ok(Self::Low),
+            _ => Err(ConfigError::PowerProfile(s.to_owned())),
+        }
+    }
+}
+
+/// The power profile, and what the low-power one does
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PowerConfig {
+    #[serde(default)]
+    pub profile: PowerProfile,
+    /// Seconds between the records published for each sensor in the
+    /// low-power profile, where the throttle settings don't call for longer
+    #[serde(default)]
+    pub min_publish_interval: Option<u64>,
+    /// Seconds over which records are batched in the low-power profile,
+    /// unless batching is already configured
+    #[serde(default)]
+    pub batch_interval: Option<u64>,
+    /// Seconds receivers listen for in each duty cycle
+    #[serde(default)]
+    pub listen: Option<u64>,
+    /// Seconds receivers sleep for in each duty cycle; 0 keeps them
+    /// listening
+    #[serde(default)]
+    pub sleep: Option<u64>,
+}
+
+impl PowerConfig {
+    pub fn get_min_publish_interval(&self) -> Duration {
+        Duration::from_secs(self.min_publish_interval.unwrap_or(300))
+    }
+
+    pub fn get_batch_interval(&self) -> Duration {
+        Duration::from_secs(self.batch_interval.unwrap_or(60).max(1))
+    }
+
+    pub fn get_listen(&self) -> Duration {
+        Duration::from_secs(self.listen.unwrap_or(60).max(1))
+    }
+
+    pub fn get_sleep(&self) -> Duration {
+        Duration::from_secs(self.sleep.unwrap_or(240))
+    }
+}
+
+/// When receivers should sleep, following the power profile as the pipeline
+/// changes it. Cloning produces another handle to the same state.
+#[derive(Clone, Debug)]
+pub struct DutyCycle {
+    low_power: Arc<AtomicBool>,
+    listen: Duration,
+    sleep: Duration,
+}
+
+impl DutyCycle {
+    /// Starts in the configured profile, following the changes published on
+    /// `events` until the radio stops
+    pub fn follow(conf: &PowerConfig, events: &EventBus) -> Self {
+        let low_power = Arc::new(AtomicBool::new(conf.profile == PowerProfile::Low));
+        let events = events.subscribe();
+        let flag = low_power.clone();
+        std::thread::spawn(move || {
+            for event in events {
+                match event {
+                    Event::PowerProfile(profile) => {
+                        flag.store(profile == PowerProfile::Low, Ordering::Relaxed)
+                    }
+                    Event::RadioStopped => return,
+                    _ => {}
+                }
+            }
+        });
+        DutyCycle {
+            low_power,
+            listen: conf.get_listen(),
+            sleep: conf.get_sleep(),
+        }
+    }
+
+    /// Whether receivers are currently duty cycled
+    pub fn is_active(&self) -> bool {
+        !self.sleep.is_zero() && self.low_power.load(Ordering::Relaxed)
+    }
+
+    /// How long receivers listen for in each cycle
+    pub fn listen(&self) -> Duration {
+        self.listen
+    }
+
+    /// How long receivers sleep for in each cycle
+    pub fn sleep(&self) -> Duration {
+        self.sleep
+    }
+}
+
+// Sends a signal to an rtl_433 process through kill(1), which saves linking
+// libc for the few signals needed
+#[cfg(unix)]
+pub(crate) fn signal(pid: u32, signal: &str) {
+    let status = std::process::Command::new("kill")
+        .arg(format!("-{}", signal))
+        .arg(pid.to_string())
+        .status();
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => log::warn!("Failed to send SIG{} to rtl_433: {}", signal, status),
+        Err(e) => log::warn!("Failed to send SIG{} to rtl_433: {:?}", signal, e),
+    }
+}
+
+// Stops an rtl_433 process through taskkill(1), Windows having no signals
+#[cfg(windows)]
+pub(crate) fn terminate(pid: u32) {
+    let status = std::process::Command::new("taskkill")
+        .args(["/F", "/PID"])
+        .arg(pid.to_string())
+        .stdout(std::process::Stdio::null())
+        .status();
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => log::warn!("Failed to stop rtl_433: {}", status),
+        Err(e) => log::warn!("Failed to stop rtl_433: {:?}", e),
+    }
+}
+
+/// Suspends the rtl_433 processes with the given ids while the duty cycle
+/// has receivers sleep, until the task is dropped. Must be run within a
+/// tokio runtime.
+pub async fn suspend_radios(pids: Vec<u32>, duty: DutyCycle) {
+    #[cfg(not(unix))]
+    {
+        let _ = (pids, duty);
+        log::warn!("Receivers can only be duty cycled on unix");
+    }
+    #[cfg(unix)]
+    loop {
+        if !duty.is_active() {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+        tokio::time::sleep(duty.listen()).await;
+        if !duty.is_active() {
+            continue;
+        }
+        log::debug!("Suspending rtl_433 for {:?}", duty.sleep());
+        for pid in &pids {
+            signal(*pid, "STOP");
+        }
+        tokio::time::sleep(duty.sleep()).await;
+        for pid in &pids {
+            signal(*pid, "CONT");
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Checks that the bridge can start with a configuration, for deployment
+//! pipelines to run before rolling it out
+//!
+//! Besides the settings only parsed once the bridge starts, the environment
+//! they point at is checked: that the rtl_433 binary exists and can be run,
+//! and is a release the bridge supports, that the broker's address
+//! resolves, and that the broker's password can be read from the keyring
+//! without prompting for it. Every problem found is reported, each with what
+//! to do about it, rather than stopping at the first.
+
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, Credentials, Receiver, Transport};
+
+/// Whether a problem is in the settings themselves, or in the environment
+/// the bridge would run in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProblemKind {
+    Settings,
+    Environment,
+}
+
+/// Something that would stop the bridge from starting
+#[derive(Debug)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    pub message: String,
+    /// What to do about it, when the message doesn't say
+    pub hint: Option<String>,
+}
+
+impl ProblemKind {
+    /// Exit status of a check finding problems of this kind at worst: 1 for
+    /// settings that won't do anywhere, 2 for settings that won't do in this
+    /// environment
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ProblemKind::Settings => 1,
+            ProblemKind::Environment => 2,
+        }
+    }
+}
+
+impl Problem {
+    fn environment(message: String, hint: &str) -> Self {
+        Problem {
+            kind: ProblemKind::Environment,
+            message,
+            hint: Some(hint.to_owned()),
+        }
+    }
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(ref hint) = self.hint {
+            write!(f, "\n  {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+// The files a program may be run from at `path`: the path itself, and on
+// Windows the path with `.exe` added when it has no extension, as
+// `rtl_433` is usually given for `rtl_433.exe`
+fn with_exe_extension(path: PathBuf) -> impl Iterator<Item = PathBuf> {
+    let exe = match std::env::consts::EXE_EXTENSION {
+        "" => None,
+        _ if path.extension().is_some() => None,
+        extension => Some(path.with_extension(extension)),
+    };
+    std::iter::once(path).chain(exe)
+}
+
+/// The file a program would be run from, searching PATH for bare names as
+/// running it would, and adding the platform's executable extension when
+/// it's left off
+pub fn find_program(program: &Path) -> Option<PathBuf> {
+    if program.components().count() > 1 {
+        return with_exe_extension(program.to_path_buf()).find(|p| p.exists());
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .flat_map(|dir| with_exe_extension(dir.join(program)))
+        .find(|p| p.is_file())
+}
+
+/// Whether `path` is a file that can be run
+#[cfg(unix)]
+pub fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+pub fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+fn check_rtl_433(conf: &Config) -> Option<Problem> {
+    if conf.receiver != Receiver::Rtl433 {
+        return None;
+    }
+    let program = match conf.rtl_433 {
+        Some(ref program) => program,
+        None => {
+            return Some(Problem::environment(
+                "Path to the rtl_433 binary not set".to_owned(),
+                "Give it with --rtl-433, or set rtl_433 in the configuration file",
+            ))
+        }
+    };
+    match find_program(program) {
+        None => Some(Problem::environment(
+            format!("rtl_433 not found at {}", program.display()),
+            "Install rtl_433, or point --rtl-433 at where it is",
+        )),
+        Some(path) if !is_executable(&path) => Some(Problem::environment(
+            format!("rtl_433 at {} is not an executable file", path.display()),
+            "Check that it's the rtl_433 binary, and make it executable with chmod +x",
+        )),
+        Some(path) => match crate::compat::Compat::probe(&path) {
+            Ok(_) => None,
+            Err(e) => Some(Problem::environment(
+                format!("{:#}", e),
+                "Install a newer rtl_433 release, or point --rtl-433 at one",
+            )),
+        },
+    }
+}
+
+fn check_broker(conf: &Config) -> Option<Problem> {
+    let mqtt = conf.mqtt.first()?;
+    let default_port = match mqtt.transport().ok()? {
+        Transport::Tcp => 1883,
+        Transport::WebSocket => 80,
+        Transport::SecureWebSocket => 443,
+    };
+    let host_port = mqtt.host_port();
+    // WebSocket addresses may leave the port to their scheme
+    let resolved = host_port
+        .to_socket_addrs()
+        .or_else(|_| (host_port, default_port).to_socket_addrs());
+    match resolved.map(|mut addresses| addresses.next()) {
+        Ok(Some(_)) => None,
+        Ok(None) => Some(Problem::environment(
+            format!("mqtt broker address '{}' resolves to nothing", host_port),
+            "Check the broker's host name, and this host's DNS settings",
+        )),
+        Err(e) => Some(Problem::environment(
+            format!("mqtt broker address '{}' doesn't resolve: {}", host_port, e),
+            "Check the broker's host name, and this host's DNS settings",
+        )),
+    }
+}
+
+fn check_keyring(conf: &Config) -> Option<Problem> {
+    let mqtt = conf.mqtt.first()?;
+    let credentials = mqtt.credentials.as_ref()?;
+    let username = match credentials {
+        Credentials::Keyring(username) if !username.is_empty() => username,
+        _ => return None,
+    };
+    match credentials.password(&mqtt.broker) {
+        Ok(Some(_)) => None,
+        Ok(None) => Some(Problem::environment(
+            format!(
+                "No mqtt password for {} on the {}",
+                username,
+                crate::config::KEYRING_NAME
+            ),
+            "Run the bridge once interactively to be prompted for it, as the user the service runs as",
+        )),
+        Err(e) => Some(Problem::environment(
+            format!("{:#}", e),
+            "Check that a keyring service is running and unlocked for the user the service runs as, or keep the password in the configuration file with --mqtt-credentials-config",
+        )),
+    }
+}
+
+/// Every problem that would stop the bridge from starting with `conf`,
+/// those in the settings first
+pub fn check(conf: &Config) -> Vec<Problem> {
+    let mut problems: Vec<Problem> = conf
+        .problems()
+        .into_iter()
+        .map(|e| Problem {
+            kind: ProblemKind::Settings,
+            message: format!("{:#}", e),
+            hint: None,
+        })
+        .collect();
+    problems.extend(check_rtl_433(conf));
+    problems.extend(check_broker(conf));
+    problems.extend(check_keyring(conf));
+    problems
+}
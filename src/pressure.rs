@@ -0,0 +1,69 @@
+//! Correction of station barometric pressure to sea level
+
+use uom::si::thermodynamic_temperature;
+use uom::si::{f32::Length, length};
+use uom::si::{f32::Pressure, pressure};
+
+use crate::derive::DerivedCalculator;
+use crate::radio::{Measurement, Record};
+
+/// Version of the sea level correction, in the lineage of its values
+pub const FORMULA_VERSION: u32 = 1;
+
+// Temperature lapse rate of the standard atmosphere, in K/m
+const LAPSE_RATE: f32 = 0.0065;
+// Exponent of the barometric formula, g·M / (R·L)
+const BAROMETRIC_EXPONENT: f32 = 5.257;
+// Temperature assumed when the record doesn't carry one, in °C
+const STANDARD_TEMPERATURE_C: f32 = 15.0;
+
+/// Derives sea level pressure from the station pressure reported by
+/// barometers, using the hypsometric formula with the temperature reported
+/// alongside the pressure
+#[derive(Clone, Debug)]
+pub struct SeaLevelCorrection {
+    altitude: Length,
+}
+
+impl SeaLevelCorrection {
+    pub fn new(altitude: Length) -> Self {
+        SeaLevelCorrection { altitude }
+    }
+
+    /// Corrects `station` pressure measured at `temperature_c` to sea level
+    pub fn correct(&self, station: Pressure, temperature_c: f32) -> Pressure {
+        let h = self.altitude.get::<length::meter>();
+        let ratio = 1.0 - LAPSE_RATE * h / (temperature_c + LAPSE_RATE * h + 273.15);
+        let hpa = station.get::<pressure::hectopascal>() * ratio.powf(-BAROMETRIC_EXPONENT);
+        Pressure::new::<pressure::hectopascal>(hpa)
+    }
+}
+
+impl DerivedCalculator for SeaLevelCorrection {
+    fn calculate(&mut self, history: &[Record]) -> Vec<Measurement> {
+        let record = match history.last() {
+            Some(record) => record,
+            None => return Vec::new(),
+        };
+        let station = match record.measurements.iter().find_map(|m| match m {
+            Measurement::Pressure(p) => Some(*p),
+            _ => None,
+        }) {
+            Some(station) => station,
+            None => return Vec::new(),
+        };
+        let temperature_c = record
+            .measurements
+            .iter()
+            .find_map(|m| match m {
+                Measurement::Temperature(t) => {
+                    Some(t.get::<thermodynamic_temperature::degree_celsius>())
+                }
+                _ => None,
+            })
+            .unwrap_or(STANDARD_TEMPERATURE_C);
+        vec![Measurement::SeaLevelPressure(
+            self.correct(station, temperature_c),
+        )]
+    }
+}
@@ -0,0 +1,201 @@
+//! Named configuration profiles, e.g. for winter or stormy weather, that
+//! adjust a group of settings at once
+//!
+//! A profile is an overlay of configuration settings, merged over the rest
+//! of the configuration while it's active: objects are merged key by key,
+//! and anything else is replaced. A profile is active when it's chosen with
+//! `--profile` or over the `profile` control topic, or otherwise when its
+//! time of year comes around in the schedule. Settings a profile changes go
+//! back to the rest of the configuration's when it's no longer active.
+//!
+//! Only the settings applied to each record as it's processed, e.g. the
+//! throttle, retain policy, limits, sampling and automation rules, follow
+//! profile switches at runtime; the rest are taken from the profile active
+//! at startup.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Datelike, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, ConfigError};
+
+/// A time of year a profile is active, from one date to another, each as
+/// `MM-DD`. The dates are inclusive, and the period may wrap around the end
+/// of the year, e.g. from `11-01` to `03-31`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProfileSchedule {
+    pub profile: String,
+    pub from: String,
+    pub until: String,
+}
+
+// Parses a `MM-DD` date into a month and day
+fn parse_date(date: &str) -> Result<(u32, u32), ConfigError> {
+    let invalid = || ConfigError::ProfileDate(date.to_owned());
+    let (month, day) = date.trim().split_once('-').ok_or_else(invalid)?;
+    let month: u32 = month.parse().map_err(|_| invalid())?;
+    let day: u32 = day.parse().map_err(|_| invalid())?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
+    }
+    Ok((month, day))
+}
+
+// Merges `overlay` into `base`, key by key for objects
+fn merge(base: &mut serde_json::Value, overlay: &serde_json::Value) {
+    match (base, overlay) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                merge(
+                    base.entry(key.clone()).or_insert(serde_json::Value::Null),
+                    value,
+                );
+            }
+        }
+        (base, overlay) => *base = overlay.clone(),
+    }
+}
+
+// A scheduled profile, and the month and day it's active from and until
+type Period = (String, (u32, u32), (u32, u32));
+
+/// The configuration's profiles, and which one is active
+#[derive(Clone, Debug)]
+pub struct Profiles {
+    // The settings any profile changes, as they are without a profile
+    base: serde_json::Map<String, serde_json::Value>,
+    overlays: BTreeMap<String, serde_json::Map<String, serde_json::Value>>,
+    schedule: Vec<Period>,
+    chosen: Option<String>,
+    active: Option<String>,
+}
+
+impl Profiles {
+    /// Takes the profiles of `conf`, which is taken to have no profile
+    /// applied, checking that every profile can be applied to it
+    pub fn new(conf: &Config) -> Result<Self, ConfigError> {
+        let json = serde_json::to_value(conf)?;
+        let mut base = serde_json::Map::new();
+        for overlay in conf.profiles.values() {
+            for key in overlay.keys() {
+                base.insert(
+                    key.clone(),
+                    json.get(key).cloned().unwrap_or(serde_json::Value::Null),
+                );
+            }
+        }
+        let schedule = conf
+            .profile_schedule
+            .iter()
+            .map(|s| {
+                Ok((
+                    s.profile.clone(),
+                    parse_date(&s.from)?,
+                    parse_date(&s.until)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, ConfigError>>()?;
+        let profiles = Profiles {
+            base,
+            overlays: conf.profiles.clone(),
+            schedule,
+            chosen: None,
+            active: None,
+        };
+        let scheduled = profiles.schedule.iter().map(|(name, _, _)| name);
+        for name in conf.profile.iter().chain(scheduled) {
+            if !profiles.overlays.contains_key(name) {
+                return Err(ConfigError::UnknownProfile(name.clone()));
+            }
+        }
+        for name in profiles.overlays.keys() {
+            profiles.apply(conf, Some(name))?;
+        }
+        Ok(Profiles {
+            chosen: conf.profile.clone(),
+            ..profiles
+        })
+    }
+
+    /// The profile that's active
+    pub fn active(&self) -> Option<&str> {
+        self.active.as_deref()
+    }
+
+    /// Chooses a profile to be active regardless of the schedule, or goes
+    /// back to the schedule
+    pub fn choose(&mut self, profile: Option<String>) -> Result<(), ConfigError> {
+        match profile {
+            Some(name) if !self.overlays.contains_key(&name) => {
+                Err(ConfigError::UnknownProfile(name))
+            }
+            profile => {
+                self.chosen = profile;
+                Ok(())
+            }
+        }
+    }
+
+    /// The profile the schedule calls for at `time`
+    pub fn scheduled(&self, time: DateTime<Local>) -> Option<&str> {
+        let today = (time.month(), time.day());
+        self.schedule
+            .iter()
+            .find(|(_, from, until)| match from <= until {
+                true => *from <= today && today <= *until,
+                false => *from <= today || today <= *until,
+            })
+            .map(|(name, _, _)| name.as_str())
+    }
+
+    /// `conf` with the settings of `profile` over it, or with those of no
+    /// profile
+    pub fn apply(&self, conf: &Config, profile: Option<&str>) -> Result<Config, ConfigError> {
+        let mut json = serde_json::to_value(conf)?;
+        if let serde_json::Value::Object(ref mut settings) = json {
+            settings.extend(self.base.clone());
+        }
+        if let Some(overlay) = profile.and_then(|name| self.overlays.get(name)) {
+            let mut overlay = overlay.clone();
+            // Profiles don't get to pick profiles
+            for key in ["profiles", "profile_schedule", "profile"] {
+                overlay.remove(key);
+            }
+            merge(&mut json, &serde_json::Value::Object(overlay));
+        }
+        let mut applied: Config = serde_json::from_value(json).map_err(|e| match profile {
+            Some(name) => ConfigError::InvalidProfile(name.to_owned(), e),
+            None => e.into(),
+        })?;
+        applied.instance_id = conf.instance_id;
+        applied.chaos = conf.chaos;
+        Ok(applied)
+    }
+
+    /// `conf` with the profile that should be active at `time` applied, if
+    /// that isn't the one already active
+    pub fn update(
+        &mut self,
+        conf: &Config,
+        time: DateTime<Local>,
+    ) -> Result<Option<Config>, ConfigError> {
+        let wanted = match self.chosen {
+            Some(ref chosen) => Some(chosen.clone()),
+            None => self.scheduled(time).map(str::to_owned),
+        };
+        if wanted == self.active {
+            return Ok(None);
+        }
+        let applied = self.apply(conf, wanted.as_deref())?;
+        match wanted {
+            Some(ref name) => log::info!("Switching to the {} configuration profile", name),
+            None => log::info!(
+                "Leaving the {} configuration profile",
+                self.active.as_deref().unwrap_or_default()
+            ),
+        }
+        self.active = wanted;
+        Ok(Some(applied))
+    }
+}
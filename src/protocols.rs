@@ -0,0 +1,208 @@
+//! Resolution of rtl_433 decoders by name, since their protocol numbers
+//! shift between rtl_433 releases
+//!
+//! Wherever radio arguments give `-R` a name rather than a number, the name
+//! is looked up in the decoders the installed rtl_433 lists with `-R help`.
+//! Case, spaces and punctuation are ignored, so `-R ambient-weather-wh31e`
+//! picks "Ambient Weather WH31E Thermo-Hygrometer Sensor, EcoWitt WH40 rain
+//! gauge". A name matching no decoder, or several, is an error naming the
+//! likely candidates.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use thiserror::Error;
+
+// Most candidates suggested for a name that doesn't match
+const MAX_SUGGESTIONS: usize = 5;
+
+#[derive(Error, Debug)]
+pub enum ProtocolError {
+    #[error("No rtl_433 decoder named '{name}'{}", suggest(.suggestions))]
+    Unknown {
+        name: String,
+        suggestions: Vec<String>,
+    },
+    #[error("rtl_433 decoder name '{name}' is ambiguous{}", suggest(.matches))]
+    Ambiguous { name: String, matches: Vec<String> },
+    #[error("rtl_433 didn't list any decoders with '-R help'")]
+    NoDecoders,
+}
+
+fn suggest(candidates: &[String]) -> String {
+    if candidates.is_empty() {
+        String::new()
+    } else {
+        format!(", did you mean: {}", candidates.join("; "))
+    }
+}
+
+/// A decoder the installed rtl_433 supports
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Decoder {
+    pub number: u32,
+    pub name: String,
+}
+
+impl std::fmt::Display for Decoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "[{}] {}", self.number, self.name)
+    }
+}
+
+// Lowercases a name and drops everything but letters and digits
+fn simplify(name: &str) -> String {
+    name.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Parses the decoder list of `rtl_433 -R help`, e.g. `  [113]  Ambient
+/// Weather WH31E ...`, where decoders disabled by default are starred
+pub fn parse_decoders(help: &str) -> Vec<Decoder> {
+    help.lines()
+        .filter_map(|line| {
+            let (number, name) = line.trim().strip_prefix('[')?.split_once(']')?;
+            Some(Decoder {
+                number: number.trim().parse().ok()?,
+                name: name.trim_start_matches('*').trim().to_owned(),
+            })
+        })
+        .filter(|decoder| !decoder.name.is_empty())
+        .collect()
+}
+
+/// Lists the decoders supported by the rtl_433 at `binpath`
+pub fn list_decoders(binpath: &Path) -> Result<Vec<Decoder>> {
+    let output = std::process::Command::new(binpath)
+        .args(["-R", "help"])
+        .stdin(std::process::Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to list decoders of {}", binpath.display()))?;
+    // rtl_433 prints its help to stderr, and exits with an error doing so
+    let mut help = String::from_utf8_lossy(&output.stderr).into_owned();
+    help.push_str(&String::from_utf8_lossy(&output.stdout));
+    let decoders = parse_decoders(&help);
+    if decoders.is_empty() {
+        return Err(ProtocolError::NoDecoders.into());
+    }
+    Ok(decoders)
+}
+
+/// Finds the decoder a name refers to: the one whose name it is, or else
+/// the only one whose name contains it
+pub fn resolve<'a>(name: &str, decoders: &'a [Decoder]) -> Result<&'a Decoder, ProtocolError> {
+    let wanted = simplify(name);
+    if let Some(decoder) = decoders.iter().find(|d| simplify(&d.name) == wanted) {
+        return Ok(decoder);
+    }
+    let matches: Vec<&Decoder> = decoders
+        .iter()
+        .filter(|d| !wanted.is_empty() && simplify(&d.name).contains(&wanted))
+        .collect();
+    match matches.as_slice() {
+        [decoder] => Ok(decoder),
+        [] => {
+            // Suggest the decoders sharing the longest start of the name
+            let suggestions = (3..wanted.len())
+                .rev()
+                .map(|len| {
+                    decoders
+                        .iter()
+                        .filter(|d| simplify(&d.name).contains(&wanted[..len]))
+                        .take(MAX_SUGGESTIONS)
+                        .map(Decoder::to_string)
+                        .collect::<Vec<String>>()
+                })
+                .find(|suggestions| !suggestions.is_empty())
+                .unwrap_or_default();
+            Err(ProtocolError::Unknown {
+                name: name.to_owned(),
+                suggestions,
+            })
+        }
+        matches => Err(ProtocolError::Ambiguous {
+            name: name.to_owned(),
+            matches: matches.iter().map(|d| d.to_string()).collect(),
+        }),
+    }
+}
+
+// Resolves a `-R` value, which may disable the decoder with a leading `-`
+// and pass it options after a `:`, to one with the decoder's number
+fn resolve_value(value: &str, decoders: &[Decoder]) -> Result<String, ProtocolError> {
+    let (disable, value) = match value.strip_prefix('-') {
+        Some(value) => ("-", value),
+        None => ("", value),
+    };
+    let (name, options) = match value.split_once(':') {
+        Some((name, options)) => (name, Some(options)),
+        None => (value, None),
+    };
+    let decoder = resolve(name, decoders)?;
+    log::debug!("rtl_433 decoder '{}' is {}", name, decoder);
+    Ok(match options {
+        Some(options) => format!("{}{}:{}", disable, decoder.number, options),
+        None => format!("{}{}", disable, decoder.number),
+    })
+}
+
+// Whether a `-R` value already names its decoder by number
+fn is_numbered(value: &str) -> bool {
+    let value = value.strip_prefix('-').unwrap_or(value);
+    let number = value.split_once(':').map(|(n, _)| n).unwrap_or(value);
+    number == "help" || number.parse::<u32>().is_ok()
+}
+
+/// Replaces the decoder names given to `-R` in rtl_433 arguments with their
+/// numbers, listing the installed decoders the first time there's a name to
+/// resolve, and only then
+#[derive(Debug)]
+pub struct Resolver<'a> {
+    binpath: &'a Path,
+    decoders: Option<Vec<Decoder>>,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(binpath: &'a Path) -> Self {
+        Resolver {
+            binpath,
+            decoders: None,
+        }
+    }
+
+    pub fn resolve_args<S: AsRef<str>>(&mut self, args: &[S]) -> Result<Vec<String>> {
+        let mut resolved = Vec::with_capacity(args.len());
+        let mut args = args.iter().map(AsRef::as_ref);
+        while let Some(arg) = args.next() {
+            let value = match arg.strip_prefix("-R") {
+                Some("") => {
+                    resolved.push(arg.to_owned());
+                    match args.next() {
+                        Some(value) => value,
+                        None => break,
+                    }
+                }
+                Some(value) => {
+                    resolved.push("-R".to_owned());
+                    value
+                }
+                None => {
+                    resolved.push(arg.to_owned());
+                    continue;
+                }
+            };
+            if is_numbered(value) {
+                resolved.push(value.to_owned());
+                continue;
+            }
+            let decoders = match &self.decoders {
+                Some(decoders) => decoders,
+                None => self.decoders.insert(list_decoders(self.binpath)?),
+            };
+            resolved.push(resolve_value(value, decoders)?);
+        }
+        Ok(resolved)
+    }
+}
@@ -0,0 +1,141 @@
+//! Removal of everything kept about a sensor, e.g. when a neighbor asks for
+//! their meter to stop being logged
+//!
+//! A sensor's records are deleted from the history database and the packed
+//! capture. Purging all of them, rather than only those before some time,
+//! also clears the retained messages of its topics on the broker, by
+//! publishing empty retained messages over them.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, TimeZone};
+
+use crate::config::Config;
+use crate::history::HistoryStore;
+use crate::radio::Record;
+
+/// What was removed for a sensor
+#[derive(Debug, Default)]
+pub struct PurgeReport {
+    /// Records deleted from the history database
+    pub history: usize,
+    /// Records removed from the packed capture
+    pub capture: usize,
+    /// Topics whose retained messages were cleared
+    pub topics: Vec<String>,
+}
+
+impl std::fmt::Display for PurgeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Deleted {} records from history", self.history)?;
+        writeln!(f, "Removed {} records from the capture", self.capture)?;
+        writeln!(f, "Cleared {} topics", self.topics.len())?;
+        for topic in &self.topics {
+            writeln!(f, "  {}", topic)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the time to purge records before, as a date (taken as local
+/// midnight) or as a timestamp in the formats replays accept
+pub fn parse_before(before: &str) -> Result<DateTime<Local>> {
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(before, "%Y-%m-%d") {
+        if let Some(midnight) = date
+            .and_hms_opt(0, 0, 0)
+            .and_then(|t| Local.from_local_datetime(&t).earliest())
+        {
+            return Ok(midnight);
+        }
+    }
+    Ok(crate::replay::parse_timestamp(before)?)
+}
+
+/// Deletes the records of `sensor_id`, or only those from before `before`,
+/// from the configured history database and capture. Purging all of them
+/// also clears the sensor's retained topics on the configured broker.
+pub fn purge(
+    conf: &Config,
+    sensor_id: &str,
+    before: Option<DateTime<Local>>,
+) -> Result<PurgeReport> {
+    let mut report = PurgeReport::default();
+    let mut latest = None;
+    let mut measurements = Vec::new();
+
+    if let Some(history) = conf.history.as_ref().filter(|h| h.path.exists()) {
+        let mut store = HistoryStore::open(&history.path, None)?;
+        latest = store.latest(sensor_id)?;
+        measurements = store.measurement_names(sensor_id)?;
+        report.history = store
+            .purge(sensor_id, before)
+            .with_context(|| format!("Failed to purge {}", history.path.display()))?;
+    }
+
+    if let Some(path) = conf.capture.as_ref().filter(|p| p.exists()) {
+        report.capture = crate::capture::retain(path, |timestamp, json| {
+            if before.map(|before| timestamp >= before).unwrap_or(false) {
+                return true;
+            }
+            match crate::radio::decode(json) {
+                Some(record) if record.sensor_id == sensor_id => {
+                    latest.get_or_insert_with(|| json.clone());
+                    false
+                }
+                _ => true,
+            }
+        })?;
+    }
+
+    // The sensor's current values are still on the broker after a purge of
+    // only its older records
+    if before.is_none() {
+        if let Some(mqtt) = conf.mqtt.first() {
+            report.topics = topics(conf, sensor_id, latest, &measurements)?;
+            let tombstones = report
+                .topics
+                .iter()
+                .map(|topic| crate::sink::Message::new_retained(topic, Vec::new(), 1))
+                .collect();
+            crate::sink::publish_once(mqtt, tombstones)?;
+        }
+    }
+    Ok(report)
+}
+
+// The topics a sensor is published to, as named by its latest record when
+// there is one, since some naming strategies name sensors by their fields
+fn topics(
+    conf: &Config,
+    sensor_id: &str,
+    latest: Option<serde_json::Value>,
+    measurements: &[String],
+) -> Result<Vec<String>> {
+    let record = latest
+        .as_ref()
+        .and_then(crate::radio::decode)
+        .filter(|record| record.sensor_id == sensor_id)
+        .unwrap_or_else(|| Record {
+            timestamp: crate::clock::now(),
+            sensor_id: sensor_id.to_owned(),
+            record_json: latest.unwrap_or_default(),
+            measurements: Vec::new(),
+            radio: None,
+        });
+    let template = conf.topics()?;
+    let location = conf.location_of(sensor_id);
+    let sensor_topic = template.render(&record, location, None);
+    let mut topics = vec![
+        crate::availability::sensor_topic(&sensor_topic),
+        format!("{}/lifecycle", sensor_topic),
+        format!("{}/{}", sensor_topic, crate::meta::META_SUFFIX),
+        format!("{}/{}", sensor_topic, crate::validate::VALIDATED_SUFFIX),
+    ];
+    for name in measurements {
+        topics.push(template.render(&record, location, Some(name)));
+    }
+    for window in conf.get_stats_windows()? {
+        topics.push(window.topic(&sensor_topic));
+    }
+    topics.insert(0, sensor_topic);
+    Ok(topics)
+}
@@ -0,0 +1,367 @@
+//! Uploading the station's current conditions to personal weather station
+//! networks: Weather Underground, and PWSWeather through its compatible
+//! endpoint
+//!
+//! Each upload keeps the latest reading of every measurement the network
+//! takes from the sensors it covers, and submits them together, in imperial
+//! units, every interval. Readings that have gone stale are left out rather
+//! than repeated, and rain over the past hour is summed from the rainfall
+//! deltas. Uploads are made with `curl`, like release checks, and a failed
+//! upload is reported on the event bus and tried again the next interval.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{ConfigError, SensorPattern};
+use crate::errors::{ErrorCode, ErrorEvent};
+use crate::events::{Event, EventBus};
+use crate::radio::{Measurement, Record};
+use crate::units::UnitSystem;
+
+/// Seconds between uploads, unless configured otherwise
+pub const DEFAULT_UPLOAD_INTERVAL: u64 = 60;
+
+/// Minutes after which readings are stale, and left out of uploads
+pub const STALE_AFTER_MINS: i64 = 15;
+
+// Seconds to wait on an upload before giving up
+const UPLOAD_TIMEOUT_SECS: &str = "30";
+
+// Lux per W/m² of sunlight, the conversion weather stations conventionally
+// use for solar radiation
+const LUX_PER_WATT_M2: f64 = 126.7;
+
+/// A network conditions are uploaded to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PwsService {
+    Wunderground,
+    Pwsweather,
+}
+
+impl PwsService {
+    fn url(&self) -> &'static str {
+        match self {
+            Self::Wunderground => {
+                "https://weatherstation.wunderground.com/weatherstation/updateweatherstation.php"
+            }
+            Self::Pwsweather => "https://pwsupdate.pwsweather.com/api/v1/submitwx",
+        }
+    }
+}
+
+fn default_interval() -> u64 {
+    DEFAULT_UPLOAD_INTERVAL
+}
+
+/// A station's uploads to one network
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PwsUpload {
+    pub service: PwsService,
+    /// Id the network knows the station by
+    pub station_id: String,
+    /// Station key, or API key for PWSWeather
+    pub key: String,
+    /// Seconds between uploads
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// Sensors whose readings are uploaded, as [`SensorPattern`]s; all of
+    /// them when empty, which suits stations with a single outdoor sensor
+    #[serde(default)]
+    pub sensors: Vec<String>,
+    /// Endpoint uploaded to in place of the network's own
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+// Keeps station keys out of debug logging
+impl std::fmt::Debug for PwsUpload {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("PwsUpload")
+            .field("service", &self.service)
+            .field("station_id", &self.station_id)
+            .field("key", &"<redacted>")
+            .field("interval", &self.interval)
+            .field("sensors", &self.sensors)
+            .field("url", &self.url)
+            .finish()
+    }
+}
+
+impl PwsUpload {
+    fn url(&self) -> &str {
+        self.url.as_deref().unwrap_or_else(|| self.service.url())
+    }
+}
+
+// The parameter of the upload protocol a measurement is reported in, and
+// its value in the units the protocol takes
+fn parameter(measurement: &Measurement) -> Option<(&'static str, f64)> {
+    let name = match measurement {
+        Measurement::Temperature(_) => "tempf",
+        Measurement::RelativeHumidity(_) => "humidity",
+        Measurement::WindSpeed(_) => "windspeedmph",
+        Measurement::WindGust(_) => "windgustmph",
+        Measurement::WindDirection(_) => "winddir",
+        Measurement::DailyRainfall(_) => "dailyrainin",
+        Measurement::SeaLevelPressure(_) => "baromin",
+        Measurement::UvIndex(_) => "UV",
+        Measurement::Pm2_5(_) => "AqPM2.5",
+        Measurement::Pm10(_) => "AqPM10",
+        Measurement::Lux(lux) => {
+            return Some(("solarradiation", f64::from(*lux) / LUX_PER_WATT_M2))
+        }
+        _ => return None,
+    };
+    Some((name, measurement.numeric_in(Some(UnitSystem::Imperial))?))
+}
+
+/// Readings by the name of their upload parameter, in the units uploaded
+pub type Readings = BTreeMap<&'static str, f64>;
+
+// Station pressure stands in for sea level pressure when the station's
+// altitude isn't configured
+const STATION_PRESSURE: &str = "stationbaromin";
+
+/// The latest conditions at the station
+#[derive(Debug, Default)]
+pub struct Conditions {
+    readings: BTreeMap<&'static str, (DateTime<Local>, f64)>,
+    // Rainfall deltas in inches, oldest first
+    rain: VecDeque<(DateTime<Local>, f64)>,
+}
+
+impl Conditions {
+    /// Takes the readings the upload protocol has parameters for from a
+    /// record, replacing older ones
+    pub fn observe(&mut self, record: &Record) {
+        for measurement in &record.measurements {
+            if let Some((name, value)) = parameter(measurement) {
+                self.readings.insert(name, (record.timestamp, value));
+            }
+            match measurement {
+                Measurement::Pressure(_) => {
+                    if let Some(value) = measurement.numeric_in(Some(UnitSystem::Imperial)) {
+                        self.readings
+                            .insert(STATION_PRESSURE, (record.timestamp, value));
+                    }
+                }
+                Measurement::RainfallDelta(_) => {
+                    if let Some(value) = measurement.numeric_in(Some(UnitSystem::Imperial)) {
+                        self.rain.push_back((record.timestamp, value));
+                    }
+                }
+                _ => {}
+            }
+        }
+        let hour_ago = record.timestamp - chrono::Duration::hours(1);
+        while self
+            .rain
+            .front()
+            .map(|(t, _)| *t < hour_ago)
+            .unwrap_or(false)
+        {
+            self.rain.pop_front();
+        }
+    }
+
+    /// The readings that aren't stale at `now`, with dew point and the past
+    /// hour's rain worked out, along with when the latest was taken, or
+    /// `None` if there are none
+    pub fn current(&self, now: DateTime<Local>) -> Option<(DateTime<Local>, Readings)> {
+        let since = now - chrono::Duration::minutes(STALE_AFTER_MINS);
+        let mut readings: Readings = self
+            .readings
+            .iter()
+            .filter(|(_, (t, _))| *t >= since)
+            .map(|(name, (_, value))| (*name, *value))
+            .collect();
+        let latest = self
+            .readings
+            .values()
+            .map(|(t, _)| *t)
+            .filter(|t| *t >= since)
+            .max()?;
+        if let Some(pressure) = readings.remove(STATION_PRESSURE) {
+            readings.entry("baromin").or_insert(pressure);
+        }
+        if let (Some(t), Some(rh)) = (readings.get("tempf"), readings.get("humidity")) {
+            readings.insert("dewptf", dew_point_f(*t, *rh));
+        }
+        if self.rain.back().map(|(t, _)| *t >= since).unwrap_or(false) {
+            let hour_ago = now - chrono::Duration::hours(1);
+            let hourly = self
+                .rain
+                .iter()
+                .filter(|(t, _)| *t >= hour_ago)
+                .map(|(_, delta)| delta)
+                .sum();
+            readings.insert("rainin", hourly);
+        }
+        Some((latest, readings))
+    }
+}
+
+// Dew point by the Magnus formula, from °F and relative humidity in percent
+fn dew_point_f(temp_f: f64, humidity: f64) -> f64 {
+    let (a, b) = (17.62, 243.12);
+    let temp_c = (temp_f - 32.0) / 1.8;
+    let gamma = (humidity.max(1.0) / 100.0).ln() + a * temp_c / (b + temp_c);
+    (b * gamma / (a - gamma)) * 1.8 + 32.0
+}
+
+/// Percent-encodes a query string or form component
+pub fn encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// The url conditions are uploaded with
+pub fn upload_url(upload: &PwsUpload, time: DateTime<Local>, readings: &Readings) -> String {
+    let mut query = vec![
+        ("ID".to_owned(), upload.station_id.clone()),
+        ("PASSWORD".to_owned(), upload.key.clone()),
+        (
+            "dateutc".to_owned(),
+            time.with_timezone(&Utc)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+        ),
+    ];
+    query.extend(
+        readings
+            .iter()
+            .map(|(name, value)| ((*name).to_owned(), format!("{:.2}", value))),
+    );
+    query.push((
+        "softwaretype".to_owned(),
+        format!("{}-{}", clap::crate_name!(), clap::crate_version!()),
+    ));
+    if upload.service == PwsService::Wunderground {
+        query.push(("action".to_owned(), "updateraw".to_owned()));
+    }
+    let query: Vec<String> = query
+        .iter()
+        .map(|(name, value)| format!("{}={}", encode(name), encode(value)))
+        .collect();
+    format!("{}?{}", upload.url(), query.join("&"))
+}
+
+/// Submits an upload. The url is handed to curl on its standard input, so
+/// that the station key doesn't show up in the process list.
+pub fn submit(url: &str) -> Result<()> {
+    let mut curl = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error"])
+        .args(["--max-time", UPLOAD_TIMEOUT_SECS])
+        .args(["--config", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| "Unable to run curl")?;
+    if let Some(mut stdin) = curl.stdin.take() {
+        writeln!(stdin, "url = \"{}\"", url).with_context(|| "Failed to pass the url to curl")?;
+    }
+    let output = curl.wait_with_output()?;
+    if !output.status.success() {
+        anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    let response = String::from_utf8_lossy(&output.stdout);
+    log::debug!("Upload response: {}", response.trim());
+    // Weather Underground answers rejected uploads with a 200 all the same
+    if response.trim().to_ascii_lowercase().starts_with("error") || response.contains("\"error\"") {
+        anyhow::bail!("upload rejected: {}", response.trim());
+    }
+    Ok(())
+}
+
+/// Starts a background thread gathering the conditions reported in the
+/// records on `events` by the sensors matching `sensors`, and handing them to
+/// `report` every interval, until the radio stops
+pub fn spawn_gatherer<F>(
+    sensors: &[String],
+    interval: Duration,
+    events: Receiver<Event>,
+    mut report: F,
+) -> Result<JoinHandle<()>, ConfigError>
+where
+    F: FnMut(DateTime<Local>, &Readings) + Send + 'static,
+{
+    let sensors = sensors
+        .iter()
+        .map(|pattern| pattern.parse())
+        .collect::<Result<Vec<SensorPattern>, ConfigError>>()?;
+    let interval = interval.max(Duration::from_secs(1));
+    Ok(std::thread::spawn(move || {
+        let mut conditions = Conditions::default();
+        let mut started = Instant::now();
+        loop {
+            match events.recv_timeout(interval.saturating_sub(started.elapsed())) {
+                Ok(Event::Record(record)) => {
+                    if sensors.is_empty() || sensors.iter().any(|s| s.matches(&record.sensor_id)) {
+                        conditions.observe(&record);
+                    }
+                }
+                Ok(Event::RadioStopped) | Err(RecvTimeoutError::Disconnected) => return,
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            }
+            if started.elapsed() < interval {
+                continue;
+            }
+            started = Instant::now();
+            if let Some((time, readings)) = conditions.current(crate::clock::now()) {
+                report(time, &readings);
+            }
+        }
+    }))
+}
+
+/// Starts a background thread uploading the conditions reported in the
+/// records on `events` every interval, until the radio stops
+pub fn spawn_uploader(
+    upload: PwsUpload,
+    events: Receiver<Event>,
+    errors: EventBus,
+) -> Result<JoinHandle<()>, ConfigError> {
+    let sensors = upload.sensors.clone();
+    let interval = Duration::from_secs(upload.interval);
+    spawn_gatherer(
+        &sensors,
+        interval,
+        events,
+        move |time, readings| match submit(&upload_url(&upload, time, readings)) {
+            Ok(()) => log::debug!(
+                "Uploaded {} readings to {:?} station {}",
+                readings.len(),
+                upload.service,
+                upload.station_id
+            ),
+            Err(e) => {
+                log::error!(
+                    "Failed to upload to {:?} station {}: {:#}",
+                    upload.service,
+                    upload.station_id,
+                    e
+                );
+                errors.publish(Event::Error(
+                    ErrorEvent::new(ErrorCode::Upload, format!("{:#}", e))
+                        .context("station_id", &upload.station_id),
+                ));
+            }
+        },
+    )
+}
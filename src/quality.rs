@@ -0,0 +1,232 @@
+//! Rolling data quality scores for each sensor, so that the sensor to
+//! re-site or replace stands out
+//!
+//! A sensor's score, out of 100, is drawn from its last hundred or so
+//! transmissions: how many failed the integrity requirements, how many had
+//! implausible readings, what share of its expected transmissions went
+//! missing, and how much its signal strength varies. Signal strength is only
+//! known when rtl_433 is asked to report it, with `-M level` in the radio's
+//! arguments. Scores are published in each sensor's `$meta`, rounded to 5 so
+//! that small changes don't republish it, and in full on the telemetry
+//! topic `weatherradio/telemetry/quality`.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use clap::crate_name;
+use serde::Serialize;
+
+use crate::events::Event;
+use crate::radio::Record;
+use crate::sink::MqttSink;
+
+// Transmissions of each sensor its score is drawn from
+const QUALITY_WINDOW: usize = 100;
+
+// Standard deviation of signal strength, in dB, at which the signal counts
+// for half the score
+const RSSI_SPREAD_DB: f64 = 20.0;
+
+/// Topic quality scores are published to
+pub fn telemetry_topic() -> String {
+    format!("{}/telemetry/quality", crate_name!())
+}
+
+// A transmission heard from a sensor
+#[derive(Clone, Debug)]
+enum Transmission {
+    Record {
+        time: DateTime<Local>,
+        plausible: bool,
+        rssi: Option<f64>,
+    },
+    IntegrityFailure,
+}
+
+/// A sensor's quality score, and what it was drawn from
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct QualityScore {
+    /// Out of 100
+    pub score: u8,
+    pub transmissions: usize,
+    pub integrity_failure_rate: f64,
+    pub outlier_rate: f64,
+    /// Share of the transmissions expected at the sensor's usual interval
+    /// that weren't received
+    pub gap_rate: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rssi_stddev_db: Option<f64>,
+}
+
+fn round(n: f64) -> f64 {
+    (n * 1000.0).round() / 1000.0
+}
+
+fn score(transmissions: &VecDeque<Transmission>) -> QualityScore {
+    let mut times = Vec::new();
+    let mut outliers = 0;
+    let mut rssi = Vec::new();
+    for transmission in transmissions {
+        if let Transmission::Record {
+            time,
+            plausible,
+            rssi: level,
+        } = transmission
+        {
+            times.push(*time);
+            outliers += usize::from(!plausible);
+            rssi.extend(level);
+        }
+    }
+    let total = transmissions.len().max(1) as f64;
+    let integrity_failure_rate = (transmissions.len() - times.len()) as f64 / total;
+    let outlier_rate = outliers as f64 / times.len().max(1) as f64;
+
+    // Gaps are judged against the sensor's median interval, which holds up
+    // against the gaps themselves
+    let mut intervals: Vec<i64> = times
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).num_milliseconds())
+        .filter(|ms| *ms > 0)
+        .collect();
+    intervals.sort_unstable();
+    let gap_rate = match intervals.get(intervals.len() / 2) {
+        Some(&usual) if intervals.len() >= 2 => {
+            let missed: i64 = intervals
+                .iter()
+                .map(|ms| ((*ms as f64 / usual as f64).round() as i64 - 1).max(0))
+                .sum();
+            missed as f64 / (intervals.len() as i64 + missed) as f64
+        }
+        _ => 0.0,
+    };
+
+    let rssi_stddev_db = if rssi.len() >= 2 {
+        let mean = rssi.iter().sum::<f64>() / rssi.len() as f64;
+        let variance = rssi.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / rssi.len() as f64;
+        Some(variance.sqrt())
+    } else {
+        None
+    };
+    let signal = 1.0 - (rssi_stddev_db.unwrap_or(0.0) / RSSI_SPREAD_DB / 2.0).min(0.5);
+
+    let score =
+        100.0 * (1.0 - integrity_failure_rate) * (1.0 - outlier_rate) * (1.0 - gap_rate) * signal;
+    QualityScore {
+        score: score.round().clamp(0.0, 100.0) as u8,
+        transmissions: transmissions.len(),
+        integrity_failure_rate: round(integrity_failure_rate),
+        outlier_rate: round(outlier_rate),
+        gap_rate: round(gap_rate),
+        rssi_stddev_db: rssi_stddev_db.map(round),
+    }
+}
+
+/// Keeps each sensor's recent transmissions to score it by. Cloning the
+/// tracker produces another handle to the same transmissions.
+#[derive(Clone, Debug, Default)]
+pub struct QualityTracker {
+    sensors: Arc<Mutex<HashMap<String, VecDeque<Transmission>>>>,
+}
+
+impl QualityTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&self, sensor_id: &str, transmission: Transmission, new_sensor: bool) {
+        let mut sensors = self.sensors.lock().expect("quality scores poisoned");
+        let transmissions = match sensors.get_mut(sensor_id) {
+            Some(transmissions) => transmissions,
+            None if new_sensor => sensors.entry(sensor_id.to_owned()).or_default(),
+            None => return,
+        };
+        transmissions.push_back(transmission);
+        if transmissions.len() > QUALITY_WINDOW {
+            transmissions.pop_front();
+        }
+    }
+
+    /// Counts a record that made it through filtering
+    pub fn observe(&self, record: &Record) {
+        let transmission = Transmission::Record {
+            time: record.timestamp,
+            plausible: record.measurements.iter().all(|m| m.is_plausible()),
+            rssi: record.record_json.get("rssi").and_then(|r| r.as_f64()),
+        };
+        self.push(&record.sensor_id, transmission, true);
+    }
+
+    /// Counts a record that failed the integrity requirements. Only sensors
+    /// that have been heard from are counted, since a corrupt record's
+    /// sensor id can't be trusted to be of a real sensor.
+    pub fn integrity_failure(&self, sensor_id: &str) {
+        self.push(sensor_id, Transmission::IntegrityFailure, false);
+    }
+
+    /// The sensor's current score, if it's been heard from
+    pub fn score(&self, sensor_id: &str) -> Option<QualityScore> {
+        let sensors = self.sensors.lock().expect("quality scores poisoned");
+        sensors.get(sensor_id).map(score)
+    }
+
+    /// Every sensor's current score
+    pub fn report(&self) -> BTreeMap<String, QualityScore> {
+        let sensors = self.sensors.lock().expect("quality scores poisoned");
+        sensors
+            .iter()
+            .map(|(sensor_id, transmissions)| (sensor_id.clone(), score(transmissions)))
+            .collect()
+    }
+}
+
+/// Starts a background thread keeping the tracker up to date with the
+/// records and integrity failures on `events`, and publishing every sensor's
+/// score to `sink` every `interval`, if given, until the radio stops
+pub fn spawn_tracker(
+    tracker: QualityTracker,
+    events: Receiver<Event>,
+    sink: Option<MqttSink>,
+    interval: Option<Duration>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut started = Instant::now();
+        loop {
+            let event = match interval {
+                Some(interval) => events.recv_timeout(interval.saturating_sub(started.elapsed())),
+                None => events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match event {
+                Ok(Event::Record(record)) => tracker.observe(&record),
+                Ok(Event::IntegrityFailure { sensor_id }) => tracker.integrity_failure(&sensor_id),
+                Ok(Event::RadioStopped) | Err(RecvTimeoutError::Disconnected) => return,
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            }
+            let (sink, interval) = match (&sink, interval) {
+                (Some(sink), Some(interval)) => (sink, interval),
+                _ => continue,
+            };
+            if started.elapsed() < interval {
+                continue;
+            }
+            started = Instant::now();
+            let report = tracker.report();
+            let topic = telemetry_topic();
+            let payload = match serde_json::to_vec(&report) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    log::error!("Failed to serialize quality scores: {:?}", e);
+                    continue;
+                }
+            };
+            match sink.publish(&topic, payload) {
+                Ok(()) => log::debug!("mqtt <== {}({} sensors)", topic, report.len()),
+                Err(e) => log::error!("Failed to publish quality scores: {:?}", e),
+            }
+        }
+    })
+}
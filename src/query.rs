@@ -0,0 +1,181 @@
+//! Ad hoc SQL queries over the history database, for analysis without
+//! exporting the records first
+//!
+//! The database is opened read only, and only statements that don't write
+//! to it are run, so a query can't disturb a bridge recording to it at the
+//! same time. Records are in the `records` table, with their measurements in
+//! `measurements` by `record_id`, and the bridge's starts, stops and errors
+//! in `events`.
+
+use std::fmt::Write;
+
+use anyhow::{Context, Result};
+use rusqlite::types::ValueRef;
+use thiserror::Error;
+
+use crate::config::Config;
+
+#[derive(Error, Debug)]
+pub enum QueryError {
+    #[error("No history database configured, see --history-db")]
+    NoHistory,
+    #[error("Only queries that don't change the history can be run")]
+    NotReadOnly,
+}
+
+/// How query results are printed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Columns aligned for reading in a terminal
+    Table,
+    Csv,
+    /// An array of objects, one per row, keyed by column name
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Self::Table),
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Unknown format '{}'", s)),
+        }
+    }
+}
+
+/// The columns and rows a query returned
+#[derive(Debug, Default)]
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<serde_json::Value>>,
+}
+
+fn value(value: ValueRef) -> serde_json::Value {
+    match value {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(i) => i.into(),
+        ValueRef::Real(f) => f.into(),
+        ValueRef::Text(text) => String::from_utf8_lossy(text).into(),
+        ValueRef::Blob(blob) => {
+            let hex: String = blob.iter().map(|b| format!("{:02x}", b)).collect();
+            hex.into()
+        }
+    }
+}
+
+// A value as it's printed in a table or csv cell
+fn cell(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_owned(),
+    }
+}
+
+impl QueryResult {
+    /// The result printed in `format`
+    pub fn format(&self, format: Format) -> Result<String> {
+        let mut out = String::new();
+        match format {
+            Format::Table => {
+                let cells: Vec<Vec<String>> = self
+                    .rows
+                    .iter()
+                    .map(|row| row.iter().map(cell).collect())
+                    .collect();
+                let widths: Vec<usize> = self
+                    .columns
+                    .iter()
+                    .enumerate()
+                    .map(|(i, column)| {
+                        cells
+                            .iter()
+                            .map(|row| row[i].chars().count())
+                            .chain(Some(column.chars().count()))
+                            .max()
+                            .unwrap_or_default()
+                    })
+                    .collect();
+                let line = |row: &[String]| {
+                    let padded: Vec<String> = row
+                        .iter()
+                        .zip(&widths)
+                        .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                        .collect();
+                    padded.join("  ").trim_end().to_owned()
+                };
+                writeln!(out, "{}", line(&self.columns))?;
+                let rule: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+                writeln!(out, "{}", rule.join("  "))?;
+                for row in &cells {
+                    writeln!(out, "{}", line(row))?;
+                }
+                writeln!(out, "({} rows)", self.rows.len())?;
+            }
+            Format::Csv => {
+                let header: Vec<String> = self.columns.iter().map(|c| csv_field(c)).collect();
+                writeln!(out, "{}", header.join(","))?;
+                for row in &self.rows {
+                    let fields: Vec<String> = row.iter().map(|v| csv_field(&cell(v))).collect();
+                    writeln!(out, "{}", fields.join(","))?;
+                }
+            }
+            Format::Json => {
+                let rows: Vec<serde_json::Map<String, serde_json::Value>> = self
+                    .rows
+                    .iter()
+                    .map(|row| self.columns.iter().cloned().zip(row.clone()).collect())
+                    .collect();
+                writeln!(out, "{}", serde_json::to_string_pretty(&rows)?)?;
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Runs a query that doesn't write over the history database at `path`
+pub fn run(path: &std::path::Path, sql: &str) -> Result<QueryResult> {
+    let conn = rusqlite::Connection::open_with_flags(
+        path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .with_context(|| format!("Failed to open history database {}", path.display()))?;
+    conn.execute_batch("PRAGMA query_only = ON;")?;
+    let mut stmt = conn.prepare(sql).context("Invalid query")?;
+    if !stmt.readonly() {
+        return Err(QueryError::NotReadOnly.into());
+    }
+    let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+    let mut rows = stmt.query([]).context("Query failed")?;
+    let mut result = QueryResult {
+        columns,
+        rows: Vec::new(),
+    };
+    while let Some(row) = rows.next().context("Query failed")? {
+        let values = (0..result.columns.len())
+            .map(|i| Ok(value(row.get_ref(i)?)))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        result.rows.push(values);
+    }
+    Ok(result)
+}
+
+/// Runs a query over the configured history database
+pub fn query(conf: &Config, sql: &str) -> Result<QueryResult> {
+    let history = conf
+        .history
+        .as_ref()
+        .filter(|h| h.path.exists())
+        .ok_or(QueryError::NoHistory)?;
+    run(&history.path, sql)
+}
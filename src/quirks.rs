@@ -0,0 +1,113 @@
+//! Corrections for the quirks of particular models, e.g. WH31 variants
+//! whose humidity reads high, or rebadged units that report in different
+//! scaling, kept in the configuration file so that quirks found in the field
+//! don't need a new release
+//!
+//! Each quirk scales and offsets a field of the matching sensors' rtl_433
+//! records, which are then decoded again, so that raw payloads, normalized
+//! payloads and derived values all see the corrected value.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{ConfigError, SensorPattern};
+use crate::radio::Record;
+
+fn one() -> f64 {
+    1.0
+}
+
+/// A correction to a field of some sensors' records
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Quirk {
+    /// Sensors the quirk applies to, as a [`SensorPattern`], e.g. a model's
+    /// prefix `Fineoffset-WH31E/`
+    pub sensor: String,
+    /// Fields the record has to have these values in for the quirk to
+    /// apply, e.g. a subtype
+    #[serde(default)]
+    pub when: BTreeMap<String, serde_json::Value>,
+    /// rtl_433 field corrected, e.g. `humidity`
+    pub field: String,
+    /// Factor the field is multiplied by
+    #[serde(default = "one")]
+    pub scale: f64,
+    /// Amount added to the field after scaling
+    #[serde(default)]
+    pub offset: f64,
+    /// Where the quirk was found, for whoever reads the configuration next
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+impl Quirk {
+    // Corrects the field, keeping whole numbers whole since some parsers
+    // only accept integers
+    fn correct(&self, json: &mut serde_json::Value) -> bool {
+        let value = match json.get_mut(&self.field) {
+            Some(serde_json::Value::Number(value)) => value,
+            _ => return false,
+        };
+        let corrected = value.as_f64().unwrap_or_default() * self.scale + self.offset;
+        let corrected = if value.is_f64() {
+            serde_json::Number::from_f64(corrected)
+        } else if corrected < 0.0 {
+            Some((corrected.round() as i64).into())
+        } else {
+            Some((corrected.round() as u64).into())
+        };
+        match corrected {
+            Some(corrected) => {
+                *value = corrected;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The quirks table, compiled once rather than for every record
+#[derive(Debug, Default)]
+pub struct Quirks {
+    quirks: Vec<(SensorPattern, Quirk)>,
+}
+
+impl Quirks {
+    pub fn new(quirks: &[Quirk]) -> Result<Self, ConfigError> {
+        let quirks = quirks
+            .iter()
+            .map(|quirk| Ok((quirk.sensor.parse()?, quirk.clone())))
+            .collect::<Result<Vec<(SensorPattern, Quirk)>, ConfigError>>()?;
+        Ok(Quirks { quirks })
+    }
+
+    /// Applies the quirks matching the record, decoding it again if any did
+    pub fn apply(&self, record: &mut Record) {
+        let mut corrected = false;
+        for (pattern, quirk) in &self.quirks {
+            if !pattern.matches(&record.sensor_id)
+                || quirk
+                    .when
+                    .iter()
+                    .any(|(field, value)| record.record_json.get(field) != Some(value))
+            {
+                continue;
+            }
+            if quirk.correct(&mut record.record_json) {
+                log::trace!("[{}] Corrected {}", record.sensor_id, quirk.field);
+                corrected = true;
+            }
+        }
+        if !corrected {
+            return;
+        }
+        match crate::radio::decode(&record.record_json) {
+            Some(decoded) => record.measurements = decoded.measurements,
+            None => log::warn!(
+                "[{}] Record no longer decodes after correcting its quirks",
+                record.sensor_id
+            ),
+        }
+    }
+}
@@ -1,136 +1,527 @@
 use anyhow::{Context, Result};
-use std::io::BufRead;
+use tokio::io::AsyncBufReadExt;
 
 use uom::fmt::DisplayStyle::Abbreviation;
 use uom::si::{angle, u16::Angle};
+use uom::si::{electric_potential, f32::ElectricPotential};
 use uom::si::{energy, f32::Energy};
 use uom::si::{f32::Length, length};
+use uom::si::{f32::MassDensity, mass_density};
+use uom::si::{f32::Pressure, pressure};
 use uom::si::{f32::ThermodynamicTemperature, thermodynamic_temperature};
+use uom::si::{f32::Volume, volume};
 use uom::si::{time, u32::Time};
 use uom::si::{u16::Velocity, velocity};
 
-pub(crate) struct RTL433;
+use crate::errors::{ErrorCode, ErrorEvent};
+use crate::events::{Event, EventBus};
+use crate::units::UnitSystem;
 
-pub(crate) struct Sensor<R> {
-    _child: std::process::Child,
-    stdout: Option<std::io::BufReader<std::process::ChildStdout>>,
-    _stderr: Option<std::io::BufReader<std::process::ChildStderr>>,
+/// Marker for sensors read through rtl_433 child processes
+pub struct RTL433;
+
+/// Marker for sensors read directly from an RTL-SDR dongle
+#[cfg(feature = "rtlsdr")]
+pub struct RtlSdrNative;
+
+// Records read ahead of the pipeline before reading from the receivers waits
+const RECORD_QUEUE: usize = 256;
+
+/// A source of [`Record`]s received over the air, iterated until one of the
+/// underlying receivers exits. Records are read by a task per receiver on
+/// the tokio runtime, so that the receivers are kept drained while the
+/// pipeline is busy.
+pub struct Sensor<R> {
+    _children: Vec<tokio::process::Child>,
+    // A `None` marks a receiver having exited
+    records: tokio::sync::mpsc::Receiver<Option<Record>>,
+    // The fatal fault a receiver was stopped for
+    failure: std::sync::Arc<std::sync::Mutex<Option<String>>>,
     channel_type: std::marker::PhantomData<R>,
 }
 
 impl Sensor<RTL433> {
-    pub(crate) fn new(conf: &crate::config::Config) -> Result<Self> {
+    /// Launches an rtl_433 for each configured radio, listening for supported
+    /// devices, reporting problems on `events`, and suspending the radios as
+    /// the power profile's duty cycle calls for. Must be called within a
+    /// tokio runtime.
+    pub fn new(conf: &crate::config::Config, events: &EventBus) -> Result<Self> {
         let binpath = conf
             .rtl_433
             .as_ref()
             .ok_or_else(|| anyhow::anyhow!("Path to rtl_433 binary not set."))?;
-        let mut proc = std::process::Command::new(binpath.as_os_str());
-        proc.arg("-Mutc")
-            .arg("-Fjson")
-            .arg("-f915M")
-            .arg("-R113")
-            .arg("-Ccustomary")
-            .stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::piped());
-
-        // Swallow all of rtl_433's stderr output, unless we're logging at debug or higher
-        if conf.get_log_level() < log::LevelFilter::Debug {
-            proc.stderr(std::process::Stdio::piped());
-        }
-
-        // When logging at trace level, add signal level and protocol information to the
-        // captured information
-        if conf.get_log_level() >= log::LevelFilter::Trace {
-            proc.arg("-Mlevel").arg("-Mprotocol");
-        }
-        let mut child = proc.spawn().with_context(|| {
-            format!(
-                "Unable to launch rtl_433 binary at the configured location ({})",
-                binpath.display()
-            )
-        })?;
-
-        let stdout = child.stdout.take().map(std::io::BufReader::new);
-        let stderr = child.stderr.take().map(std::io::BufReader::new);
+        // As found on PATH, and with the `.exe` it's usually given without on
+        // Windows, since paths aren't given it when launched
+        let binpath = &crate::preflight::find_program(binpath).unwrap_or_else(|| binpath.clone());
+        let capture = conf
+            .capture
+            .as_deref()
+            .map(crate::capture::Capture::open)
+            .transpose()?;
+        let capture = std::sync::Arc::new(std::sync::Mutex::new(capture));
+        let default_args = conf.region.radio_args();
+        let radios: Vec<(Option<&str>, Vec<&str>)> = if conf.radios.is_empty() {
+            vec![(None, default_args.iter().map(String::as_str).collect())]
+        } else {
+            conf.radios
+                .iter()
+                .map(|(name, args)| {
+                    (
+                        Some(name.as_str()),
+                        args.iter().map(String::as_str).collect(),
+                    )
+                })
+                .collect()
+        };
+        let compat = crate::compat::Compat::probe(binpath)?;
+        let failure = std::sync::Arc::new(std::sync::Mutex::new(None));
+        let mut protocols = crate::protocols::Resolver::new(binpath);
+        let (sender, records) = tokio::sync::mpsc::channel(RECORD_QUEUE);
+        let mut children = Vec::new();
+        for (radio, args) in radios {
+            let args = protocols.resolve_args(&args).with_context(|| {
+                format!(
+                    "Failed to resolve the decoders of radio {:?}",
+                    radio.unwrap_or("default")
+                )
+            })?;
+            // When logging at trace level, add signal level and protocol information to the
+            // captured information
+            let detailed = conf.get_log_level() >= log::LevelFilter::Trace;
+            let args = compat.args(&args, conf.units, detailed).with_context(|| {
+                format!("Radio {:?} can't be launched", radio.unwrap_or("default"))
+            })?;
+            let mut proc = tokio::process::Command::new(binpath.as_os_str());
+            proc.args(&args)
+                .stdin(std::process::Stdio::piped())
+                .stdout(std::process::Stdio::piped())
+                .stderr(std::process::Stdio::piped())
+                .kill_on_drop(true);
+            log::debug!("Launching rtl_433 for radio {:?}: {:?}", radio, proc);
+            let mut child = proc.spawn().with_context(|| {
+                format!(
+                    "Unable to launch rtl_433 binary at the configured location ({})",
+                    binpath.display()
+                )
+            })?;
+
+            let stdout = child
+                .stdout
+                .take()
+                .ok_or_else(|| anyhow::anyhow!("No output pipe for rtl_433 process!"))?;
+            if let Some(stderr) = child.stderr.take() {
+                tokio::spawn(watch_stderr(
+                    stderr,
+                    radio.map(str::to_owned),
+                    child.id(),
+                    failure.clone(),
+                    events.clone(),
+                ));
+            }
+            tokio::spawn(read_records(
+                stdout,
+                radio.map(str::to_owned),
+                sender.clone(),
+                capture.clone(),
+                conf.integrity.clone(),
+                conf.chaos,
+                events.clone(),
+            ));
+            children.push(child);
+        }
+        let pids = children.iter().filter_map(|child| child.id()).collect();
+        tokio::spawn(crate::power::suspend_radios(
+            pids,
+            crate::power::DutyCycle::follow(&conf.power, events),
+        ));
         Ok(Sensor {
-            _child: child,
-            stdout,
-            _stderr: stderr,
+            _children: children,
+            records,
+            failure,
             channel_type: std::marker::PhantomData,
         })
     }
+}
 
-    pub(crate) fn get_line(&mut self) -> Option<String> {
-        if let Some(stdout) = &mut self.stdout {
-            let mut line = String::new();
-            while line.is_empty() {
-                let result = stdout.read_line(&mut line);
-                log::trace!("Reading from rtl_433: {:?} => '{}'", result, line);
-                match result {
-                    Ok(0) => return None,
-                    Ok(_) => return Some(line),
-                    Err(_) => (),
+#[cfg(feature = "rtlsdr")]
+impl Sensor<RtlSdrNative> {
+    /// Opens the configured RTL-SDR dongle, listening for the Fine Offset
+    /// sensors the raw packet decoders support, and reporting problems on
+    /// `events`. The dongle is read on a thread of its own, which closes it
+    /// once the sensor is dropped.
+    pub fn new(conf: &crate::config::Config, events: &EventBus) -> Result<Self> {
+        let device = crate::rtlsdr::Device::open(&conf.rtlsdr, conf.region)?;
+        let capture = conf
+            .capture
+            .as_deref()
+            .map(crate::capture::Capture::open)
+            .transpose()?;
+        let capture = std::sync::Mutex::new(capture);
+        let integrity = conf.integrity.clone();
+        let (sender, records) = tokio::sync::mpsc::channel(RECORD_QUEUE);
+        let duty = crate::power::DutyCycle::follow(&conf.power, events);
+        let events = events.clone();
+        std::thread::spawn(move || {
+            crate::rtlsdr::receive(device, duty, |packet, timestamp| {
+                let json = match crate::fineoffset::packet_json(packet, timestamp) {
+                    Ok(json) => json,
+                    Err(e) => {
+                        log::debug!("Error decoding packet from RTL-SDR: {:?}", e);
+                        events.publish(Event::Error(
+                            ErrorEvent::new(ErrorCode::Decode, format!("{:#}", e))
+                                .context("receiver", "rtl-sdr"),
+                        ));
+                        return true;
+                    }
+                };
+                capture_with(&capture, &events, |writer| {
+                    writer.write_line(&json.to_string())
+                });
+                match accept(&json, None, &capture, &integrity, &events) {
+                    Some(record) => sender.blocking_send(Some(record)).is_ok(),
+                    None => true,
                 }
-                log::error!("Error reading from rtl_433: {:?}", result);
-            }
-            unreachable!();
-        } else {
-            log::error!("No output pipe for rtl_433 process!");
-            None
-        }
+            });
+            log::warn!("RTL-SDR stopped");
+            events.publish(Event::Error(
+                ErrorEvent::new(ErrorCode::RadioStopped, "RTL-SDR stopped")
+                    .context("receiver", "rtl-sdr"),
+            ));
+            let _ = sender.blocking_send(None);
+        });
+        Ok(Sensor {
+            _children: Vec::new(),
+            records,
+            failure: Default::default(),
+            channel_type: std::marker::PhantomData,
+        })
     }
 }
 
-impl Iterator for Sensor<RTL433> {
+impl<R> Sensor<R> {
+    /// Waits for the next record, or `None` once a receiver exits
+    pub async fn recv(&mut self) -> Option<Record> {
+        self.records.recv().await.flatten()
+    }
+
+    /// The fatal fault a receiver was stopped for, if it was
+    pub fn failure(&self) -> Option<String> {
+        self.failure.lock().expect("failure poisoned").clone()
+    }
+
+    /// Where the fatal fault a receiver was stopped for is kept, for finding
+    /// out once the sensor's been handed off to be read from
+    pub fn failure_handle(&self) -> std::sync::Arc<std::sync::Mutex<Option<String>>> {
+        self.failure.clone()
+    }
+}
+
+/// Blocks waiting for each record, so must not be iterated from within an
+/// async task
+impl<R> Iterator for Sensor<R> {
     type Item = Record;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // retry getting lines and parsing them as json until we get one that
-        // parses correctly, or until we reach the end of child process
-        loop {
-            let line = match self.get_line() {
-                None => return None,
-                Some(l) => l,
-            };
-            let json_result: std::result::Result<serde_json::Value, serde_json::Error> =
-                serde_json::from_str(&line);
-            let json = match json_result {
-                Ok(json) => json,
-                Err(e) => {
-                    log::error!("Error parsing rtl_433 output: {:?}", e);
-                    return None;
-                }
-            };
-            if let Ok(record) = crate::ambientweather::try_parse(&json) {
-                return Some(record);
+        self.records.blocking_recv().flatten()
+    }
+}
+
+// Writes to the capture, if there is one
+fn capture_with<F>(
+    capture: &std::sync::Mutex<Option<crate::capture::Capture>>,
+    events: &EventBus,
+    write: F,
+) where
+    F: FnOnce(&mut crate::capture::Capture) -> Result<()>,
+{
+    let mut capture = capture.lock().expect("capture poisoned");
+    if let Some(ref mut writer) = *capture {
+        // The capture is a nicety, so don't let it take the radio down
+        if let Err(e) = write(writer) {
+            log::error!("Failed to capture record, no longer capturing: {:?}", e);
+            events.publish(Event::Error(ErrorEvent::new(
+                ErrorCode::Capture,
+                format!("No longer capturing: {:#}", e),
+            )));
+            *capture = None;
+        }
+    }
+}
+
+// Appends a record to the capture, if there is one
+fn capture_record(
+    capture: &std::sync::Mutex<Option<crate::capture::Capture>>,
+    record: Option<&Record>,
+    json: &serde_json::Value,
+    events: &EventBus,
+) {
+    let timestamp = record
+        .map(|r| r.timestamp)
+        .unwrap_or_else(crate::clock::now);
+    capture_with(capture, events, |writer| {
+        writer.write_record(timestamp, json)
+    });
+}
+
+// Decodes json received from a radio, appending it to the capture if there
+// is one, and returns the record tagged with the radio if it's from a
+// supported device and meets the integrity requirements
+fn accept(
+    json: &serde_json::Value,
+    radio: Option<&str>,
+    capture: &std::sync::Mutex<Option<crate::capture::Capture>>,
+    integrity: &crate::config::IntegrityConfig,
+    events: &EventBus,
+) -> Option<Record> {
+    let record = decode_received(json);
+    crate::usage::observe(json, record.as_ref());
+    capture_record(capture, record.as_ref(), json, events);
+    let mut record = match record {
+        Some(record) => record,
+        None => {
+            events.publish(Event::Unparsed(json.clone()));
+            return None;
+        }
+    };
+    if !integrity.accepts(&record) {
+        events.publish(Event::IntegrityFailure {
+            sensor_id: record.sensor_id,
+        });
+        return None;
+    }
+    record.radio = radio.map(str::to_owned);
+    Some(record)
+}
+
+// Reads rtl_433 output, passing on the records that decode and meet the
+// integrity requirements, tagged with the radio they came from, until
+// rtl_433 exits or emits something that isn't json. Everything that is json
+// is appended to a packed capture, if there is one, and every line to a
+// capture of lines, before it's parsed.
+async fn read_records(
+    stdout: tokio::process::ChildStdout,
+    radio: Option<String>,
+    records: tokio::sync::mpsc::Sender<Option<Record>>,
+    capture: std::sync::Arc<std::sync::Mutex<Option<crate::capture::Capture>>>,
+    integrity: crate::config::IntegrityConfig,
+    chaos: crate::chaos::ChaosConfig,
+    events: EventBus,
+) {
+    let error = |code, message: String| {
+        let event = ErrorEvent::new(code, message);
+        let event = match radio {
+            Some(ref radio) => event.context("radio", radio),
+            None => event,
+        };
+        events.publish(Event::Error(event));
+    };
+    let mut lines = tokio::io::BufReader::new(stdout).lines();
+    loop {
+        let result = lines.next_line().await;
+        log::trace!("Reading from rtl_433: {:?}", result);
+        let line = match result {
+            Ok(Some(line)) if crate::chaos::roll(chaos.malformed_rate) => {
+                log::warn!("Injecting malformed rtl_433 output");
+                crate::chaos::mangle(&line)
             }
-            if let Ok(record) = crate::idm::try_parse(&json) {
-                return Some(record);
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                log::error!("Error reading from rtl_433: {:?}", e);
+                error(ErrorCode::RadioRead, e.to_string());
+                continue;
             }
+        };
+        capture_with(&capture, &events, |writer| writer.write_line(&line));
+        let json: serde_json::Value = match serde_json::from_str(&line) {
+            Ok(json) => json,
+            // A garbled line is skipped, rtl_433 carries on with the next
+            Err(e) => {
+                log::error!("Error parsing rtl_433 output: {:?}", e);
+                crate::counters::count(|counters| counters.parse_errors += 1);
+                error(ErrorCode::MalformedOutput, format!("{}: {}", e, line));
+                continue;
+            }
+        };
+        let record = match accept(&json, radio.as_deref(), &capture, &integrity, &events) {
+            Some(record) => record,
+            None => continue,
+        };
+        if records.send(Some(record)).await.is_err() {
+            return;
         }
-        /*
-        if let Ok(Some(status)) = self.child.try_wait() {
-            return None;
+    }
+    if let Some(ref radio) = radio {
+        log::warn!("rtl_433 for radio {} stopped", radio);
+    }
+    crate::counters::count(|counters| counters.rtl_433_exits += 1);
+    error(ErrorCode::RadioStopped, "rtl_433 stopped".to_owned());
+    let _ = records.send(None).await;
+}
+
+// Reads rtl_433's stderr, logging it at debug level, until rtl_433 exits.
+// Lines reporting a known fault are logged as warnings and reported on the
+// event bus instead, and the first fatal one stops rtl_433, so that the
+// bridge exits.
+async fn watch_stderr(
+    stderr: tokio::process::ChildStderr,
+    radio: Option<String>,
+    pid: Option<u32>,
+    failure: std::sync::Arc<std::sync::Mutex<Option<String>>>,
+    events: EventBus,
+) {
+    let name = radio.as_deref().unwrap_or("default");
+    let mut lines = tokio::io::BufReader::new(stderr).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let fault = match crate::faults::recognize(&line) {
+            Some(fault) => fault,
+            None => {
+                log::debug!("rtl_433: {}", line);
+                continue;
+            }
+        };
+        log::warn!("rtl_433 for radio {} reported {}: {}", name, fault, line);
+        let event = ErrorEvent::new(ErrorCode::RadioFault, line.trim()).context("fault", fault);
+        events.publish(Event::Error(match radio {
+            Some(ref radio) => event.context("radio", radio),
+            None => event,
+        }));
+        let mut failure = failure.lock().expect("failure poisoned");
+        if !fault.is_fatal() || failure.is_some() {
+            continue;
+        }
+        log::error!("Stopping rtl_433 for radio {} after {}", name, fault);
+        *failure = Some(format!(
+            "rtl_433 for radio {} reported {}: {}",
+            name,
+            fault,
+            line.trim()
+        ));
+        #[cfg(unix)]
+        if let Some(pid) = pid {
+            crate::power::signal(pid, "KILL");
+        }
+        #[cfg(windows)]
+        if let Some(pid) = pid {
+            crate::power::terminate(pid);
+        }
+        #[cfg(not(any(unix, windows)))]
+        let _ = pid;
+    }
+}
+
+type Parser = fn(&serde_json::Value) -> Result<Record>;
+
+// Parsers, in order of preference when tied, with a bonus reflecting how
+// selective each is. The SCM and IDM parsers only accept their own models,
+// but the Ambient Weather parser accepts anything with a model and id, and
+// the generic parser, when enabled, anything with a model at all.
+const PARSERS: &[(&str, i32, Parser)] = &[
+    ("scm", 2, crate::scm::try_parse),
+    ("idm", 2, crate::idm::try_parse),
+    ("ambientweather", 0, crate::ambientweather::try_parse),
+    ("generic", -1, crate::generic::try_parse),
+];
+
+/// Decodes a json record from rtl_433 with the parser most confident in its
+/// result, or `None` if it isn't from a supported device
+pub fn decode(json: &serde_json::Value) -> Option<Record> {
+    decode_with_parser(json).map(|(_, record)| record)
+}
+
+/// Decodes a json record received from rtl_433 as [`decode`] does, counting
+/// it, and which parser decoded it, towards the bridge's
+/// [counters](crate::counters)
+pub fn decode_received(json: &serde_json::Value) -> Option<Record> {
+    let decoded = decode_with_parser(json);
+    crate::counters::count(|counters| {
+        counters.records_received += 1;
+        match &decoded {
+            Some((parser, _)) => *counters.parsers.entry((*parser).to_owned()).or_default() += 1,
+            None => counters.parse_errors += 1,
+        }
+    });
+    decoded.map(|(_, record)| record)
+}
+
+// Decodes a json record from rtl_433, along with the name of the parser that
+// decoded it
+fn decode_with_parser(json: &serde_json::Value) -> Option<(&'static str, Record)> {
+    let mut best: Option<(&'static str, i32, Record)> = None;
+    for (name, selectivity, parse) in PARSERS {
+        let record = match parse(json) {
+            Ok(record) => record,
+            Err(_) => continue,
+        };
+        let score = selectivity + record.confidence();
+        match best {
+            Some((best_name, best_score, _)) if score == best_score => {
+                log::debug!(
+                    "[{}] Ambiguous packet, {} and {} parsers tied with confidence {}: {}",
+                    record.sensor_id,
+                    best_name,
+                    name,
+                    score,
+                    json
+                );
+            }
+            Some((_, best_score, _)) if score < best_score => {}
+            _ => best = Some((name, score, record)),
+        }
+    }
+    let record = best.map(|(name, _, record)| (name, record));
+    // Records rtl_433 couldn't make sense of may still carry a raw packet
+    // the Fine Offset decoders can
+    #[cfg(feature = "raw-decoders")]
+    if record
+        .as_ref()
+        .is_none_or(|(_, r)| r.measurements.is_empty())
+    {
+        match crate::fineoffset::raw_data_json(json) {
+            Ok(raw) => return decode_with_parser(&raw).or(record),
+            Err(e) => log::trace!("No raw packet decoded from {}: {:?}", json, e),
         }
-        */
     }
+    record
 }
 
-#[allow(dead_code)]
+/// A single typed value reported by, or derived from, a sensor
 #[derive(Clone, Debug, PartialEq)]
-pub(crate) enum Measurement {
+pub enum Measurement {
     TotalEnergyConsumption(Energy),
     DifferentialEnergyConsumption(Energy, Time),
+    VolumeConsumption(Volume),
     BatteryOk(bool),
     Temperature(ThermodynamicTemperature),
+    /// Temperature of one of several probes reported in suffixed fields,
+    /// e.g. `temperature_2_C`, labeled with the probe's configured name or
+    /// else its number
+    ProbeTemperature(String, ThermodynamicTemperature),
     RelativeHumidity(u8),
     BatteryLevelRaw(u8),
     Clock(chrono::Utc),
     Rainfall(Length),
-    Lux(u16),
+    RainfallDelta(Length),
+    /// Rainfall so far in the rain day, which starts at a configured time
+    DailyRainfall(Length),
+    RainRate(uom::si::f32::Velocity),
+    Lux(u32),
+    /// UV index, on the WHO scale
+    UvIndex(f32),
     WindSpeed(Velocity),
     WindGust(Velocity),
     WindDirection(Angle),
+    Pm2_5(MassDensity),
+    Pm10(MassDensity),
+    /// Carbon dioxide concentration, in parts per million
+    Co2(u16),
+    /// Barometric pressure at the station
+    Pressure(Pressure),
+    /// Barometric pressure corrected to sea level for the station's altitude
+    SeaLevelPressure(Pressure),
+    /// Voltage of the sensor's power supply, e.g. a solar charged supercap
+    Voltage(ElectricPotential),
+    Derived(crate::derive::DerivedValue),
     None,
 }
 
@@ -141,27 +532,307 @@ impl std::fmt::Display for Measurement {
 }
 
 impl Measurement {
-    pub(crate) fn name(&self) -> String {
+    /// Short name of the kind of measurement, used in topics and metadata
+    pub fn name(&self) -> String {
         let text = match self {
             Self::TotalEnergyConsumption(_) => "TotalEnergy",
             Self::DifferentialEnergyConsumption(_, _) => "EnergyOverTime",
+            Self::VolumeConsumption(_) => "TotalVolume",
             Self::BatteryOk(_) => "BatteryOk",
             Self::Temperature(_) => "TemperatureF",
             Self::RelativeHumidity(_) => "Humidity",
             Self::BatteryLevelRaw(_) => "BatteryLevel",
             Self::Clock(_) => "Clock",
             Self::Rainfall(_) => "Rainfall",
+            Self::RainfallDelta(_) => "RainfallDelta",
+            Self::DailyRainfall(_) => "DailyRainfall",
+            Self::RainRate(_) => "RainRate",
             Self::Lux(_) => "Lux",
+            Self::UvIndex(_) => "UvIndex",
             Self::WindSpeed(_) => "WindSpeed",
             Self::WindGust(_) => "WindGust",
             Self::WindDirection(_) => "WindDirection",
+            Self::Pm2_5(_) => "PM2.5",
+            Self::Pm10(_) => "PM10",
+            Self::Co2(_) => "CO2",
+            Self::Pressure(_) => "Pressure",
+            Self::SeaLevelPressure(_) => "SeaLevelPressure",
+            Self::Voltage(_) => "Voltage",
+            Self::ProbeTemperature(probe, _) => return probe.clone(),
+            Self::Derived(d) => return d.name.clone(),
             Self::None => "None",
         };
 
         text.to_owned()
     }
 
-    pub(crate) fn value(&self) -> String {
+    /// Short name of the kind of measurement as published in the given
+    /// system of units. Temperatures are named after the scale they're
+    /// given in, `TemperatureF` as historically and in imperial units, so
+    /// that the name doesn't contradict the value published under it.
+    pub fn name_in(&self, system: Option<UnitSystem>) -> String {
+        match (self, system) {
+            (Self::Temperature(_), Some(UnitSystem::Metric)) => "TemperatureC".to_owned(),
+            (Self::Temperature(_), Some(UnitSystem::Si)) => "TemperatureK".to_owned(),
+            _ => self.name(),
+        }
+    }
+
+    /// Whether the value is within the range a real sensor could report,
+    /// which decoding garbage as the wrong device often isn't
+    pub fn is_plausible(&self) -> bool {
+        match self {
+            Self::Temperature(t) => {
+                (-60.0..=70.0).contains(&t.get::<thermodynamic_temperature::degree_celsius>())
+            }
+            Self::RelativeHumidity(h) => *h <= 100,
+            Self::Pressure(p) => (500.0..=1100.0).contains(&p.get::<pressure::hectopascal>()),
+            Self::Pm2_5(p) | Self::Pm10(p) => {
+                (0.0..=1000.0).contains(&p.get::<mass_density::microgram_per_cubic_meter>())
+            }
+            Self::Co2(c) => (250..=10000).contains(c),
+            Self::UvIndex(u) => (0.0..=20.0).contains(u),
+            Self::WindDirection(w) => w.get::<angle::degree>() <= 360,
+            Self::Rainfall(r) => r.get::<length::millimeter>() >= 0.0,
+            _ => true,
+        }
+    }
+
+    /// The measurement with `offset` added to its value, given in the metric
+    /// unit [`crate::normalize::key`] names it with; `None` for kinds of
+    /// measurement that aren't calibrated
+    pub fn calibrated(&self, offset: f32) -> Option<Measurement> {
+        let counter = |value: u16| (value as f32 + offset).round().max(0.0) as u16;
+        let measurement = match self {
+            Self::Temperature(t) => {
+                let celsius = t.get::<thermodynamic_temperature::degree_celsius>() + offset;
+                Self::Temperature(ThermodynamicTemperature::new::<
+                    thermodynamic_temperature::degree_celsius,
+                >(celsius))
+            }
+            Self::ProbeTemperature(probe, t) => {
+                let celsius = t.get::<thermodynamic_temperature::degree_celsius>() + offset;
+                Self::ProbeTemperature(
+                    probe.clone(),
+                    ThermodynamicTemperature::new::<thermodynamic_temperature::degree_celsius>(
+                        celsius,
+                    ),
+                )
+            }
+            Self::RelativeHumidity(h) => {
+                Self::RelativeHumidity((*h as f32 + offset).round().clamp(0.0, 100.0) as u8)
+            }
+            Self::Pressure(p) => {
+                let hpa = p.get::<pressure::hectopascal>() + offset;
+                Self::Pressure(Pressure::new::<pressure::hectopascal>(hpa))
+            }
+            Self::Pm2_5(p) | Self::Pm10(p) => {
+                let pm = p.get::<mass_density::microgram_per_cubic_meter>() + offset;
+                let pm = MassDensity::new::<mass_density::microgram_per_cubic_meter>(pm.max(0.0));
+                match self {
+                    Self::Pm2_5(_) => Self::Pm2_5(pm),
+                    _ => Self::Pm10(pm),
+                }
+            }
+            Self::Co2(c) => Self::Co2(counter(*c)),
+            Self::Lux(l) => Self::Lux((*l as f32 + offset).round().max(0.0) as u32),
+            Self::UvIndex(u) => Self::UvIndex((u + offset).max(0.0)),
+            _ => return None,
+        };
+        Some(measurement)
+    }
+
+    /// Whether the measurement was computed by weatherradio rather than
+    /// reported by the sensor
+    pub fn is_derived(&self) -> bool {
+        matches!(
+            self,
+            Self::RainfallDelta(_)
+                | Self::DailyRainfall(_)
+                | Self::RainRate(_)
+                | Self::SeaLevelPressure(_)
+                | Self::Derived(_)
+        )
+    }
+
+    /// How the measurement was computed, for derived measurements
+    pub fn lineage(&self) -> Option<crate::derive::Lineage> {
+        use crate::derive::Lineage;
+        let rain = |formula| Lineage::new(&["Rainfall"], formula, crate::rain::FORMULA_VERSION);
+        match self {
+            Self::RainfallDelta(_) => Some(rain("rainfall_delta")),
+            Self::DailyRainfall(_) => Some(rain("daily_rainfall")),
+            Self::RainRate(_) => Some(rain("rain_rate")),
+            Self::SeaLevelPressure(_) => Some(Lineage::new(
+                &["Pressure", "TemperatureF"],
+                "hypsometric",
+                crate::pressure::FORMULA_VERSION,
+            )),
+            Self::Derived(d) => d.lineage.clone(),
+            _ => None,
+        }
+    }
+
+    /// The value and unit abbreviation of quantities in the given system of
+    /// units, or `None` for measurements that aren't physical quantities
+    fn converted(&self, system: UnitSystem) -> Option<(f32, &'static str)> {
+        use UnitSystem::{Imperial, Metric, Si};
+        let converted = match (self, system) {
+            (Self::TotalEnergyConsumption(e) | Self::DifferentialEnergyConsumption(e, _), Si) => {
+                (e.get::<energy::joule>(), "J")
+            }
+            (Self::TotalEnergyConsumption(e) | Self::DifferentialEnergyConsumption(e, _), _) => {
+                (e.get::<energy::kilowatt_hour>(), "kWh")
+            }
+            (Self::VolumeConsumption(v), Imperial) => (v.get::<volume::cubic_foot>(), "ft³"),
+            (Self::VolumeConsumption(v), _) => (v.get::<volume::cubic_meter>(), "m³"),
+            (Self::Temperature(t) | Self::ProbeTemperature(_, t), Metric) => {
+                (t.get::<thermodynamic_temperature::degree_celsius>(), "°C")
+            }
+            (Self::Temperature(t) | Self::ProbeTemperature(_, t), Imperial) => (
+                t.get::<thermodynamic_temperature::degree_fahrenheit>(),
+                "°F",
+            ),
+            (Self::Temperature(t) | Self::ProbeTemperature(_, t), Si) => {
+                (t.get::<thermodynamic_temperature::kelvin>(), "K")
+            }
+            (Self::Rainfall(m) | Self::RainfallDelta(m) | Self::DailyRainfall(m), Imperial) => {
+                (m.get::<length::inch>(), "in")
+            }
+            (Self::Rainfall(m) | Self::RainfallDelta(m) | Self::DailyRainfall(m), _) => {
+                (m.get::<length::millimeter>(), "mm")
+            }
+            (Self::RainRate(r), Imperial) => {
+                (r.get::<velocity::inch_per_second>() * 3600.0, "in/h")
+            }
+            (Self::RainRate(r), _) => (r.get::<velocity::millimeter_per_minute>() * 60.0, "mm/h"),
+            // Wind speeds are held as whole m/s, so convert from those to
+            // avoid rounding twice
+            (Self::WindSpeed(w) | Self::WindGust(w), system) => {
+                let ms = f32::from(w.get::<velocity::meter_per_second>());
+                match system {
+                    Metric => (ms * 3.6, "km/h"),
+                    Imperial => (ms * 3600.0 / 1609.344, "mph"),
+                    Si => (ms, "m/s"),
+                }
+            }
+            (Self::Pressure(p) | Self::SeaLevelPressure(p), Metric) => {
+                (p.get::<pressure::hectopascal>(), "hPa")
+            }
+            (Self::Pressure(p) | Self::SeaLevelPressure(p), Imperial) => {
+                (p.get::<pressure::inch_of_mercury>(), "inHg")
+            }
+            (Self::Pressure(p) | Self::SeaLevelPressure(p), Si) => {
+                (p.get::<pressure::pascal>(), "Pa")
+            }
+            _ => return None,
+        };
+        Some(converted)
+    }
+
+    /// Abbreviation of the unit that `value_in()` presents the measurement
+    /// in, for the given system of units
+    pub fn unit_in(&self, system: Option<UnitSystem>) -> String {
+        match system.and_then(|s| self.converted(s)) {
+            Some((_, unit)) => unit.to_owned(),
+            None => self.unit(),
+        }
+    }
+
+    /// The measured value as a plain number in the units of `unit_in()`
+    pub fn numeric_in(&self, system: Option<UnitSystem>) -> Option<f64> {
+        match system.and_then(|s| self.converted(s)) {
+            Some((value, _)) => Some(f64::from(value)),
+            None => self.numeric(),
+        }
+    }
+
+    /// The measured value, formatted for publishing in the given system of
+    /// units. Without one, each measurement is presented in its historical
+    /// units, as `value()` does.
+    pub fn value_in(&self, system: Option<UnitSystem>) -> String {
+        let (value, unit) = match system.and_then(|s| self.converted(s)) {
+            Some(converted) => converted,
+            None => return self.value(),
+        };
+        match self {
+            Self::DifferentialEnergyConsumption(_, t) => format!(
+                "{:.*} {} over the last {:.1}",
+                crate::units::PRECISION,
+                value,
+                unit,
+                t.into_format_args(time::hour, Abbreviation)
+            ),
+            _ => format!("{:.*} {}", crate::units::PRECISION, value, unit),
+        }
+    }
+
+    /// Abbreviation of the unit that `value()` is presented in
+    pub fn unit(&self) -> String {
+        let text = match self {
+            Self::TotalEnergyConsumption(_) => "kWh",
+            Self::DifferentialEnergyConsumption(_, _) => "kWh",
+            Self::VolumeConsumption(_) => "m³",
+            Self::Temperature(_) | Self::ProbeTemperature(_, _) => "°F",
+            Self::RelativeHumidity(_) => "%",
+            Self::Rainfall(_) => "mm",
+            Self::RainfallDelta(_) => "mm",
+            Self::DailyRainfall(_) => "mm",
+            Self::RainRate(_) => "mm/h",
+            Self::Lux(_) => "lx",
+            Self::UvIndex(_) => "",
+            Self::WindSpeed(_) => "km/h",
+            Self::WindGust(_) => "km/h",
+            Self::WindDirection(_) => "°",
+            Self::Pm2_5(_) => "µg/m³",
+            Self::Pm10(_) => "µg/m³",
+            Self::Co2(_) => "ppm",
+            Self::Pressure(_) => "hPa",
+            Self::SeaLevelPressure(_) => "hPa",
+            Self::Voltage(_) => "V",
+            Self::Derived(d) => return d.unit.clone(),
+            Self::BatteryOk(_) | Self::BatteryLevelRaw(_) | Self::Clock(_) | Self::None => "",
+        };
+
+        text.to_owned()
+    }
+
+    /// The measured value as a plain number in the units of `unit()`, for
+    /// measurements that have one
+    pub fn numeric(&self) -> Option<f64> {
+        let number = match self {
+            Self::TotalEnergyConsumption(e) => e.get::<energy::kilowatt_hour>(),
+            Self::DifferentialEnergyConsumption(e, _) => e.get::<energy::kilowatt_hour>(),
+            Self::VolumeConsumption(v) => v.get::<volume::cubic_meter>(),
+            Self::BatteryOk(b) => f32::from(u8::from(*b)),
+            Self::Temperature(t) | Self::ProbeTemperature(_, t) => {
+                t.get::<thermodynamic_temperature::degree_fahrenheit>()
+            }
+            Self::RelativeHumidity(h) => f32::from(*h),
+            Self::BatteryLevelRaw(b) => f32::from(*b),
+            Self::Rainfall(m) => m.get::<length::millimeter>(),
+            Self::RainfallDelta(m) => m.get::<length::millimeter>(),
+            Self::DailyRainfall(m) => m.get::<length::millimeter>(),
+            Self::RainRate(r) => r.get::<velocity::millimeter_per_minute>() * 60.0,
+            Self::Lux(l) => *l as f32,
+            Self::UvIndex(u) => *u,
+            Self::WindSpeed(w) => f32::from(w.get::<velocity::kilometer_per_hour>()),
+            Self::WindGust(w) => f32::from(w.get::<velocity::kilometer_per_hour>()),
+            Self::WindDirection(w) => f32::from(w.get::<angle::degree>()),
+            Self::Pm2_5(p) => p.get::<mass_density::microgram_per_cubic_meter>(),
+            Self::Pm10(p) => p.get::<mass_density::microgram_per_cubic_meter>(),
+            Self::Co2(c) => f32::from(*c),
+            Self::Pressure(p) => p.get::<pressure::hectopascal>(),
+            Self::SeaLevelPressure(p) => p.get::<pressure::hectopascal>(),
+            Self::Voltage(v) => v.get::<electric_potential::volt>(),
+            Self::Derived(d) => d.value,
+            Self::Clock(_) | Self::None => return None,
+        };
+        Some(f64::from(number))
+    }
+
+    /// The measured value, formatted for publishing
+    pub fn value(&self) -> String {
         match self {
             Self::TotalEnergyConsumption(e) => e
                 .into_format_args(energy::kilowatt_hour, Abbreviation)
@@ -171,8 +842,12 @@ impl Measurement {
                 e.into_format_args(energy::kilowatt_hour, Abbreviation),
                 t.into_format_args(time::hour, Abbreviation)
             ),
+            Self::VolumeConsumption(v) => format!(
+                "{:.1}",
+                v.into_format_args(volume::cubic_meter, Abbreviation)
+            ),
             Self::BatteryOk(b) => b.to_string(),
-            Self::Temperature(t) => format!(
+            Self::Temperature(t) | Self::ProbeTemperature(_, t) => format!(
                 "{:.1}",
                 t.into_format_args(thermodynamic_temperature::degree_fahrenheit, Abbreviation)
             ),
@@ -182,7 +857,15 @@ impl Measurement {
             Self::Rainfall(m) => m
                 .into_format_args(length::millimeter, Abbreviation)
                 .to_string(),
+            Self::RainfallDelta(m) | Self::DailyRainfall(m) => m
+                .into_format_args(length::millimeter, Abbreviation)
+                .to_string(),
+            Self::RainRate(r) => format!(
+                "{:.1} mm/h",
+                r.get::<velocity::millimeter_per_minute>() * 60.0
+            ),
             Self::Lux(l) => l.to_string(),
+            Self::UvIndex(u) => format!("{:.1}", u),
             Self::WindSpeed(w) => w
                 .into_format_args(velocity::kilometer_per_hour, Abbreviation)
                 .to_string(),
@@ -190,17 +873,76 @@ impl Measurement {
                 .into_format_args(velocity::kilometer_per_hour, Abbreviation)
                 .to_string(),
             Self::WindDirection(w) => w.into_format_args(angle::degree, Abbreviation).to_string(),
+            Self::Pm2_5(p) => format!(
+                "{:.1}",
+                p.into_format_args(mass_density::microgram_per_cubic_meter, Abbreviation)
+            ),
+            Self::Pm10(p) => format!(
+                "{:.1}",
+                p.into_format_args(mass_density::microgram_per_cubic_meter, Abbreviation)
+            ),
+            Self::Co2(c) => format!("{} ppm", c),
+            Self::Pressure(p) => format!(
+                "{:.1}",
+                p.into_format_args(pressure::hectopascal, Abbreviation)
+            ),
+            Self::SeaLevelPressure(p) => format!(
+                "{:.1}",
+                p.into_format_args(pressure::hectopascal, Abbreviation)
+            ),
+            Self::Voltage(v) => format!(
+                "{:.1}",
+                v.into_format_args(electric_potential::volt, Abbreviation)
+            ),
+            Self::Derived(d) if d.unit.is_empty() => {
+                format!("{:.*}", crate::units::PRECISION, d.value)
+            }
+            Self::Derived(d) => format!("{:.*} {}", crate::units::PRECISION, d.value, d.unit),
             Self::None => String::new(),
         }
     }
 }
 
+/// A single transmission received from a sensor
 #[derive(Clone, Debug, PartialEq)]
-pub(crate) struct Record {
-    pub(crate) timestamp: chrono::DateTime<chrono::Local>,
-    pub(crate) sensor_id: String,
-    pub(crate) record_json: serde_json::value::Value,
-    pub(crate) measurements: Vec<Measurement>,
+pub struct Record {
+    /// When the transmission was received
+    pub timestamp: chrono::DateTime<chrono::Local>,
+    /// Identifier of the transmitting sensor, unique across supported devices
+    pub sensor_id: String,
+    /// The record exactly as rtl_433 reported it
+    pub record_json: serde_json::value::Value,
+    /// Values decoded from the record
+    pub measurements: Vec<Measurement>,
+    /// Name of the radio that received the record, when several are
+    /// configured
+    pub radio: Option<String>,
+}
+
+impl Record {
+    /// How likely the record is to have been decoded as the right device:
+    /// the strength of its integrity check, plus a point per plausible
+    /// measurement, less two per implausible one
+    pub fn confidence(&self) -> i32 {
+        let integrity = self.integrity() as i32 * 2;
+        self.measurements.iter().fold(integrity, |score, m| {
+            if m.is_plausible() {
+                score + 1
+            } else {
+                score - 2
+            }
+        })
+    }
+
+    /// The integrity check rtl_433 applied when decoding this record
+    pub fn integrity(&self) -> crate::config::Integrity {
+        match self.record_json.get("mic") {
+            Some(serde_json::Value::String(mic)) => {
+                mic.parse().unwrap_or(crate::config::Integrity::None)
+            }
+            _ => crate::config::Integrity::None,
+        }
+    }
 }
 
 impl std::fmt::Display for Record {
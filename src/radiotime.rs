@@ -0,0 +1,243 @@
+//! The time broadcast by radio-controlled clock sensors, as a reference for
+//! checking the system clock of stations without network time
+//!
+//! Some sensors, like the WH31B, relay the DCF77 or WWVB time signal they
+//! pick up, which rtl_433 reports in a `radio_clock` field. Each broadcast is
+//! compared with when rtl_433 received it by the system clock, and the drift
+//! logged, with a warning once it grows beyond `warn_drift`. The radio time
+//! and drift can be published retained to `weatherradio/status/radio_time`,
+//! and a helper program run to step the system clock to the radio time when
+//! the drift grows beyond `step_drift`, e.g. a script running `date -s`
+//! through sudo, since the bridge shouldn't be able to set the clock itself.
+//!
+//! The broadcasts only give the time to the second, and rtl_433 only
+//! timestamps to the second, so the reference is good to a couple of
+//! seconds: enough to catch a clock that's wandered or been reset, not to
+//! replace NTP.
+
+use std::path::PathBuf;
+use std::sync::mpsc::Receiver;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local, LocalResult, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{ErrorCode, ErrorEvent};
+use crate::events::{Event, EventBus};
+use crate::radio::Record;
+use crate::sink::MqttSink;
+
+// How long after stepping the clock before it may be stepped again, so that
+// a bad broadcast or a helper that doesn't take can't keep it jumping
+const STEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+fn default_warn_drift() -> u64 {
+    60
+}
+
+fn default_step_drift() -> u64 {
+    300
+}
+
+/// Checking the system clock against the time radio-controlled clock
+/// sensors broadcast
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RadioTimeConfig {
+    /// Whether the radio time is published
+    #[serde(default)]
+    pub publish: bool,
+    /// Whether the broadcasts are in UTC rather than local time
+    #[serde(default)]
+    pub utc: bool,
+    /// Seconds of drift from the radio time warned about
+    #[serde(default = "default_warn_drift")]
+    pub warn_drift: u64,
+    /// Program run with the radio time, in RFC 3339, to step the system
+    /// clock to it; the clock is left alone when unset
+    #[serde(default)]
+    pub step_command: Option<PathBuf>,
+    /// Seconds of drift from the radio time the clock is stepped beyond
+    #[serde(default = "default_step_drift")]
+    pub step_drift: u64,
+}
+
+impl Default for RadioTimeConfig {
+    fn default() -> Self {
+        RadioTimeConfig {
+            publish: false,
+            utc: false,
+            warn_drift: default_warn_drift(),
+            step_command: None,
+            step_drift: default_step_drift(),
+        }
+    }
+}
+
+/// Topic the radio time is published to
+pub fn radio_time_topic(status_topic: &str) -> String {
+    format!("{}/radio_time", status_topic)
+}
+
+/// A broadcast time, and how far the system clock was from it
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RadioTime {
+    pub sensor_id: String,
+    /// The time broadcast
+    pub time: DateTime<Local>,
+    /// When it was received, by the system clock
+    pub received: DateTime<Local>,
+    /// Seconds the system clock was behind the radio time, negative when
+    /// it was ahead
+    pub drift: i64,
+}
+
+/// The time a record broadcasts, if it's from a radio-controlled clock
+pub fn radio_time(record: &Record, utc: bool) -> Option<RadioTime> {
+    let clock = record.record_json.get("radio_clock")?.as_str()?;
+    let naive = match NaiveDateTime::parse_from_str(clock, "%Y-%m-%dT%H:%M:%S") {
+        Ok(naive) => naive,
+        Err(e) => {
+            log::debug!(
+                "[{}] Invalid radio clock '{}': {}",
+                record.sensor_id,
+                clock,
+                e
+            );
+            return None;
+        }
+    };
+    let time = match (utc, Local.from_local_datetime(&naive)) {
+        (true, _) => Utc.from_utc_datetime(&naive).with_timezone(&Local),
+        (false, LocalResult::Single(time)) => time,
+        // An hour repeated when the clocks go back is in daylight saving
+        // time, the greater offset from UTC, the first time round, and in
+        // standard time the second, as broadcasts with a DST flag say; those
+        // without are taken to be the first time round
+        (false, LocalResult::Ambiguous(a, b)) => {
+            let offset = |time: &DateTime<Local>| time.offset().local_minus_utc();
+            let (summer, winter) = match offset(&a) > offset(&b) {
+                true => (a, b),
+                false => (b, a),
+            };
+            match record.record_json.get("dst").and_then(|dst| dst.as_u64()) {
+                Some(0) => winter,
+                _ => summer,
+            }
+        }
+        (false, LocalResult::None) => return None,
+    };
+    Some(RadioTime {
+        sensor_id: record.sensor_id.clone(),
+        time,
+        received: record.timestamp,
+        drift: (time - record.timestamp).num_seconds(),
+    })
+}
+
+/// Follows the drift of the system clock from the radio time
+#[derive(Debug)]
+pub struct Monitor {
+    conf: RadioTimeConfig,
+    drifted: bool,
+    stepped: Option<Instant>,
+}
+
+impl Monitor {
+    pub fn new(conf: RadioTimeConfig) -> Self {
+        Monitor {
+            conf,
+            drifted: false,
+            stepped: None,
+        }
+    }
+
+    /// Notes a broadcast time, warning when the clock has drifted too far
+    /// from it, and stepping the clock if it's drifted further
+    pub fn observe(&mut self, time: &RadioTime, errors: &EventBus) {
+        let drift = time.drift.unsigned_abs();
+        log::debug!(
+            "[{}] Radio time {}, {}s from the system clock",
+            time.sensor_id,
+            time.time,
+            time.drift
+        );
+        let drifted = drift > self.conf.warn_drift;
+        if drifted && !self.drifted {
+            log::warn!(
+                "System clock is {}s off the radio time of {}",
+                time.drift.abs(),
+                time.sensor_id
+            );
+        } else if !drifted && self.drifted {
+            log::info!("System clock is back within {}s of the radio time", drift);
+        }
+        self.drifted = drifted;
+        let command = match self.conf.step_command {
+            Some(ref command) if drift > self.conf.step_drift => command,
+            _ => return,
+        };
+        if self.stepped.is_some_and(|at| at.elapsed() < STEP_INTERVAL) {
+            return;
+        }
+        self.stepped = Some(Instant::now());
+        // The drift was measured when the record was received, so carry the
+        // radio time forward to now
+        let now = (time.time + (crate::clock::now() - time.received))
+            .to_rfc3339_opts(SecondsFormat::Secs, false);
+        log::warn!("Stepping the system clock by {}s to {}", time.drift, now);
+        let result = std::process::Command::new(command).arg(&now).status();
+        let error = match result {
+            Ok(status) if status.success() => return,
+            Ok(status) => format!("{} exited with {}", command.display(), status),
+            Err(e) => format!("Failed to run {}: {}", command.display(), e),
+        };
+        log::warn!("{}", error);
+        errors.publish(Event::Error(
+            ErrorEvent::new(ErrorCode::Clock, error).context("sensor", &time.sensor_id),
+        ));
+    }
+}
+
+/// Follows the radio time broadcast in records on `events` until the radio
+/// stops, publishing it to `sink` if configured
+pub fn spawn_monitor(
+    conf: RadioTimeConfig,
+    sink: Option<MqttSink>,
+    events: Receiver<Event>,
+    errors: EventBus,
+) -> JoinHandle<()> {
+    let sink = sink.filter(|_| conf.publish);
+    let utc = conf.utc;
+    let mut monitor = Monitor::new(conf);
+    std::thread::spawn(move || {
+        for event in events {
+            let record = match event {
+                Event::Record(record) => record,
+                Event::RadioStopped => return,
+                _ => continue,
+            };
+            let time = match radio_time(&record, utc) {
+                Some(time) => time,
+                None => continue,
+            };
+            monitor.observe(&time, &errors);
+            let sink = match sink {
+                Some(ref sink) => sink,
+                None => continue,
+            };
+            let result = serde_json::to_vec(&time)
+                .map_err(anyhow::Error::from)
+                .and_then(|payload| {
+                    sink.publish_retained(
+                        &radio_time_topic(sink.status_topic()),
+                        payload,
+                        sink.qos(),
+                    )
+                });
+            if let Err(e) = result {
+                log::warn!("Failed to publish the radio time: {:#}", e);
+            }
+        }
+    })
+}
@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use chrono::{NaiveDate, NaiveTime};
+use serde::{Deserialize, Serialize};
+use uom::si::{f32::Length, length};
+use uom::si::{f32::Velocity, velocity};
+
+use crate::radio::{Measurement, Record};
+
+/// Version of the rainfall, rain rate and daily rainfall calculations, in
+/// the lineage of their values
+pub const FORMULA_VERSION: u32 = 1;
+
+// The WH40 reports its rainfall total as a 16-bit count of 0.1mm tips
+const RAINFALL_COUNTER_MAX_MM: f32 = 6553.6;
+// How close to the counter limit the previous total must have been for a
+// decrease to be treated as a rollover rather than a reset
+const ROLLOVER_MARGIN_MM: f32 = 100.0;
+
+#[derive(Clone, Debug)]
+struct RainState {
+    timestamp: chrono::DateTime<chrono::Local>,
+    total: Length,
+}
+
+// What's kept of each sensor's rainfall in the state file, so that the day's
+// total survives restarts
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SavedRain {
+    timestamp: chrono::DateTime<chrono::Local>,
+    total_mm: f32,
+    day: NaiveDate,
+    day_mm: f32,
+}
+
+// Accumulation of each sensor's rainfall over the rain day
+#[derive(Debug)]
+struct DailyRain {
+    day_start: NaiveTime,
+    path: Option<PathBuf>,
+    days: HashMap<String, (NaiveDate, f32)>,
+}
+
+impl DailyRain {
+    // The rain day a time falls in, which starts at `day_start` local time
+    fn day_of(&self, timestamp: chrono::DateTime<chrono::Local>) -> NaiveDate {
+        (timestamp.naive_local() - (self.day_start - NaiveTime::MIN)).date()
+    }
+
+    // Adds rainfall to the sensor's total for the record's day, returning
+    // the total
+    fn add(&mut self, sensor_id: &str, timestamp: chrono::DateTime<chrono::Local>, mm: f32) -> f32 {
+        let day = self.day_of(timestamp);
+        let total = self.days.entry(sensor_id.to_owned()).or_insert((day, 0.0));
+        if total.0 != day {
+            log::debug!("[{}] Rainfall on {}: {} mm", sensor_id, total.0, total.1);
+            *total = (day, 0.0);
+        }
+        total.1 += mm;
+        total.1
+    }
+}
+
+/// Tracks the monotonically increasing rainfall totals reported by rain
+/// gauges, and derives the rainfall since the previous report and the rain
+/// rate from them, and optionally the rainfall so far that day.
+#[derive(Debug, Default)]
+pub struct RainTracker {
+    last: HashMap<String, RainState>,
+    daily: Option<DailyRain>,
+}
+
+impl RainTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also derives the rainfall over each rain day, which starts at
+    /// `day_start` local time, keeping it in the state file at `path` if
+    /// given so that it survives restarts
+    pub fn daily(mut self, day_start: NaiveTime, path: Option<PathBuf>) -> Self {
+        let mut days = HashMap::new();
+        if let Some(saved) = path.as_deref().and_then(load) {
+            for (sensor_id, saved) in saved {
+                days.insert(sensor_id.clone(), (saved.day, saved.day_mm));
+                self.last.insert(
+                    sensor_id,
+                    RainState {
+                        timestamp: saved.timestamp,
+                        total: Length::new::<length::millimeter>(saved.total_mm),
+                    },
+                );
+            }
+        }
+        self.daily = Some(DailyRain {
+            day_start,
+            path,
+            days,
+        });
+        self
+    }
+
+    /// Appends `RainfallDelta`, `RainRate` and, if enabled, `DailyRainfall`
+    /// measurements to records carrying a rainfall total
+    pub fn process(&mut self, record: &mut Record) {
+        let delta = self.process_total(record);
+        let daily = match self.daily {
+            Some(ref mut daily) => daily,
+            None => return,
+        };
+        let day_mm = daily.add(&record.sensor_id, record.timestamp, delta.unwrap_or(0.0));
+        record.measurements.push(Measurement::DailyRainfall(
+            Length::new::<length::millimeter>(day_mm),
+        ));
+        if delta.is_some() {
+            self.save();
+        }
+    }
+
+    // Writes the state file, if there is one. Losing it only loses the day's
+    // total so far, so failures are only logged.
+    fn save(&self) {
+        let (path, days) = match self.daily {
+            Some(DailyRain {
+                path: Some(ref path),
+                ref days,
+                ..
+            }) => (path, days),
+            _ => return,
+        };
+        let saved: HashMap<&String, SavedRain> = days
+            .iter()
+            .filter_map(|(sensor_id, (day, day_mm))| {
+                let last = self.last.get(sensor_id)?;
+                Some((
+                    sensor_id,
+                    SavedRain {
+                        timestamp: last.timestamp,
+                        total_mm: last.total.get::<length::millimeter>(),
+                        day: *day,
+                        day_mm: *day_mm,
+                    },
+                ))
+            })
+            .collect();
+        let result = path
+            .parent()
+            .map(std::fs::create_dir_all)
+            .transpose()
+            .and_then(|_| std::fs::write(path, serde_json::to_vec(&saved)?));
+        if let Err(e) = result {
+            log::warn!("Failed to save rainfall to {}: {:?}", path.display(), e);
+        }
+    }
+
+    // Derives the rainfall since the sensor's previous total, returning it
+    fn process_total(&mut self, record: &mut Record) -> Option<f32> {
+        let total = record.measurements.iter().find_map(|m| match m {
+            Measurement::Rainfall(total) => Some(*total),
+            _ => None,
+        })?;
+
+        let current = RainState {
+            timestamp: record.timestamp,
+            total,
+        };
+        let previous = match self.last.insert(record.sensor_id.clone(), current) {
+            Some(previous) => previous,
+            None => {
+                log::debug!(
+                    "[{}] First rainfall total: {}",
+                    record.sensor_id,
+                    total.get::<length::millimeter>()
+                );
+                return None;
+            }
+        };
+
+        let total_mm = total.get::<length::millimeter>();
+        let previous_mm = previous.total.get::<length::millimeter>();
+        let delta_mm = if total_mm >= previous_mm {
+            total_mm - previous_mm
+        } else if previous_mm > RAINFALL_COUNTER_MAX_MM - ROLLOVER_MARGIN_MM {
+            log::info!(
+                "[{}] Rainfall counter rolled over ({} -> {})",
+                record.sensor_id,
+                previous_mm,
+                total_mm
+            );
+            RAINFALL_COUNTER_MAX_MM - previous_mm + total_mm
+        } else {
+            // Most likely the batteries were swapped, and the counter started
+            // over from zero, so everything it has counted is new rainfall
+            log::info!(
+                "[{}] Rainfall counter reset ({} -> {})",
+                record.sensor_id,
+                previous_mm,
+                total_mm
+            );
+            total_mm
+        };
+        record.measurements.push(Measurement::RainfallDelta(
+            Length::new::<length::millimeter>(delta_mm),
+        ));
+
+        let elapsed = record.timestamp - previous.timestamp;
+        if elapsed > chrono::Duration::zero() {
+            let minutes = elapsed.num_milliseconds() as f32 / 60_000.0;
+            let rate = Velocity::new::<velocity::millimeter_per_minute>(delta_mm / minutes);
+            record.measurements.push(Measurement::RainRate(rate));
+        }
+        Some(delta_mm)
+    }
+}
+
+// Reads the state file, which is started over if missing or unreadable
+fn load(path: &std::path::Path) -> Option<HashMap<String, SavedRain>> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            log::warn!("Failed to read rainfall from {}: {:?}", path.display(), e);
+            return None;
+        }
+    };
+    match serde_json::from_slice(&contents) {
+        Ok(saved) => Some(saved),
+        Err(e) => {
+            log::warn!(
+                "Ignoring unreadable rainfall in {}: {:?}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn rain_at(hour: u32, min: u32, total_mm: f32) -> Record {
+        Record {
+            timestamp: chrono::Local
+                .with_ymd_and_hms(2024, 1, 15, hour, min, 0)
+                .unwrap(),
+            sensor_id: "Fineoffset-WH40/1".to_owned(),
+            record_json: serde_json::Value::Null,
+            measurements: vec![Measurement::Rainfall(Length::new::<length::millimeter>(
+                total_mm,
+            ))],
+            radio: None,
+        }
+    }
+
+    fn delta_mm(record: &Record) -> Option<f32> {
+        record.measurements.iter().find_map(|m| match m {
+            Measurement::RainfallDelta(delta) => Some(delta.get::<length::millimeter>()),
+            _ => None,
+        })
+    }
+
+    fn daily_mm(record: &Record) -> Option<f32> {
+        record.measurements.iter().find_map(|m| match m {
+            Measurement::DailyRainfall(day) => Some(day.get::<length::millimeter>()),
+            _ => None,
+        })
+    }
+
+    fn assert_mm(actual: Option<f32>, expected: f32) {
+        let actual = actual.expect("no rainfall derived");
+        assert!(
+            (actual - expected).abs() < 0.01,
+            "{} mm, expected {} mm",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn first_total_has_no_delta() {
+        let mut tracker = RainTracker::new();
+        let mut record = rain_at(10, 0, 120.0);
+        tracker.process(&mut record);
+        assert_eq!(delta_mm(&record), None);
+    }
+
+    #[test]
+    fn delta_and_rate_follow_the_total() {
+        let mut tracker = RainTracker::new();
+        tracker.process(&mut rain_at(10, 0, 120.0));
+        let mut record = rain_at(10, 10, 123.0);
+        tracker.process(&mut record);
+        assert_mm(delta_mm(&record), 3.0);
+        let rate = record.measurements.iter().find_map(|m| match m {
+            Measurement::RainRate(rate) => Some(rate.get::<velocity::millimeter_per_minute>()),
+            _ => None,
+        });
+        assert_mm(rate, 0.3);
+    }
+
+    #[test]
+    fn counter_rollover_continues_the_total() {
+        let mut tracker = RainTracker::new();
+        tracker.process(&mut rain_at(10, 0, 6550.0));
+        let mut record = rain_at(10, 10, 1.5);
+        tracker.process(&mut record);
+        assert_mm(delta_mm(&record), 5.1);
+    }
+
+    #[test]
+    fn counter_reset_counts_from_zero() {
+        let mut tracker = RainTracker::new();
+        tracker.process(&mut rain_at(10, 0, 500.0));
+        let mut record = rain_at(10, 10, 2.0);
+        tracker.process(&mut record);
+        assert_mm(delta_mm(&record), 2.0);
+    }
+
+    #[test]
+    fn daily_rainfall_starts_over_at_the_day_start() {
+        let day_start = NaiveTime::from_hms_opt(9, 0, 0).unwrap();
+        let mut tracker = RainTracker::new().daily(day_start, None);
+
+        let mut record = rain_at(7, 0, 100.0);
+        tracker.process(&mut record);
+        assert_mm(daily_mm(&record), 0.0);
+        let mut record = rain_at(8, 30, 104.0);
+        tracker.process(&mut record);
+        assert_mm(daily_mm(&record), 4.0);
+
+        // The rain that fell since 08:30 is counted in the new day
+        let mut record = rain_at(9, 30, 105.5);
+        tracker.process(&mut record);
+        assert_mm(daily_mm(&record), 1.5);
+        let mut record = rain_at(11, 0, 106.0);
+        tracker.process(&mut record);
+        assert_mm(daily_mm(&record), 2.0);
+    }
+}
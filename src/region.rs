@@ -0,0 +1,80 @@
+//! Regional presets for the band Fine Offset sensors, and their rebadges
+//! like Ecowitt, Ambient Weather and Froggit, transmit in where they're sold
+//!
+//! A region selects the frequency listened on, the rtl_433 decoders of the
+//! sensors sold there, and the band reported to software expecting an
+//! Ecowitt gateway, so that European and Australian stations need a single
+//! setting rather than radio arguments of their own. Radios configured
+//! explicitly, and a frequency set for an RTL-SDR, take precedence over the
+//! region's.
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::ConfigError;
+
+/// Where the station's sensors were sold
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Region {
+    /// North America, on 915 MHz
+    #[default]
+    #[serde(rename = "US915")]
+    Us915,
+    /// Europe and the UK, on 868 MHz, e.g. Froggit and European Ecowitt
+    /// models
+    #[serde(rename = "EU868")]
+    Eu868,
+    /// Australia and New Zealand, on 433 MHz
+    #[serde(rename = "AU")]
+    Au,
+}
+
+impl std::str::FromStr for Region {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "US915" => Ok(Region::Us915),
+            "EU868" => Ok(Region::Eu868),
+            "AU" => Ok(Region::Au),
+            _ => Err(ConfigError::Region(s.to_owned())),
+        }
+    }
+}
+
+impl Region {
+    /// Frequency the region's sensors transmit on, in Hz
+    pub fn frequency(self) -> u32 {
+        match self {
+            Region::Us915 => 915_000_000,
+            Region::Eu868 => 868_300_000,
+            Region::Au => 433_920_000,
+        }
+    }
+
+    /// Band an Ecowitt gateway sold in the region reports
+    pub fn band(self) -> &'static str {
+        match self {
+            Region::Us915 => "915M",
+            Region::Eu868 => "868M",
+            Region::Au => "433M",
+        }
+    }
+
+    /// rtl_433 arguments of the single radio run when none are configured:
+    /// the region's frequency, and the decoders of the sensors sold there.
+    /// The WH31E decoder covers the WH31 and WH40 wherever they're sold, and
+    /// the older WH1080 family is still common outside North America.
+    pub fn radio_args(self) -> Vec<String> {
+        let mut args = vec![format!("-f{}M", f64::from(self.frequency()) / 1e6)];
+        if self == Region::Eu868 {
+            // As rtl_433 recommends for 868 MHz, its default sample rate
+            // being too narrow for the band's sensors
+            args.extend(["-s", "1024k"].map(str::to_owned));
+        }
+        args.extend(["-R", "ambient-weather-wh31e"].map(str::to_owned));
+        if self != Region::Us915 {
+            args.extend(["-R", "fine-offset-electronics-wh1080"].map(str::to_owned));
+        }
+        args
+    }
+}
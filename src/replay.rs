@@ -0,0 +1,204 @@
+//! Replay of captured rtl_433 json output in place of a live radio, for
+//! exercising derived measurements and alerts with realistic timing. Both
+//! json lines and [packed](crate::capture) captures are replayed, as are
+//! [archives](crate::archive) kept with the raw records.
+
+use std::io::BufRead;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, TimeZone};
+use thiserror::Error;
+
+use crate::capture::CaptureReader;
+use crate::clock::ManualClock;
+use crate::radio::Record;
+
+#[derive(Error, Debug)]
+pub enum ReplayError {
+    #[error("Invalid replay speed '{0}', expected a positive factor like '60x'")]
+    InvalidSpeed(String),
+    #[error("Invalid timestamp '{0}', expected 'YYYY-MM-DD HH:MM:SS' or RFC 3339")]
+    InvalidTimestamp(String),
+}
+
+/// Parses a replay speed factor, e.g. `60x` or `0.5`
+pub fn parse_speed(speed: &str) -> Result<f64, ReplayError> {
+    speed
+        .trim()
+        .trim_end_matches(['x', 'X'])
+        .parse::<f64>()
+        .ok()
+        .filter(|s| s.is_finite() && *s > 0.0)
+        .ok_or_else(|| ReplayError::InvalidSpeed(speed.to_owned()))
+}
+
+/// Parses a timestamp in rtl_433's local time format, or RFC 3339
+pub fn parse_timestamp(timestamp: &str) -> Result<DateTime<Local>, ReplayError> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(timestamp) {
+        return Ok(t.with_timezone(&Local));
+    }
+    chrono::NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .and_then(|t| Local.from_local_datetime(&t).earliest())
+        .ok_or_else(|| ReplayError::InvalidTimestamp(timestamp.to_owned()))
+}
+
+/// How records are re-timed as they are replayed
+#[derive(Clone, Debug, Default)]
+pub struct ReplayTiming {
+    /// Factor the gaps between records are divided by; records are replayed
+    /// without delay when unset
+    pub speed: Option<f64>,
+    /// Records before this time are replayed without delay, to bring derived
+    /// state up to date. From this time on, records are replayed at `speed`
+    /// (real time, if unset), with their timestamps shifted so that this
+    /// time lands on the moment it is reached. The gaps between timestamps
+    /// are kept, so rates are unaffected by the replay speed.
+    pub realtime_from: Option<DateTime<Local>>,
+    /// Records before this time are skipped, which packed captures do
+    /// without reading most of them
+    pub start: Option<DateTime<Local>>,
+}
+
+enum Source {
+    Lines(std::io::Lines<std::io::BufReader<std::fs::File>>),
+    Packed(CaptureReader),
+}
+
+/// A source of [`Record`]s read from a file of captured rtl_433 json output,
+/// either one record per line, or packed
+pub struct Replay {
+    source: Source,
+    integrity: crate::config::IntegrityConfig,
+    timing: ReplayTiming,
+    last: Option<DateTime<Local>>,
+    offset: Option<chrono::Duration>,
+    clock: Option<Arc<ManualClock>>,
+}
+
+impl Replay {
+    pub fn open(
+        path: &std::path::Path,
+        conf: &crate::config::Config,
+        timing: ReplayTiming,
+    ) -> Result<Self> {
+        let source = if crate::capture::is_packed(path)? {
+            let mut reader = CaptureReader::open(path)?;
+            if let Some(start) = timing.start {
+                reader.seek(start)?;
+            }
+            Source::Packed(reader)
+        } else {
+            let file = std::fs::File::open(path)
+                .with_context(|| format!("Failed to open replay file {}", path.display()))?;
+            Source::Lines(std::io::BufReader::new(file).lines())
+        };
+        Ok(Replay {
+            source,
+            integrity: conf.integrity.clone(),
+            timing,
+            last: None,
+            offset: None,
+            clock: None,
+        })
+    }
+
+    /// Sets `clock` to the timestamp of each record as it is replayed, so
+    /// that whatever goes by the clock sees time pass as the records do
+    pub fn follow(mut self, clock: Arc<ManualClock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Waits out the scaled gap since the previous record, and shifts the
+    /// record's timestamp once real time replay has been reached
+    fn retime(&mut self, record: &mut Record) {
+        let original = record.timestamp;
+        let speed = match (self.timing.speed, self.timing.realtime_from) {
+            (_, Some(from)) if original < from => None,
+            (None, Some(_)) => Some(1.0),
+            (speed, _) => speed,
+        };
+        if let (Some(speed), Some(last)) = (speed, self.last) {
+            if let Ok(gap) = (original - last).to_std() {
+                std::thread::sleep(gap.div_f64(speed));
+            }
+        }
+        self.last = Some(original);
+        if speed.is_some() && self.timing.realtime_from.is_some() {
+            let offset = *self.offset.get_or_insert_with(|| Local::now() - original);
+            record.timestamp = original + offset;
+        }
+    }
+}
+
+impl Replay {
+    // Reads the json of the next record, skipping unparseable lines
+    fn next_json(&mut self) -> Option<serde_json::Value> {
+        loop {
+            let line = match self.source {
+                Source::Lines(ref mut lines) => lines.next()?,
+                Source::Packed(ref mut reader) => match reader.next()? {
+                    Ok(json) => return Some(json),
+                    Err(e) => {
+                        log::error!("Error reading replay file: {:?}", e);
+                        return None;
+                    }
+                },
+            };
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    log::error!("Error reading replay file: {:?}", e);
+                    return None;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            // Captures are often hand-edited, so skip bad lines rather than
+            // ending the replay
+            match serde_json::from_str::<serde_json::Value>(&line) {
+                // Archived records are replayed from the record rtl_433
+                // reported, when it was archived with them
+                Ok(mut json) if json.get("sensor").is_some() && json["raw"].is_object() => {
+                    return Some(json["raw"].take())
+                }
+                Ok(json) => return Some(json),
+                Err(e) => log::warn!("Skipping unparseable replay line: {:?}", e),
+            }
+        }
+    }
+}
+
+impl Iterator for Replay {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let json = self.next_json()?;
+            let record = crate::radio::decode_received(&json);
+            crate::usage::observe(&json, record.as_ref());
+            let mut record = match record {
+                Some(record) => record,
+                None => continue,
+            };
+            if !self.integrity.accepts(&record) {
+                continue;
+            }
+            if self
+                .timing
+                .start
+                .is_some_and(|start| record.timestamp < start)
+            {
+                continue;
+            }
+            self.retime(&mut record);
+            if let Some(ref clock) = self.clock {
+                clock.set(record.timestamp);
+            }
+            return Some(record);
+        }
+    }
+}
@@ -0,0 +1,446 @@
+//! Receiving Fine Offset sensors directly from an RTL-SDR dongle through
+//! librtlsdr, rather than through an rtl_433 child process
+//!
+//! Samples are FSK demodulated and sliced into bits here, and the packets
+//! following each sync word are handed to the raw packet decoders in
+//! [`crate::fineoffset`]. Only the Fine Offset devices those decoders
+//! support are received this way.
+
+use std::os::raw::{c_char, c_int, c_void};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+// Sampling well above the bit rate leaves room for the clock to be recovered
+// from the transitions
+const SAMPLE_RATE: u32 = 250_000;
+// Fine Offset sensors send 58 µs bits
+const BIT_RATE: f32 = 1_000_000.0 / 58.0;
+// Bytes read from the dongle at a time, a multiple of its 512 byte transfers
+const READ_LEN: usize = 1 << 16;
+
+// Signal power, in squared 8 bit sample units, below which the band is taken
+// to be quiet and nothing is demodulated
+const SQUELCH_POWER: f32 = 200.0;
+// Smoothing of the signal power, the instantaneous frequency, and the level
+// frequencies are sliced at
+const POWER_ALPHA: f32 = 0.05;
+const FREQUENCY_ALPHA: f32 = 0.3;
+const LEVEL_ALPHA: f32 = 0.01;
+
+#[derive(Error, Debug)]
+pub enum RtlSdrError {
+    #[error("No RTL-SDR device {index}, {count} found")]
+    NoDevice { index: u32, count: u32 },
+    #[error("RTL-SDR call {call} failed with {code}")]
+    Call { call: &'static str, code: i32 },
+}
+
+/// Settings for receiving directly from an RTL-SDR dongle
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RtlSdrConfig {
+    /// Index of the dongle, in the order librtlsdr finds them
+    pub device: u32,
+    /// Frequency to tune to, in Hz; the region's when unset
+    pub frequency: Option<u32>,
+    /// Tuner gain in dB; automatic gain control is used when unset
+    pub gain: Option<f32>,
+}
+
+#[repr(C)]
+struct RtlSdrDev {
+    _private: [u8; 0],
+}
+
+#[link(name = "rtlsdr")]
+extern "C" {
+    fn rtlsdr_get_device_count() -> u32;
+    fn rtlsdr_get_device_name(index: u32) -> *const c_char;
+    fn rtlsdr_open(dev: *mut *mut RtlSdrDev, index: u32) -> c_int;
+    fn rtlsdr_close(dev: *mut RtlSdrDev) -> c_int;
+    fn rtlsdr_set_center_freq(dev: *mut RtlSdrDev, freq: u32) -> c_int;
+    fn rtlsdr_set_sample_rate(dev: *mut RtlSdrDev, rate: u32) -> c_int;
+    fn rtlsdr_set_tuner_gain_mode(dev: *mut RtlSdrDev, manual: c_int) -> c_int;
+    fn rtlsdr_set_tuner_gain(dev: *mut RtlSdrDev, gain: c_int) -> c_int;
+    fn rtlsdr_reset_buffer(dev: *mut RtlSdrDev) -> c_int;
+    fn rtlsdr_read_sync(
+        dev: *mut RtlSdrDev,
+        buf: *mut c_void,
+        len: c_int,
+        n_read: *mut c_int,
+    ) -> c_int;
+}
+
+fn check(call: &'static str, code: c_int) -> Result<(), RtlSdrError> {
+    if code < 0 {
+        Err(RtlSdrError::Call { call, code })
+    } else {
+        Ok(())
+    }
+}
+
+/// An open RTL-SDR dongle, tuned for receiving Fine Offset sensors, which is
+/// closed when dropped
+pub struct Device {
+    dev: *mut RtlSdrDev,
+}
+
+// librtlsdr devices aren't tied to the thread that opened them, and the
+// handle is only ever used through `&mut self`
+unsafe impl Send for Device {}
+
+impl Device {
+    pub fn open(conf: &RtlSdrConfig, region: crate::region::Region) -> Result<Self> {
+        // SAFETY: the device pointer is only used once open succeeds, and is
+        // owned by the returned `Device` from then on
+        unsafe {
+            let count = rtlsdr_get_device_count();
+            if conf.device >= count {
+                return Err(RtlSdrError::NoDevice {
+                    index: conf.device,
+                    count,
+                }
+                .into());
+            }
+            let name = std::ffi::CStr::from_ptr(rtlsdr_get_device_name(conf.device));
+            log::debug!("Opening RTL-SDR device {}: {:?}", conf.device, name);
+            let mut dev = std::ptr::null_mut();
+            check("rtlsdr_open", rtlsdr_open(&mut dev, conf.device))?;
+            let device = Device { dev };
+            check(
+                "rtlsdr_set_sample_rate",
+                rtlsdr_set_sample_rate(dev, SAMPLE_RATE),
+            )?;
+            check(
+                "rtlsdr_set_center_freq",
+                rtlsdr_set_center_freq(dev, conf.frequency.unwrap_or(region.frequency())),
+            )?;
+            match conf.gain {
+                Some(gain) => {
+                    check(
+                        "rtlsdr_set_tuner_gain_mode",
+                        rtlsdr_set_tuner_gain_mode(dev, 1),
+                    )?;
+                    // librtlsdr takes gains in tenths of a dB
+                    check(
+                        "rtlsdr_set_tuner_gain",
+                        rtlsdr_set_tuner_gain(dev, (gain * 10.0).round() as c_int),
+                    )?;
+                }
+                None => check(
+                    "rtlsdr_set_tuner_gain_mode",
+                    rtlsdr_set_tuner_gain_mode(dev, 0),
+                )?,
+            }
+            check("rtlsdr_reset_buffer", rtlsdr_reset_buffer(dev))?;
+            Ok(device)
+        }
+    }
+
+    /// Discards the samples buffered while the dongle wasn't read
+    pub fn reset_buffer(&mut self) -> Result<(), RtlSdrError> {
+        // SAFETY: the device was opened by `Device::open`
+        check("rtlsdr_reset_buffer", unsafe {
+            rtlsdr_reset_buffer(self.dev)
+        })
+    }
+
+    /// Reads the next block of interleaved 8 bit I and Q samples into `buf`,
+    /// returning how many bytes were read
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, RtlSdrError> {
+        let mut n_read = 0;
+        // SAFETY: librtlsdr writes at most `len` bytes into `buf`
+        let code = unsafe {
+            rtlsdr_read_sync(
+                self.dev,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len() as c_int,
+                &mut n_read,
+            )
+        };
+        check("rtlsdr_read_sync", code)?;
+        Ok(n_read.max(0) as usize)
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        // SAFETY: the device was opened by `Device::open`, and isn't used
+        // after this
+        unsafe {
+            rtlsdr_close(self.dev);
+        }
+    }
+}
+
+// A packet being collected after its sync word
+#[derive(Debug)]
+struct Packet {
+    // Whether the sync word was received with the frequencies swapped, so
+    // every bit needs flipping
+    inverted: bool,
+    bytes: Vec<u8>,
+    bits: u8,
+}
+
+/// FSK demodulator, turning samples into the Fine Offset packets following
+/// each sync word. Which of the two frequencies is a one is told from the
+/// sync word itself.
+#[derive(Debug)]
+pub struct Demodulator {
+    previous: (f32, f32),
+    power: f32,
+    frequency: f32,
+    level: f32,
+    bit: bool,
+    // Samples until the middle of the next bit
+    clock: f32,
+    shift: u16,
+    packet: Option<Packet>,
+}
+
+impl Default for Demodulator {
+    fn default() -> Self {
+        Demodulator {
+            previous: (0.0, 0.0),
+            power: 0.0,
+            frequency: 0.0,
+            level: 0.0,
+            bit: false,
+            clock: 0.0,
+            shift: 0,
+            packet: None,
+        }
+    }
+}
+
+impl Demodulator {
+    /// Demodulates interleaved 8 bit I and Q samples, returning the packets
+    /// completed within them, without their sync words. A packet is complete
+    /// once it's as long as the longest decoded, or the transmission ends.
+    pub fn process(&mut self, samples: &[u8]) -> Vec<Vec<u8>> {
+        let samples_per_bit = SAMPLE_RATE as f32 / BIT_RATE;
+        let mut packets = Vec::new();
+        for iq in samples.chunks_exact(2) {
+            let (i, q) = (f32::from(iq[0]) - 127.5, f32::from(iq[1]) - 127.5);
+            let (pi, pq) = self.previous;
+            self.previous = (i, q);
+            self.power += (i * i + q * q - self.power) * POWER_ALPHA;
+            if self.power < SQUELCH_POWER {
+                // Packets shorter than the longest decoded end with the
+                // transmission, less any partial byte
+                if let Some(mut packet) = self.packet.take() {
+                    if packet.bits != 0 {
+                        packet.bytes.pop();
+                    }
+                    if !packet.bytes.is_empty() {
+                        packets.push(packet.bytes);
+                    }
+                }
+                self.shift = 0;
+                continue;
+            }
+
+            // The phase step between samples is the instantaneous frequency
+            let step = (q * pi - i * pq).atan2(i * pi + q * pq);
+            self.frequency += (step - self.frequency) * FREQUENCY_ALPHA;
+            // The level is followed over the alternating preamble, and held
+            // through the packet, whose runs of the same bit would otherwise
+            // drag it onto their frequency
+            if self.packet.is_none() {
+                self.level += (self.frequency - self.level) * LEVEL_ALPHA;
+            }
+            let bit = self.frequency > self.level;
+            if bit != self.bit {
+                self.bit = bit;
+                self.clock = samples_per_bit / 2.0;
+                continue;
+            }
+            self.clock -= 1.0;
+            if self.clock <= 0.0 {
+                self.clock += samples_per_bit;
+                if let Some(packet) = self.push_bit(bit) {
+                    packets.push(packet);
+                }
+            }
+        }
+        packets
+    }
+
+    // Adds a bit to the packet being collected, or looks for a sync word when
+    // there isn't one, returning the packet once it's complete
+    fn push_bit(&mut self, bit: bool) -> Option<Vec<u8>> {
+        match self.packet {
+            Some(ref mut packet) => {
+                if packet.bits == 0 {
+                    packet.bytes.push(0);
+                }
+                let byte = packet.bytes.last_mut().expect("packet has no bytes");
+                *byte = *byte << 1 | u8::from(bit != packet.inverted);
+                packet.bits = (packet.bits + 1) % 8;
+                if packet.bits == 0 && packet.bytes.len() == crate::fineoffset::MAX_PACKET_LEN {
+                    return self.packet.take().map(|packet| packet.bytes);
+                }
+            }
+            None => {
+                self.shift = self.shift << 1 | u16::from(bit);
+                let sync = u16::from_be_bytes(crate::fineoffset::SYNC);
+                if self.shift == sync || self.shift == !sync {
+                    self.packet = Some(Packet {
+                        inverted: self.shift != sync,
+                        bytes: Vec::with_capacity(crate::fineoffset::MAX_PACKET_LEN),
+                        bits: 0,
+                    });
+                    self.shift = 0;
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Reads from the dongle until it fails or `packet` returns false, calling
+/// `packet` with each packet demodulated and when it was received. The
+/// dongle isn't read while `duty` has receivers sleep.
+pub fn receive<F>(mut device: Device, duty: crate::power::DutyCycle, mut packet: F)
+where
+    F: FnMut(&[u8], chrono::DateTime<chrono::Local>) -> bool,
+{
+    let mut demodulator = Demodulator::default();
+    let mut buf = vec![0; READ_LEN];
+    let mut listening = std::time::Instant::now();
+    loop {
+        if !duty.is_active() {
+            listening = std::time::Instant::now();
+        } else if listening.elapsed() >= duty.listen() {
+            log::debug!("Pausing RTL-SDR for {:?}", duty.sleep());
+            std::thread::sleep(duty.sleep());
+            if let Err(e) = device.reset_buffer() {
+                log::error!("Error resuming RTL-SDR: {:?}", e);
+                return;
+            }
+            demodulator = Demodulator::default();
+            listening = std::time::Instant::now();
+        }
+        let n_read = match device.read(&mut buf) {
+            Ok(n_read) => n_read,
+            Err(e) => {
+                log::error!("Error reading from RTL-SDR: {:?}", e);
+                return;
+            }
+        };
+        let timestamp = crate::clock::now();
+        for bytes in demodulator.process(&buf[..n_read]) {
+            if !packet(&bytes, timestamp) {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Frequency deviation of the synthesized transmissions, in Hz
+    const DEVIATION: f32 = 40_000.0;
+
+    // Synthesizes the samples of a transmission of `bytes`, each bit sent
+    // `deviation` above the transmitter's frequency for a one and below it
+    // for a zero, the transmitter being `offset` off the tuned frequency,
+    // between stretches of a quiet band
+    fn transmit(bytes: &[u8], offset: f32, deviation: f32) -> Vec<u8> {
+        let quiet = vec![128; 400];
+        let bits: Vec<bool> = bytes
+            .iter()
+            .flat_map(|byte| (0..8).rev().map(move |i| byte >> i & 1 == 1))
+            .collect();
+        let samples = (bits.len() as f32 * SAMPLE_RATE as f32 / BIT_RATE) as usize;
+        let mut iq = quiet.clone();
+        let mut phase = 0.0f32;
+        for n in 0..samples {
+            let bit = bits[(n as f32 * BIT_RATE / SAMPLE_RATE as f32) as usize];
+            let frequency = if bit {
+                offset + deviation
+            } else {
+                offset - deviation
+            };
+            phase += std::f32::consts::TAU * frequency / SAMPLE_RATE as f32;
+            iq.push((127.5 + 100.0 * phase.cos()).round() as u8);
+            iq.push((127.5 + 100.0 * phase.sin()).round() as u8);
+        }
+        iq.extend(quiet);
+        iq
+    }
+
+    fn with_sync(payload: &[u8]) -> Vec<u8> {
+        let preamble = [0xaa; 8];
+        [&preamble[..], &crate::fineoffset::SYNC, payload].concat()
+    }
+
+    // A WS90 packet, as long as the longest decoded
+    const WS90: &str = "900b0f1a015991016d58002d0c003fffffff0030393600000000000000865f6e";
+    // A WH45 packet, which ends with the transmission
+    const WH45: &str = "4500234502712d407b40c902643cb3";
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn packet_after_sync_word() {
+        let mut demodulator = Demodulator::default();
+        let packets = demodulator.process(&transmit(&with_sync(&bytes(WS90)), 0.0, DEVIATION));
+        assert_eq!(packets, vec![bytes(WS90)]);
+    }
+
+    #[test]
+    fn packet_from_transmitter_off_frequency() {
+        let mut demodulator = Demodulator::default();
+        let iq = transmit(&with_sync(&bytes(WS90)), 25_000.0, DEVIATION);
+        assert_eq!(demodulator.process(&iq), vec![bytes(WS90)]);
+    }
+
+    #[test]
+    fn packet_with_swapped_frequencies() {
+        let mut demodulator = Demodulator::default();
+        let packets = demodulator.process(&transmit(&with_sync(&bytes(WS90)), 0.0, -DEVIATION));
+        assert_eq!(packets, vec![bytes(WS90)]);
+    }
+
+    #[test]
+    fn short_packet_ends_with_transmission() {
+        let mut demodulator = Demodulator::default();
+        let packets = demodulator.process(&transmit(&with_sync(&bytes(WH45)), 0.0, DEVIATION));
+        assert_eq!(packets.len(), 1);
+        assert!(
+            packets[0].starts_with(&bytes(WH45)),
+            "{:02x?} doesn't start with {}",
+            packets[0],
+            WH45
+        );
+    }
+
+    #[test]
+    fn packet_split_across_reads() {
+        let mut demodulator = Demodulator::default();
+        let iq = transmit(&with_sync(&bytes(WS90)), 0.0, DEVIATION);
+        let (first, second) = iq.split_at(iq.len() / 2);
+        assert!(demodulator.process(first).is_empty());
+        assert_eq!(demodulator.process(second), vec![bytes(WS90)]);
+    }
+
+    #[test]
+    fn nothing_without_sync_word() {
+        let mut demodulator = Demodulator::default();
+        let preamble = [0xaa; 24];
+        assert!(demodulator
+            .process(&transmit(&preamble, 0.0, DEVIATION))
+            .is_empty());
+    }
+}
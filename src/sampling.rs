@@ -0,0 +1,110 @@
+//! Sampling of the records of high-rate protocols, e.g. tire pressure
+//! sensors and utility meters, whose traffic can overwhelm the sinks
+//!
+//! Sampling applies by model, as rtl_433 names it, to each sensor of the
+//! model separately, and drops records before anything else is done with
+//! them. How many records each model had sampled out is kept count of.
+
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{ConfigError, SensorPattern};
+use crate::radio::Record;
+
+/// Which of a sensor's records are kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleRule {
+    /// Keeps one record in this many
+    Every(u32),
+    /// Keeps the first record in each interval of this many seconds
+    Interval(u64),
+}
+
+impl std::str::FromStr for SampleRule {
+    type Err = ConfigError;
+
+    /// Parses `1/N` for one record in N, or e.g. `60s` for the first record
+    /// in each minute
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rule = if let Some(n) = s.strip_prefix("1/") {
+            n.parse().ok().filter(|n| *n > 0).map(Self::Every)
+        } else if let Some(secs) = s.strip_suffix('s') {
+            secs.parse().ok().map(Self::Interval)
+        } else {
+            None
+        };
+        rule.ok_or_else(|| ConfigError::SampleRule(s.to_owned()))
+    }
+}
+
+// What's been kept of a sensor's records so far
+#[derive(Debug, Default)]
+struct SensorSample {
+    seen: u64,
+    kept_at: Option<DateTime<Local>>,
+}
+
+/// Applies the sampling rules to records, counting those dropped
+#[derive(Debug, Default)]
+pub struct Sampler {
+    rules: Vec<(SensorPattern, SampleRule)>,
+    sensors: HashMap<String, SensorSample>,
+    dropped: BTreeMap<String, u64>,
+}
+
+impl Sampler {
+    /// Compiles the rules, by [`SensorPattern`] over model names; exact
+    /// models take precedence over other patterns
+    pub fn new(rules: &BTreeMap<String, SampleRule>) -> Result<Self, ConfigError> {
+        let mut rules = rules
+            .iter()
+            .map(|(pattern, rule)| Ok((pattern.parse()?, *rule)))
+            .collect::<Result<Vec<(SensorPattern, SampleRule)>, ConfigError>>()?;
+        rules.sort_by_key(|(pattern, _)| !matches!(pattern, SensorPattern::Exact(_)));
+        Ok(Sampler {
+            rules,
+            sensors: HashMap::new(),
+            dropped: BTreeMap::new(),
+        })
+    }
+
+    /// Whether a record is kept, going by its timestamp so that replays are
+    /// sampled as they were received
+    pub fn keep(&mut self, record: &Record) -> bool {
+        let model = match record.record_json.get("model").and_then(|m| m.as_str()) {
+            Some(model) => model,
+            None => return true,
+        };
+        let rule = match self
+            .rules
+            .iter()
+            .find(|(pattern, _)| pattern.matches(model))
+        {
+            Some((_, rule)) => *rule,
+            None => return true,
+        };
+        let sensor = self.sensors.entry(record.sensor_id.clone()).or_default();
+        sensor.seen += 1;
+        let keep = match rule {
+            SampleRule::Every(n) => (sensor.seen - 1).is_multiple_of(u64::from(n)),
+            SampleRule::Interval(secs) => sensor
+                .kept_at
+                .map(|kept_at| record.timestamp - kept_at >= chrono::Duration::seconds(secs as i64))
+                .unwrap_or(true),
+        };
+        if keep {
+            sensor.kept_at = Some(record.timestamp);
+        } else {
+            *self.dropped.entry(model.to_owned()).or_default() += 1;
+        }
+        keep
+    }
+
+    /// How many records of each model have been sampled out
+    pub fn dropped(&self) -> &BTreeMap<String, u64> {
+        &self.dropped
+    }
+}
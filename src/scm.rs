@@ -0,0 +1,164 @@
+use chrono::{Local, TimeZone};
+
+use anyhow::Result;
+use thiserror::Error;
+
+use uom::si::{energy, f32::Energy};
+use uom::si::{f32::Volume, volume};
+
+#[derive(Error, Debug)]
+pub enum MeasurementError {
+    #[error("Record root not dictionary")]
+    NotDictionary,
+    #[error("Record missing timestamp")]
+    MissingTimestamp,
+    #[error("Failed while parsing record timestamp from json record data")]
+    TimestampFormat(#[from] chrono::format::ParseError),
+    #[error("Record not an SCM or SCM+ message")]
+    UnsupportedModel,
+    #[error("Record missing sensor id")]
+    MissingSensorId,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Commodity {
+    Electric,
+    Gas,
+    Water,
+    Unknown,
+}
+
+impl Commodity {
+    // ERT type to commodity mapping, as commonly observed in the field
+    fn from_ert_type(ert_type: u8) -> Self {
+        match ert_type {
+            4 | 5 | 7 | 8 => Self::Electric,
+            2 | 9 | 12 => Self::Gas,
+            11 | 13 => Self::Water,
+            _ => Self::Unknown,
+        }
+    }
+
+    fn from_meter_type(meter_type: &str) -> Self {
+        match meter_type {
+            "Electric" => Self::Electric,
+            "Gas" => Self::Gas,
+            "Water" => Self::Water,
+            _ => Self::Unknown,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Electric => "Electric",
+            Self::Gas => "Gas",
+            Self::Water => "Water",
+            Self::Unknown => "Unknown",
+        }
+    }
+
+    fn measurement(&self, consumption: u64) -> Option<crate::radio::Measurement> {
+        let consumption = consumption as f32;
+        match self {
+            Self::Electric => Some(crate::radio::Measurement::TotalEnergyConsumption(
+                Energy::new::<energy::kilowatt_hour>(consumption),
+            )),
+            Self::Gas => {
+                let cubic_feet = Volume::new::<volume::cubic_foot>(consumption);
+                Some(crate::radio::Measurement::VolumeConsumption(cubic_feet))
+            }
+            Self::Water => {
+                let gallons = Volume::new::<volume::gallon>(consumption);
+                Some(crate::radio::Measurement::VolumeConsumption(gallons))
+            }
+            Self::Unknown => None,
+        }
+    }
+}
+
+// {
+//      "time" : "2021-09-02 18:31:07",
+//      "model" : "SCM",
+//      "id" : 37293711,
+//      "physical_tamper" : 0,
+//      "ert_type" : 12,
+//      "encoder_tamper" : 0,
+//      "consumption_data" : 412753,
+//      "mic" : "CRC"
+// }
+// {
+//      "time" : "2021-09-02 18:31:12",
+//      "model" : "SCMplus",
+//      "id" : 71044518,
+//      "ProtocolID" : "0x1E",
+//      "EndpointType" : "0xAB",
+//      "EndpointID" : 71044518,
+//      "Consumption" : 1093812,
+//      "Tamper" : "0x4900",
+//      "PacketCRC" : "0x39BE",
+//      "MeterType" : "Water",
+//      "mic" : "CRC"
+// }
+/// Parses a json record from rtl_433 as an SCM/SCM+ utility meter
+pub fn try_parse(json: &serde_json::Value) -> Result<crate::radio::Record> {
+    if let serde_json::Value::Object(m) = json {
+        let timestamp: chrono::DateTime<chrono::Local> =
+            if let Some(serde_json::Value::String(time)) = m.get("time") {
+                let from = chrono::NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S")?;
+                Local
+                    .from_local_datetime(&from)
+                    .earliest()
+                    .ok_or(anyhow::anyhow!("Invalid datetime string conversion"))?
+            } else {
+                return Err(MeasurementError::MissingTimestamp.into());
+            };
+        let (commodity, consumption) = match m.get("model") {
+            Some(serde_json::Value::String(model)) if model == "SCM" => {
+                let commodity = if let Some(serde_json::Value::Number(ert_type)) = m.get("ert_type")
+                {
+                    ert_type
+                        .as_u64()
+                        .map(|ert_type| Commodity::from_ert_type(ert_type as u8))
+                        .unwrap_or(Commodity::Unknown)
+                } else {
+                    Commodity::Unknown
+                };
+                (commodity, m.get("consumption_data"))
+            }
+            Some(serde_json::Value::String(model)) if model == "SCMplus" => {
+                let commodity =
+                    if let Some(serde_json::Value::String(meter_type)) = m.get("MeterType") {
+                        Commodity::from_meter_type(meter_type)
+                    } else {
+                        Commodity::Unknown
+                    };
+                (commodity, m.get("Consumption"))
+            }
+            _ => return Err(MeasurementError::UnsupportedModel.into()),
+        };
+        let meter_id = if let Some(serde_json::Value::Number(meter_id)) = m.get("id") {
+            meter_id.as_u64().map(|meter_id| meter_id as u32)
+        } else {
+            None
+        };
+        let sensor_id = match meter_id {
+            Some(id) => format!("{}/{}", commodity.as_str(), id),
+            None => return Err(MeasurementError::MissingSensorId.into()),
+        };
+        let mut measurements = Vec::new();
+        if let Some(serde_json::Value::Number(c)) = consumption {
+            if let Some(measurement) = c.as_u64().and_then(|c| commodity.measurement(c)) {
+                measurements.push(measurement);
+            }
+        }
+        Ok(crate::radio::Record {
+            timestamp,
+            sensor_id,
+            record_json: json.clone(),
+            measurements,
+            radio: None,
+        })
+    } else {
+        Err(MeasurementError::NotDictionary.into())
+    }
+}
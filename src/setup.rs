@@ -0,0 +1,199 @@
+//! Interactive first-run setup, for station owners who'd rather answer a
+//! few questions than write the configuration file by hand
+//!
+//! Each answer is checked as it's given: the rtl_433 binary has to be found
+//! and runnable, and the broker has to accept a connection with the account
+//! given, with the chance to answer again when it doesn't. The settings
+//! already in effect are offered as the defaults, so running setup again
+//! changes only what's answered differently.
+
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use crate::config::{Config, Receiver};
+use crate::region::Region;
+
+/// Asks the questions on `output`, reading the answers from `input`
+pub struct Setup<R, W> {
+    input: R,
+    output: W,
+}
+
+fn region_name(region: Region) -> &'static str {
+    match region {
+        Region::Us915 => "US915",
+        Region::Eu868 => "EU868",
+        Region::Au => "AU",
+    }
+}
+
+impl<R: BufRead, W: Write> Setup<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Setup { input, output }
+    }
+
+    /// Asks a question, returning the answer, or `default` for an empty one
+    fn ask(&mut self, question: &str, default: Option<&str>) -> Result<String> {
+        match default {
+            Some(default) if !default.is_empty() => {
+                write!(self.output, "{} [{}]: ", question, default)?
+            }
+            _ => write!(self.output, "{}: ", question)?,
+        }
+        self.output.flush()?;
+        let mut answer = String::new();
+        if self.input.read_line(&mut answer)? == 0 {
+            anyhow::bail!("Setup cancelled");
+        }
+        let answer = answer.trim();
+        match (answer.is_empty(), default) {
+            (true, Some(default)) => Ok(default.to_owned()),
+            _ => Ok(answer.to_owned()),
+        }
+    }
+
+    /// Asks a yes or no question
+    fn confirm(&mut self, question: &str, default: bool) -> Result<bool> {
+        let options = if default { "Y/n" } else { "y/N" };
+        loop {
+            let answer = self.ask(&format!("{} [{}]", question, options), None)?;
+            match answer.to_ascii_lowercase().as_str() {
+                "" => return Ok(default),
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => writeln!(self.output, "Please answer yes or no")?,
+            }
+        }
+    }
+
+    /// The rtl_433 binary, found on PATH unless there's one configured
+    fn ask_rtl_433(&mut self, current: Option<PathBuf>) -> Result<PathBuf> {
+        let detected = current
+            .filter(|path| crate::preflight::find_program(path).is_some())
+            .or_else(|| crate::preflight::find_program("rtl_433".as_ref()));
+        if let Some(ref path) = detected {
+            writeln!(self.output, "Found rtl_433 at {}", path.display())?;
+        }
+        loop {
+            let default = detected.as_ref().map(|path| path.display().to_string());
+            let answer = self.ask("Path to the rtl_433 binary", default.as_deref())?;
+            if answer.is_empty() {
+                continue;
+            }
+            let path = PathBuf::from(answer);
+            match crate::preflight::find_program(&path) {
+                Some(found) if crate::preflight::is_executable(&found) => return Ok(path),
+                Some(found) => {
+                    writeln!(self.output, "{} is not an executable file", found.display())?
+                }
+                None => writeln!(self.output, "rtl_433 not found at {}", path.display())?,
+            }
+            if !self.confirm("Try another path?", true)? {
+                return Ok(path);
+            }
+        }
+    }
+
+    fn ask_region(&mut self, current: Region) -> Result<Region> {
+        writeln!(
+            self.output,
+            "Sensors sold in North America transmit on 915 MHz (US915), in Europe and the UK on 868 MHz (EU868), and in Australia and New Zealand on 433 MHz (AU)"
+        )?;
+        loop {
+            let answer = self.ask("Frequency region", Some(region_name(current)))?;
+            match answer.parse() {
+                Ok(region) => return Ok(region),
+                Err(e) => writeln!(self.output, "{}", e)?,
+            }
+        }
+    }
+
+    /// The broker and the account to connect with, checking that it accepts
+    /// the connection
+    #[cfg(feature = "mqtt")]
+    fn ask_mqtt(
+        &mut self,
+        mut current: Option<crate::config::MqttConfig>,
+    ) -> Result<Option<crate::config::MqttConfig>> {
+        use crate::config::{Credentials, MqttConfig};
+
+        loop {
+            let default = current.as_ref().map(|mqtt| mqtt.broker.clone());
+            let broker = self.ask(
+                "mqtt broker address, e.g. localhost:1883, or none to not publish",
+                default.as_deref(),
+            )?;
+            if broker.is_empty() || broker == "none" {
+                return Ok(None);
+            }
+            let mut mqtt = match current.clone() {
+                Some(mqtt) => MqttConfig { broker, ..mqtt },
+                None => MqttConfig::new(broker),
+            };
+            let username = mqtt.credentials.as_ref().and_then(Credentials::username);
+            let username = self.ask("mqtt username, or none for anonymous", username.as_deref())?;
+            mqtt.credentials = match username.as_str() {
+                "" | "none" => None,
+                username => {
+                    let password =
+                        rpassword::prompt_password(format!("mqtt password for {}: ", username))?;
+                    let keyring = cfg!(feature = "keyring")
+                        && self.confirm(
+                            &format!("Keep the password on the {}?", crate::config::KEYRING_NAME),
+                            true,
+                        )?;
+                    let credentials = match keyring {
+                        true => Credentials::Keyring(username.to_owned()),
+                        false => Credentials::ConfigFile(username.to_owned(), String::new()),
+                    };
+                    Some(credentials.update_password(&mqtt.broker, &password)?)
+                }
+            };
+            writeln!(self.output, "Connecting to {}...", mqtt.broker)?;
+            match crate::sink::MqttSink::check(&mqtt) {
+                Ok(()) => {
+                    writeln!(self.output, "Connected")?;
+                    return Ok(Some(mqtt));
+                }
+                Err(e) => writeln!(self.output, "{:#}", e)?,
+            }
+            if !self.confirm("Try different settings?", true)? {
+                return Ok(Some(mqtt));
+            }
+            current = Some(mqtt);
+        }
+    }
+
+    /// Asks for the settings a station needs, over those of `conf`
+    pub fn run(&mut self, mut conf: Config) -> Result<Config> {
+        if conf.receiver == Receiver::Rtl433 {
+            conf.rtl_433 = Some(self.ask_rtl_433(conf.rtl_433.take())?);
+        }
+        conf.region = self.ask_region(conf.region)?;
+        #[cfg(feature = "mqtt")]
+        {
+            // Only the first broker is set up, keeping any others
+            match self.ask_mqtt(conf.mqtt.first().cloned())? {
+                Some(mqtt) if conf.mqtt.is_empty() => conf.mqtt.push(mqtt),
+                Some(mqtt) => conf.mqtt[0] = mqtt,
+                None => conf.mqtt.clear(),
+            }
+        }
+        #[cfg(not(feature = "mqtt"))]
+        writeln!(
+            self.output,
+            "Built without mqtt support, so there's no broker to set up"
+        )?;
+        Ok(conf)
+    }
+
+    /// Asks whether to write the configuration to `path`
+    pub fn confirm_write(&mut self, path: &std::path::Path) -> Result<bool> {
+        self.confirm(
+            &format!("Write the configuration to {}?", path.display()),
+            true,
+        )
+    }
+}
@@ -0,0 +1,780 @@
+//! Output sinks that records are published to
+//!
+//! Publishing goes through one of two mqtt client libraries, chosen at build
+//! time: paho (the `paho` feature), which links the paho C library, or the
+//! pure Rust rumqttc (the `rumqttc` feature), which is easier to cross
+//! compile. rumqttc is used when both are enabled.
+//!
+//! Publishes are queued for a task on the tokio runtime, so that a slow
+//! broker holds up neither the pipeline nor the radio. Messages are dropped
+//! while the queue is full. When a publish fails or times out, or the
+//! connection is lost, the task connects to the broker again, [`RETRY_SECS`]
+//! after the last attempt failed, doubling up to [`MAX_RETRY_SECS`], and
+//! subscribes again to what was subscribed to. Messages published in the
+//! meantime are held, up to the queue's capacity, dropping the oldest, and
+//! published once it's connected again, after announcing the bridge online.
+//! The latency of each record's publish is tracked once it completes.
+//!
+//! Brokers connected to with MQTT 5 (`protocol_version` 5) are published
+//! records with MQTT 5 properties: an expiry, so that a broker doesn't hand
+//! subscribers readings that have long gone stale, an `application/json`
+//! content type, and user properties naming the sensor's model and channel.
+//! MQTT 3.1.1 connections publish the same messages without them.
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::Instant;
+
+use crate::audit::AuditChain;
+use crate::availability;
+use crate::config::MqttConfig;
+use crate::events::{Event, EventBus};
+use crate::latency::LatencyTracker;
+use crate::radio::Record;
+
+#[cfg(all(feature = "paho", not(feature = "rumqttc")))]
+mod paho;
+#[cfg(feature = "rumqttc")]
+mod rumqtt;
+#[cfg(feature = "rumqttc")]
+mod rumqtt5;
+
+/// Seconds after failing to connect to a broker before connecting to it
+/// again, doubling with every failure in a row
+pub const RETRY_SECS: u64 = 1;
+
+/// Seconds the wait before connecting to a broker again grows to at most
+pub const MAX_RETRY_SECS: u64 = 60;
+
+// How often a connection that isn't being published to is checked for
+// having been lost
+const CHECK_PERIOD: Duration = Duration::from_secs(5);
+
+/// A message to publish, or one received on a subscribed topic
+#[derive(Clone, Debug)]
+pub struct Message {
+    topic: String,
+    payload: Vec<u8>,
+    qos: i32,
+    retained: bool,
+    /// When the record the message carries was received, for tracking
+    /// publish latency
+    received: Option<chrono::DateTime<chrono::Local>>,
+    properties: MessageProperties,
+}
+
+/// MQTT 5 properties of a message, which MQTT 3.1.1 connections publish it
+/// without
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MessageProperties {
+    /// Seconds after which the broker discards the message
+    pub expiry: Option<u32>,
+    /// MIME type of the payload
+    pub content_type: Option<String>,
+    /// Name and value pairs published along with the payload
+    pub user_properties: Vec<(String, String)>,
+}
+
+impl MessageProperties {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+impl Message {
+    pub fn new<P: Into<Vec<u8>>>(topic: &str, payload: P, qos: i32) -> Self {
+        Message {
+            topic: topic.to_owned(),
+            payload: payload.into(),
+            qos,
+            retained: false,
+            received: None,
+            properties: MessageProperties::default(),
+        }
+    }
+
+    /// A message the broker retains for future subscribers
+    pub fn new_retained<P: Into<Vec<u8>>>(topic: &str, payload: P, qos: i32) -> Self {
+        Message {
+            retained: true,
+            ..Self::new(topic, payload, qos)
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub fn payload_str(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.payload)
+    }
+
+    pub fn qos(&self) -> i32 {
+        self.qos
+    }
+
+    pub fn retained(&self) -> bool {
+        self.retained
+    }
+
+    pub fn properties(&self) -> &MessageProperties {
+        &self.properties
+    }
+}
+
+/// An mqtt client library connection that [`MqttSink`] publishes through
+pub trait MqttBackend: Send + Sync {
+    /// Publishes a message, failing if the connection to the broker is lost
+    fn publish(&self, message: Message) -> Result<()>;
+
+    /// Subscribes to `topic`, which may contain wildcards, returning a
+    /// receiver for the messages published to it. A `None` message signals
+    /// that the connection to the broker was lost.
+    fn subscribe(&self, topic: &str) -> Result<Receiver<Option<Message>>>;
+
+    fn disconnect(&self) -> Result<()>;
+}
+
+// Connects with the backend this was built with, registering any `will` as
+// the last will and testament
+#[cfg(feature = "rumqttc")]
+fn connect_backend(mqtt: &MqttConfig, will: Option<Message>) -> Result<Arc<dyn MqttBackend>> {
+    if mqtt.get_protocol_version() == 5 {
+        return Ok(Arc::new(rumqtt5::Rumqttc5Backend::connect(mqtt, will)?));
+    }
+    Ok(Arc::new(rumqtt::RumqttcBackend::connect(mqtt, will)?))
+}
+
+#[cfg(all(feature = "paho", not(feature = "rumqttc")))]
+fn connect_backend(mqtt: &MqttConfig, will: Option<Message>) -> Result<Arc<dyn MqttBackend>> {
+    Ok(Arc::new(paho::PahoBackend::connect(mqtt, will)?))
+}
+
+#[cfg(not(any(feature = "paho", feature = "rumqttc")))]
+fn connect_backend(mqtt: &MqttConfig, _will: Option<Message>) -> Result<Arc<dyn MqttBackend>> {
+    anyhow::bail!(
+        "Unable to publish to {}, built without mqtt support",
+        mqtt.broker
+    )
+}
+
+enum Request {
+    /// A message to publish, failing the publish instead when injecting a
+    /// fault
+    Publish { message: Message, fail: bool },
+    /// Acknowledged, with how many messages are held for the broker, once
+    /// every request queued before it has been handled
+    Flush(std::sync::mpsc::Sender<usize>),
+}
+
+// The connection to the broker, while connected
+#[derive(Default)]
+struct Connection {
+    backend: Option<Arc<dyn MqttBackend>>,
+    // Counts the connections made, so that a connection being lost isn't
+    // mistaken for the one after it being lost
+    generation: u64,
+    // Set once the sink has disconnected for good
+    closed: bool,
+}
+
+// What a sink's clones and the task publishing their queue share
+struct Link {
+    broker: String,
+    // Where the bridge announces whether it's running on this broker
+    status_topic: String,
+    connection: Mutex<Connection>,
+    // Topics subscribed to, subscribed to again on every connection
+    subscriptions: Mutex<Vec<(String, Sender<Option<Message>>)>>,
+    // Where failures are reported, besides the log
+    events: Mutex<Option<EventBus>>,
+}
+
+impl Link {
+    fn new(broker: &str, status_topic: &str) -> Self {
+        Link {
+            broker: broker.to_owned(),
+            status_topic: status_topic.to_owned(),
+            connection: Mutex::new(Connection::default()),
+            subscriptions: Mutex::new(Vec::new()),
+            events: Mutex::new(None),
+        }
+    }
+
+    // The current connection and its generation, if connected
+    fn backend(&self) -> Option<(u64, Arc<dyn MqttBackend>)> {
+        let connection = self.connection.lock().expect("connection poisoned");
+        let backend = connection.backend.clone()?;
+        Some((connection.generation, backend))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.connection.lock().expect("connection poisoned").closed
+    }
+
+    // Takes on a new connection, subscribing it to the topics subscribed to
+    fn connected(self: &Arc<Self>, backend: Arc<dyn MqttBackend>) -> Result<()> {
+        let mut connection = self.connection.lock().expect("connection poisoned");
+        if connection.closed {
+            anyhow::bail!("Disconnected from {}", self.broker);
+        }
+        let generation = connection.generation + 1;
+        for (topic, subscriber) in self
+            .subscriptions
+            .lock()
+            .expect("subscriptions poisoned")
+            .iter()
+        {
+            self.forward(generation, backend.as_ref(), topic, subscriber.clone())?;
+        }
+        connection.backend = Some(backend);
+        connection.generation = generation;
+        Ok(())
+    }
+
+    // Subscribes the connection to `topic`, passing the messages received on
+    // to `subscriber` until the connection is lost
+    fn forward(
+        self: &Arc<Self>,
+        generation: u64,
+        backend: &dyn MqttBackend,
+        topic: &str,
+        subscriber: Sender<Option<Message>>,
+    ) -> Result<()> {
+        let incoming = backend.subscribe(topic)?;
+        let link = self.clone();
+        std::thread::spawn(move || {
+            while let Ok(Some(message)) = incoming.recv() {
+                if subscriber.send(Some(message)).is_err() {
+                    return;
+                }
+            }
+            link.lost(generation, format!("Lost connection to {}", link.broker));
+        });
+        Ok(())
+    }
+
+    fn subscribe(self: &Arc<Self>, topic: &str) -> Result<Receiver<Option<Message>>> {
+        let (subscriber, receiver) = std::sync::mpsc::channel();
+        let connection = self.connection.lock().expect("connection poisoned");
+        if let Some(ref backend) = connection.backend {
+            self.forward(
+                connection.generation,
+                backend.as_ref(),
+                topic,
+                subscriber.clone(),
+            )?;
+        }
+        self.subscriptions
+            .lock()
+            .expect("subscriptions poisoned")
+            .push((topic.to_owned(), subscriber));
+        Ok(receiver)
+    }
+
+    // Gives up on the connection `generation`, if it's still the current
+    // one, to connect again
+    fn lost(&self, generation: u64, error: String) {
+        let backend = {
+            let mut connection = self.connection.lock().expect("connection poisoned");
+            if connection.generation != generation || connection.closed {
+                return;
+            }
+            match connection.backend.take() {
+                Some(backend) => backend,
+                None => return,
+            }
+        };
+        self.report(error);
+        // What's left of the connection may take a while to go away
+        std::thread::spawn(move || {
+            let _ = backend.disconnect();
+        });
+    }
+
+    // Logs a failure, and reports it on the event bus
+    fn report(&self, error: String) {
+        log::error!("{}", error);
+        if let Some(ref events) = *self.events.lock().expect("sink events poisoned") {
+            events.publish(Event::SinkError {
+                sink: self.broker.clone(),
+                error,
+            });
+        }
+    }
+
+    // Disconnects for good, returning the connection if connected, and
+    // signalling the subscribers
+    fn close(&self) -> Option<Arc<dyn MqttBackend>> {
+        let mut connection = self.connection.lock().expect("connection poisoned");
+        connection.closed = true;
+        for (_, subscriber) in self
+            .subscriptions
+            .lock()
+            .expect("subscriptions poisoned")
+            .drain(..)
+        {
+            let _ = subscriber.send(None);
+        }
+        connection.backend.take()
+    }
+}
+
+// Publishes a message through `backend`, recording the latency of those
+// carrying records, or describes why it couldn't be published
+async fn publish_with(
+    backend: &Arc<dyn MqttBackend>,
+    message: Message,
+    broker: &str,
+    timeout: Duration,
+    latency: &LatencyTracker,
+) -> std::result::Result<(), String> {
+    let topic = message.topic().to_owned();
+    let received = message.received;
+    let publish = tokio::task::spawn_blocking({
+        let backend = backend.clone();
+        move || backend.publish(message)
+    });
+    match tokio::time::timeout(timeout, publish).await {
+        Ok(Ok(Ok(()))) => {
+            if let Some(received) = received {
+                latency.record(received);
+            }
+            Ok(())
+        }
+        Ok(Ok(Err(e))) => Err(format!(
+            "Failed publishing to {} on {}: {:#}",
+            topic, broker, e
+        )),
+        Ok(Err(e)) => Err(format!(
+            "Failed publishing to {} on {}: {}",
+            topic, broker, e
+        )),
+        Err(_) => Err(format!(
+            "Timed out publishing to {} on {} after {:?}",
+            topic, broker, timeout
+        )),
+    }
+}
+
+// Messages held while the broker isn't connected, to publish in order once
+// it is
+struct Held {
+    messages: VecDeque<Message>,
+    capacity: usize,
+    // Messages dropped to make room since the broker was last connected
+    dropped: u64,
+}
+
+impl Held {
+    fn push(&mut self, message: Message, broker: &str) {
+        if self.messages.len() >= self.capacity {
+            if self.dropped == 0 {
+                log::warn!("Too many messages held for {}, dropping the oldest", broker);
+            }
+            self.messages.pop_front();
+            self.dropped += 1;
+        }
+        self.messages.push_back(message);
+    }
+}
+
+// Publishes queued messages in order until every sender is dropped,
+// connecting to the broker again whenever the connection is lost, and
+// holding the messages published until it is
+async fn publish_queued(
+    link: Arc<Link>,
+    mqtt: MqttConfig,
+    mut requests: tokio::sync::mpsc::Receiver<Request>,
+    latency: LatencyTracker,
+) {
+    let broker = mqtt.broker.clone();
+    let timeout = mqtt.get_publish_timeout();
+    let mut held = Held {
+        messages: VecDeque::new(),
+        capacity: mqtt.get_queue_capacity(),
+        dropped: 0,
+    };
+    let mut retry = Duration::from_secs(RETRY_SECS);
+    let mut retry_at = Instant::now();
+    loop {
+        if link.backend().is_none() && !link.is_closed() && Instant::now() >= retry_at {
+            log::debug!("Establishing connection to mqtt broker {}", broker);
+            let connect = tokio::task::spawn_blocking({
+                let (link, mqtt) = (link.clone(), mqtt.clone());
+                move || {
+                    let backend =
+                        connect_backend(&mqtt, Some(availability::last_will(&link.status_topic)))?;
+                    link.connected(backend)
+                }
+            });
+            match connect.await.map_err(anyhow::Error::from).and_then(|r| r) {
+                Ok(()) => {
+                    log::info!("Connected to mqtt broker {}", broker);
+                    retry = Duration::from_secs(RETRY_SECS);
+                    if held.dropped > 0 {
+                        log::warn!(
+                            "Dropped {} messages for {} while disconnected",
+                            held.dropped,
+                            broker
+                        );
+                        held.dropped = 0;
+                    }
+                    // The broker published the last will when the connection
+                    // was lost
+                    let online = Message::new_retained(&link.status_topic, availability::ONLINE, 1);
+                    held.messages.push_front(online);
+                }
+                Err(e) => {
+                    link.report(format!(
+                        "Failed to establish connection to broker {}: {:#}",
+                        broker, e
+                    ));
+                    retry_at = Instant::now() + retry;
+                    retry = (retry * 2).min(Duration::from_secs(MAX_RETRY_SECS));
+                }
+            }
+        }
+        if let Some((generation, backend)) = link.backend() {
+            while let Some(message) = held.messages.pop_front() {
+                let publish = publish_with(&backend, message.clone(), &broker, timeout, &latency);
+                if let Err(failure) = publish.await {
+                    held.messages.push_front(message);
+                    link.lost(generation, failure);
+                    retry_at = Instant::now();
+                    break;
+                }
+            }
+        }
+        let deadline = match link.backend() {
+            Some(_) => Instant::now() + CHECK_PERIOD,
+            None => retry_at.max(Instant::now() + Duration::from_millis(100)),
+        };
+        let request = match tokio::time::timeout_at(deadline, requests.recv()).await {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(_) => continue,
+        };
+        let (message, fail) = match request {
+            Request::Publish { message, fail } => (message, fail),
+            Request::Flush(done) => {
+                let _ = done.send(held.messages.len());
+                continue;
+            }
+        };
+        match link.backend() {
+            Some((generation, backend)) if held.messages.is_empty() => {
+                let result = match fail {
+                    true => Err(format!(
+                        "Injected failure publishing to {} on {}",
+                        message.topic(),
+                        broker
+                    )),
+                    false => {
+                        publish_with(&backend, message.clone(), &broker, timeout, &latency).await
+                    }
+                };
+                if let Err(failure) = result {
+                    held.push(message, &broker);
+                    link.lost(generation, failure);
+                    retry_at = Instant::now();
+                }
+            }
+            _ => held.push(message, &broker),
+        }
+    }
+}
+
+/// Publishes messages in order through a connection of their own, for one-off
+/// commands that may run alongside the bridge. The connection has no last
+/// will, doesn't announce the bridge, and takes the default client id with a
+/// clean session, so that it doesn't disturb a running bridge's connection.
+pub fn publish_once(mqtt: &MqttConfig, messages: Vec<Message>) -> Result<()> {
+    let mqtt = MqttConfig {
+        client_id: None,
+        clean_session: Some(true),
+        ..mqtt.clone()
+    };
+    let backend = connect_backend(&mqtt, None)
+        .with_context(|| format!("Failed to establish connection to broker {}", mqtt.broker))?;
+    for message in messages {
+        let topic = message.topic().to_owned();
+        backend
+            .publish(message)
+            .with_context(|| format!("Failed publishing to {} on {}", topic, mqtt.broker))?;
+    }
+    backend.disconnect()
+}
+
+/// A connection to an mqtt broker that records are published to
+#[derive(Clone)]
+pub struct MqttSink {
+    link: Arc<Link>,
+    qos: i32,
+    /// Seconds before the broker discards a record, if ever
+    message_expiry: Option<u32>,
+    chaos: crate::chaos::ChaosConfig,
+    requests: tokio::sync::mpsc::Sender<Request>,
+    latency: LatencyTracker,
+    audit: Option<AuditChain>,
+}
+
+impl MqttSink {
+    /// Connects to the configured broker in the background, registering a
+    /// last will that marks the bridge offline, and announcing the bridge as
+    /// online on `status_topic` once connected. A broker that can't be
+    /// connected to is connected to again as when the connection is lost,
+    /// holding what's published until then. Must be called within a tokio
+    /// runtime.
+    pub fn connect(mqtt: &MqttConfig, status_topic: &str) -> Self {
+        Self::spawn(Arc::new(Link::new(&mqtt.broker, status_topic)), mqtt)
+    }
+
+    /// Connects to the broker and disconnects again, to check that it
+    /// accepts the connection
+    pub fn check(mqtt: &MqttConfig) -> Result<()> {
+        let backend = connect_backend(mqtt, None)
+            .with_context(|| format!("Failed to establish connection to broker {}", mqtt.broker))?;
+        backend.disconnect()
+    }
+
+    /// Publishes through an already connected backend, queueing as
+    /// configured, and announces the bridge as online on `status_topic`.
+    /// Must be called within a tokio runtime.
+    pub fn with_backend(
+        backend: Arc<dyn MqttBackend>,
+        mqtt: &MqttConfig,
+        status_topic: &str,
+    ) -> Result<Self> {
+        let link = Arc::new(Link::new(&mqtt.broker, status_topic));
+        link.connected(backend)?;
+        let sink = Self::spawn(link, mqtt);
+        sink.publish_retained(status_topic, availability::ONLINE, 1)?;
+        Ok(sink)
+    }
+
+    // Starts the task publishing the queue
+    fn spawn(link: Arc<Link>, mqtt: &MqttConfig) -> Self {
+        let (requests, queue) = tokio::sync::mpsc::channel(mqtt.get_queue_capacity());
+        let latency = LatencyTracker::new();
+        tokio::spawn(publish_queued(
+            link.clone(),
+            mqtt.clone(),
+            queue,
+            latency.clone(),
+        ));
+        MqttSink {
+            link,
+            qos: mqtt.get_qos(),
+            message_expiry: mqtt.get_message_expiry(),
+            chaos: Default::default(),
+            requests,
+            latency,
+            audit: None,
+        }
+    }
+
+    /// Address of the broker this sink publishes to
+    pub fn broker(&self) -> &str {
+        &self.link.broker
+    }
+
+    /// Topic the bridge announces whether it's running on, which the
+    /// bridge's other status topics go under
+    pub fn status_topic(&self) -> &str {
+        &self.link.status_topic
+    }
+
+    /// Quality of service records are published with
+    pub fn qos(&self) -> i32 {
+        self.qos
+    }
+
+    /// Latencies of the records published so far
+    pub fn latency(&self) -> &LatencyTracker {
+        &self.latency
+    }
+
+    /// Injects faults into publishes at the given rates
+    pub fn chaos(mut self, chaos: crate::chaos::ChaosConfig) -> Self {
+        self.chaos = chaos;
+        self
+    }
+
+    /// Reports failing to publish to, or connect to, the broker on `events`
+    pub fn events(self, events: EventBus) -> Self {
+        *self.link.events.lock().expect("sink events poisoned") = Some(events);
+        self
+    }
+
+    /// Extends `chain` with every record published
+    pub fn audit(mut self, chain: AuditChain) -> Self {
+        self.audit = Some(chain);
+        self
+    }
+
+    /// Publishes a message with the configured quality of service
+    pub fn publish<P: Into<Vec<u8>>>(&self, topic: &str, payload: P) -> Result<()> {
+        self.send(Message::new(topic, payload, self.qos))
+    }
+
+    /// Publishes a json record received at `received` with the configured
+    /// quality of service and expiry, optionally retained, tracking the
+    /// latency of its publish
+    pub fn publish_record<P: Into<Vec<u8>>>(
+        &self,
+        topic: &str,
+        payload: P,
+        received: chrono::DateTime<chrono::Local>,
+        retained: bool,
+    ) -> Result<()> {
+        self.send(self.record_message(topic, payload, received, retained))
+    }
+
+    /// Publishes a record's json payload as [`publish_record`] does, along
+    /// with the model and channel of the sensor it's from
+    ///
+    /// [`publish_record`]: Self::publish_record
+    pub fn publish_reading<P: Into<Vec<u8>>>(
+        &self,
+        topic: &str,
+        payload: P,
+        record: &Record,
+        retained: bool,
+    ) -> Result<()> {
+        let mut message = self.record_message(topic, payload, record.timestamp, retained);
+        for field in ["model", "channel"] {
+            match record.record_json.get(field) {
+                Some(serde_json::Value::String(value)) => message
+                    .properties
+                    .user_properties
+                    .push((field.to_owned(), value.clone())),
+                Some(serde_json::Value::Number(value)) => message
+                    .properties
+                    .user_properties
+                    .push((field.to_owned(), value.to_string())),
+                _ => {}
+            }
+        }
+        self.send(message)
+    }
+
+    fn record_message<P: Into<Vec<u8>>>(
+        &self,
+        topic: &str,
+        payload: P,
+        received: chrono::DateTime<chrono::Local>,
+        retained: bool,
+    ) -> Message {
+        Message {
+            received: Some(received),
+            retained,
+            properties: MessageProperties {
+                expiry: self.message_expiry,
+                content_type: Some("application/json".to_owned()),
+                user_properties: Vec::new(),
+            },
+            ..Message::new(topic, payload, self.qos)
+        }
+    }
+
+    /// Publishes a message the broker retains for future subscribers
+    pub fn publish_retained<P: Into<Vec<u8>>>(
+        &self,
+        topic: &str,
+        payload: P,
+        qos: i32,
+    ) -> Result<()> {
+        self.send(Message::new_retained(topic, payload, qos))
+    }
+
+    /// Subscribes to `topic`, which may contain wildcards, returning a
+    /// receiver for the messages published to it, on this connection to the
+    /// broker and every one after it. A `None` message signals that the sink
+    /// has disconnected.
+    pub fn subscribe(&self, topic: &str) -> Result<Receiver<Option<Message>>> {
+        self.link
+            .subscribe(topic)
+            .with_context(|| format!("Failed subscribing to {} on {}", topic, self.broker()))
+    }
+
+    fn send(&self, message: Message) -> Result<()> {
+        if let (Some(ref audit), Some(_)) = (&self.audit, message.received) {
+            audit.record(message.topic(), message.payload());
+        }
+        if crate::chaos::roll(self.chaos.disconnect_rate) {
+            if let Some((generation, _)) = self.link.backend() {
+                log::warn!("Injecting disconnect from {}", self.broker());
+                self.link.lost(
+                    generation,
+                    format!("Injected disconnect from {}", self.broker()),
+                );
+            }
+        }
+        let fail = crate::chaos::roll(self.chaos.sink_failure_rate);
+        if fail {
+            log::warn!("Injecting failure publishing to {}", message.topic());
+        }
+        match self.requests.try_send(Request::Publish { message, fail }) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(Request::Publish { message, .. })) => {
+                log::warn!(
+                    "Publish queue for {} is full, dropping message to {}",
+                    self.broker(),
+                    message.topic()
+                );
+                Ok(())
+            }
+            Err(_) => anyhow::bail!("Publishing to {} has stopped", self.broker()),
+        }
+    }
+
+    // Waits for everything queued so far to be published or held, returning
+    // how many messages are held until the broker is connected to again
+    fn held(&self) -> Result<usize> {
+        let (done, flushed) = std::sync::mpsc::channel();
+        if self.requests.blocking_send(Request::Flush(done)).is_err() {
+            anyhow::bail!("Publishing to {} has stopped", self.broker());
+        }
+        flushed
+            .recv()
+            .with_context(|| format!("Publishing to {} has stopped", self.broker()))
+    }
+
+    /// Waits for everything queued so far to be published, or held while
+    /// the broker isn't connected. Must not be called from within an async
+    /// task.
+    pub fn flush(&self) -> Result<()> {
+        self.held().map(|_| ())
+    }
+
+    /// Announces the bridge as offline, and disconnects from the broker once
+    /// everything queued has been published. Must not be called from within
+    /// an async task.
+    pub fn disconnect(self) -> Result<()> {
+        // Make room in the queue for the announcement
+        self.flush()?;
+        self.publish_retained(self.status_topic(), availability::OFFLINE, 1)?;
+        let held = self.held()?;
+        if held > 0 {
+            log::warn!(
+                "Disconnecting from {} with {} messages never published",
+                self.broker(),
+                held
+            );
+        }
+        match self.link.close() {
+            Some(backend) => backend.disconnect(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! [`MqttBackend`] over the paho C library
+//!
+//! With MQTT 5, each message's [`MessageProperties`] are published along with
+//! it.
+
+use std::sync::mpsc::Receiver;
+
+use anyhow::Result;
+
+use super::{Message, MessageProperties, MqttBackend};
+use crate::config::{MqttConfig, Transport};
+
+pub struct PahoBackend {
+    session: paho_mqtt::Client,
+    /// Whether the session speaks MQTT 5
+    v5: bool,
+}
+
+impl PahoBackend {
+    pub fn connect(mqtt: &MqttConfig, will: Option<Message>) -> Result<Self> {
+        let broker_uri = mqtt.server_uri()?;
+        let v5 = mqtt.get_protocol_version() == 5;
+        let mut create_opts = paho_mqtt::CreateOptionsBuilder::new();
+        create_opts = create_opts.server_uri(broker_uri);
+        if v5 {
+            create_opts = create_opts.mqtt_version(paho_mqtt::MQTT_VERSION_5);
+        }
+        if let Some(client_id) = &mqtt.client_id {
+            create_opts = create_opts.client_id(client_id);
+        }
+        let session = paho_mqtt::Client::new(create_opts.finalize())?;
+        let mut mqtt_opts = match (mqtt.transport()?, v5) {
+            (Transport::Tcp, false) => paho_mqtt::ConnectOptionsBuilder::new(),
+            (Transport::Tcp, true) => paho_mqtt::ConnectOptionsBuilder::new_v5(),
+            (_, false) => paho_mqtt::ConnectOptionsBuilder::new_ws(),
+            (_, true) => paho_mqtt::ConnectOptionsBuilder::new_ws_v5(),
+        };
+        if mqtt.transport()? == Transport::SecureWebSocket {
+            mqtt_opts.ssl_options(paho_mqtt::SslOptionsBuilder::new().finalize());
+        }
+        mqtt_opts.keep_alive_interval(mqtt.get_keep_alive());
+        // The session flag of MQTT 3.1.1 became the clean start flag in MQTT 5
+        if v5 {
+            mqtt_opts.clean_start(mqtt.get_clean_session());
+        } else {
+            mqtt_opts.clean_session(mqtt.get_clean_session());
+        }
+        if let Some(will) = will {
+            mqtt_opts.will_message(to_paho(will, v5)?);
+        }
+        if let Some(cred) = &mqtt.credentials {
+            if let Some((u, p)) = cred.get(&mqtt.broker) {
+                mqtt_opts.user_name(u);
+                mqtt_opts.password(p);
+            }
+        }
+        session.connect(mqtt_opts.finalize())?;
+        Ok(PahoBackend { session, v5 })
+    }
+}
+
+fn properties(properties: &MessageProperties) -> Result<paho_mqtt::Properties> {
+    let mut props = paho_mqtt::Properties::new();
+    if let Some(expiry) = properties.expiry {
+        props.push_u32(paho_mqtt::PropertyCode::MessageExpiryInterval, expiry)?;
+    }
+    if let Some(ref content_type) = properties.content_type {
+        props.push_string(paho_mqtt::PropertyCode::ContentType, content_type)?;
+    }
+    for (name, value) in &properties.user_properties {
+        props.push_string_pair(paho_mqtt::PropertyCode::UserProperty, name, value)?;
+    }
+    Ok(props)
+}
+
+// Converts a message, with its properties if the session speaks MQTT 5
+fn to_paho(message: Message, v5: bool) -> Result<paho_mqtt::Message> {
+    let mut builder = paho_mqtt::MessageBuilder::new()
+        .topic(message.topic)
+        .payload(message.payload)
+        .qos(message.qos)
+        .retained(message.retained);
+    if v5 && !message.properties.is_empty() {
+        builder = builder.properties(properties(&message.properties)?);
+    }
+    Ok(builder.finalize())
+}
+
+impl MqttBackend for PahoBackend {
+    fn publish(&self, message: Message) -> Result<()> {
+        Ok(self.session.publish(to_paho(message, self.v5)?)?)
+    }
+
+    fn subscribe(&self, topic: &str) -> Result<Receiver<Option<Message>>> {
+        let incoming = self.session.start_consuming();
+        self.session.subscribe(topic, 1)?;
+        // paho hands out its own channel and message type, so messages are
+        // passed along converted
+        let (messages, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for message in incoming.iter() {
+                let message = message.map(|m| Message {
+                    topic: m.topic().to_owned(),
+                    payload: m.payload().to_vec(),
+                    qos: m.qos(),
+                    retained: m.retained(),
+                    received: None,
+                    properties: Default::default(),
+                });
+                if messages.send(message).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(receiver)
+    }
+
+    fn disconnect(&self) -> Result<()> {
+        Ok(self.session.disconnect(None)?)
+    }
+}
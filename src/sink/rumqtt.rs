@@ -0,0 +1,200 @@
+//! [`MqttBackend`] over the pure Rust rumqttc library
+//!
+//! rumqttc queues requests for an event loop that has to be driven
+//! continually, so a background thread does that, passing received messages
+//! to subscribers. Where paho would fail a publish once the connection is
+//! lost, the event loop records the error for the next publish to report.
+
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use anyhow::Result;
+use clap::crate_name;
+use rumqttc::{Client, Connection, Event, LastWill, MqttOptions, Outgoing, Packet, QoS};
+
+use super::{Message, MqttBackend};
+use crate::config::{MqttConfig, Transport};
+
+const DEFAULT_PORT: u16 = 1883;
+// Requests queued for the event loop before publishing blocks
+pub(super) const REQUEST_CAPACITY: usize = 64;
+
+/// State shared between a connection and the thread driving its event loop
+#[derive(Default)]
+pub(super) struct Shared {
+    /// Why the event loop stopped, once it has
+    error: Mutex<Option<String>>,
+    subscribers: Mutex<Vec<Sender<Option<Message>>>>,
+}
+
+impl Shared {
+    /// Passes a received message to the subscribers
+    pub(super) fn received(&self, message: Message) {
+        let mut subscribers = self.subscribers.lock().expect("subscribers poisoned");
+        subscribers.retain(|s| s.send(Some(message.clone())).is_ok());
+    }
+
+    /// Records why the event loop stopped, signalling the subscribers
+    pub(super) fn stopped(&self, error: String) {
+        log::debug!("mqtt event loop stopped: {}", error);
+        *self.error.lock().expect("event loop error poisoned") = Some(error);
+        for subscriber in self
+            .subscribers
+            .lock()
+            .expect("subscribers poisoned")
+            .drain(..)
+        {
+            let _ = subscriber.send(None);
+        }
+    }
+
+    /// Fails if the event loop has stopped
+    pub(super) fn check(&self) -> Result<()> {
+        if let Some(ref error) = *self.error.lock().expect("event loop error poisoned") {
+            anyhow::bail!("{}", error);
+        }
+        Ok(())
+    }
+
+    /// Returns a receiver for the messages received from now on
+    pub(super) fn subscribe(&self) -> Receiver<Option<Message>> {
+        let (messages, receiver) = std::sync::mpsc::channel();
+        self.subscribers
+            .lock()
+            .expect("subscribers poisoned")
+            .push(messages);
+        receiver
+    }
+}
+
+/// The host, port and client id to connect to a broker with over tcp
+pub(super) fn address(mqtt: &MqttConfig) -> Result<(String, u16, String)> {
+    if mqtt.transport()? != Transport::Tcp {
+        anyhow::bail!(
+            "WebSocket brokers like {} need the paho mqtt backend",
+            mqtt.broker
+        );
+    }
+    let (host, port) = match mqtt.host_port().rsplit_once(':') {
+        Some((host, port)) => (host, port.parse()?),
+        None => (mqtt.host_port(), DEFAULT_PORT),
+    };
+    let client_id = mqtt
+        .client_id
+        .clone()
+        .unwrap_or_else(|| format!("{}-{}", crate_name!(), std::process::id()));
+    Ok((host.to_owned(), port, client_id))
+}
+
+pub struct RumqttcBackend {
+    client: Client,
+    shared: Arc<Shared>,
+    event_loop: Mutex<Option<JoinHandle<()>>>,
+}
+
+fn qos(qos: i32) -> QoS {
+    match qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    }
+}
+
+impl RumqttcBackend {
+    pub fn connect(mqtt: &MqttConfig, will: Option<Message>) -> Result<Self> {
+        let (host, port, client_id) = address(mqtt)?;
+        let mut options = MqttOptions::new(client_id, host, port);
+        options
+            .set_keep_alive(mqtt.get_keep_alive())
+            .set_clean_session(mqtt.get_clean_session());
+        if let Some(will) = will {
+            options.set_last_will(LastWill::new(
+                will.topic,
+                will.payload,
+                qos(will.qos),
+                will.retained,
+            ));
+        }
+        if let Some(cred) = &mqtt.credentials {
+            if let Some((u, p)) = cred.get(&mqtt.broker) {
+                options.set_credentials(u, p);
+            }
+        }
+        let (client, mut connection) = Client::new(options, REQUEST_CAPACITY);
+        // Wait for the broker to accept the connection, so that a broker that
+        // can't be reached fails startup just as it does with paho
+        loop {
+            match connection.iter().next() {
+                Some(Ok(Event::Incoming(Packet::ConnAck(_)))) => break,
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+                None => anyhow::bail!("Connection closed before the broker accepted it"),
+            }
+        }
+        let shared = Arc::new(Shared::default());
+        let event_loop = {
+            let shared = shared.clone();
+            std::thread::spawn(move || drive(connection, &shared))
+        };
+        Ok(RumqttcBackend {
+            client,
+            shared,
+            event_loop: Mutex::new(Some(event_loop)),
+        })
+    }
+}
+
+// Runs the event loop until the connection is lost or closed
+fn drive(mut connection: Connection, shared: &Shared) {
+    let error = loop {
+        match connection.iter().next() {
+            Some(Ok(Event::Incoming(Packet::Publish(publish)))) => {
+                let message = Message {
+                    topic: publish.topic,
+                    payload: publish.payload.to_vec(),
+                    qos: publish.qos as i32,
+                    retained: publish.retain,
+                    received: None,
+                    properties: Default::default(),
+                };
+                shared.received(message);
+            }
+            Some(Ok(Event::Outgoing(Outgoing::Disconnect))) => {
+                break "Disconnected from broker".to_owned();
+            }
+            Some(Ok(_)) => {}
+            Some(Err(e)) => break e.to_string(),
+            None => break "Connection closed".to_owned(),
+        }
+    };
+    shared.stopped(error);
+}
+
+impl MqttBackend for RumqttcBackend {
+    fn publish(&self, message: Message) -> Result<()> {
+        self.shared.check()?;
+        self.client.publish(
+            message.topic,
+            qos(message.qos),
+            message.retained,
+            message.payload,
+        )?;
+        Ok(())
+    }
+
+    fn subscribe(&self, topic: &str) -> Result<Receiver<Option<Message>>> {
+        let receiver = self.shared.subscribe();
+        self.client.subscribe(topic, QoS::AtLeastOnce)?;
+        Ok(receiver)
+    }
+
+    fn disconnect(&self) -> Result<()> {
+        self.client.disconnect()?;
+        // Wait for the requests queued ahead of the disconnect to go out
+        if let Some(event_loop) = self.event_loop.lock().expect("event loop poisoned").take() {
+            let _ = event_loop.join();
+        }
+        Ok(())
+    }
+}
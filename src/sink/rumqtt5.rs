@@ -0,0 +1,139 @@
+//! [`MqttBackend`] over rumqttc's MQTT 5 client
+//!
+//! It works as the MQTT 3.1.1 [`RumqttcBackend`](super::rumqtt::RumqttcBackend)
+//! does, with a background thread driving the event loop, and publishes each
+//! message's [`MessageProperties`] along with it.
+
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use anyhow::Result;
+use rumqttc::v5::mqttbytes::v5::{LastWill, Packet, PublishProperties};
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::{Client, Connection, Event, MqttOptions};
+use rumqttc::Outgoing;
+
+use super::rumqtt::{address, Shared, REQUEST_CAPACITY};
+use super::{Message, MessageProperties, MqttBackend};
+use crate::config::MqttConfig;
+
+pub struct Rumqttc5Backend {
+    client: Client,
+    shared: Arc<Shared>,
+    event_loop: Mutex<Option<JoinHandle<()>>>,
+}
+
+fn qos(qos: i32) -> QoS {
+    match qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    }
+}
+
+fn properties(properties: MessageProperties) -> PublishProperties {
+    PublishProperties {
+        message_expiry_interval: properties.expiry,
+        content_type: properties.content_type,
+        user_properties: properties.user_properties,
+        ..Default::default()
+    }
+}
+
+impl Rumqttc5Backend {
+    pub fn connect(mqtt: &MqttConfig, will: Option<Message>) -> Result<Self> {
+        let (host, port, client_id) = address(mqtt)?;
+        let mut options = MqttOptions::new(client_id, host, port);
+        options
+            .set_keep_alive(mqtt.get_keep_alive())
+            .set_clean_start(mqtt.get_clean_session());
+        if let Some(will) = will {
+            options.set_last_will(LastWill::new(
+                will.topic,
+                will.payload,
+                qos(will.qos),
+                will.retained,
+                None,
+            ));
+        }
+        if let Some(cred) = &mqtt.credentials {
+            if let Some((u, p)) = cred.get(&mqtt.broker) {
+                options.set_credentials(u, p);
+            }
+        }
+        let (client, mut connection) = Client::new(options, REQUEST_CAPACITY);
+        loop {
+            match connection.iter().next() {
+                Some(Ok(Event::Incoming(Packet::ConnAck(_)))) => break,
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+                None => anyhow::bail!("Connection closed before the broker accepted it"),
+            }
+        }
+        let shared = Arc::new(Shared::default());
+        let event_loop = {
+            let shared = shared.clone();
+            std::thread::spawn(move || drive(connection, &shared))
+        };
+        Ok(Rumqttc5Backend {
+            client,
+            shared,
+            event_loop: Mutex::new(Some(event_loop)),
+        })
+    }
+}
+
+// Runs the event loop until the connection is lost or closed
+fn drive(mut connection: Connection, shared: &Shared) {
+    let error = loop {
+        match connection.iter().next() {
+            Some(Ok(Event::Incoming(Packet::Publish(publish)))) => {
+                shared.received(Message {
+                    topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+                    payload: publish.payload.to_vec(),
+                    qos: publish.qos as i32,
+                    retained: publish.retain,
+                    received: None,
+                    properties: Default::default(),
+                });
+            }
+            Some(Ok(Event::Outgoing(Outgoing::Disconnect))) => {
+                break "Disconnected from broker".to_owned();
+            }
+            Some(Ok(_)) => {}
+            Some(Err(e)) => break e.to_string(),
+            None => break "Connection closed".to_owned(),
+        }
+    };
+    shared.stopped(error);
+}
+
+impl MqttBackend for Rumqttc5Backend {
+    fn publish(&self, message: Message) -> Result<()> {
+        self.shared.check()?;
+        self.client.publish_with_properties(
+            message.topic,
+            qos(message.qos),
+            message.retained,
+            message.payload,
+            properties(message.properties),
+        )?;
+        Ok(())
+    }
+
+    fn subscribe(&self, topic: &str) -> Result<Receiver<Option<Message>>> {
+        let receiver = self.shared.subscribe();
+        self.client.subscribe(topic, QoS::AtLeastOnce)?;
+        Ok(receiver)
+    }
+
+    fn disconnect(&self) -> Result<()> {
+        self.client.disconnect()?;
+        // Wait for the requests queued ahead of the disconnect to go out
+        if let Some(event_loop) = self.event_loop.lock().expect("event loop poisoned").take() {
+            let _ = event_loop.join();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,306 @@
+//! The stages records go through on their way from the radio to the sinks
+//!
+//! Without a `pipeline` section in the configuration, records go through
+//! every stage, in the order the bridge has always used. With one, only the
+//! stages it lists are run, in the order it lists them, within the order of
+//! their kinds: filters, calibrators, derivers, aggregators, and then sinks.
+//! Stages whose options are left out take them from the settings of the same
+//! name outside the section.
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, ConfigError};
+
+/// What a stage does to records, in the order stages of each kind are run
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StageKind {
+    Filter,
+    Calibrator,
+    Deriver,
+    Aggregator,
+    Sink,
+}
+
+impl std::fmt::Display for StageKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            StageKind::Filter => "filter",
+            StageKind::Calibrator => "calibrator",
+            StageKind::Deriver => "deriver",
+            StageKind::Aggregator => "aggregator",
+            StageKind::Sink => "sink",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A stage of the pipeline, with its options
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "stage", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Stage {
+    /// Drops records of ignored sensors, and of those not allowed
+    SensorFilter,
+    /// Merges the packets some devices split a message across, by the
+    /// `bursts` rules
+    Burst,
+    /// Drops records sampled out by the `sampling` rules
+    Sample,
+    /// Corrects the quirks of particular models
+    Quirks,
+    /// Drops records beyond the size and cardinality `limits`
+    Guardrails,
+    /// Drops repeated transmissions of a record
+    Dedup,
+    /// Labels probe measurements with the names in `probes`
+    ProbeNames,
+    /// Adds the `calibration` offsets to measurements
+    Calibrate,
+    /// Derives the day's rainfall
+    Rain {
+        /// Local time of day rain days start at, in place of
+        /// `rain_day_start`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        day_start: Option<String>,
+    },
+    /// Derives pressure corrected to sea level
+    SeaLevel {
+        /// Altitude of the station in meters, in place of
+        /// `station_altitude`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        altitude: Option<f32>,
+    },
+    /// Derives the days until batteries run out, with the `battery` settings
+    Battery,
+    /// Keeps the rolling statistics of measurements
+    Stats {
+        /// Windows the statistics are kept over, in place of
+        /// `stats_windows`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        windows: Option<Vec<String>>,
+    },
+    /// Hands records to the subscribers of the event bus: history, uploads,
+    /// webhooks, the API and the like
+    Events,
+    /// Publishes records to the mqtt broker
+    Mqtt,
+}
+
+impl Stage {
+    /// The stage's name, as given in the configuration
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::SensorFilter => "sensor-filter",
+            Stage::Burst => "burst",
+            Stage::Sample => "sample",
+            Stage::Quirks => "quirks",
+            Stage::Guardrails => "guardrails",
+            Stage::Dedup => "dedup",
+            Stage::ProbeNames => "probe-names",
+            Stage::Calibrate => "calibrate",
+            Stage::Rain { .. } => "rain",
+            Stage::SeaLevel { .. } => "sea-level",
+            Stage::Battery => "battery",
+            Stage::Stats { .. } => "stats",
+            Stage::Events => "events",
+            Stage::Mqtt => "mqtt",
+        }
+    }
+
+    pub fn kind(&self) -> StageKind {
+        match self {
+            Stage::SensorFilter
+            | Stage::Burst
+            | Stage::Sample
+            | Stage::Quirks
+            | Stage::Guardrails
+            | Stage::Dedup => StageKind::Filter,
+            Stage::ProbeNames | Stage::Calibrate => StageKind::Calibrator,
+            Stage::Rain { .. } | Stage::SeaLevel { .. } | Stage::Battery => StageKind::Deriver,
+            Stage::Stats { .. } => StageKind::Aggregator,
+            Stage::Events | Stage::Mqtt => StageKind::Sink,
+        }
+    }
+
+    /// Local time of day the rain stage's days start at
+    pub fn rain_day_start(&self, conf: &Config) -> anyhow::Result<chrono::NaiveTime> {
+        match self {
+            Stage::Rain {
+                day_start: Some(start),
+            } => chrono::NaiveTime::parse_from_str(start, "%H:%M")
+                .map_err(|_| ConfigError::RainDayStart(start.to_owned()).into()),
+            _ => conf.get_rain_day_start(),
+        }
+    }
+
+    /// Altitude the sea level stage corrects pressure from
+    pub fn altitude(&self, conf: &Config) -> Option<f32> {
+        match self {
+            Stage::SeaLevel {
+                altitude: Some(altitude),
+            } => Some(*altitude),
+            _ => conf.station_altitude,
+        }
+    }
+
+    /// Windows the statistics stage keeps statistics over
+    pub fn stats_windows(&self, conf: &Config) -> anyhow::Result<Vec<crate::stats::StatsWindow>> {
+        match self {
+            Stage::Stats {
+                windows: Some(windows),
+            } => Ok(windows
+                .iter()
+                .map(|window| window.parse())
+                .collect::<Result<_, _>>()?),
+            _ => conf.get_stats_windows(),
+        }
+    }
+
+    /// What the stage does with the settings of `conf`, for the pipeline's
+    /// graph
+    fn describe(&self, conf: &Config) -> String {
+        let count = |n: usize, what: &str| match n {
+            1 => format!("1 {}", what),
+            n => format!("{} {}s", n, what),
+        };
+        match self {
+            Stage::SensorFilter => format!(
+                "{} ignored, {} allowed",
+                count(conf.sensor_ignores.len(), "pattern"),
+                match conf.sensor_allows.len() {
+                    0 => "all".to_owned(),
+                    n => count(n, "pattern"),
+                }
+            ),
+            Stage::Burst => count(conf.bursts.len(), "rule"),
+            Stage::Sample => count(conf.sampling.len(), "rule"),
+            Stage::Quirks => count(conf.quirks.len(), "quirk"),
+            Stage::Guardrails => "size and cardinality limits".to_owned(),
+            Stage::Dedup => format!("within {:?}", conf.get_dedup_window()),
+            Stage::ProbeNames => count(conf.probes.len(), "device"),
+            Stage::Calibrate => count(conf.calibration.len(), "sensor"),
+            Stage::Rain { .. } => match self.rain_day_start(conf) {
+                Ok(start) => format!("days starting {}", start.format("%H:%M")),
+                Err(e) => e.to_string(),
+            },
+            Stage::SeaLevel { .. } => match self.altitude(conf) {
+                Some(altitude) => format!("from {} m", altitude),
+                None => "no altitude".to_owned(),
+            },
+            Stage::Battery => {
+                let battery = conf.battery.clone().unwrap_or_default();
+                format!("warning {} days ahead", battery.warn_days)
+            }
+            Stage::Stats { windows } => {
+                let windows = windows.as_ref().unwrap_or(&conf.stats_windows);
+                match windows.is_empty() {
+                    true => "no windows".to_owned(),
+                    false => windows.join(", "),
+                }
+            }
+            Stage::Events => {
+                let subscribers = [
+                    ("history", conf.history.is_some()),
+                    (
+                        "uploads",
+                        !conf.uploads.is_empty() || !conf.ecowitt.is_empty(),
+                    ),
+                    ("cwop", conf.cwop.is_some()),
+                    ("webhooks", !conf.webhooks.is_empty()),
+                    ("gateway", conf.gateway.is_some()),
+                    ("api", conf.api_listen.is_some()),
+                    ("arrow", conf.arrow_listen.is_some()),
+                ];
+                let names: Vec<&str> = subscribers
+                    .iter()
+                    .filter(|(_, on)| *on)
+                    .map(|(name, _)| *name)
+                    .collect();
+                match names.is_empty() {
+                    true => "no subscribers".to_owned(),
+                    false => names.join(", "),
+                }
+            }
+            Stage::Mqtt => match conf.mqtt.is_empty() {
+                true => "no broker".to_owned(),
+                false => {
+                    let brokers: Vec<&str> =
+                        conf.mqtt.iter().map(|mqtt| mqtt.broker.as_str()).collect();
+                    brokers.join(", ")
+                }
+            },
+        }
+    }
+}
+
+/// The stages of the pipeline when the configuration doesn't list them:
+/// every one, with those deriving from settings that aren't set left out
+pub fn default_stages(conf: &Config) -> Vec<Stage> {
+    let mut stages = vec![Stage::SensorFilter];
+    if !conf.bursts.is_empty() {
+        stages.push(Stage::Burst);
+    }
+    stages.extend([
+        Stage::Sample,
+        Stage::Quirks,
+        Stage::Guardrails,
+        Stage::Dedup,
+        Stage::ProbeNames,
+        Stage::Calibrate,
+        Stage::Rain { day_start: None },
+    ]);
+    if conf.station_altitude.is_some() {
+        stages.push(Stage::SeaLevel { altitude: None });
+    }
+    if conf.battery.is_some() {
+        stages.push(Stage::Battery);
+    }
+    stages.extend([Stage::Stats { windows: None }, Stage::Events, Stage::Mqtt]);
+    stages
+}
+
+/// Checks that stages are each given once, in the order of their kinds,
+/// with options that can be used
+pub fn validate(stages: &[Stage], conf: &Config) -> anyhow::Result<()> {
+    for (i, stage) in stages.iter().enumerate() {
+        if stages[..i].iter().any(|s| s.name() == stage.name()) {
+            return Err(ConfigError::PipelineDuplicate(stage.name().to_owned()).into());
+        }
+        if let Some(previous) = stages[..i].iter().find(|s| s.kind() > stage.kind()) {
+            return Err(ConfigError::PipelineOrder(
+                stage.name().to_owned(),
+                previous.name().to_owned(),
+            )
+            .into());
+        }
+        match stage {
+            Stage::Rain { .. } => {
+                stage.rain_day_start(conf)?;
+            }
+            Stage::SeaLevel { .. } if stage.altitude(conf).is_none() => {
+                return Err(ConfigError::PipelineAltitude.into())
+            }
+            Stage::Stats { .. } => {
+                stage.stats_windows(conf)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The stages records go through, one per line from the radio down, with
+/// what each does with the settings of `conf`
+pub fn graph(stages: &[Stage], conf: &Config) -> String {
+    let width = stages.iter().map(|s| s.name().len()).max().unwrap_or(0);
+    let mut graph = String::from("radio\n");
+    for stage in stages {
+        graph.push_str(&format!(
+            "  -> {:10} {:width$}  {}\n",
+            stage.kind().to_string(),
+            stage.name(),
+            stage.describe(conf),
+            width = width
+        ));
+    }
+    graph
+}
@@ -0,0 +1,170 @@
+//! Rolling statistics of each sensor's measurements, e.g. the highest gust
+//! in the last hour or the day's temperature extremes, without needing a
+//! database
+//!
+//! The minimum, maximum and mean of each numeric measurement over each
+//! configured window are published to `<sensor topic>/stats/<window>` with
+//! every record, in the metric units of normalized payloads.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::config::ConfigError;
+use crate::radio::Record;
+use crate::units::UnitSystem;
+
+/// Topic level statistics are published under, below a sensor's topic
+pub const STATS_SUFFIX: &str = "stats";
+
+/// A window statistics are computed over, named as configured, e.g. `5m`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatsWindow {
+    name: String,
+    duration: chrono::Duration,
+}
+
+impl std::str::FromStr for StatsWindow {
+    type Err = ConfigError;
+
+    /// Parses a number of seconds, minutes, hours or days, e.g. `90s`, `5m`,
+    /// `1h` or `1d`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ConfigError::StatsWindow(s.to_owned());
+        let split = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+        let count: i64 = s[..split].parse().map_err(|_| invalid())?;
+        let duration = match &s[split..] {
+            "s" => chrono::Duration::seconds(count),
+            "m" => chrono::Duration::minutes(count),
+            "h" => chrono::Duration::hours(count),
+            "d" => chrono::Duration::days(count),
+            _ => return Err(invalid()),
+        };
+        if count <= 0 {
+            return Err(invalid());
+        }
+        Ok(StatsWindow {
+            name: s.to_owned(),
+            duration,
+        })
+    }
+}
+
+impl StatsWindow {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Topic the statistics of a sensor over this window are published to
+    pub fn topic(&self, sensor_topic: &str) -> String {
+        format!("{}/{}/{}", sensor_topic, STATS_SUFFIX, self.name)
+    }
+}
+
+// A measurement's readings, oldest first
+type Readings = VecDeque<(DateTime<Local>, f64)>;
+
+/// Statistics of one measurement over a window
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Summary {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub count: usize,
+}
+
+/// Keeps each sensor's numeric measurements for as long as the longest
+/// window, to summarize them over each window
+#[derive(Debug, Default)]
+pub struct Aggregator {
+    windows: Vec<StatsWindow>,
+    longest: chrono::Duration,
+    // Readings by sensor and then normalized measurement name
+    readings: HashMap<String, BTreeMap<String, Readings>>,
+}
+
+impl Aggregator {
+    pub fn new(windows: Vec<StatsWindow>) -> Self {
+        let longest = windows
+            .iter()
+            .map(|w| w.duration)
+            .max()
+            .unwrap_or_else(chrono::Duration::zero);
+        Aggregator {
+            windows,
+            longest,
+            readings: HashMap::new(),
+        }
+    }
+
+    /// Adds a record's numeric measurements, dropping its sensor's readings
+    /// that have aged out of every window
+    pub fn observe(&mut self, record: &Record) {
+        if self.windows.is_empty() {
+            return;
+        }
+        let sensor = self.readings.entry(record.sensor_id.clone()).or_default();
+        for measurement in &record.measurements {
+            let key = match crate::normalize::key(measurement) {
+                Some(key) => key,
+                None => continue,
+            };
+            if let Some(value) = measurement.numeric_in(Some(UnitSystem::Metric)) {
+                sensor
+                    .entry(key)
+                    .or_default()
+                    .push_back((record.timestamp, value));
+            }
+        }
+        let oldest = record.timestamp - self.longest;
+        for readings in sensor.values_mut() {
+            while readings.front().map(|(t, _)| *t < oldest).unwrap_or(false) {
+                readings.pop_front();
+            }
+        }
+        sensor.retain(|_, readings| !readings.is_empty());
+    }
+
+    /// The statistics of each of a sensor's measurements over each window,
+    /// ending at `now`
+    pub fn summarize(
+        &self,
+        sensor_id: &str,
+        now: DateTime<Local>,
+    ) -> Vec<(&StatsWindow, BTreeMap<String, Summary>)> {
+        let sensor = match self.readings.get(sensor_id) {
+            Some(sensor) => sensor,
+            None => return Vec::new(),
+        };
+        self.windows
+            .iter()
+            .map(|window| {
+                let since = now - window.duration;
+                let summaries = sensor
+                    .iter()
+                    .filter_map(|(key, readings)| Some((key.clone(), summarize(readings, since)?)))
+                    .collect();
+                (window, summaries)
+            })
+            .collect()
+    }
+}
+
+fn summarize(readings: &Readings, since: DateTime<Local>) -> Option<Summary> {
+    let values: Vec<f64> = readings
+        .iter()
+        .filter(|(t, _)| *t >= since)
+        .map(|(_, value)| *value)
+        .collect();
+    if values.is_empty() {
+        return None;
+    }
+    let round = |n: f64| (n * 1000.0).round() / 1000.0;
+    Some(Summary {
+        min: round(values.iter().copied().fold(f64::INFINITY, f64::min)),
+        max: round(values.iter().copied().fold(f64::NEG_INFINITY, f64::max)),
+        mean: round(values.iter().sum::<f64>() / values.len() as f64),
+        count: values.len(),
+    })
+}
@@ -0,0 +1,70 @@
+//! Periodic summary log lines, for keeping the log readable at sites busy
+//! enough that a line per record would bury everything else
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::events::Event;
+
+#[derive(Debug, Default)]
+struct Summary {
+    // By sensor label
+    records: BTreeMap<String, u64>,
+    publish_failures: u64,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total: u64 = self.records.values().sum();
+        write!(f, "{} records from {} sensors", total, self.records.len())?;
+        if !self.records.is_empty() {
+            let counts: Vec<String> = self
+                .records
+                .iter()
+                .map(|(label, count)| format!("{}: {}", label, count))
+                .collect();
+            write!(f, " ({})", counts.join(", "))?;
+        }
+        write!(f, ", {} publish failures", self.publish_failures)
+    }
+}
+
+/// Starts a background thread that logs a summary of the records processed
+/// and publishes failed every `interval`, and for the time since the last
+/// summary once the radio stops. Sensors are referred to by their `labels`,
+/// or else their ids.
+pub fn spawn_logger(
+    events: Receiver<Event>,
+    interval: Duration,
+    labels: BTreeMap<String, String>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut summary = Summary::default();
+        let mut started = Instant::now();
+        loop {
+            let event = events.recv_timeout(interval.saturating_sub(started.elapsed()));
+            match event {
+                Ok(Event::Record(record)) => {
+                    let label = labels.get(&record.sensor_id).cloned();
+                    let label = label.unwrap_or(record.sensor_id);
+                    *summary.records.entry(label).or_default() += 1;
+                }
+                Ok(Event::SinkError { .. }) => summary.publish_failures += 1,
+                Ok(Event::RadioStopped) | Err(RecvTimeoutError::Disconnected) => {
+                    let elapsed = Duration::from_secs(started.elapsed().as_secs());
+                    log::info!("Last {:?}: {}", elapsed, summary);
+                    return;
+                }
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            }
+            if started.elapsed() >= interval {
+                log::info!("Last {:?}: {}", interval, summary);
+                summary = Summary::default();
+                started = Instant::now();
+            }
+        }
+    })
+}
@@ -0,0 +1,193 @@
+//! Limits on how often records are published, for sites busy enough that a
+//! record per transmission would flood the broker, e.g. neighborhoods full
+//! of utility meters
+//!
+//! Each sensor can be held to a minimum interval between the records
+//! published for it, and records can be batched into a single json array
+//! published every so often, rather than each to its sensor's topic.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use clap::crate_name;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{ConfigError, SensorPattern};
+use crate::radio::Record;
+use crate::sink::MqttSink;
+
+/// Topic batches of records are published to
+pub fn batch_topic() -> String {
+    format!("{}/batch", crate_name!())
+}
+
+/// How often records are published
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ThrottleConfig {
+    /// Seconds that must pass between the records published for a sensor
+    /// without an override; records in between are still recorded, but not
+    /// published
+    #[serde(default)]
+    pub min_interval: Option<u64>,
+    /// Per-sensor minimum intervals, by [`SensorPattern`]; sensor ids take
+    /// precedence over other patterns
+    #[serde(default)]
+    pub sensors: BTreeMap<String, u64>,
+    /// Seconds over which records are collected into a single batch
+    /// published to [`batch_topic`]; records are published individually
+    /// when unset
+    #[serde(default)]
+    pub batch_interval: Option<u64>,
+}
+
+impl ThrottleConfig {
+    pub fn get_batch_interval(&self) -> Option<Duration> {
+        self.batch_interval
+            .map(|secs| Duration::from_secs(secs.max(1)))
+    }
+}
+
+/// Holds each sensor to its minimum interval between published records
+#[derive(Debug, Default)]
+pub struct Throttle {
+    min_interval: Option<chrono::Duration>,
+    sensors: Vec<(SensorPattern, chrono::Duration)>,
+    floor: Option<chrono::Duration>,
+    last: HashMap<String, DateTime<Local>>,
+}
+
+impl Throttle {
+    pub fn new(conf: &ThrottleConfig) -> Result<Self, ConfigError> {
+        let interval = |secs: u64| chrono::Duration::seconds(secs as i64);
+        let mut sensors = conf
+            .sensors
+            .iter()
+            .map(|(pattern, secs)| Ok((pattern.parse()?, interval(*secs))))
+            .collect::<Result<Vec<(SensorPattern, chrono::Duration)>, ConfigError>>()?;
+        sensors.sort_by_key(|(pattern, _)| !matches!(pattern, SensorPattern::Exact(_)));
+        Ok(Throttle {
+            min_interval: conf.min_interval.map(interval),
+            sensors,
+            floor: None,
+            last: HashMap::new(),
+        })
+    }
+
+    /// Holds every sensor to at least `floor` between published records,
+    /// e.g. while saving power, or lifts that again if `None`
+    pub fn set_floor(&mut self, floor: Option<Duration>) {
+        self.floor = floor.and_then(|floor| chrono::Duration::from_std(floor).ok());
+    }
+
+    /// Whether a record may be published, going by its timestamp so that
+    /// replays are throttled as they were received. Admitted records start
+    /// their sensor's interval over.
+    pub fn admit(&mut self, record: &Record) -> bool {
+        let interval = self
+            .sensors
+            .iter()
+            .find(|(pattern, _)| pattern.matches(&record.sensor_id))
+            .map(|(_, interval)| *interval)
+            .or(self.min_interval)
+            .max(self.floor);
+        let interval = match interval {
+            Some(interval) => interval,
+            None => return true,
+        };
+        if let Some(last) = self.last.get(&record.sensor_id) {
+            if record.timestamp - *last < interval {
+                return false;
+            }
+        }
+        self.last.insert(record.sensor_id.clone(), record.timestamp);
+        true
+    }
+}
+
+/// A record waiting to be published in a batch
+#[derive(Debug, Serialize)]
+struct Batched {
+    topic: String,
+    payload: serde_json::Value,
+    #[serde(skip)]
+    received: DateTime<Local>,
+}
+
+/// Collects records into batches published by a background thread
+#[derive(Debug)]
+pub struct Batcher {
+    records: Sender<Batched>,
+    publisher: JoinHandle<()>,
+}
+
+impl Batcher {
+    /// Starts a background thread that publishes the records added to the
+    /// batcher as a json array every `interval`
+    pub fn spawn(sink: MqttSink, interval: Duration) -> Self {
+        let (records, received) = std::sync::mpsc::channel();
+        let publisher = std::thread::spawn(move || publish_batches(sink, received, interval));
+        Batcher { records, publisher }
+    }
+
+    /// Adds the payload of a record bound for `topic` to the next batch
+    pub fn add(&self, topic: &str, payload: serde_json::Value, received: DateTime<Local>) {
+        let _ = self.records.send(Batched {
+            topic: topic.to_owned(),
+            payload,
+            received,
+        });
+    }
+
+    /// Publishes the last batch, waiting for the publisher to finish
+    pub fn finish(self) {
+        drop(self.records);
+        if self.publisher.join().is_err() {
+            log::error!("Batch publisher panicked");
+        }
+    }
+}
+
+fn publish_batches(sink: MqttSink, records: Receiver<Batched>, interval: Duration) {
+    let topic = batch_topic();
+    let mut batch: Vec<Batched> = Vec::new();
+    let mut started = Instant::now();
+    loop {
+        let stopped = match records.recv_timeout(interval.saturating_sub(started.elapsed())) {
+            Ok(record) => {
+                batch.push(record);
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+        if (started.elapsed() >= interval || stopped) && !batch.is_empty() {
+            // The batch's latency is that of the record held back longest
+            let received = batch
+                .iter()
+                .map(|b| b.received)
+                .min()
+                .unwrap_or_else(crate::clock::now);
+            let published = serde_json::to_vec(&batch)
+                .map_err(anyhow::Error::from)
+                .and_then(|payload| sink.publish_record(&topic, payload, received, false));
+            match published {
+                Ok(()) => log::debug!("mqtt <== {}({} records)", topic, batch.len()),
+                Err(e) => log::error!(
+                    "Failed to publish batch of {} records: {:#}",
+                    batch.len(),
+                    e
+                ),
+            }
+            batch.clear();
+        }
+        if started.elapsed() >= interval {
+            started = Instant::now();
+        }
+        if stopped {
+            return;
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! A live view of a running bridge's sensors, for inspecting a headless
+//! receiver from another machine
+//!
+//! `weatherradio top --connect <host:port>` polls the [HTTP API](crate::api)
+//! of a bridge started with `--api`, and redraws the terminal every interval
+//! with the bridge's health and a line per sensor: when it was last heard
+//! from, its quality score and its latest readings. A bridge that can't be
+//! reached, e.g. while it restarts, is shown as such and polled again.
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+
+// Seconds to wait on the bridge before giving up on a poll
+const TIMEOUT_SECS: u64 = 5;
+
+// Clears the terminal and moves the cursor to its top left
+const CLEAR: &str = "\x1b[2J\x1b[H";
+
+// GETs `path` from the API at `address`, answering with its json body
+fn get(address: &str, path: &str) -> Result<serde_json::Value> {
+    let timeout = Duration::from_secs(TIMEOUT_SECS);
+    let socket = address
+        .to_socket_addrs()
+        .with_context(|| format!("Invalid address {}", address))?
+        .next()
+        .with_context(|| format!("No address found for {}", address))?;
+    let mut stream = TcpStream::connect_timeout(&socket, timeout)
+        .with_context(|| format!("Failed to connect to {}", address))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, address
+    )?;
+    // The server closes the connection once it's answered
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .with_context(|| format!("Malformed response to {}", path))?;
+    let status = head.split_whitespace().nth(1).unwrap_or_default();
+    let body =
+        serde_json::from_str(body).with_context(|| format!("Malformed response to {}", path))?;
+    match status {
+        // The health check answers 503 with the same body when unhealthy
+        "200" | "503" => Ok(body),
+        status => anyhow::bail!("{} answered {}", path, status),
+    }
+}
+
+// Percent-encodes what a sensor id may contain that can't go in a path as
+// is, leaving its `/`s, which the API takes as they are
+fn encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// How long ago `time` was, to the second, minute, hour or day
+fn ago(time: Option<DateTime<Local>>) -> String {
+    let seconds = match time {
+        Some(time) => (crate::clock::now() - time).num_seconds().max(0),
+        None => return "-".to_owned(),
+    };
+    match seconds {
+        0..=119 => format!("{}s", seconds),
+        120..=7199 => format!("{}m", seconds / 60),
+        7200..=172_799 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
+
+// A sensor's latest readings, as `name=value` pairs
+fn readings(latest: &serde_json::Value) -> String {
+    let measurements = match latest["measurements"].as_object() {
+        Some(measurements) => measurements,
+        None => return String::new(),
+    };
+    measurements
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Renders the view of the bridge at `address`
+pub fn render(address: &str) -> Result<String> {
+    let health = get(address, "/health")?;
+    let sensors = get(address, "/sensors")?;
+    let mut view = format!(
+        "{} {} at {}: {}, up {}s, {} sensors, {} radio faults\n\n",
+        clap::crate_name!(),
+        health["version"].as_str().unwrap_or("?"),
+        address,
+        health["status"].as_str().unwrap_or("?"),
+        health["uptime_secs"],
+        health["sensors"],
+        health["radio_faults"],
+    );
+    let sensors = match sensors.as_object() {
+        Some(sensors) => sensors,
+        None => return Ok(view),
+    };
+    let width = sensors.keys().map(String::len).max().unwrap_or(0).max(6);
+    view += &format!(
+        "{:width$}  {:>5}  {:>7}  READINGS\n",
+        "SENSOR", "SEEN", "QUALITY"
+    );
+    for (sensor_id, sensor) in sensors {
+        let last_seen = serde_json::from_value(sensor["last_seen"].clone()).ok();
+        let quality = match sensor["quality"].as_u64() {
+            Some(quality) => quality.to_string(),
+            None => "-".to_owned(),
+        };
+        // A sensor can be forgotten between the polls
+        let latest =
+            get(address, &format!("/sensors/{}/latest", encode(sensor_id))).unwrap_or_default();
+        view += &format!(
+            "{:width$}  {:>5}  {:>7}  {}\n",
+            sensor_id,
+            ago(last_seen),
+            quality,
+            readings(&latest)
+        );
+    }
+    Ok(view)
+}
+
+/// Redraws the view of the bridge at `address` every `interval` until
+/// interrupted
+pub fn run(address: &str, interval: Duration) -> Result<()> {
+    let mut stdout = std::io::stdout();
+    loop {
+        let view = match render(address) {
+            Ok(view) => view,
+            Err(e) => format!("{} unreachable: {:#}\n", address, e),
+        };
+        write!(stdout, "{}{}", CLEAR, view)?;
+        stdout.flush()?;
+        std::thread::sleep(interval);
+    }
+}
@@ -0,0 +1,266 @@
+//! Construction of the mqtt topics records are published to
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::radio::Record;
+
+#[derive(Error, Debug)]
+pub enum TopicError {
+    #[error("Topic template contains unknown placeholder '{{{0}}}'")]
+    UnknownPlaceholder(String),
+    #[error("Topic template contains an unterminated placeholder")]
+    UnterminatedPlaceholder,
+    #[error("The custom topic naming strategy requires a topic template")]
+    MissingTemplate,
+    #[error("A topic template is only used by the custom topic naming strategy, not {0:?}")]
+    UnusedTemplate(NamingStrategy),
+}
+
+/// The template used when none is configured, which publishes each sensor
+/// to its sensor id, grouped under its location if it has one
+pub const DEFAULT_TEMPLATE: &str = "{location}/{sensor_id}";
+
+const PLACEHOLDERS: &[&str] = &[
+    "sensor_id",
+    "model",
+    "id",
+    "channel",
+    "location",
+    "measurement",
+    "uuid",
+];
+
+/// How sensors are named in the topics they're published to, and so in
+/// everything derived from those topics, e.g. availability and lifecycle
+/// topics
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NamingStrategy {
+    /// By alias or sensor id, grouped under the sensor's location if it has
+    /// one
+    #[default]
+    Location,
+    /// By alias or sensor id alone
+    Alias,
+    /// By model and channel, which survive the id changes some sensors go
+    /// through on a battery swap; models without channels are named by id
+    ModelChannel,
+    /// By a UUID derived from the sensor id, which is stable but doesn't
+    /// reveal what the sensor is
+    Uuid,
+    /// By the configured topic template
+    Custom,
+}
+
+impl NamingStrategy {
+    /// The template the strategy names sensors with, or `None` for a custom
+    /// template
+    pub fn template(self) -> Option<&'static str> {
+        match self {
+            Self::Location => Some(DEFAULT_TEMPLATE),
+            Self::Alias => Some("{sensor_id}"),
+            Self::ModelChannel => Some("{model}/{channel|id}"),
+            Self::Uuid => Some("{uuid}"),
+            Self::Custom => None,
+        }
+    }
+}
+
+impl std::str::FromStr for NamingStrategy {
+    type Err = crate::config::ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "location" => Ok(Self::Location),
+            "alias" => Ok(Self::Alias),
+            "model-channel" => Ok(Self::ModelChannel),
+            "uuid" => Ok(Self::Uuid),
+            "custom" => Ok(Self::Custom),
+            _ => Err(crate::config::ConfigError::TopicNaming(s.to_owned())),
+        }
+    }
+}
+
+/// Stable UUID naming a sensor, derived from its id
+pub fn sensor_uuid(sensor_id: &str) -> uuid::Uuid {
+    let mut bytes = [0; 16];
+    let mut iso = crc_any::CRCu64::crc64iso();
+    iso.digest(sensor_id.as_bytes());
+    let mut we = crc_any::CRCu64::crc64we();
+    we.digest(sensor_id.as_bytes());
+    bytes[..8].copy_from_slice(&iso.get_crc().to_be_bytes());
+    bytes[8..].copy_from_slice(&we.get_crc().to_be_bytes());
+    uuid::Builder::from_custom_bytes(bytes).into_uuid()
+}
+
+/// A topic layout with `{placeholder}` substitution, e.g.
+/// `weather/{model}/{id}/{channel}/{measurement}`. Placeholders that have no
+/// value for a record are dropped along with their topic level, and
+/// alternatives can be given for them, e.g. `{channel|id}` falls back to the
+/// id for sensors without a channel. Sensors with an alias have it
+/// substituted for `{sensor_id}`.
+#[derive(Clone, Debug)]
+pub struct TopicTemplate {
+    prefix: Option<String>,
+    template: String,
+    aliases: BTreeMap<String, String>,
+}
+
+impl Default for TopicTemplate {
+    fn default() -> Self {
+        TopicTemplate {
+            prefix: None,
+            template: DEFAULT_TEMPLATE.to_owned(),
+            aliases: BTreeMap::new(),
+        }
+    }
+}
+
+impl TopicTemplate {
+    /// Creates the template for a naming strategy, verifying that a custom
+    /// template only uses known placeholders
+    pub fn new(
+        prefix: Option<&str>,
+        naming: NamingStrategy,
+        template: Option<&str>,
+    ) -> Result<Self, TopicError> {
+        let template = match (naming.template(), template) {
+            (Some(_), Some(_)) => return Err(TopicError::UnusedTemplate(naming)),
+            (Some(template), None) | (None, Some(template)) => template.to_owned(),
+            (None, None) => return Err(TopicError::MissingTemplate),
+        };
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or(TopicError::UnterminatedPlaceholder)?;
+            for name in rest[start + 1..start + end].split('|') {
+                if !PLACEHOLDERS.contains(&name) {
+                    return Err(TopicError::UnknownPlaceholder(name.to_owned()));
+                }
+            }
+            rest = &rest[start + end + 1..];
+        }
+        Ok(TopicTemplate {
+            prefix: prefix
+                .map(|p| p.trim_matches('/').to_owned())
+                .filter(|p| !p.is_empty()),
+            template,
+            aliases: BTreeMap::new(),
+        })
+    }
+
+    /// Publishes the sensors with the given ids under their aliases
+    pub fn aliases(mut self, aliases: BTreeMap<String, String>) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    /// Publishes a sensor under an alias, or under its id again if `None`
+    pub fn set_alias(&mut self, sensor_id: &str, alias: Option<&str>) {
+        match alias {
+            Some(alias) => self.aliases.insert(sensor_id.to_owned(), alias.to_owned()),
+            None => self.aliases.remove(sensor_id),
+        };
+    }
+
+    /// The topic levels every rendered topic starts with, which are those of
+    /// the prefix and any of the template before its first placeholder
+    pub fn root(&self) -> String {
+        let fixed = self
+            .template
+            .split('/')
+            .take_while(|level| !level.contains('{'));
+        let levels: Vec<&str> = self
+            .prefix
+            .iter()
+            .map(|p| p.as_str())
+            .chain(fixed)
+            .filter(|level| !level.is_empty())
+            .collect();
+        levels.join("/")
+    }
+
+    /// Renders the topic for a record, or for one of its measurements
+    pub fn render(
+        &self,
+        record: &Record,
+        location: Option<&str>,
+        measurement: Option<&str>,
+    ) -> String {
+        let mut topic = String::new();
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = start + rest[start..].find('}').expect("template was verified");
+            topic.push_str(&rest[..start]);
+            let value = rest[start + 1..end]
+                .split('|')
+                .find_map(|name| self.value(name, record, location, measurement));
+            topic.push_str(&value.unwrap_or_default());
+            rest = &rest[end + 1..];
+        }
+        topic.push_str(rest);
+        if let Some(measurement) = measurement {
+            if !self.template.contains("{measurement") && !self.template.contains("|measurement") {
+                topic = format!("{}/{}", topic, sanitize(measurement));
+            }
+        }
+        let levels: Vec<&str> = self
+            .prefix
+            .iter()
+            .map(|p| p.as_str())
+            .chain(topic.split('/'))
+            .filter(|level| !level.is_empty())
+            .collect();
+        levels.join("/")
+    }
+
+    // The value of a placeholder for a record, if it has one
+    fn value(
+        &self,
+        name: &str,
+        record: &Record,
+        location: Option<&str>,
+        measurement: Option<&str>,
+    ) -> Option<String> {
+        let value = match name {
+            // The sensor id and location are already topic hierarchies
+            "sensor_id" => Some(sanitize_levels(
+                self.aliases
+                    .get(&record.sensor_id)
+                    .unwrap_or(&record.sensor_id),
+            )),
+            "location" => location.map(sanitize_levels),
+            "measurement" => measurement.map(sanitize),
+            "uuid" => Some(sensor_uuid(&record.sensor_id).to_string()),
+            field => match record.record_json.get(field) {
+                Some(serde_json::Value::String(s)) => Some(sanitize(s)),
+                Some(serde_json::Value::Number(n)) => Some(n.to_string()),
+                _ => None,
+            },
+        };
+        value.filter(|value| !value.is_empty())
+    }
+}
+
+/// Replaces characters that aren't permitted within a single topic level
+pub fn sanitize(value: &str) -> String {
+    sanitize_levels(value).replace('/', "_")
+}
+
+/// Replaces characters that aren't permitted in a topic, preserving `/`
+/// level separators
+pub fn sanitize_levels(value: &str) -> String {
+    value
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '+' | '#' | '\0' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
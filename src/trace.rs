@@ -0,0 +1,66 @@
+//! Following the records of chosen sensors through the bridge, without
+//! raising the log level for every other sensor's
+//!
+//! A traced record is logged as it's received, when a stage drops it, with
+//! its measurements once they're derived, and with what's published of it.
+//! The lines are logged at info level under the [`TARGET`] target, which is
+//! let through whatever the log level, and prefixed with the sensor id.
+//! Sensors are traced by id, or by the decoder reporting them, each matched
+//! as a [`SensorPattern`](crate::config::SensorPattern).
+//!
+//! What's traced is set by the `trace` setting, and can be changed while the
+//! bridge runs: over the `trace` control topic, whose payload is the
+//! patterns separated by commas, or empty to stop tracing, or by editing the
+//! configuration file and sending the bridge SIGUSR1, which takes the
+//! `trace` setting from it again.
+
+use std::sync::RwLock;
+
+use crate::config::{ConfigError, SensorPattern};
+use crate::radio::Record;
+
+/// Log target of the lines about traced records
+pub const TARGET: &str = "weatherradio::trace";
+
+// The patterns of the sensors and decoders traced
+static TRACED: RwLock<Vec<SensorPattern>> = RwLock::new(Vec::new());
+
+/// Traces the sensors, and those of the decoders, matching `patterns`, and
+/// no others
+pub fn set<S: AsRef<str>>(patterns: &[S]) -> Result<(), ConfigError> {
+    let compiled = patterns
+        .iter()
+        .map(|pattern| pattern.as_ref().parse())
+        .collect::<Result<Vec<SensorPattern>, _>>()?;
+    let mut traced = TRACED.write().expect("trace patterns poisoned");
+    if compiled.is_empty() && !traced.is_empty() {
+        log::info!("No longer tracing any sensors");
+    } else if !compiled.is_empty() {
+        let patterns: Vec<&str> = patterns.iter().map(AsRef::as_ref).collect();
+        log::info!("Tracing sensors matching {}", patterns.join(", "));
+    }
+    *traced = compiled;
+    Ok(())
+}
+
+/// Whether `record`'s sensor, or the decoder reporting it, is traced
+pub fn is_traced(record: &Record) -> bool {
+    let traced = TRACED.read().expect("trace patterns poisoned");
+    if traced.is_empty() {
+        return false;
+    }
+    let model = record.record_json.get("model").and_then(|m| m.as_str());
+    traced.iter().any(|pattern| {
+        pattern.matches(&record.sensor_id) || model.is_some_and(|model| pattern.matches(model))
+    })
+}
+
+/// Splits the payload of the `trace` control topic into its patterns
+pub fn parse_patterns(payload: &str) -> Vec<String> {
+    payload
+        .split(',')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
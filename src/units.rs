@@ -0,0 +1,157 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use uom::si::f32::{Energy, Length, Pressure, ThermodynamicTemperature, Velocity, Volume};
+use uom::si::thermodynamic_temperature::{degree_celsius, degree_fahrenheit, kelvin};
+use uom::si::{energy, length, pressure, velocity, volume};
+
+#[derive(Error, Debug)]
+pub enum UnitError {
+    #[error("Unknown unit system '{0}', expected metric, imperial, or si")]
+    UnknownSystem(String),
+    #[error("Value '{0}' is not a number followed by a unit, e.g. '74.3F'")]
+    ValueFormat(String),
+    #[error("Unknown unit '{0}'")]
+    UnknownUnit(String),
+    #[error("Cannot convert a {0} to {1}")]
+    Incompatible(String, String),
+}
+
+/// Decimal places used when presenting converted values, matching what gets
+/// published for measurements
+pub const PRECISION: usize = 1;
+
+/// System of units published values are presented in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnitSystem {
+    /// °C, mm, km/h, hPa, m³, kWh
+    Metric,
+    /// °F, in, mph, inHg, ft³, kWh
+    Imperial,
+    /// K, m/s, Pa, m³, J; rainfall stays in mm, as is meteorological
+    /// practice, since meters would round away everything but floods
+    Si,
+}
+
+impl std::str::FromStr for UnitSystem {
+    type Err = UnitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match normalize(s).as_str() {
+            "metric" => Ok(Self::Metric),
+            "imperial" => Ok(Self::Imperial),
+            "si" => Ok(Self::Si),
+            _ => Err(UnitError::UnknownSystem(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Quantity {
+    Temperature(ThermodynamicTemperature),
+    Length(Length),
+    Velocity(Velocity),
+    Energy(Energy),
+    Volume(Volume),
+    Pressure(Pressure),
+}
+
+impl Quantity {
+    fn new(value: f32, unit: &str) -> Result<Self> {
+        let quantity = match normalize(unit).as_str() {
+            "f" | "°f" | "degf" => {
+                Self::Temperature(ThermodynamicTemperature::new::<degree_fahrenheit>(value))
+            }
+            "c" | "°c" | "degc" => {
+                Self::Temperature(ThermodynamicTemperature::new::<degree_celsius>(value))
+            }
+            "k" => Self::Temperature(ThermodynamicTemperature::new::<kelvin>(value)),
+            "mm" => Self::Length(Length::new::<length::millimeter>(value)),
+            "cm" => Self::Length(Length::new::<length::centimeter>(value)),
+            "m" => Self::Length(Length::new::<length::meter>(value)),
+            "in" => Self::Length(Length::new::<length::inch>(value)),
+            "ft" => Self::Length(Length::new::<length::foot>(value)),
+            "km/h" | "kph" => Self::Velocity(Velocity::new::<velocity::kilometer_per_hour>(value)),
+            "mi/h" | "mph" => Self::Velocity(Velocity::new::<velocity::mile_per_hour>(value)),
+            "m/s" => Self::Velocity(Velocity::new::<velocity::meter_per_second>(value)),
+            "kn" | "kt" => Self::Velocity(Velocity::new::<velocity::knot>(value)),
+            "wh" => Self::Energy(Energy::new::<energy::watt_hour>(value)),
+            "kwh" => Self::Energy(Energy::new::<energy::kilowatt_hour>(value)),
+            "m3" | "m³" => Self::Volume(Volume::new::<volume::cubic_meter>(value)),
+            "l" => Self::Volume(Volume::new::<volume::liter>(value)),
+            "gal" => Self::Volume(Volume::new::<volume::gallon>(value)),
+            "ft3" | "ft³" => Self::Volume(Volume::new::<volume::cubic_foot>(value)),
+            "hpa" | "mbar" => Self::Pressure(Pressure::new::<pressure::hectopascal>(value)),
+            "kpa" => Self::Pressure(Pressure::new::<pressure::kilopascal>(value)),
+            "inhg" => Self::Pressure(Pressure::new::<pressure::inch_of_mercury>(value)),
+            _ => return Err(UnitError::UnknownUnit(unit.to_owned()).into()),
+        };
+        Ok(quantity)
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Temperature(_) => "temperature",
+            Self::Length(_) => "length",
+            Self::Velocity(_) => "velocity",
+            Self::Energy(_) => "energy",
+            Self::Volume(_) => "volume",
+            Self::Pressure(_) => "pressure",
+        }
+    }
+
+    fn get(&self, unit: &str) -> Result<f32> {
+        let value = match (self, normalize(unit).as_str()) {
+            (Self::Temperature(t), "f" | "°f" | "degf") => t.get::<degree_fahrenheit>(),
+            (Self::Temperature(t), "c" | "°c" | "degc") => t.get::<degree_celsius>(),
+            (Self::Temperature(t), "k") => t.get::<kelvin>(),
+            (Self::Length(l), "mm") => l.get::<length::millimeter>(),
+            (Self::Length(l), "cm") => l.get::<length::centimeter>(),
+            (Self::Length(l), "m") => l.get::<length::meter>(),
+            (Self::Length(l), "in") => l.get::<length::inch>(),
+            (Self::Length(l), "ft") => l.get::<length::foot>(),
+            (Self::Velocity(v), "km/h" | "kph") => v.get::<velocity::kilometer_per_hour>(),
+            (Self::Velocity(v), "mi/h" | "mph") => v.get::<velocity::mile_per_hour>(),
+            (Self::Velocity(v), "m/s") => v.get::<velocity::meter_per_second>(),
+            (Self::Velocity(v), "kn" | "kt") => v.get::<velocity::knot>(),
+            (Self::Energy(e), "wh") => e.get::<energy::watt_hour>(),
+            (Self::Energy(e), "kwh") => e.get::<energy::kilowatt_hour>(),
+            (Self::Volume(v), "m3" | "m³") => v.get::<volume::cubic_meter>(),
+            (Self::Volume(v), "l") => v.get::<volume::liter>(),
+            (Self::Volume(v), "gal") => v.get::<volume::gallon>(),
+            (Self::Volume(v), "ft3" | "ft³") => v.get::<volume::cubic_foot>(),
+            (Self::Pressure(p), "hpa" | "mbar") => p.get::<pressure::hectopascal>(),
+            (Self::Pressure(p), "kpa") => p.get::<pressure::kilopascal>(),
+            (Self::Pressure(p), "inhg") => p.get::<pressure::inch_of_mercury>(),
+            (q, _) => {
+                // Distinguish unknown units from known but incompatible ones
+                Quantity::new(0.0, unit)?;
+                return Err(UnitError::Incompatible(q.kind().to_owned(), unit.to_owned()).into());
+            }
+        };
+        Ok(value)
+    }
+}
+
+fn normalize(unit: &str) -> String {
+    unit.trim().to_lowercase()
+}
+
+/// Converts a value with a unit suffix (e.g. `74.3F`) to the target unit,
+/// formatted with the crate's standard precision
+pub fn convert(value: &str, target: &str) -> Result<String> {
+    let value = value.trim();
+    let split = value
+        .char_indices()
+        .find(|(i, c)| !(c.is_ascii_digit() || *c == '.' || (*i == 0 && (*c == '-' || *c == '+'))))
+        .map(|(i, _)| i)
+        .ok_or_else(|| UnitError::ValueFormat(value.to_owned()))?;
+    let (number, unit) = value.split_at(split);
+    let number: f32 = number
+        .parse()
+        .map_err(|_| UnitError::ValueFormat(value.to_owned()))?;
+    let converted = Quantity::new(number, unit)?.get(target)?;
+    Ok(format!("{:.*}", PRECISION, converted))
+}
@@ -0,0 +1,142 @@
+//! Records rtl_433 reported that none of the parsers make sense of, e.g.
+//! from devices the bridge doesn't support yet
+//!
+//! Rather than being dropped without a trace, they're counted by model, and
+//! summarized in a log line every `summary_interval` that any were heard.
+//! With `publish`, they're also published as rtl_433 reported them to
+//! `weatherradio/unparsed`, or the configured `topic`, at most one of each
+//! model a minute, so that a neighbour's tire pressure sensors can't flood
+//! the broker.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use clap::crate_name;
+use serde::{Deserialize, Serialize};
+
+use crate::events::Event;
+use crate::sink::MqttSink;
+
+// How long after publishing a model's record before the next is published
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(60);
+
+// Model unparsed records without one are counted under
+const NO_MODEL: &str = "(no model)";
+
+fn default_summary_interval() -> u64 {
+    300
+}
+
+/// Default topic unparsed records are published to
+pub fn unparsed_topic() -> String {
+    format!("{}/unparsed", crate_name!())
+}
+
+/// What's done with the records none of the parsers make sense of
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UnparsedConfig {
+    /// Whether they're published
+    #[serde(default)]
+    pub publish: bool,
+    /// Topic they're published to, [`unparsed_topic`] when unset
+    #[serde(default)]
+    pub topic: Option<String>,
+    /// Seconds between the log lines summarizing them
+    #[serde(default = "default_summary_interval")]
+    pub summary_interval: u64,
+}
+
+impl Default for UnparsedConfig {
+    fn default() -> Self {
+        UnparsedConfig {
+            publish: false,
+            topic: None,
+            summary_interval: default_summary_interval(),
+        }
+    }
+}
+
+impl UnparsedConfig {
+    pub fn get_topic(&self) -> String {
+        self.topic.clone().unwrap_or_else(unparsed_topic)
+    }
+}
+
+// The model an unparsed record says it's from
+fn model(json: &serde_json::Value) -> &str {
+    json.get("model")
+        .and_then(|model| model.as_str())
+        .unwrap_or(NO_MODEL)
+}
+
+// The unparsed records counted since the last summary, by model
+#[derive(Debug, Default)]
+struct Counts(BTreeMap<String, u64>);
+
+impl std::fmt::Display for Counts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let total: u64 = self.0.values().sum();
+        let models: Vec<String> = self
+            .0
+            .iter()
+            .map(|(model, count)| format!("{} ({})", model, count))
+            .collect();
+        write!(f, "{} unparsed records: {}", total, models.join(", "))
+    }
+}
+
+/// Starts a background thread counting and summarizing the unparsed records
+/// on `events`, and publishing them to `sink` if configured, until the radio
+/// stops
+pub fn spawn_reporter(
+    conf: UnparsedConfig,
+    sink: Option<MqttSink>,
+    events: Receiver<Event>,
+) -> JoinHandle<()> {
+    let sink = sink.filter(|_| conf.publish);
+    let topic = conf.get_topic();
+    let interval = Duration::from_secs(conf.summary_interval.max(1));
+    std::thread::spawn(move || {
+        let mut counts = Counts::default();
+        let mut published: HashMap<String, Instant> = HashMap::new();
+        let mut started = Instant::now();
+        loop {
+            let event = events.recv_timeout(interval.saturating_sub(started.elapsed()));
+            match event {
+                Ok(Event::Unparsed(json)) => {
+                    let model = model(&json);
+                    log::debug!("Unparsed record: {}", json);
+                    *counts.0.entry(model.to_owned()).or_default() += 1;
+                    let due = !matches!(published.get(model), Some(at) if at.elapsed() < PUBLISH_INTERVAL);
+                    if let (Some(sink), true) = (&sink, due) {
+                        published.insert(model.to_owned(), Instant::now());
+                        let result = serde_json::to_vec(&json)
+                            .map_err(anyhow::Error::from)
+                            .and_then(|payload| sink.publish(&topic, payload));
+                        match result {
+                            Ok(()) => log::debug!("mqtt <== {}({})", topic, json),
+                            Err(e) => log::warn!("Failed to publish unparsed record: {:#}", e),
+                        }
+                    }
+                }
+                Ok(Event::RadioStopped) | Err(RecvTimeoutError::Disconnected) => {
+                    if !counts.0.is_empty() {
+                        let elapsed = Duration::from_secs(started.elapsed().as_secs());
+                        log::info!("Last {:?}: {}", elapsed, counts);
+                    }
+                    return;
+                }
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            }
+            if started.elapsed() >= interval {
+                if !counts.0.is_empty() {
+                    log::info!("Last {:?}: {}", interval, counts);
+                }
+                counts = Counts::default();
+                started = Instant::now();
+            }
+        }
+    })
+}
@@ -0,0 +1,300 @@
+//! Checking for newer releases, announcing the running version, and
+//! replacing a statically linked install with the latest release
+//!
+//! Releases are fetched with `curl`, over https only, from the GitHub
+//! releases API unless another url serving the same json is configured. A
+//! downloaded binary only replaces the running one once its SHA-256 matches
+//! the checksum published with the release, either as a `.sha256` asset of
+//! its own or a line of a checksums file like `SHA256SUMS`.
+
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use clap::{crate_name, crate_version};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::sink::MqttSink;
+
+/// Where the latest release is looked up, unless configured otherwise
+pub const DEFAULT_RELEASES_URL: &str =
+    "https://api.github.com/repos/compenguy/weatherradio/releases/latest";
+
+// Seconds to wait on a download before giving up
+const FETCH_TIMEOUT_SECS: &str = "60";
+
+#[derive(Error, Debug)]
+pub enum UpdateError {
+    #[error("Failed to fetch {url}: {reason}")]
+    Fetch { url: String, reason: String },
+    #[error("Release version '{0}' isn't a version number")]
+    Version(String),
+    #[error("Release {version} has no binary for {platform}")]
+    NoAsset { version: String, platform: String },
+    #[error("Release {version} publishes no checksum for {asset}")]
+    NoChecksum { version: String, asset: String },
+    #[error("Download of {asset} doesn't match its published checksum, expected {expected} but got {actual}")]
+    ChecksumMismatch {
+        asset: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("Refusing to fetch '{0}' over anything but https")]
+    Insecure(String),
+    #[error("Self-update isn't supported on this platform")]
+    UnsupportedPlatform,
+}
+
+/// Whether and where to look for newer releases
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UpdateConfig {
+    /// Check for a newer release at startup
+    #[serde(default)]
+    pub check: bool,
+    /// Url of the latest release, in the json layout of the GitHub releases
+    /// API
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+impl UpdateConfig {
+    pub fn get_url(&self) -> &str {
+        self.url.as_deref().unwrap_or(DEFAULT_RELEASES_URL)
+    }
+
+    pub fn validate(&self) -> Result<(), crate::config::ConfigError> {
+        match self.url {
+            Some(ref url) if !url.starts_with("https://") => {
+                Err(crate::config::ConfigError::UpdateUrl(url.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A downloadable file attached to a release
+#[derive(Clone, Debug, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    pub browser_download_url: String,
+}
+
+/// A published release
+#[derive(Clone, Debug, Deserialize)]
+pub struct Release {
+    #[serde(rename = "tag_name")]
+    pub version: String,
+    #[serde(default)]
+    pub html_url: Option<String>,
+    #[serde(default)]
+    pub assets: Vec<Asset>,
+}
+
+impl Release {
+    /// Whether the release is newer than the running version
+    pub fn is_newer(&self) -> Result<bool, UpdateError> {
+        let mut release = parse_version(&self.version)
+            .ok_or_else(|| UpdateError::Version(self.version.clone()))?;
+        let mut running = parse_version(crate_version!())
+            .ok_or_else(|| UpdateError::Version(crate_version!().to_owned()))?;
+        // Missing components count as zero, so that 1.0 and 1.0.0 are equal
+        let len = release.len().max(running.len());
+        release.resize(len, 0);
+        running.resize(len, 0);
+        Ok(release > running)
+    }
+
+    /// The statically linked binary for the platform this is running on;
+    /// archives and checksums are skipped
+    pub fn asset_for_platform(&self) -> Option<&Asset> {
+        let os_names: &[&str] = match std::env::consts::OS {
+            "macos" => &["macos", "darwin", "apple"],
+            os => &[os],
+        };
+        self.assets.iter().find(|asset| {
+            let name = asset.name.to_lowercase();
+            let archive = [".tar", ".gz", ".zip", ".sha256", ".sig", ".asc"]
+                .iter()
+                .any(|ext| name.contains(ext));
+            !archive
+                && name.contains(std::env::consts::ARCH)
+                && os_names.iter().any(|os| name.contains(os))
+        })
+    }
+
+    /// The SHA-256 published for `asset`, from a `<asset>.sha256` asset, or
+    /// failing that a checksums file listing it
+    pub fn checksum_for(&self, asset: &Asset) -> Result<String> {
+        let no_checksum = || UpdateError::NoChecksum {
+            version: self.version.clone(),
+            asset: asset.name.clone(),
+        };
+        let own = format!("{}.sha256", asset.name).to_lowercase();
+        let sums = self
+            .assets
+            .iter()
+            .find(|a| a.name.to_lowercase() == own)
+            .or_else(|| {
+                self.assets.iter().find(|a| {
+                    let name = a.name.to_lowercase();
+                    name.contains("sha256sums") || name.contains("checksums")
+                })
+            })
+            .ok_or_else(no_checksum)?;
+        let sums = fetch(&sums.browser_download_url)?;
+        // Lines of `sha256sum` output, `<hex>  <name>`, or a bare hex digest
+        // in an asset's own checksum file
+        String::from_utf8_lossy(&sums)
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let digest = fields.next()?;
+                match fields.next().map(|name| name.trim_start_matches('*')) {
+                    Some(name) if name != asset.name => None,
+                    _ => Some(digest.to_lowercase()),
+                }
+            })
+            .find(|digest| digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()))
+            .ok_or_else(|| no_checksum().into())
+    }
+}
+
+// Parses a version like `v0.1.1` into its numeric components, ignoring any
+// pre-release suffix
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let version = version.trim().trim_start_matches('v');
+    let version = version.split(['-', '+']).next()?;
+    version.split('.').map(|part| part.parse().ok()).collect()
+}
+
+fn fetch(url: &str) -> Result<Vec<u8>, UpdateError> {
+    if !url.starts_with("https://") {
+        return Err(UpdateError::Insecure(url.to_owned()));
+    }
+    log::debug!("Fetching {}", url);
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location"])
+        // Redirects included
+        .args(["--proto", "=https", "--proto-redir", "=https"])
+        .args(["--max-time", FETCH_TIMEOUT_SECS])
+        .arg(url)
+        .output()
+        .map_err(|e| UpdateError::Fetch {
+            url: url.to_owned(),
+            reason: format!("unable to run curl ({})", e),
+        })?;
+    if !output.status.success() {
+        return Err(UpdateError::Fetch {
+            url: url.to_owned(),
+            reason: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        });
+    }
+    Ok(output.stdout)
+}
+
+/// Looks up the latest release
+pub fn latest_release(conf: &UpdateConfig) -> Result<Release> {
+    let url = conf.get_url();
+    let body = fetch(url)?;
+    serde_json::from_slice(&body).with_context(|| format!("Unexpected release info from {}", url))
+}
+
+/// Topic the running version, and the latest release if checked for, are
+/// published to
+pub fn version_topic(status_topic: &str) -> String {
+    format!("{}/version", status_topic)
+}
+
+/// Publishes the running version, retained, after checking for a newer
+/// release if configured to. Checking happens in the background, so that a
+/// slow or unreachable release server doesn't hold up startup.
+pub fn announce(conf: UpdateConfig, sink: Option<MqttSink>) {
+    std::thread::spawn(move || {
+        let mut announcement = serde_json::json!({ "version": crate_version!() });
+        if conf.check {
+            match latest_release(&conf).and_then(|r| Ok((r.is_newer()?, r))) {
+                Ok((true, release)) => {
+                    log::warn!(
+                        "{} {} is available (running {}){}",
+                        crate_name!(),
+                        release.version,
+                        crate_version!(),
+                        release
+                            .html_url
+                            .as_ref()
+                            .map(|url| format!(": {}", url))
+                            .unwrap_or_default()
+                    );
+                    announcement["latest"] = release.version.into();
+                    announcement["update_available"] = true.into();
+                }
+                Ok((false, release)) => {
+                    log::info!("{} is the latest release", release.version);
+                    announcement["latest"] = release.version.into();
+                    announcement["update_available"] = false.into();
+                }
+                Err(e) => log::warn!("Failed checking for updates: {:#}", e),
+            }
+        }
+        if let Some(sink) = sink {
+            let topic = version_topic(sink.status_topic());
+            if let Err(e) = sink.publish_retained(&topic, announcement.to_string(), 1) {
+                log::error!("Failed to publish version: {:?}", e);
+            }
+        }
+    });
+}
+
+/// Replaces the running executable with the latest release's binary for
+/// this platform, returning the version installed, or `None` if already up
+/// to date
+pub fn self_update(conf: &UpdateConfig) -> Result<Option<String>> {
+    let release = latest_release(conf)?;
+    if !release.is_newer()? {
+        return Ok(None);
+    }
+    let asset = release
+        .asset_for_platform()
+        .ok_or_else(|| UpdateError::NoAsset {
+            version: release.version.clone(),
+            platform: format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+        })?;
+    let expected = release.checksum_for(asset)?;
+    let exe = std::env::current_exe().with_context(|| "Unable to locate running executable")?;
+    log::info!("Downloading {} to {}", asset.name, exe.display());
+    let binary = fetch(&asset.browser_download_url)?;
+    let actual: String = Sha256::digest(&binary)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if actual != expected {
+        return Err(UpdateError::ChecksumMismatch {
+            asset: asset.name.clone(),
+            expected,
+            actual,
+        }
+        .into());
+    }
+    replace_executable(&exe, &binary)
+        .with_context(|| format!("Failed to replace {}", exe.display()))?;
+    Ok(Some(release.version))
+}
+
+// Writes the new binary alongside the old one, then renames it into place,
+// so that a failed download never leaves a partial executable behind
+#[cfg(unix)]
+fn replace_executable(exe: &std::path::Path, binary: &[u8]) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let staged = exe.with_extension("new");
+    std::fs::write(&staged, binary)?;
+    std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o755))?;
+    std::fs::rename(&staged, exe)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn replace_executable(_exe: &std::path::Path, _binary: &[u8]) -> Result<()> {
+    Err(UpdateError::UnsupportedPlatform.into())
+}
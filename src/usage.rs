@@ -0,0 +1,161 @@
+//! Anonymous statistics of which devices are heard and how many of their
+//! records decode, to help choose the decoders to add next
+//!
+//! Every rtl_433 record is counted under its model, along with whether the
+//! bridge could decode it, and nothing else of it: no sensor ids, no
+//! readings, no times. Submitting the counts is strictly opt-in, with
+//! `usage` in the configuration file, which POSTs them to its `endpoint`
+//! every `interval` and starts counting afresh; the counts of the last,
+//! partial interval are dropped when the bridge stops. The same report can
+//! be printed from a capture with `weatherradio usage`, without sending
+//! anything anywhere.
+
+use std::collections::BTreeMap;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::ConfigError;
+use crate::errors::{ErrorCode, ErrorEvent};
+use crate::events::{Event, EventBus};
+use crate::radio::Record;
+
+fn default_interval() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+/// Submitting anonymous usage statistics
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UsageConfig {
+    /// Where the statistics are POSTed, as json
+    pub endpoint: String,
+    /// Seconds between submissions
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+impl UsageConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !self.endpoint.starts_with("http://") && !self.endpoint.starts_with("https://") {
+            return Err(ConfigError::UsageEndpoint(self.endpoint.clone()));
+        }
+        if self.interval == 0 {
+            return Err(ConfigError::UsageInterval);
+        }
+        Ok(())
+    }
+}
+
+/// The records of a model heard
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ModelUsage {
+    pub records: u64,
+    /// How many of them the bridge got measurements out of
+    pub decoded: u64,
+}
+
+/// The statistics submitted, of the records heard over `seconds`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Report {
+    /// Release of the bridge counting them
+    pub version: &'static str,
+    pub seconds: u64,
+    pub models: BTreeMap<String, ModelUsage>,
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (model, usage) in &self.models {
+            writeln!(
+                f,
+                "{}: {} records, {} decoded ({:.0}%)",
+                model,
+                usage.records,
+                usage.decoded,
+                100.0 * usage.decoded as f64 / usage.records as f64
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// The records counted since the last report
+static COUNTS: Mutex<BTreeMap<String, ModelUsage>> = Mutex::new(BTreeMap::new());
+
+/// Counts a record rtl_433 reported, as decoded when the bridge got any
+/// measurements out of it
+pub fn observe(json: &serde_json::Value, record: Option<&Record>) {
+    let decoded = record.is_some_and(|record| !record.measurements.is_empty());
+    let model = match json.get("model").and_then(|model| model.as_str()) {
+        Some(model) => model,
+        None => return,
+    };
+    let mut counts = COUNTS.lock().expect("usage counts poisoned");
+    let usage = match counts.get_mut(model) {
+        Some(usage) => usage,
+        None => counts.entry(model.to_owned()).or_default(),
+    };
+    usage.records += 1;
+    if decoded {
+        usage.decoded += 1;
+    }
+}
+
+/// Takes the records counted, over the `seconds` they were counted for, and
+/// starts counting afresh
+pub fn report(seconds: u64) -> Report {
+    let models = std::mem::take(&mut *COUNTS.lock().expect("usage counts poisoned"));
+    Report {
+        version: clap::crate_version!(),
+        seconds,
+        models,
+    }
+}
+
+/// Starts a background thread submitting the statistics every interval,
+/// until the radio stops
+pub fn spawn_reporter(
+    conf: UsageConfig,
+    events: Receiver<Event>,
+    errors: EventBus,
+) -> JoinHandle<()> {
+    let interval = Duration::from_secs(conf.interval);
+    std::thread::spawn(move || {
+        let mut started = Instant::now();
+        loop {
+            match events.recv_timeout(interval.saturating_sub(started.elapsed())) {
+                Ok(Event::RadioStopped) | Err(RecvTimeoutError::Disconnected) => return,
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            }
+            if started.elapsed() < interval {
+                continue;
+            }
+            let report = report(started.elapsed().as_secs());
+            started = Instant::now();
+            if report.models.is_empty() {
+                continue;
+            }
+            let result = serde_json::to_string(&report)
+                .map_err(anyhow::Error::from)
+                .and_then(|body| crate::webhook::post(&conf.endpoint, &body));
+            match result {
+                Ok(()) => log::info!(
+                    "Submitted usage statistics of {} models to {}",
+                    report.models.len(),
+                    conf.endpoint
+                ),
+                Err(e) => {
+                    let error = format!("Failed to submit usage statistics: {:#}", e);
+                    log::warn!("{}", error);
+                    errors.publish(Event::Error(
+                        ErrorEvent::new(ErrorCode::Usage, error)
+                            .context("endpoint", &conf.endpoint),
+                    ));
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,190 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use uom::si::thermodynamic_temperature;
+
+use crate::grace::StartupGrace;
+use crate::radio::{Measurement, Record};
+
+pub const VALIDATED_SUFFIX: &str = "validated";
+
+#[derive(Debug)]
+struct Pending {
+    topic: String,
+    record: Record,
+    received: Instant,
+    confirmations: u32,
+}
+
+#[derive(Debug, Default)]
+struct ValidatorState {
+    pending: VecDeque<Pending>,
+    last_valid: HashMap<String, Record>,
+}
+
+/// Holds records back for a short delay before republishing them on a
+/// `validated` topic, only once repeated transmissions of the same reading,
+/// e.g. heard by another receiver, have confirmed it, and rejecting readings
+/// that jump implausibly far from the sensor's last validated reading.
+#[derive(Clone, Debug)]
+pub struct Validator {
+    state: Arc<Mutex<ValidatorState>>,
+    delay: Duration,
+    confirmations: u32,
+    grace: StartupGrace,
+    log_level: log::Level,
+}
+
+impl Validator {
+    pub fn new(delay: Duration) -> Self {
+        Validator {
+            state: Arc::new(Mutex::new(ValidatorState::default())),
+            delay,
+            confirmations: 1,
+            grace: StartupGrace::default(),
+            log_level: log::Level::Info,
+        }
+    }
+
+    /// Requires `confirmations` repeats of a reading within the delay before
+    /// publishing it
+    pub fn confirmations(mut self, confirmations: u32) -> Self {
+        self.confirmations = confirmations;
+        self
+    }
+
+    /// Logs published records at `level`
+    pub fn log_level(mut self, level: log::Level) -> Self {
+        self.log_level = level;
+        self
+    }
+
+    /// Accepts readings without checking them against the last validated
+    /// reading during the startup grace period
+    pub fn grace(mut self, grace: StartupGrace) -> Self {
+        self.grace = grace;
+        self
+    }
+
+    /// Queues a record for validation, or counts it as a confirmation of an
+    /// identical reading already awaiting validation. Should be called with
+    /// every record received, including duplicates.
+    pub fn observe(&self, topic: &str, record: &Record) {
+        let mut state = self.state.lock().expect("validator state poisoned");
+        if let Some(pending) = state.pending.iter_mut().find(|p| {
+            p.record.sensor_id == record.sensor_id && p.record.measurements == record.measurements
+        }) {
+            pending.confirmations += 1;
+            return;
+        }
+        state.pending.push_back(Pending {
+            topic: topic.to_owned(),
+            record: record.clone(),
+            received: Instant::now(),
+            confirmations: 0,
+        });
+    }
+
+    /// Removes records whose validation delay has elapsed, returning the
+    /// topic and payload of those that passed validation
+    fn due(&self) -> Vec<(String, serde_json::Value)> {
+        let mut state = self.state.lock().expect("validator state poisoned");
+        let mut validated = Vec::new();
+        while state
+            .pending
+            .front()
+            .map(|p| p.received.elapsed() >= self.delay)
+            .unwrap_or(false)
+        {
+            let pending = state.pending.pop_front().expect("pending record vanished");
+            if pending.confirmations < self.confirmations {
+                log::debug!(
+                    "[{}] Dropping reading with {} of {} confirmations",
+                    pending.record.sensor_id,
+                    pending.confirmations,
+                    self.confirmations
+                );
+                continue;
+            }
+            let previous = state
+                .last_valid
+                .get(&pending.record.sensor_id)
+                .filter(|_| !self.grace.is_active());
+            if let Some(previous) = previous {
+                let outliers = outliers(previous, &pending.record);
+                if !outliers.is_empty() {
+                    log::warn!(
+                        "[{}] Rejecting reading with implausible change: {}",
+                        pending.record.sensor_id,
+                        outliers.join(", ")
+                    );
+                    continue;
+                }
+            }
+            let mut payload = pending.record.record_json.clone();
+            if let serde_json::Value::Object(ref mut m) = payload {
+                m.insert("confirmations".to_owned(), pending.confirmations.into());
+            }
+            validated.push((format!("{}/{}", pending.topic, VALIDATED_SUFFIX), payload));
+            state
+                .last_valid
+                .insert(pending.record.sensor_id.clone(), pending.record);
+        }
+        validated
+    }
+
+    /// Starts a background thread that publishes records as their
+    /// validation delay elapses
+    pub fn spawn_publisher(&self, sink: crate::sink::MqttSink) {
+        let validator = self.clone();
+        let period = std::cmp::min(validator.delay, Duration::from_secs(1));
+        std::thread::spawn(move || loop {
+            std::thread::sleep(period);
+            for (topic, payload) in validator.due() {
+                let bytes = match serde_json::to_vec(&payload) {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        log::error!("Failed to serialize validated record: {:?}", e);
+                        continue;
+                    }
+                };
+                match sink.publish(&topic, bytes) {
+                    Ok(()) => log::log!(validator.log_level, "mqtt <== {}({})", topic, payload),
+                    Err(e) => log::error!("Failed to publish validated record: {:?}", e),
+                }
+            }
+        });
+    }
+}
+
+/// Describes each measurement that changed implausibly far between two
+/// readings from the same sensor
+fn outliers(previous: &Record, current: &Record) -> Vec<String> {
+    current
+        .measurements
+        .iter()
+        .filter_map(|cur| {
+            let prev = previous
+                .measurements
+                .iter()
+                .find(|p| std::mem::discriminant(*p) == std::mem::discriminant(cur))?;
+            let plausible = match (prev, cur) {
+                (Measurement::Temperature(p), Measurement::Temperature(c)) => {
+                    let p = p.get::<thermodynamic_temperature::degree_celsius>();
+                    let c = c.get::<thermodynamic_temperature::degree_celsius>();
+                    (c - p).abs() <= 10.0
+                }
+                (Measurement::RelativeHumidity(p), Measurement::RelativeHumidity(c)) => {
+                    (i16::from(*c) - i16::from(*p)).abs() <= 30 && *c <= 100
+                }
+                _ => true,
+            };
+            if plausible {
+                None
+            } else {
+                Some(format!("{} -> {}", prev, cur))
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,242 @@
+//! Notifications POSTed to webhooks when sensors' records match a rule, e.g.
+//! to ntfy, Gotify or a Slack incoming webhook, without an integration for
+//! each
+//!
+//! A rule matches the records of the sensors matching its pattern, and, when
+//! it watches a measurement, only those with a reading of it, by its
+//! normalized name and in its metric unit. With a threshold, a rule fires
+//! when the reading crosses it, and again only once the reading has been
+//! back past it by more than the hysteresis, so that a reading hovering
+//! around the threshold doesn't flood the webhook. The body POSTed is the
+//! record's normalized payload, or the rule's template with `{name}`
+//! placeholders filled from it. Notifications are sent with `curl`, and a
+//! failed one is reported on the event bus and not tried again.
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::Receiver;
+use std::thread::JoinHandle;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{ConfigError, SensorPattern};
+use crate::errors::{ErrorCode, ErrorEvent};
+use crate::events::{Event, EventBus};
+use crate::radio::Record;
+use crate::units::UnitSystem;
+
+// Seconds to wait on a webhook before giving up
+const TIMEOUT_SECS: &str = "30";
+
+/// A rule POSTing a notification to webhooks for the records matching it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Webhook {
+    /// Where notifications are POSTed
+    pub urls: Vec<String>,
+    /// Sensors the rule applies to, as a [`SensorPattern`]
+    pub sensor: String,
+    /// Normalized name of the measurement watched, e.g. `temperature_c`;
+    /// every record of the sensors matches when unset
+    #[serde(default)]
+    pub measurement: Option<String>,
+    /// Fires when the measurement drops below this value
+    #[serde(default)]
+    pub below: Option<f64>,
+    /// Fires when the measurement rises above this value
+    #[serde(default)]
+    pub above: Option<f64>,
+    /// How far back past the threshold the value has to go before the rule
+    /// can fire again
+    #[serde(default)]
+    pub hysteresis: f64,
+    /// Template of the body POSTed, with placeholders like `{sensor}`,
+    /// `{value}` or `{humidity}` filled from the record; the record's
+    /// normalized payload when unset
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+impl Webhook {
+    // Whether `value` is beyond the threshold, given whether it was
+    fn beyond(&self, value: f64, was: bool) -> bool {
+        let margin = if was { self.hysteresis } else { 0.0 };
+        self.below
+            .map(|below| value < below + margin)
+            .unwrap_or(false)
+            || self
+                .above
+                .map(|above| value > above - margin)
+                .unwrap_or(false)
+    }
+}
+
+// The text a placeholder is filled with, escaped for the inside of a json
+// string
+fn placeholder(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(s) => {
+            let quoted = serde_json::Value::String(s.clone()).to_string();
+            quoted[1..quoted.len() - 1].to_owned()
+        }
+        value => value.to_string(),
+    }
+}
+
+/// Fills the `{name}` placeholders in `template`: the fields of the
+/// normalized `payload`, e.g. `{sensor}` or `{ts}`, its measurements, e.g.
+/// `{temperature_c}`, and `{measurement}` and `{value}` for the measurement
+/// the rule watches. Unknown placeholders are left empty, and braces around
+/// anything but a name, as in json objects, are left alone.
+pub fn render(template: &str, payload: &serde_json::Value, watched: Option<(&str, f64)>) -> String {
+    let placeholders = regex::Regex::new(r"\{([a-z0-9_]+)\}").expect("valid regex");
+    placeholders
+        .replace_all(template, |caps: &regex::Captures| {
+            let name = &caps[1];
+            let value = match (name, watched) {
+                ("measurement", Some((measurement, _))) => measurement.into(),
+                ("value", Some((_, value))) => value.into(),
+                (name, _) => payload
+                    .get(name)
+                    .or_else(|| payload["measurements"].get(name))
+                    .cloned()
+                    .unwrap_or_default(),
+            };
+            placeholder(&value)
+        })
+        .into_owned()
+}
+
+/// POSTs a json body to a webhook
+pub fn post(url: &str, body: &str) -> Result<()> {
+    let mut curl = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error"])
+        .args(["--max-time", TIMEOUT_SECS])
+        .args(["--header", "Content-Type: application/json"])
+        .args(["--data-binary", "@-"])
+        .arg(url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| "Unable to run curl")?;
+    if let Some(mut stdin) = curl.stdin.take() {
+        stdin
+            .write_all(body.as_bytes())
+            .with_context(|| "Failed to pass the body to curl")?;
+    }
+    let output = curl.wait_with_output()?;
+    if !output.status.success() {
+        anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
+
+/// Evaluates the webhook rules against each record, remembering which
+/// sensors' readings are beyond their rules' thresholds
+#[derive(Debug, Default)]
+pub struct Notifier {
+    rules: Vec<(SensorPattern, Webhook)>,
+    // Rules, by index, and sensors whose readings are beyond the threshold
+    beyond: HashSet<(usize, String)>,
+}
+
+impl Notifier {
+    pub fn new(webhooks: &[Webhook]) -> Result<Self, ConfigError> {
+        let rules = webhooks
+            .iter()
+            .map(|webhook| {
+                let threshold = webhook.below.is_some() || webhook.above.is_some();
+                if threshold && webhook.measurement.is_none() {
+                    return Err(ConfigError::WebhookThreshold(webhook.sensor.clone()));
+                }
+                Ok((webhook.sensor.parse()?, webhook.clone()))
+            })
+            .collect::<Result<Vec<(SensorPattern, Webhook)>, ConfigError>>()?;
+        Ok(Notifier {
+            rules,
+            beyond: HashSet::new(),
+        })
+    }
+
+    /// The rules that fire for the record, and the bodies to POST for them
+    pub fn evaluate(&mut self, record: &Record) -> Vec<(&Webhook, String)> {
+        let payload = crate::normalize::normalize(record, UnitSystem::Metric, false, false);
+        let mut fired = Vec::new();
+        for (i, (pattern, rule)) in self.rules.iter().enumerate() {
+            if !pattern.matches(&record.sensor_id) {
+                continue;
+            }
+            let watched = match rule.measurement {
+                Some(ref name) => {
+                    let value = record
+                        .measurements
+                        .iter()
+                        .find(|m| crate::normalize::key(m).as_deref() == Some(name.as_str()))
+                        .and_then(|m| m.numeric_in(Some(UnitSystem::Metric)));
+                    match value {
+                        // As rounded in normalized payloads
+                        Some(value) => Some((name.as_str(), (value * 1000.0).round() / 1000.0)),
+                        None => continue,
+                    }
+                }
+                None => None,
+            };
+            if let (Some((_, value)), true) =
+                (watched, rule.below.is_some() || rule.above.is_some())
+            {
+                let key = (i, record.sensor_id.clone());
+                let was = self.beyond.contains(&key);
+                let beyond = rule.beyond(value, was);
+                match beyond {
+                    true => self.beyond.insert(key),
+                    false => self.beyond.remove(&key),
+                };
+                if !beyond || was {
+                    continue;
+                }
+            }
+            let body = match rule.body {
+                Some(ref template) => render(template, &payload, watched),
+                None => payload.to_string(),
+            };
+            fired.push((rule, body));
+        }
+        fired
+    }
+}
+
+/// Starts a background thread that POSTs notifications for the records on
+/// `events` matching the webhook rules, until the radio stops
+pub fn spawn_notifier(
+    webhooks: &[Webhook],
+    events: Receiver<Event>,
+    errors: EventBus,
+) -> Result<JoinHandle<()>, ConfigError> {
+    let mut notifier = Notifier::new(webhooks)?;
+    Ok(std::thread::spawn(move || {
+        for event in events {
+            let record = match event {
+                Event::Record(record) => record,
+                Event::RadioStopped => return,
+                _ => continue,
+            };
+            for (rule, body) in notifier.evaluate(&record) {
+                for url in &rule.urls {
+                    match post(url, &body) {
+                        Ok(()) => log::info!("[{}] Notified {}", record.sensor_id, url),
+                        Err(e) => {
+                            log::error!("Failed to notify {}: {:#}", url, e);
+                            errors.publish(Event::Error(
+                                ErrorEvent::new(ErrorCode::Webhook, format!("{:#}", e))
+                                    .context("url", url),
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+    }))
+}